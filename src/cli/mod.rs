@@ -45,6 +45,22 @@ pub fn parse_cli_args() -> Args {
             p.args.subcommand = "info";
             info(&mut p);
         }
+        "r" | "render" => {
+            p.args.subcommand = "render";
+            render(&mut p);
+        }
+        "t" | "textures" => {
+            p.args.subcommand = "textures";
+            textures(&mut p);
+        }
+        "import" => {
+            p.args.subcommand = "import";
+            import(&mut p);
+        }
+        "encode-texture" => {
+            p.args.subcommand = "encode-texture";
+            encode_texture(&mut p);
+        }
         "help" => {
             p.args.subcommand = "help";
             help(&mut p);
@@ -90,9 +106,93 @@ static MORE_TEXTURES_OPT: Opt = Opt {
     short: "", long: "more-textures", flag: true,
     help: "--more-textures           try to dump images for unused textures too",
 };
+static SCENE_OPT: Opt = Opt {
+    short: "", long: "scene", flag: true,
+    help: "--scene                   put all the models in a file in one glTF scene",
+};
 static FORMAT_OPT: Opt = Opt {
     short: "f", long: "format", flag: false,
-    help: "-f, --format <format>     output model format (dae, glb, gltf)",
+    help: "-f, --format <format>     output model format (dae, glb, gltf, obj)",
+};
+static PATTERNS_OPT: Opt = Opt {
+    short: "", long: "patterns", flag: false,
+    help: "--patterns <mode>         export pattern anims to glTF as an atlas or as material variants (atlas, variants)",
+};
+static MODEL_OPT: Opt = Opt {
+    short: "", long: "model", flag: false,
+    help: "--model <name>            only use models with this name, regex, or index",
+};
+static FILE_OPT: Opt = Opt {
+    short: "", long: "file", flag: false,
+    help: "--file <path>             only use models from the file with this path (or path ending)",
+};
+static ANIMATION_OPT: Opt = Opt {
+    short: "", long: "animation", flag: false,
+    help: "--animation <name>        pose the model with the joint anim with this name, regex, or index",
+};
+static ONLY_ANIMATION_OPT: Opt = Opt {
+    short: "", long: "animation", flag: false,
+    help: "--animation <name>        only attach joint anims with this name, regex, or index",
+};
+static START_OPT: Opt = Opt {
+    short: "", long: "start", flag: false,
+    help: "--start <name>            start at the model with this name, regex, or index",
+};
+static FRAME_OPT: Opt = Opt {
+    short: "", long: "frame", flag: false,
+    help: "--frame <n>               animation frame to pose the model at (default 0)",
+};
+static POSE_OPT: Opt = Opt {
+    short: "", long: "pose", flag: false,
+    help: "--pose <anim>:<frame>     bake this frame of an animation into a static mesh",
+};
+static TURNTABLE_OPT: Opt = Opt {
+    short: "", long: "turntable", flag: false,
+    help: "--turntable <n>           render n images going once around the model",
+};
+static JSON_OPT: Opt = Opt {
+    short: "", long: "json", flag: true,
+    help: "--json                    print the info as JSON",
+};
+static PALETTES_OPT: Opt = Opt {
+    short: "", long: "palettes", flag: false,
+    help: "--palettes <mode>         which palettes to decode textures with (matched, all)",
+};
+static TEXTURE_FORMAT_OPT: Opt = Opt {
+    short: "f", long: "format", flag: false,
+    help: "-f, --format <format>     texture format (a3i5, pal4, pal16, pal256, 4x4, a5i3, direct)",
+};
+static INTO_OPT: Opt = Opt {
+    short: "", long: "into", flag: false,
+    help: "--into <file>             replace textures with the same names in this .nsbmd/.nsbtx",
+};
+static SIZE_OPT: Opt = Opt {
+    short: "", long: "size", flag: false,
+    help: "--size <w>x<h>            size of the images (default 256x256)",
+};
+static CONNECTIONS_OPT: Opt = Opt {
+    short: "", long: "connections", flag: false,
+    help: "--connections <file>      JSON file saying which textures/anims go with which models",
+};
+static RECURSIVE_OPT: Opt = Opt {
+    short: "r", long: "recursive", flag: true,
+    help: "-r, --recursive           look for files in subdirectories of input directories too",
+};
+static INCLUDE_OPT: Opt = Opt {
+    short: "", long: "include", flag: false,
+    help: "--include <globs>         only use files in input directories matching these (comma-separated)",
+};
+static EXCLUDE_OPT: Opt = Opt {
+    short: "", long: "exclude", flag: false,
+    help: "--exclude <globs>         skip files in input directories matching these (comma-separated)",
+};
+static EXT_OPT: Opt = Opt {
+    short: "", long: "ext", flag: false,
+    help: "--ext <exts>              only use files in input directories with these extensions (eg. nsbmd,nsbtx)",
+};
+static DUMP_CONNECTIONS_OPT: Opt = Opt {
+    short: "", long: "dump-connections", flag: true,
+    help: "--dump-connections        print the connections in the --connections format",
 };
 
 
@@ -121,8 +221,12 @@ fn show_usage_and_exit() -> ! {
         "\n",
         "    extract        Extract Nitro files\n",
         "    view           Nitro model viewer\n",
-        "    convert        Convert Nitro models to .dae/.gltf\n",
+        "    convert        Convert Nitro models to .dae/.gltf/.obj\n",
         "    info           Display debugging info for Nitro files\n",
+        "    render         Render Nitro models to PNG images\n",
+        "    textures       Dump all textures to PNGs\n",
+        "    import         Make a Nitro model from a .gltf/.glb/.dae\n",
+        "    encode-texture Encode PNGs as Nitro textures\n",
         "    help           Display help\n",
         "\n",
         "  Run `apicula help COMMAND` for more information on specific commands.\n",
@@ -140,6 +244,10 @@ fn help(p: &mut Parse) -> ! {
         Some("view") => show_view_help_and_exit(),
         Some("convert") => show_convert_help_and_exit(),
         Some("info") => show_info_help_and_exit(),
+        Some("render") => show_render_help_and_exit(),
+        Some("textures") => show_textures_help_and_exit(),
+        Some("import") => show_import_help_and_exit(),
+        Some("encode-texture") => show_encode_texture_help_and_exit(),
         _ => show_usage_and_exit(),
     }
 }
@@ -167,7 +275,8 @@ fn show_extract_help_and_exit() -> ! {
         "  Usage: apicula extract <input> -o <outdir>\n",
         "\n",
         "  Extract Nitro files (models, textures, animations, etc.) from <input>.\n",
-        "  Try it on an .nds rom.\n",
+        "  Try it on an .nds rom. Files in the ROM's filesystem are extracted\n",
+        "  with their original paths.\n",
         "\n",
     ));
     show_opts_help(EXTRACT_OPTS);
@@ -176,7 +285,7 @@ fn show_extract_help_and_exit() -> ! {
 }
 
 
-static INFO_OPTS: &[&Opt] = &[&HELP_OPT];
+static INFO_OPTS: &[&Opt] = &[&JSON_OPT, &DUMP_CONNECTIONS_OPT, &CONNECTIONS_OPT, &RECURSIVE_OPT, &INCLUDE_OPT, &EXCLUDE_OPT, &EXT_OPT, &HELP_OPT];
 
 fn info(p: &mut Parse) {
     parse_opts(p, INFO_OPTS);
@@ -199,7 +308,7 @@ fn show_info_help_and_exit() -> ! {
 }
 
 
-static VIEW_OPTS: &[&Opt] = &[&ALL_ANIMATIONS_OPT, &HELP_OPT];
+static VIEW_OPTS: &[&Opt] = &[&START_OPT, &ONLY_ANIMATION_OPT, &ALL_ANIMATIONS_OPT, &CONNECTIONS_OPT, &RECURSIVE_OPT, &INCLUDE_OPT, &EXCLUDE_OPT, &EXT_OPT, &HELP_OPT];
 
 fn view(p: &mut Parse) {
     parse_opts(p, VIEW_OPTS);
@@ -222,13 +331,15 @@ fn show_view_help_and_exit() -> ! {
 }
 
 
-static CONVERT_OPTS: &[&Opt] = &[&OUTPUT_OPT, &FORMAT_OPT, &OVERWRITE_OPT, &MORE_TEXTURES_OPT, &ALL_ANIMATIONS_OPT, &HELP_OPT];
+static CONVERT_OPTS: &[&Opt] = &[&OUTPUT_OPT, &FORMAT_OPT, &OVERWRITE_OPT, &MODEL_OPT, &FILE_OPT, &ONLY_ANIMATION_OPT, &MORE_TEXTURES_OPT, &ALL_ANIMATIONS_OPT, &POSE_OPT, &SCENE_OPT, &CONNECTIONS_OPT, &PATTERNS_OPT, &RECURSIVE_OPT, &INCLUDE_OPT, &EXCLUDE_OPT, &EXT_OPT, &HELP_OPT];
 
 fn convert(p: &mut Parse) {
     parse_opts(p, CONVERT_OPTS);
     if p.args.flags.contains(&"help") { show_convert_help_and_exit(); }
     check_nitro_input(p);
     check_format(p);
+    check_patterns(p);
+    check_pose(p);
     check_output_dir(p);
 }
 
@@ -237,7 +348,7 @@ fn show_convert_help_and_exit() -> ! {
         "\n",
         "  Usage: apicula convert <input>... -o <ourdir>\n",
         "\n",
-        "  Converts Nitro models to .dae/.gltf. Default is .dae.\n",
+        "  Converts Nitro models to .dae/.gltf/.obj. Default is .dae.\n",
         "  The textures and animations on each model will be the same as with `apicula view`.\n",
         "\n",
     ));
@@ -247,6 +358,120 @@ fn show_convert_help_and_exit() -> ! {
 }
 
 
+static RENDER_OPTS: &[&Opt] = &[&OUTPUT_OPT, &OVERWRITE_OPT, &MODEL_OPT, &FILE_OPT, &ANIMATION_OPT, &FRAME_OPT, &TURNTABLE_OPT, &SIZE_OPT, &ALL_ANIMATIONS_OPT, &CONNECTIONS_OPT, &RECURSIVE_OPT, &INCLUDE_OPT, &EXCLUDE_OPT, &EXT_OPT, &HELP_OPT];
+
+fn render(p: &mut Parse) {
+    parse_opts(p, RENDER_OPTS);
+    if p.args.flags.contains(&"help") { show_render_help_and_exit(); }
+    check_nitro_input(p);
+    check_number(p, "frame");
+    check_number(p, "turntable");
+    check_size(p);
+    check_output_dir(p);
+}
+
+fn show_render_help_and_exit() -> ! {
+    print!(concat!(
+        "\n",
+        "  Usage: apicula render <input>... -o <outdir>\n",
+        "\n",
+        "  Renders Nitro models to PNG images, without needing a GPU.\n",
+        "  Writes one still per model, or a sequence of images turning around\n",
+        "  the model with --turntable.\n",
+        "\n",
+    ));
+    show_opts_help(RENDER_OPTS);
+    println!();
+    exit(0);
+}
+
+
+static TEXTURES_OPTS: &[&Opt] = &[&OUTPUT_OPT, &OVERWRITE_OPT, &PALETTES_OPT, &RECURSIVE_OPT, &INCLUDE_OPT, &EXCLUDE_OPT, &EXT_OPT, &HELP_OPT];
+
+fn textures(p: &mut Parse) {
+    parse_opts(p, TEXTURES_OPTS);
+    if p.args.flags.contains(&"help") { show_textures_help_and_exit(); }
+    check_nitro_input(p);
+    check_palettes(p);
+    check_output_dir(p);
+}
+
+fn show_textures_help_and_exit() -> ! {
+    print!(concat!(
+        "\n",
+        "  Usage: apicula textures <input>... -o <outdir>\n",
+        "\n",
+        "  Decodes every texture to a PNG, whether or not a model uses it, and\n",
+        "  writes textures.json describing each image. By default textures are\n",
+        "  paired with palettes by name; --palettes all decodes each texture with\n",
+        "  every palette big enough for it.\n",
+        "\n",
+    ));
+    show_opts_help(TEXTURES_OPTS);
+    println!();
+    exit(0);
+}
+
+
+static IMPORT_OPTS: &[&Opt] = &[&OUTPUT_OPT, &OVERWRITE_OPT, &HELP_OPT];
+
+fn import(p: &mut Parse) {
+    parse_opts(p, IMPORT_OPTS);
+    if p.args.flags.contains(&"help") { show_import_help_and_exit(); }
+    if p.args.free_args.len() != 1 {
+        error!("give me one .gltf, .glb, or .dae file to import");
+        exit(1);
+    }
+    check_output_dir(p);
+}
+
+fn show_import_help_and_exit() -> ! {
+    print!(concat!(
+        "\n",
+        "  Usage: apicula import <input> -o <outdir>\n",
+        "\n",
+        "  Makes an .nsbmd from a glTF (.gltf or .glb) or COLLADA (.dae) model.\n",
+        "  Skinning, materials, and texture references are kept. Textures\n",
+        "  themselves aren't imported.\n",
+        "\n",
+    ));
+    show_opts_help(IMPORT_OPTS);
+    println!();
+    exit(0);
+}
+
+
+static ENCODE_TEXTURE_OPTS: &[&Opt] = &[&OUTPUT_OPT, &TEXTURE_FORMAT_OPT, &INTO_OPT, &OVERWRITE_OPT, &HELP_OPT];
+
+fn encode_texture(p: &mut Parse) {
+    parse_opts(p, ENCODE_TEXTURE_OPTS);
+    if p.args.flags.contains(&"help") { show_encode_texture_help_and_exit(); }
+    if p.args.free_args.is_empty() {
+        error!("give me some PNGs to encode");
+        exit(1);
+    }
+    check_texture_format(p);
+    check_output_dir(p);
+}
+
+fn show_encode_texture_help_and_exit() -> ! {
+    print!(concat!(
+        "\n",
+        "  Usage: apicula encode-texture <png>... -f <format> -o <outdir>\n",
+        "\n",
+        "  Encodes PNGs as Nitro textures, named after the PNG files, and writes\n",
+        "  them to a new .nsbtx. With --into, the textures (and their palettes,\n",
+        "  named <texture>_pl) replace the ones with the same names in the given\n",
+        "  file instead, and the changed file is written to <outdir>.\n",
+        "  Images must have power-of-two sizes from 8 to 1024.\n",
+        "\n",
+    ));
+    show_opts_help(ENCODE_TEXTURE_OPTS);
+    println!();
+    exit(0);
+}
+
+
 fn check_nitro_input(p: &Parse) {
     if p.args.free_args.is_empty() {
         error!("give me some input files");
@@ -254,19 +479,105 @@ fn check_nitro_input(p: &Parse) {
     }
 }
 
+fn check_patterns(p: &Parse) {
+    let mode = p.args.get_opt("patterns");
+    if let Some(mode) = mode {
+        match mode.to_str() {
+            Some("atlas") | Some("variants") => (),
+            _ => {
+                error!("bad pattern export mode, should be one of: atlas variants");
+                exit(1);
+            }
+        }
+    }
+}
+
+fn check_palettes(p: &Parse) {
+    let mode = p.args.get_opt("palettes");
+    if let Some(mode) = mode {
+        match mode.to_str() {
+            Some("matched") | Some("all") => (),
+            _ => {
+                error!("bad palette mode, should be one of: matched all");
+                exit(1);
+            }
+        }
+    }
+}
+
 fn check_format(p: &Parse) {
     let format = p.args.get_opt("format");
     if let Some(format) = format {
         match format.to_str() {
-            Some("dae") | Some("glb") | Some("gltf") => (),
+            Some("dae") | Some("glb") | Some("gltf") | Some("obj") => (),
             _ => {
-                error!("bad output format, should be one of: dae glb gltf");
+                error!("bad output format, should be one of: dae glb gltf obj");
                 exit(1);
             }
         }
     }
 }
 
+fn check_texture_format(p: &Parse) {
+    use crate::nds::TextureFormat;
+    let format = p.args.get_opt("format");
+    if format.is_none() {
+        error!("which texture format? Pass it with --format");
+        exit(1);
+    }
+    if format.unwrap().to_str().and_then(TextureFormat::from_short_name).is_none() {
+        error!("bad texture format, should be one of: a3i5 pal4 pal16 pal256 4x4 a5i3 direct");
+        exit(1);
+    }
+}
+
+fn check_number(p: &Parse, opt: &'static str) {
+    if let Some(x) = p.args.get_opt(opt) {
+        if x.to_str().and_then(|x| x.parse::<u16>().ok()).is_none() {
+            error!("--{} should be a number", opt);
+            exit(1);
+        }
+    }
+}
+
+fn check_size(p: &Parse) {
+    if let Some(size) = p.args.get_opt("size") {
+        if size.to_str().and_then(parse_size).is_none() {
+            error!("bad image size, should look like 256x256");
+            exit(1);
+        }
+    }
+}
+
+/// Parses an image size like "256x192".
+pub fn parse_size(s: &str) -> Option<(u32, u32)> {
+    let mut it = s.splitn(2, 'x');
+    let w = it.next()?.parse::<u32>().ok()?;
+    let h = it.next()?.parse::<u32>().ok()?;
+    if w == 0 || h == 0 || w > 8192 || h > 8192 {
+        return None;
+    }
+    Some((w, h))
+}
+
+fn check_pose(p: &Parse) {
+    if let Some(pose) = p.args.get_opt("pose") {
+        if pose.to_str().and_then(parse_pose).is_none() {
+            error!("bad pose, should look like <animation>:<frame>");
+            exit(1);
+        }
+    }
+}
+
+/// Parses a pose like "walk:12" into the animation name and the frame.
+pub fn parse_pose(s: &str) -> Option<(&str, u16)> {
+    let (name, frame) = s.rsplit_once(':')?;
+    if name.is_empty() {
+        return None;
+    }
+    Some((name, frame.parse::<u16>().ok()?))
+}
+
 fn check_output_dir(p: &Parse) {
     let output = p.args.get_opt("output");
     if output.is_none() {
@@ -0,0 +1,675 @@
+//! Model connection to textures, animations, etc.
+//!
+//! There is no data in the actual Nitro files (that I know of) that tells us
+//! which animation applies to which model or what texture to use when a model
+//! says to use texture with name such-and-such. Presumably game code would just
+//! call supply the right files to the right calls. That leaves us to figure it
+//! out for ourselves. This modules contains the heuristics for that.
+
+use crate::cli::Args;
+use crate::db::{
+    Database, AnimationId, TextureId, PaletteId, ModelId, PatternId, MatAnimId,
+    MatColorAnimId, VisAnimId, FileId,
+};
+use crate::errors::Result;
+use crate::nitro::{Animation, Name};
+use crate::nitro::animation::Curve;
+use crate::nitro::model::{Material, Model};
+use cgmath::{vec3, Matrix3, SquareMatrix};
+use std::path::Path;
+use std::rc::Rc;
+
+mod overrides;
+pub use self::overrides::{dump as dump_overrides, Overrides};
+
+/// A Connection records interrelationships between Nitro resources, namely how
+/// all the other resources relate to the models.
+pub struct Connection {
+    pub models: Vec<ModelConnection>,
+}
+
+/// Records for a model (1) what texture/palette each material should use, and
+/// (2) which animations can be applied to it.
+pub struct ModelConnection {
+    pub materials: Vec<MaterialConnection>,
+    /// List of animations that can be applied to the model, most likely
+    /// first.
+    pub animations: Vec<AnimationId>,
+    /// How confident we are (0 to 1) that each of the above animations goes
+    /// with the model.
+    pub animation_scores: Vec<f64>,
+    /// List of patterns that can be applied to the model (and how to apply
+    /// them).
+    pub patterns: Vec<PatternConnection>,
+    /// List of material animations that can be applied to the model.
+    pub mat_anims: Vec<MatAnimConnection>,
+    /// List of material color animations that can be applied to the model.
+    pub mat_color_anims: Vec<MatColorAnimId>,
+    /// List of visibility animations that can be applied to the model.
+    pub vis_anims: Vec<VisAnimId>,
+}
+
+/// Result of resolving which texture/palette a material should use.
+pub enum MaterialConnection {
+    NoTexture,
+    TextureMissing,
+    TextureOkNoPalette {
+        texture: Match<TextureId>,
+    },
+    TextureOkPaletteMissing {
+        texture: Match<TextureId>,
+     },
+    TextureOkPaletteOk {
+        texture: Match<TextureId>,
+        palette: Match<PaletteId>,
+    },
+}
+
+/// Result of resolving a texture/palette name to a matching texture/palette ID.
+#[derive(Copy, Clone)]
+pub struct Match<T: Copy> {
+    pub id: T,
+    /// True if the match was the best possible amoung all our candidates
+    /// (high confidence we picked the correct one).
+    pub best: bool,
+}
+
+impl MaterialConnection {
+    pub fn texture(&self) -> Option<Match<TextureId>> {
+        match *self {
+            MaterialConnection::NoTexture |
+            MaterialConnection::TextureMissing =>
+                None,
+            MaterialConnection::TextureOkNoPalette { texture } |
+            MaterialConnection::TextureOkPaletteMissing { texture } |
+            MaterialConnection::TextureOkPaletteOk { texture, .. } =>
+                Some(texture)
+        }
+    }
+
+    pub fn texture_id(&self) -> Option<TextureId> {
+        match *self {
+            MaterialConnection::NoTexture |
+            MaterialConnection::TextureMissing =>
+                None,
+            MaterialConnection::TextureOkNoPalette { texture } |
+            MaterialConnection::TextureOkPaletteMissing { texture } |
+            MaterialConnection::TextureOkPaletteOk { texture, .. } =>
+                Some(texture.id),
+        }
+    }
+
+    pub fn palette_id(&self) -> Option<TextureId> {
+        match *self {
+            MaterialConnection::NoTexture |
+            MaterialConnection::TextureMissing |
+            MaterialConnection::TextureOkNoPalette { .. } |
+            MaterialConnection::TextureOkPaletteMissing { .. } =>
+                None,
+            MaterialConnection::TextureOkPaletteOk { palette, .. } =>
+                Some(palette.id)
+        }
+    }
+
+    /// Produces None if there was no texture, the texture/palette if there was
+    /// and everything resolved sucessfully, or an Err if there was any
+    /// resolving error.
+    pub fn image_id(&self) -> Result<Option<(TextureId, Option<PaletteId>)>> {
+        match *self {
+            MaterialConnection::NoTexture =>
+                Ok(None),
+            MaterialConnection::TextureMissing =>
+                bail!("texture missing"),
+            MaterialConnection::TextureOkNoPalette { texture } =>
+                Ok(Some((texture.id, None))),
+            MaterialConnection::TextureOkPaletteMissing { .. } =>
+                bail!("palette missing"),
+            MaterialConnection::TextureOkPaletteOk { texture, palette } =>
+                Ok(Some((texture.id, Some(palette.id)))),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ConnectionOptions {
+    /// Apply all animations to every model.
+    pub all_animations: bool,
+    /// User-supplied connections that replace the heuristic ones.
+    pub overrides: Option<Rc<Overrides>>,
+}
+
+impl ConnectionOptions {
+    /// Creates a ConnectionOptions from the CLI arguments.
+    pub(crate) fn from_cli_args(args: &Args) -> Result<ConnectionOptions> {
+        let overrides = match args.get_opt("connections") {
+            Some(path) => Some(Rc::new(Overrides::load(Path::new(path))?)),
+            None => None,
+        };
+        Ok(ConnectionOptions {
+            all_animations: args.flags.contains(&"all-animations"),
+            overrides,
+        })
+    }
+}
+
+impl Connection {
+    pub fn build(db: &Database, options: ConnectionOptions) -> Connection {
+        // Record whether we failed to resolve any materials so we can warn
+        let mut missing_textures = false;
+
+        let models = db.models.iter().enumerate().map(|(model_id, model)| {
+            let materials = (0..model.materials.len())
+                .map(|material_id| resolve_material(db, model_id, material_id))
+                .collect();
+
+            let (animations, animation_scores) =
+                find_applicable_animations(db, model_id, &options);
+            let patterns = find_applicable_patterns(db, model_id);
+            let mat_anims = find_applicable_mat_anims(db, model_id);
+            let mat_color_anims = find_applicable_mat_color_anims(db, model_id);
+            let vis_anims = find_applicable_vis_anims(db, model_id);
+            let mut mdl_conn = ModelConnection {
+                materials, animations, animation_scores, patterns, mat_anims,
+                mat_color_anims, vis_anims,
+            };
+
+            if let Some(ref overrides) = options.overrides {
+                overrides.apply(db, model_id, &mut mdl_conn);
+            }
+
+            if mdl_conn.materials.iter().any(|mat_conn| mat_conn.image_id().is_err()) {
+                missing_textures = true;
+            }
+
+            mdl_conn
+        }).collect();
+
+        if let Some(ref overrides) = options.overrides {
+            overrides.check_models(db);
+        }
+
+        if missing_textures {
+            warn!("A matching texture/palette couldn't be found for some materials!");
+            info!("Hint: textures are sometimes stored in a separate .nsbtx file.");
+        }
+
+        Connection { models }
+    }
+}
+
+
+// HEURISTICS:
+
+/// TO RESOLVE A MATERIAL TEXTURE: The model file stores the texture name, so
+/// our initial set of candidates is all the texture in the DB with that name.
+/// If the material specifies a palette, we won't match a texture that doesn't
+/// require a palette, and the other way around too. If there are multiple
+/// candidates, we prefer one from the same file as the model (this is a good
+/// heuristic for models that store their textures/palettes in the same NSBMD
+/// file, which most do), and failing that, one from the nearest directory
+/// (for when the textures are in a separate NSBTX file next to the model). If
+/// there are still multiple candidates, we prefer the first one (but we record
+/// a note about the match being tentative). See pick_nearest.
+///
+/// Palettes are subsequently resolved similarly, prefering palettes near the
+/// texture.
+fn resolve_material(db: &Database, model_id: ModelId, material_idx: usize) -> MaterialConnection {
+    let material = &db.models[model_id].materials[material_idx];
+
+    let texture_name = match material.texture_name {
+        None => return MaterialConnection::NoTexture,
+        Some(ref name) => name,
+    };
+    let has_palette = material.palette_name.is_some();
+
+    // Resolve the texture name. Start with all textures with the right name.
+    let model_file = db.models_found_in[model_id];
+    let texture_match = match resolve_texture(db, texture_name, has_palette, model_file) {
+        Some(m) => m,
+        None => return MaterialConnection::TextureMissing,
+    };
+
+    resolve_palette(db, material, texture_match)
+}
+
+/// Resolves the palette for a material once its texture is known.
+fn resolve_palette(db: &Database, material: &Material, texture_match: Match<TextureId>)
+-> MaterialConnection {
+    // If there was no palette, we're done!
+    let palette_name = match material.palette_name {
+        None => return MaterialConnection::TextureOkNoPalette {
+            texture: texture_match,
+        },
+        Some(ref name) => name,
+    };
+
+    // Otherwise, resolve the palette. Start with candidates that have the right
+    // name.
+    let texture_file = db.textures_found_in[texture_match.id];
+    let palette_match = match resolve_palette_name(db, palette_name, texture_file) {
+        Some(m) => m,
+        None => return MaterialConnection::TextureOkPaletteMissing { texture: texture_match },
+    };
+
+    MaterialConnection::TextureOkPaletteOk {
+        texture: texture_match,
+        palette: palette_match,
+    }
+}
+
+/// Resolves a texture name for something in near_file. The texture must
+/// require a palette iff has_palette.
+fn resolve_texture(db: &Database, name: &Name, has_palette: bool, near_file: FileId)
+-> Option<Match<TextureId>> {
+    let mut candidates = db.textures_by_name.get(name)
+        .cloned().unwrap_or(vec![]);
+
+    // If there's a palette, discard candidates that don't use one, and
+    // conversely.
+    candidates.retain(|&tex_id| {
+        let requires_palette = db.textures[tex_id].params
+            .format().desc().requires_palette;
+        requires_palette == has_palette
+    });
+
+    pick_nearest(db, &candidates, &db.textures_found_in, near_file)
+}
+
+/// Resolves a palette name for something in near_file.
+fn resolve_palette_name(db: &Database, name: &Name, near_file: FileId)
+-> Option<Match<PaletteId>> {
+    let candidates = db.palettes_by_name.get(name)
+        .map(|ids| &ids[..]).unwrap_or(&[]);
+    pick_nearest(db, candidates, &db.palettes_found_in, near_file)
+}
+
+/// Picks the candidate nearest to near_file, given the files the candidates
+/// were found in. Ones in the same file are nearest (this includes files in
+/// the same NARC, which all get the NARC's file ID). After that, ones whose
+/// directories share more leading components with near_file's directory are
+/// nearer. Of the nearest, the first is picked; the match is only the best
+/// if it was the only one.
+fn pick_nearest(db: &Database, candidates: &[usize], found_in: &[FileId], near_file: FileId)
+-> Option<Match<usize>> {
+    let near_dir = db.file_paths[near_file].parent();
+    let nearness = |id: usize| {
+        let file = found_in[id];
+        let dir = db.file_paths[file].parent();
+        let shared_dirs = match (dir, near_dir) {
+            (Some(a), Some(b)) =>
+                a.components().zip(b.components()).take_while(|(x, y)| x == y).count(),
+            _ => 0,
+        };
+        (file == near_file, shared_dirs)
+    };
+
+    let nearest = candidates.iter().map(|&id| nearness(id)).max()?;
+    let mut winners = candidates.iter().cloned().filter(|&id| nearness(id) == nearest);
+    let id = winners.next()?;
+    Some(Match { id, best: winners.next().is_none() })
+}
+
+/// TO DETERMINE WHICH ANIMATIONS APPLY: An animation varies the values of the
+/// model's object matrices, so the obvious heuristic is that an animation
+/// applies to a model if it animates as many objects as the model has. This
+/// obviously gives false-positives since any two models with the same number of
+/// objects have the same set of animations applied to them. Surprisingly it
+/// also gives false-negatives: some animations that certainly go with a certain
+/// model have a different number of objects (maybe so it can be re-used amoung
+/// multiple models??).
+///
+/// So we weigh some other evidence too (see score_animation) and apply the
+/// animations that score well enough, best first. The user can still disable
+/// this and apply all the animations to every model.
+fn find_applicable_animations(db: &Database, model_id: ModelId, options: &ConnectionOptions)
+-> (Vec<AnimationId>, Vec<f64>) {
+    let mut scored = (0..db.animations.len())
+        .map(|anim_id| (anim_id, score_animation(db, model_id, anim_id)))
+        .filter(|&(_, score)| options.all_animations || score >= MIN_ANIMATION_SCORE)
+        .collect::<Vec<_>>();
+
+    // Best first; the sort is stable so ties stay in DB order.
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+    scored.into_iter().unzip()
+}
+
+/// Animations scoring less than this don't get applied.
+const MIN_ANIMATION_SCORE: f64 = 0.5;
+
+/// Scores how likely it is that an animation goes with a model, from 0 to 1.
+///
+/// * Having the same number of objects counts for the most.
+/// * Constant curves usually hold an object at its rest pose, so we check how
+///   many of them agree with the model's rest pose.
+/// * Animations are often in the same file as their model, or in a file with
+///   the same basename (hero.nsbmd, hero.nsbca).
+/// * Animation names often start with the model name (hero, hero_walk).
+///
+/// Same object count alone scores just over the threshold when there are no
+/// constant curves to compare, so the old behavior mostly still holds. A
+/// different object count needs all the other evidence to get over it, so
+/// users of an animation can't assume it has curves for every object.
+fn score_animation(db: &Database, model_id: ModelId, anim_id: AnimationId) -> f64 {
+    let model = &db.models[model_id];
+    let anim = &db.animations[anim_id];
+
+    let same_count = model.objects.len() == anim.objects_curves.len();
+    let agreement = rest_pose_agreement(model, anim);
+
+    let model_path = &db.file_paths[db.models_found_in[model_id]];
+    let anim_path = &db.file_paths[db.animations_found_in[anim_id]];
+    let related_files =
+        db.models_found_in[model_id] == db.animations_found_in[anim_id] ||
+        match (model_path.file_stem(), anim_path.file_stem()) {
+            (Some(a), Some(b)) => is_related_name(&a.to_string_lossy(), &b.to_string_lossy()),
+            _ => false,
+        };
+    let related_names = is_related_name(&model.name.to_string(), &anim.name.to_string());
+
+    let mut score = 0.0;
+    if same_count {
+        score += 0.4;
+    }
+    score += 0.3 * agreement.unwrap_or(if same_count { 0.5 } else { 0.0 });
+    if related_files {
+        score += 0.2;
+    }
+    if related_names {
+        score += 0.1;
+    }
+    score
+}
+
+/// The fraction of the animation's constant curves that agree with the model's
+/// rest pose, or None if there are none to compare.
+fn rest_pose_agreement(model: &Model, anim: &Animation) -> Option<f64> {
+    let close = |a: f64, b: f64| (a - b).abs() <= 1e-3 * a.abs().max(b.abs()).max(1.0);
+
+    let mut num_compared = 0;
+    let mut num_agree = 0;
+    let mut compare = |agrees: bool| {
+        num_compared += 1;
+        if agrees { num_agree += 1; }
+    };
+
+    for (object, curves) in model.objects.iter().zip(anim.objects_curves.iter()) {
+        let trans = object.trans.unwrap_or_else(|| vec3(0.0, 0.0, 0.0));
+        let scale = object.scale.unwrap_or_else(|| vec3(1.0, 1.0, 1.0));
+        for i in 0..3 {
+            if let Curve::Constant(x) = curves.trans[i] {
+                compare(close(x, trans[i]));
+            }
+            if let Curve::Constant(x) = curves.scale[i] {
+                compare(close(x, scale[i]));
+            }
+        }
+        if let Curve::Constant(ref m) = curves.rotation {
+            let rot = object.rot.unwrap_or_else(Matrix3::identity);
+            compare((0..3).all(|c| (0..3).all(|r| close(m[c][r], rot[c][r]))));
+        }
+    }
+
+    if num_compared == 0 {
+        None
+    } else {
+        Some(num_agree as f64 / num_compared as f64)
+    }
+}
+
+/// Whether the second name looks like a variation of the first, ie. they're
+/// the same up to a suffix (hero, hero_walk) or share a longish prefix
+/// (hero_model, hero_walk).
+fn is_related_name(a: &str, b: &str) -> bool {
+    let a = a.to_lowercase();
+    let b = b.to_lowercase();
+    if a.is_empty() || b.is_empty() {
+        return false;
+    }
+    let common = a.chars().zip(b.chars()).take_while(|(x, y)| x == y).count();
+    common == a.chars().count() || common == b.chars().count() || common >= 4
+}
+
+/// Indicates that a model can have the specified pattern applied to it, and
+/// tells what the texture/palette names in that pattern should resolve to for
+/// that model.
+pub struct PatternConnection {
+    pub pattern_id: PatternId,
+    pub texture_ids: Vec<Option<Match<TextureId>>>,
+    /// Indexed by texture, then by palette name. A palette is resolved near
+    /// the texture it's used with, so the same name can resolve differently
+    /// for different textures.
+    pub palette_ids: Vec<Vec<Option<Match<PaletteId>>>>,
+}
+
+/// TO DETERMINE WHICH PATTERNS APPLY: A pattern track targets a material by
+/// name, so apply a pattern to a model when every track in the pattern targets
+/// a valid material in that model. The texture/palette names in the pattern
+/// are resolved the same way as a material's: textures near the model's file,
+/// and palettes near the texture's file.
+fn find_applicable_patterns(db: &Database, model_id: ModelId) -> Vec<PatternConnection> {
+    let model = &db.models[model_id];
+    db.patterns.iter().enumerate().filter_map(|(pattern_id, pattern)| {
+        if !targets_materials(model, pattern.material_tracks.iter().map(|track| &track.name)) {
+            return None;
+        }
+
+        Some(pattern_connection(db, model_id, pattern_id))
+    }).collect()
+}
+
+/// Resolves the texture/palette names in a pattern for a model.
+fn pattern_connection(db: &Database, model_id: ModelId, pattern_id: PatternId) -> PatternConnection {
+    let pattern = &db.patterns[pattern_id];
+    let model_file = db.models_found_in[model_id];
+
+    // Every keyframe uses a palette, so the textures should need one.
+    let has_palette = !pattern.palette_names.is_empty();
+    let texture_ids: Vec<_> = pattern.texture_names.iter()
+        .map(|name| resolve_texture(db, name, has_palette, model_file))
+        .collect();
+    let palette_ids = texture_ids.iter()
+        .map(|texture_match| {
+            let texture_file = texture_match.map(|m| db.textures_found_in[m.id]);
+            pattern.palette_names.iter()
+                .map(|name| resolve_palette_name(db, name, texture_file?))
+                .collect()
+        })
+        .collect();
+
+    PatternConnection {
+        pattern_id,
+        texture_ids,
+        palette_ids,
+    }
+}
+
+impl PatternConnection {
+    /// The image a pattern keyframe shows, or None if the texture/palette
+    /// couldn't be resolved.
+    pub fn image_id(&self, texture_idx: u8, palette_idx: u8)
+    -> Option<(TextureId, Option<PaletteId>)> {
+        let texture = self.texture_ids[texture_idx as usize]?;
+        let palette = self.palette_ids[texture_idx as usize][palette_idx as usize]?;
+        Some((texture.id, Some(palette.id)))
+    }
+}
+
+/// Indicates that a model can have the specified material animations applied to
+/// it.
+pub struct MatAnimConnection {
+    pub mat_anim_id: MatAnimId,
+}
+
+fn find_applicable_mat_anims(db: &Database, model_id: ModelId) -> Vec<MatAnimConnection> {
+    let model = &db.models[model_id];
+    db.mat_anims.iter().enumerate().filter_map(|(mat_anim_id, mat_anim)| {
+        let valid = targets_materials(model, mat_anim.tracks.iter().map(|track| &track.name));
+        if !valid { None } else { Some(MatAnimConnection { mat_anim_id }) }
+    }).collect()
+}
+
+fn find_applicable_mat_color_anims(db: &Database, model_id: ModelId) -> Vec<MatColorAnimId> {
+    let model = &db.models[model_id];
+    db.mat_color_anims.iter().enumerate().filter_map(|(id, mat_color_anim)| {
+        let valid = targets_materials(model, mat_color_anim.tracks.iter().map(|track| &track.name));
+        if !valid { None } else { Some(id) }
+    }).collect()
+}
+
+/// Whether every track (by the name of the material it targets) targets a
+/// material in the model. Pattern, material, and material color animations
+/// only apply to models where this holds.
+fn targets_materials<'a>(model: &Model, mut track_names: impl Iterator<Item = &'a Name>) -> bool {
+    track_names.all(|name| model.materials.iter().any(|mat| mat.name == *name))
+}
+
+/// TO DETERMINE WHICH VISIBILITY ANIMATIONS APPLY: A visibility animation has a
+/// bit for each object, so use the same heuristic as for animations: it applies
+/// when it has as many objects as the model.
+fn find_applicable_vis_anims(db: &Database, model_id: ModelId) -> Vec<VisAnimId> {
+    let num_model_objs = db.models[model_id].objects.len();
+    (0..db.vis_anims.len())
+        .filter(|&vis_anim_id| {
+            db.vis_anims[vis_anim_id].num_objects as usize == num_model_objs
+        })
+        .collect()
+}
+
+#[test]
+fn test_pick_nearest() {
+    use std::path::PathBuf;
+    let mut db = Database::default();
+    db.file_paths = vec![
+        PathBuf::from("game/hero/hero.nsbmd"),
+        PathBuf::from("game/hero/hero.nsbtx"),
+        PathBuf::from("game/enemy/enemy.nsbtx"),
+        PathBuf::from("game/hero/other.nsbtx"),
+    ];
+    let found_in = [0, 1, 2, 3];
+    let pick = |candidates: &[usize]| {
+        pick_nearest(&db, candidates, &found_in, 0).map(|m| (m.id, m.best))
+    };
+    assert_eq!(pick(&[2, 1]), Some((1, true)));
+    assert_eq!(pick(&[2, 3, 1]), Some((3, false)));
+    assert_eq!(pick(&[1, 0]), Some((0, true)));
+    assert_eq!(pick(&[2]), Some((2, true)));
+    assert_eq!(pick(&[]), None);
+}
+
+#[test]
+fn test_is_related_name() {
+    assert!(is_related_name("hero", "hero_walk"));
+    assert!(is_related_name("Hero", "hero"));
+    assert!(is_related_name("hero_model", "hero_walk"));
+    assert!(!is_related_name("hero", "enemy_walk"));
+    assert!(!is_related_name("pl", "pc_walk"));
+    assert!(!is_related_name("", "walk"));
+}
+
+#[test]
+fn test_score_animation() {
+    use crate::nitro::animation::TRSCurves;
+    use crate::test_util::{add_model, db_with_files, ModelBuilder};
+
+    // Holds object x at a constant, or doesn't say
+    let curves = |x: Option<f64>| TRSCurves {
+        trans: [x.map(Curve::Constant).unwrap_or(Curve::None), Curve::None, Curve::None],
+        rotation: Curve::None,
+        scale: [Curve::None, Curve::None, Curve::None],
+    };
+    let anim = |name, objects_curves| Animation {
+        name: Name::from_str_truncated(name),
+        num_frames: 1,
+        objects_curves,
+    };
+
+    let mut db = db_with_files(&["hero.nsbmd", "hero.nsbca", "enemy.nsbca"]);
+    // Both objects are at x=1 at rest
+    add_model(&mut db, ModelBuilder::new("hero").chain(&["hip", "arm"]).build(), 0);
+    let anims = vec![
+        // Everything agrees
+        (anim("hero_walk", vec![curves(Some(1.0)), curves(Some(1.0))]), 1),
+        // Same count, but nothing else
+        (anim("run", vec![curves(None), curves(None)]), 2),
+        // Different count, but everything else agrees
+        (anim("hero_wave", vec![curves(Some(1.0))]), 1),
+        // Different count and disagrees
+        (anim("enemy_walk", vec![curves(Some(5.0))]), 2),
+        // Different count and nothing to compare
+        (anim("hero_idle", (0..3).map(|_| curves(None)).collect()), 1),
+    ];
+    for (a, file_id) in anims {
+        db.animations.push(a);
+        db.animations_found_in.push(file_id);
+    }
+
+    let scores = (0..db.animations.len())
+        .map(|anim_id| score_animation(&db, 0, anim_id))
+        .collect::<Vec<_>>();
+    let expected = [1.0, 0.55, 0.6, 0.0, 0.3];
+    for (score, expected) in scores.iter().zip(expected.iter()) {
+        assert!((score - expected).abs() < 1e-9, "{:?} != {:?}", scores, expected);
+    }
+
+    let (ids, _) = find_applicable_animations(&db, 0, &ConnectionOptions::default());
+    assert_eq!(ids, vec![0, 2, 1]);
+}
+
+#[test]
+fn test_pattern_palettes_near_texture() {
+    use crate::nds::TextureParams;
+    use crate::nitro::pattern::Pattern;
+    use crate::nitro::tex::{Palette, Texture};
+    use crate::test_util::{add_model, db_with_files, ModelBuilder};
+    use std::sync::Arc;
+
+    let mut db = db_with_files(&[
+        "game/hero/hero.nsbmd",
+        "game/eye.nsbtx",
+        "game/hero/hero.nsbtx",
+        "game/mouth.nsbtx",
+    ]);
+    add_model(&mut db, ModelBuilder::new("hero").build(), 0);
+    let name = Name::from_str_truncated;
+    for (tex_name, file_id) in [("eye", 1), ("mouth", 3)] {
+        db.textures_by_name.entry(name(tex_name)).or_default().push(db.textures.len());
+        db.textures.push(Texture {
+            name: name(tex_name),
+            params: TextureParams(3 << 26), // 16-color
+            data1: Default::default(),
+            data2: Default::default(),
+            unknown: 0,
+        });
+        db.textures_found_in.push(file_id);
+    }
+    // One in the eye's file, one next to the model
+    for file_id in [1, 2] {
+        db.palettes_by_name.entry(name("eye_pl")).or_default().push(db.palettes.len());
+        db.palettes.push(Palette {
+            name: name("eye_pl"),
+            off: 0,
+            pal_block: Arc::new(vec![0; 32].into_boxed_slice()),
+            unknown: 0,
+        });
+        db.palettes_found_in.push(file_id);
+    }
+    db.patterns.push(Pattern {
+        name: name("blink"),
+        num_frames: 1,
+        texture_names: vec![name("eye"), name("mouth")],
+        palette_names: vec![name("eye_pl")],
+        material_tracks: vec![],
+    });
+    db.patterns_found_in.push(0);
+
+    let conn = pattern_connection(&db, 0, 0);
+    let pick = |texture_idx: usize| {
+        conn.palette_ids[texture_idx][0].map(|m| (m.id, m.best))
+    };
+    // The eye's own file beats the one next to the model
+    assert_eq!(pick(0), Some((0, true)));
+    // Neither is nearer the mouth
+    assert_eq!(pick(1), Some((0, false)));
+    assert_eq!(conn.image_id(0, 0), Some((0, Some(0))));
+}
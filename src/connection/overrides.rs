@@ -0,0 +1,491 @@
+//! User-supplied overrides for the connection heuristics (`--connections`).
+//!
+//! The file is JSON, like this:
+//!
+//! ```json
+//!     {
+//!       "models": [
+//!         {
+//!           "model": { "file": "a.nsbmd", "name": "hero" },
+//!           "materials": {
+//!             "mat_face": {
+//!               "texture": { "file": "a.nsbtx", "name": "face" },
+//!               "palette": { "file": "a.nsbtx", "name": "face_pl" }
+//!             }
+//!           },
+//!           "animations": [ { "file": "a.nsbca", "name": "walk" } ],
+//!           "patterns": [],
+//!           "material_animations": [],
+//!           "material_color_animations": [],
+//!           "visibility_animations": []
+//!         }
+//!       ]
+//!     }
+//! ```
+//!
+//! Every resource is referred to by name and, optionally, the file it's in. A
+//! file matches if its path ends with the given path. Materials that aren't
+//! listed, and lists that are left out, keep the heuristic results.
+//!
+//! `info --dump-connections` writes the current connections in this format,
+//! to start from.
+
+use super::{
+    pattern_connection, resolve_palette, targets_materials, Connection,
+    MatAnimConnection, MaterialConnection, Match, ModelConnection,
+};
+use crate::db::{Database, FileId, ModelId};
+use crate::errors::Result;
+use crate::nitro::Name;
+use json::JsonValue;
+use std::path::Path;
+
+pub struct Overrides {
+    models: Vec<ModelOverride>,
+}
+
+struct ModelOverride {
+    model: Ref,
+    materials: Vec<(String, MaterialOverride)>,
+    animations: Option<Vec<Ref>>,
+    patterns: Option<Vec<Ref>>,
+    mat_anims: Option<Vec<Ref>>,
+    mat_color_anims: Option<Vec<Ref>>,
+    vis_anims: Option<Vec<Ref>>,
+}
+
+struct MaterialOverride {
+    texture: Ref,
+    palette: Option<Ref>,
+}
+
+/// Refers to a resource by name, and optionally, file.
+struct Ref {
+    file: Option<String>,
+    name: String,
+}
+
+impl Overrides {
+    pub fn load(path: &Path) -> Result<Overrides> {
+        let s = std::fs::read_to_string(path)?;
+        let json = json::parse(&s)
+            .map_err(|e| errmsg!("{}: {}", path.display(), e))?;
+        Overrides::from_json(&json)
+            .map_err(|e| errmsg!("{}: {}", path.display(), e).into())
+    }
+
+    fn from_json(json: &JsonValue) -> Result<Overrides> {
+        if !json["models"].is_array() {
+            bail!("expected a \"models\" array");
+        }
+
+        let mut models = vec![];
+        for (i, model) in json["models"].members().enumerate() {
+            let model_ref = match parse_ref(&model["model"]) {
+                Some(r) => r,
+                None => bail!("models[{}]: expected a \"model\" like {{\"name\": ...}}", i),
+            };
+
+            let mut materials = vec![];
+            for (mat_name, mat) in model["materials"].entries() {
+                let texture = match parse_ref(&mat["texture"]) {
+                    Some(r) => r,
+                    None => bail!("models[{}]: material {}: expected a \"texture\"", i, mat_name),
+                };
+                let palette = parse_ref(&mat["palette"]);
+                materials.push((mat_name.to_string(), MaterialOverride { texture, palette }));
+            }
+
+            let list = |key: &str| -> Result<Option<Vec<Ref>>> {
+                let x = &model[key];
+                if x.is_null() {
+                    return Ok(None);
+                }
+                if !x.is_array() {
+                    bail!("models[{}]: expected \"{}\" to be an array", i, key);
+                }
+                let mut refs = vec![];
+                for r in x.members() {
+                    match parse_ref(r) {
+                        Some(r) => refs.push(r),
+                        None => bail!("models[{}]: bad entry in \"{}\"", i, key),
+                    }
+                }
+                Ok(Some(refs))
+            };
+
+            models.push(ModelOverride {
+                model: model_ref,
+                materials,
+                animations: list("animations")?,
+                patterns: list("patterns")?,
+                mat_anims: list("material_animations")?,
+                mat_color_anims: list("material_color_animations")?,
+                vis_anims: list("visibility_animations")?,
+            });
+        }
+
+        Ok(Overrides { models })
+    }
+
+    /// Replaces the heuristic results for a model with the overrides for it.
+    pub fn apply(&self, db: &Database, model_id: ModelId, mdl_conn: &mut ModelConnection) {
+        let model = &db.models[model_id];
+        let model_file = db.models_found_in[model_id];
+
+        for ov in &self.models {
+            if !ov.model.matches(db, &model.name, model_file) {
+                continue;
+            }
+
+            for (mat_name, mat_ov) in &ov.materials {
+                let material_idx = model.materials.iter()
+                    .position(|mat| mat.name.to_string() == *mat_name);
+                let material_idx = match material_idx {
+                    Some(idx) => idx,
+                    None => {
+                        warn!("connections: model {} has no material {}", model.name, mat_name);
+                        continue;
+                    }
+                };
+
+                let texture_names = db.textures.iter().map(|t| t.name).collect::<Vec<_>>();
+                let texture_id = match mat_ov.texture.find(db, &texture_names, &db.textures_found_in) {
+                    Some(id) => id,
+                    None => {
+                        warn!("connections: no texture {}", mat_ov.texture);
+                        continue;
+                    }
+                };
+                let texture = Match { id: texture_id, best: true };
+
+                let material = &model.materials[material_idx];
+                let requires_palette = db.textures[texture_id].params.format().desc().requires_palette;
+                mdl_conn.materials[material_idx] = if !requires_palette {
+                    MaterialConnection::TextureOkNoPalette { texture }
+                } else {
+                    let palette_names = db.palettes.iter().map(|p| p.name).collect::<Vec<_>>();
+                    match mat_ov.palette.as_ref() {
+                        Some(pal_ref) => match pal_ref.find(db, &palette_names, &db.palettes_found_in) {
+                            Some(id) => MaterialConnection::TextureOkPaletteOk {
+                                texture,
+                                palette: Match { id, best: true },
+                            },
+                            None => {
+                                warn!("connections: no palette {}", pal_ref);
+                                MaterialConnection::TextureOkPaletteMissing { texture }
+                            }
+                        },
+                        None if material.palette_name.is_none() => {
+                            warn!("connections: texture {} needs a palette", mat_ov.texture);
+                            MaterialConnection::TextureOkPaletteMissing { texture }
+                        }
+                        None => resolve_palette(db, material, texture),
+                    }
+                };
+            }
+
+            // Animations that don't fit the model are dropped, the same as the
+            // heuristics would.
+            let fits_materials = |kind: &str, name: &Name, track_names: Vec<&Name>| {
+                let fits = targets_materials(model, track_names.into_iter());
+                if !fits {
+                    warn!("connections: {} {} targets materials model {} doesn't have; \
+                        skipping", kind, name, model.name);
+                }
+                fits
+            };
+
+            if let Some(ref refs) = ov.animations {
+                let names = db.animations.iter().map(|a| a.name).collect::<Vec<_>>();
+                mdl_conn.animations =
+                    find_all(db, refs, &names, &db.animations_found_in, "animation")
+                    .into_iter()
+                    .filter(|&id| {
+                        let anim = &db.animations[id];
+                        let fits = anim.objects_curves.len() >= model.objects.len();
+                        if !fits {
+                            warn!("connections: animation {} has {} objects, but model {} \
+                                has {}; skipping", anim.name, anim.objects_curves.len(),
+                                model.name, model.objects.len());
+                        }
+                        fits
+                    })
+                    .collect();
+                mdl_conn.animation_scores = vec![1.0; mdl_conn.animations.len()];
+            }
+            if let Some(ref refs) = ov.patterns {
+                let names = db.patterns.iter().map(|a| a.name).collect::<Vec<_>>();
+                mdl_conn.patterns =
+                    find_all(db, refs, &names, &db.patterns_found_in, "pattern")
+                    .into_iter()
+                    .filter(|&id| {
+                        let pattern = &db.patterns[id];
+                        let tracks = pattern.material_tracks.iter().map(|t| &t.name).collect();
+                        fits_materials("pattern", &pattern.name, tracks)
+                    })
+                    .map(|pattern_id| pattern_connection(db, model_id, pattern_id))
+                    .collect();
+            }
+            if let Some(ref refs) = ov.mat_anims {
+                let names = db.mat_anims.iter().map(|a| a.name).collect::<Vec<_>>();
+                mdl_conn.mat_anims =
+                    find_all(db, refs, &names, &db.mat_anims_found_in, "material animation")
+                    .into_iter()
+                    .filter(|&id| {
+                        let mat_anim = &db.mat_anims[id];
+                        let tracks = mat_anim.tracks.iter().map(|t| &t.name).collect();
+                        fits_materials("material animation", &mat_anim.name, tracks)
+                    })
+                    .map(|mat_anim_id| MatAnimConnection { mat_anim_id })
+                    .collect();
+            }
+            if let Some(ref refs) = ov.mat_color_anims {
+                let names = db.mat_color_anims.iter().map(|a| a.name).collect::<Vec<_>>();
+                mdl_conn.mat_color_anims =
+                    find_all(db, refs, &names, &db.mat_color_anims_found_in, "material color animation")
+                    .into_iter()
+                    .filter(|&id| {
+                        let anim = &db.mat_color_anims[id];
+                        let tracks = anim.tracks.iter().map(|t| &t.name).collect();
+                        fits_materials("material color animation", &anim.name, tracks)
+                    })
+                    .collect();
+            }
+            if let Some(ref refs) = ov.vis_anims {
+                let names = db.vis_anims.iter().map(|a| a.name).collect::<Vec<_>>();
+                mdl_conn.vis_anims =
+                    find_all(db, refs, &names, &db.vis_anims_found_in, "visibility animation");
+            }
+        }
+    }
+
+    /// Warns about overrides for models that aren't in the database.
+    pub fn check_models(&self, db: &Database) {
+        for ov in &self.models {
+            let found = (0..db.models.len()).any(|id| {
+                ov.model.matches(db, &db.models[id].name, db.models_found_in[id])
+            });
+            if !found {
+                warn!("connections: no model {}", ov.model);
+            }
+        }
+    }
+}
+
+fn find_all(db: &Database, refs: &[Ref], names: &[Name], found_in: &[FileId], kind: &str) -> Vec<usize> {
+    refs.iter()
+        .filter_map(|r| {
+            let id = r.find(db, names, found_in);
+            if id.is_none() {
+                warn!("connections: no {} {}", kind, r);
+            }
+            id
+        })
+        .collect()
+}
+
+fn parse_ref(json: &JsonValue) -> Option<Ref> {
+    let name = json["name"].as_str()?.to_string();
+    let file = json["file"].as_str().map(|s| s.to_string());
+    Some(Ref { file, name })
+}
+
+impl Ref {
+    fn matches(&self, db: &Database, name: &Name, file_id: FileId) -> bool {
+        if name.to_string() != self.name {
+            return false;
+        }
+        match self.file {
+            Some(ref file) => db.file_paths[file_id].ends_with(Path::new(file)),
+            None => true,
+        }
+    }
+
+    /// Finds the first resource this refers to, given the names of all the
+    /// resources of some kind and the files they're in.
+    fn find(&self, db: &Database, names: &[Name], found_in: &[FileId]) -> Option<usize> {
+        let mut ids = (0..names.len())
+            .filter(|&id| self.matches(db, &names[id], found_in[id]));
+        let id = ids.next()?;
+        if ids.next().is_some() {
+            warn!("connections: {} is ambiguous; using the first one", self);
+        }
+        Some(id)
+    }
+
+    fn to_json(db: &Database, name: &Name, file_id: FileId) -> JsonValue {
+        object!(
+            "file" => db.file_paths[file_id].to_string_lossy().into_owned(),
+            "name" => name.to_string(),
+        )
+    }
+}
+
+impl std::fmt::Display for Ref {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.file {
+            Some(ref file) => write!(f, "{} (in {})", self.name, file),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Writes the connections in the format of an overrides file.
+pub fn dump(db: &Database, conn: &Connection) -> JsonValue {
+    let mut models = array!();
+    for (model_id, mdl_conn) in conn.models.iter().enumerate() {
+        let model = &db.models[model_id];
+
+        let mut materials = object!();
+        for (material, mat_conn) in model.materials.iter().zip(mdl_conn.materials.iter()) {
+            let texture_id = match mat_conn.texture_id() {
+                Some(id) => id,
+                None => continue,
+            };
+            let mut entry = object!(
+                "texture" => Ref::to_json(db, &db.textures[texture_id].name, db.textures_found_in[texture_id]),
+            );
+            if let Some(palette_id) = mat_conn.palette_id() {
+                entry["palette"] = Ref::to_json(db, &db.palettes[palette_id].name, db.palettes_found_in[palette_id]);
+            }
+            materials[material.name.to_string()] = entry;
+        }
+
+        let r = |name: Name, file_id: FileId| Ref::to_json(db, &name, file_id);
+        let animations = mdl_conn.animations.iter()
+            .map(|&id| r(db.animations[id].name, db.animations_found_in[id]))
+            .collect::<Vec<_>>();
+        let patterns = mdl_conn.patterns.iter()
+            .map(|pat_conn| pat_conn.pattern_id)
+            .map(|id| r(db.patterns[id].name, db.patterns_found_in[id]))
+            .collect::<Vec<_>>();
+        let mat_anims = mdl_conn.mat_anims.iter()
+            .map(|mat_anim_conn| mat_anim_conn.mat_anim_id)
+            .map(|id| r(db.mat_anims[id].name, db.mat_anims_found_in[id]))
+            .collect::<Vec<_>>();
+        let mat_color_anims = mdl_conn.mat_color_anims.iter()
+            .map(|&id| r(db.mat_color_anims[id].name, db.mat_color_anims_found_in[id]))
+            .collect::<Vec<_>>();
+        let vis_anims = mdl_conn.vis_anims.iter()
+            .map(|&id| r(db.vis_anims[id].name, db.vis_anims_found_in[id]))
+            .collect::<Vec<_>>();
+
+        models.push(object!(
+            "model" => r(model.name, db.models_found_in[model_id]),
+            "materials" => materials,
+            "animations" => animations,
+            "patterns" => patterns,
+            "material_animations" => mat_anims,
+            "material_color_animations" => mat_color_anims,
+            "visibility_animations" => vis_anims,
+        )).unwrap();
+    }
+
+    object!("models" => models)
+}
+
+#[test]
+fn test_from_json() {
+    let json = json::parse(r#"{
+        "models": [
+            {
+                "model": { "name": "hero" },
+                "materials": {
+                    "face": { "texture": { "file": "a.nsbtx", "name": "face" } }
+                },
+                "animations": [ { "name": "walk" } ]
+            }
+        ]
+    }"#).unwrap();
+    let overrides = Overrides::from_json(&json).unwrap();
+    let ov = &overrides.models[0];
+    assert_eq!(ov.model.name, "hero");
+    assert_eq!(ov.materials[0].0, "face");
+    assert_eq!(ov.materials[0].1.texture.file.as_ref().unwrap(), "a.nsbtx");
+    assert!(ov.materials[0].1.palette.is_none());
+    assert_eq!(ov.animations.as_ref().unwrap().len(), 1);
+    assert!(ov.patterns.is_none());
+
+    let bad = json::parse(r#"{ "models": [ { "animations": [] } ] }"#).unwrap();
+    assert!(Overrides::from_json(&bad).is_err());
+}
+
+#[test]
+fn test_apply_drops_unfit() {
+    use super::ConnectionOptions;
+    use crate::nitro::animation::{Animation, Curve, TRSCurves};
+    use crate::nitro::material_animation::{MaterialAnimation, MaterialTrack, MaterialChannel, MatChannelTarget};
+    use crate::nitro::pattern::{Pattern, PatternTrack};
+    use crate::test_util::{add_model, db_with_files, ModelBuilder};
+    use std::rc::Rc;
+
+    let name = Name::from_str_truncated;
+    let curves = || TRSCurves {
+        trans: [Curve::None, Curve::None, Curve::None],
+        rotation: Curve::None,
+        scale: [Curve::None, Curve::None, Curve::None],
+    };
+    let pattern = |pattern_name, track_name| Pattern {
+        name: name(pattern_name),
+        num_frames: 1,
+        texture_names: vec![],
+        palette_names: vec![],
+        material_tracks: vec![PatternTrack { name: name(track_name), keyframes: vec![] }],
+    };
+    let channel = |target| MaterialChannel { num_frames: 1, target, curve: Curve::None };
+    let mat_anim = |anim_name, track_name| MaterialAnimation {
+        name: name(anim_name),
+        num_frames: 1,
+        tracks: vec![MaterialTrack {
+            name: name(track_name),
+            channels: [
+                channel(MatChannelTarget::ScaleU),
+                channel(MatChannelTarget::ScaleV),
+                channel(MatChannelTarget::Rotation),
+                channel(MatChannelTarget::TranslationU),
+                channel(MatChannelTarget::TranslationV),
+            ],
+        }],
+    };
+
+    let mut db = db_with_files(&["hero.nsbmd"]);
+    // Two objects and one material, "default"
+    let model = ModelBuilder::new("hero")
+        .chain(&["hip", "arm"])
+        .material("default")
+        .build();
+    add_model(&mut db, model, 0);
+    db.animations.push(Animation { name: name("walk"), num_frames: 1, objects_curves: vec![curves(), curves()] });
+    db.animations.push(Animation { name: name("wave"), num_frames: 1, objects_curves: vec![curves()] });
+    db.animations_found_in = vec![0, 0];
+    db.patterns.push(pattern("blink", "default"));
+    db.patterns.push(pattern("frown", "missing"));
+    db.patterns_found_in = vec![0, 0];
+    db.mat_anims.push(mat_anim("scroll", "default"));
+    db.mat_anims.push(mat_anim("spin", "missing"));
+    db.mat_anims_found_in = vec![0, 0];
+
+    let json = json::parse(r#"{
+        "models": [
+            {
+                "model": { "name": "hero" },
+                "animations": [ { "name": "walk" }, { "name": "wave" } ],
+                "patterns": [ { "name": "blink" }, { "name": "frown" } ],
+                "material_animations": [ { "name": "scroll" }, { "name": "spin" } ]
+            }
+        ]
+    }"#).unwrap();
+    let overrides = Overrides::from_json(&json).unwrap();
+    let conn = Connection::build(&db, ConnectionOptions {
+        overrides: Some(Rc::new(overrides)),
+        ..ConnectionOptions::default()
+    });
+
+    // Only the ones that fit the model are kept
+    let mdl_conn = &conn.models[0];
+    assert_eq!(mdl_conn.animations, vec![0]);
+    assert_eq!(mdl_conn.animation_scores, vec![1.0]);
+    assert_eq!(mdl_conn.patterns.iter().map(|p| p.pattern_id).collect::<Vec<_>>(), vec![0]);
+    assert_eq!(mdl_conn.mat_anims.iter().map(|m| m.mat_anim_id).collect::<Vec<_>>(), vec![0]);
+}
@@ -0,0 +1,112 @@
+//! Texture atlases for exporting pattern animations.
+//!
+//! glTF can't animate which image a material uses, but it can animate the
+//! texture transform. So we put every image a material can show into one
+//! image, stacked vertically in equal-size cells, and move the texture
+//! transform from cell to cell as the pattern plays.
+//!
+//! Texcoords outside `[0,1]` will show the neighboring cells, so this won't look
+//! right on materials that repeat their texture.
+
+use crate::connection::Connection;
+use crate::convert::image_namer::ImageId;
+use crate::db::{Database, ModelId};
+use crate::errors::Result;
+use crate::nds::decode_texture;
+
+pub struct Atlas {
+    /// The images in each cell, from top to bottom. The material's own image
+    /// (if it has one) is first.
+    pub images: Vec<ImageId>,
+}
+
+impl Atlas {
+    /// The atlas for a material, or None if no pattern changes its image.
+    pub fn for_material(
+        db: &Database,
+        conn: &Connection,
+        model_id: ModelId,
+        material_idx: usize,
+    ) -> Option<Atlas> {
+        let mdl_conn = &conn.models[model_id];
+        let material = &db.models[model_id].materials[material_idx];
+
+        let mut images = vec![];
+        if let Ok(Some(image_id)) = mdl_conn.materials[material_idx].image_id() {
+            images.push(image_id);
+        }
+        let num_rest_images = images.len();
+
+        for pat_conn in &mdl_conn.patterns {
+            let pat = &db.patterns[pat_conn.pattern_id];
+            let tracks = pat.material_tracks.iter()
+                .filter(|track| track.name == material.name);
+            for track in tracks {
+                for keyframe in &track.keyframes {
+                    let image_id = pat_conn.image_id(keyframe.texture_idx, keyframe.palette_idx);
+                    if let Some(image_id) = image_id {
+                        if !images.contains(&image_id) {
+                            images.push(image_id);
+                        }
+                    }
+                }
+            }
+        }
+
+        if images.len() == num_rest_images {
+            return None;
+        }
+        Some(Atlas { images })
+    }
+
+    /// Size of each cell (big enough for any of the images).
+    pub fn cell_dim(&self, db: &Database) -> (u32, u32) {
+        self.images.iter().fold((1, 1), |(w, h), &(texture_id, _)| {
+            let (tw, th) = db.textures[texture_id].params.dim();
+            (w.max(tw), h.max(th))
+        })
+    }
+
+    pub fn dim(&self, db: &Database) -> (u32, u32) {
+        let (w, h) = self.cell_dim(db);
+        (w, h * self.images.len() as u32)
+    }
+
+    /// The KHR_texture_transform scale to use for a material with the given
+    /// dimensions. Texcoords are in units of the material's dimensions no
+    /// matter what image is bound, so this is the same for every cell.
+    pub fn scale(&self, db: &Database, (mat_w, mat_h): (u16, u16)) -> [f64; 2] {
+        let (cell_w, cell_h) = self.cell_dim(db);
+        let n = self.images.len() as f64;
+        [mat_w as f64 / cell_w as f64, mat_h as f64 / (n * cell_h as f64)]
+    }
+
+    /// The KHR_texture_transform offset that shows the given image.
+    pub fn offset(&self, image_id: ImageId) -> Option<[f64; 2]> {
+        let i = self.images.iter().position(|&id| id == image_id)?;
+        Some([0.0, i as f64 / self.images.len() as f64])
+    }
+
+    /// Draws the atlas image.
+    pub fn rgba(&self, db: &Database) -> Result<Vec<u8>> {
+        let (cell_w, cell_h) = self.cell_dim(db);
+        let (w, h) = self.dim(db);
+        let mut rgba = vec![0; 4 * w as usize * h as usize];
+
+        for (i, &(texture_id, palette_id)) in self.images.iter().enumerate() {
+            let texture = &db.textures[texture_id];
+            let palette = palette_id.map(|id| &db.palettes[id]);
+            let image = decode_texture(texture, palette)?;
+
+            let (tw, th) = texture.params.dim();
+            for y in 0..th as usize {
+                let src = 4 * y * tw as usize;
+                let dst = 4 * ((i * cell_h as usize + y) * cell_w as usize);
+                rgba[dst..dst + 4 * tw as usize]
+                    .copy_from_slice(&image.0[src..src + 4 * tw as usize]);
+            }
+        }
+
+        Ok(rgba)
+    }
+}
@@ -4,6 +4,7 @@ mod make_invertible;
 
 use cgmath::{Matrix4, One};
 use crate::convert::image_namer::ImageNamer;
+use crate::convert::pose::Pose;
 use crate::db::{Database, ModelId};
 use crate::skeleton::{Skeleton, Transform, SMatrix};
 use crate::primitives::{self, Primitives, DynamicState};
@@ -25,6 +26,8 @@ struct Ctx<'a> {
     objects: &'a [Matrix4<f64>],
     prims: &'a Primitives,
     skel: &'a Skeleton,
+    /// Whether the mesh is baked into a --pose (so it has no skin).
+    posed: bool,
 }
 
 pub fn write(
@@ -32,23 +35,36 @@ pub fn write(
     conn: &Connection,
     image_namer: &ImageNamer,
     model_id: ModelId,
+    pose: Option<&Pose>,
 ) -> String {
     let model = &db.models[model_id];
+    let posed = pose.is_some();
 
     // We need invertible matrices since we're obliged to give values for
     // inverse bind matrices.
     use self::make_invertible::make_invertible;
-    let objects = &model.objects.iter()
-        .map(|o| make_invertible(&o.matrix))
-        .collect::<Vec<_>>();
-    let uv_mats = &model.materials.iter()
-        .map(|mat| mat.texture_mat)
-        .collect::<Vec<_>>();
-    let state = DynamicState { objects, uv_mats };
-    let prims = &Primitives::build(model, primitives::PolyType::TrisAndQuads, state);
+    let objects = &match pose {
+        Some(pose) => pose.objects.clone(),
+        None => model.objects.iter().map(|o| make_invertible(&o.matrix)).collect(),
+    };
+    let uv_mats = &match pose {
+        Some(pose) => pose.uv_mats.clone(),
+        None => model.materials.iter().map(|mat| mat.texture_mat).collect(),
+    };
+    // Hidden objects are included in the mesh; we hide them by scaling their
+    // joints to zero instead. A posed mesh has no joints, so their pieces are
+    // left out.
+    let visibility = &match pose {
+        Some(_) => model.objects.iter().map(|o| o.visible).collect(),
+        None => vec![true; model.objects.len()],
+    };
+    let state = DynamicState { objects, uv_mats, visibility };
+    let mut prims = Primitives::build(model, primitives::PolyType::TrisAndQuads, state);
+    prims.remove_hidden_draw_calls();
+    let prims = &prims;
     let skel = &Skeleton::build(model, objects);
 
-    let ctx = Ctx { model_id, model, db, conn, image_namer, objects, prims, skel };
+    let ctx = Ctx { model_id, model, db, conn, image_namer, objects, prims, skel, posed };
 
     let mut xml = Xml::with_capacity(1024 * 1024); // 1MiB
 
@@ -62,9 +78,11 @@ pub fn write(
     library_effects(&mut xml, &ctx);
     if !ctx.prims.vertices.is_empty() {
         library_geometries(&mut xml, &ctx);
-        library_controllers(&mut xml, &ctx);
-        library_animations(&mut xml, &ctx);
-        library_animation_clips(&mut xml, &ctx);
+        if !ctx.posed {
+            library_controllers(&mut xml, &ctx);
+            library_animations(&mut xml, &ctx);
+            library_animation_clips(&mut xml, &ctx);
+        }
         library_visual_scenes(&mut xml, &ctx);
         scene(&mut xml, &ctx);
     }
@@ -467,7 +485,8 @@ fn library_controllers(xml: &mut Xml, ctx: &Ctx) {
 
 fn library_animations(xml: &mut Xml, ctx: &Ctx) {
     let anims = &ctx.conn.models[ctx.model_id].animations;
-    if anims.is_empty() { return; }
+    let vis_anims = &ctx.conn.models[ctx.model_id].vis_anims;
+    if anims.is_empty() && vis_anims.is_empty() { return; }
 
     xml!(xml;
         <library_animations>;
@@ -558,15 +577,104 @@ fn library_animations(xml: &mut Xml, ctx: &Ctx) {
             );
         }
     }
+    for &vis_anim_id in vis_anims {
+        let vis_anim = &ctx.db.vis_anims[vis_anim_id];
+        let num_frames = vis_anim.num_frames;
+
+        for joint_index in vis_animated_joints(ctx, vis_anim_id) {
+            let object_id = match ctx.skel.tree[joint_index].local_to_parent {
+                Transform::SMatrix(SMatrix::Object { object_idx }) => object_idx as usize,
+                _ => unreachable!(),
+            };
+            let rest = ctx.objects[object_id];
+            let hidden = rest * Matrix4::from_scale(0.0);
+
+            xml!(xml;
+                <animation id=["vis"(vis_anim_id)"-joint"(joint_index)]>;
+                    <source id=["vis"(vis_anim_id)"-joint"(joint_index)"-time"]>;
+                        <float_array id=["vis"(vis_anim_id)"-joint"(joint_index)"-time-array"] count=[(num_frames)]>
+                        for frame in (0..num_frames) {
+                            (frame as f64 * FRAME_LENGTH)" "
+                        }
+                        </float_array>;
+                        <technique_common>;
+                            <accessor source=["#vis"(vis_anim_id)"-joint"(joint_index)"-time-array"] count=[(num_frames)]>;
+                                <param name=["TIME"] type=["float"]/>;
+                            /accessor>;
+                        /technique_common>;
+                    /source>;
+            );
+            xml!(xml;
+                    <source id=["vis"(vis_anim_id)"-joint"(joint_index)"-matrix"]>;
+                        <float_array id=["vis"(vis_anim_id)"-joint"(joint_index)"-matrix-array"] count=[(16 * num_frames)]>
+                        for frame in (0..num_frames) {
+                            MATRIX(
+                                if vis_anim.is_visible(frame, object_id) { &rest } else { &hidden }
+                            )" "
+                        }
+                        </float_array>;
+                        <technique_common>;
+                            <accessor source=["#vis"(vis_anim_id)"-joint"(joint_index)"-matrix-array"] count=[(num_frames)] stride=["16"]>;
+                                <param name=["TRANSFORM"] type=["float4x4"]/>;
+                            /accessor>;
+                        /technique_common>;
+                    /source>;
+            );
+            xml!(xml;
+                    <source id=["vis"(vis_anim_id)"-joint"(joint_index)"-interpolation"]>;
+                        <Name_array id=["vis"(vis_anim_id)"-joint"(joint_index)"-interpolation-array"] count=[(num_frames)]>
+                        for _frame in (0..num_frames) {
+                            "STEP "
+                        }
+                        </Name_array>;
+                        <technique_common>;
+                            <accessor source=["#vis"(vis_anim_id)"-joint"(joint_index)"-interpolation-array"] count=[(num_frames)]>;
+                                <param name=["INTERPOLATION"] type=["Name"]/>;
+                            /accessor>;
+                        /technique_common>;
+                    /source>;
+            );
+            xml!(xml;
+                    <sampler id=["vis"(vis_anim_id)"-joint"(joint_index)"-sampler"]>;
+                        <input semantic=["INPUT"] source=["#vis"(vis_anim_id)"-joint"(joint_index)"-time"]/>;
+                        <input semantic=["OUTPUT"] source=["#vis"(vis_anim_id)"-joint"(joint_index)"-matrix"]/>;
+                        <input semantic=["INTERPOLATION"] source=["#vis"(vis_anim_id)"-joint"(joint_index)"-interpolation"]/>;
+                    /sampler>;
+                    <channel
+                        source=["#vis"(vis_anim_id)"-joint"(joint_index)"-sampler"]
+                        target=["joint"(joint_index)"/transform"]/>;
+                /animation>;
+            );
+        }
+    }
     xml!(xml;
         /library_animations>;
     );
 }
 
+/// The joints a visibility animation needs to animate. A hidden object gets its
+/// joint scaled to zero. Note that this also hides its children, which the DS
+/// wouldn't.
+fn vis_animated_joints(ctx: &Ctx, vis_anim_id: usize) -> Vec<NodeIdx> {
+    let vis_anim = &ctx.db.vis_anims[vis_anim_id];
+    ctx.skel.tree.node_idxs()
+        .filter(|&joint_index| {
+            match ctx.skel.tree[joint_index].local_to_parent {
+                Transform::SMatrix(SMatrix::Object { object_idx }) => {
+                    let rest_visible = ctx.model.objects[object_idx as usize].visible;
+                    vis_anim.animates(object_idx as usize, rest_visible)
+                }
+                _ => false,
+            }
+        })
+        .collect()
+}
+
 
 fn library_animation_clips(xml: &mut Xml, ctx: &Ctx) {
     let anims = &ctx.conn.models[ctx.model_id].animations;
-    if anims.is_empty() { return ;}
+    let vis_anims = &ctx.conn.models[ctx.model_id].vis_anims;
+    if anims.is_empty() && vis_anims.is_empty() { return ;}
 
     xml!(xml;
         <library_animation_clips>;
@@ -584,6 +692,20 @@ fn library_animation_clips(xml: &mut Xml, ctx: &Ctx) {
             /animation_clip>;
         );
     }
+    for &vis_anim_id in vis_anims {
+        let vis_anim = &ctx.db.vis_anims[vis_anim_id];
+        let joints = vis_animated_joints(ctx, vis_anim_id);
+        if joints.is_empty() { continue; }
+        let end_time = (vis_anim.num_frames - 1) as f64 * FRAME_LENGTH;
+
+        xml!(xml;
+            <animation_clip id=["vis"(vis_anim_id)] name=[(vis_anim.name.print_safe())] end=[(end_time)]>;
+            for j in (joints) {
+                <instance_animation url=["#vis"(vis_anim_id)"-joint"(j)]/>;
+            }
+            /animation_clip>;
+        );
+    }
     xml!(xml;
         /library_animation_clips>;
     );
@@ -597,24 +719,31 @@ fn library_visual_scenes(xml: &mut Xml, ctx: &Ctx) {
             <visual_scene id=["scene0"] name=[(model_name.print_safe())]>;
     );
 
-    joint_hierarchy(xml, ctx);
+    if ctx.posed {
+        // Just the mesh, with the pose already baked into it.
+        xml!(xml;
+            <node id=["node"] name=[(model_name.print_safe())] type=["NODE"]>;
+                <instance_geometry url=["#geometry"]>;
+        );
+        bind_material(xml, ctx);
+        xml!(xml;
+                /instance_geometry>;
+            /node>;
+        );
+    } else {
+        joint_hierarchy(xml, ctx);
 
-    xml!(xml;
-        <node id=["node"] name=[(model_name.print_safe())] type=["NODE"]>;
-            <instance_controller url=["#controller"]>;
-                <skeleton>"#joint"(ctx.skel.root)</skeleton>;
-                <bind_material>;
-                    <technique_common>;
-                    for i in (0..ctx.model.materials.len()) {
-                        <instance_material symbol=["material"(i)] target=["#material"(i)]>;
-                            <bind_vertex_input semantic=["tc"] input_semantic=["TEXCOORD"]/>;
-                        /instance_material>;
-                    }
-                    /technique_common>;
-                /bind_material>;
-            /instance_controller>;
-        /node>;
-    );
+        xml!(xml;
+            <node id=["node"] name=[(model_name.print_safe())] type=["NODE"]>;
+                <instance_controller url=["#controller"]>;
+                    <skeleton>"#joint"(ctx.skel.root)</skeleton>;
+        );
+        bind_material(xml, ctx);
+        xml!(xml;
+                /instance_controller>;
+            /node>;
+        );
+    }
 
     xml!(xml;
             /visual_scene>;
@@ -622,6 +751,20 @@ fn library_visual_scenes(xml: &mut Xml, ctx: &Ctx) {
     );
 }
 
+fn bind_material(xml: &mut Xml, ctx: &Ctx) {
+    xml!(xml;
+        <bind_material>;
+            <technique_common>;
+            for i in (0..ctx.model.materials.len()) {
+                <instance_material symbol=["material"(i)] target=["#material"(i)]>;
+                    <bind_vertex_input semantic=["tc"] input_semantic=["TEXCOORD"]/>;
+                /instance_material>;
+            }
+            /technique_common>;
+        /bind_material>;
+    );
+}
+
 fn joint_hierarchy(xml: &mut Xml, ctx: &Ctx) {
     /// Write the name for a joint that will appear in DCC programs.
     fn joint_name(ctx: &Ctx, node: NodeIdx) -> String {
@@ -652,8 +795,14 @@ fn joint_hierarchy(xml: &mut Xml, ctx: &Ctx) {
         let mat = match tree[node].local_to_parent {
             Transform::Root =>
                 Matrix4::one(),
-            Transform::SMatrix(SMatrix::Object { object_idx }) =>
-                ctx.objects[object_idx as usize],
+            Transform::SMatrix(SMatrix::Object { object_idx }) => {
+                let mat = ctx.objects[object_idx as usize];
+                if ctx.model.objects[object_idx as usize].visible {
+                    mat
+                } else {
+                    mat * Matrix4::from_scale(0.0)
+                }
+            }
             Transform::SMatrix(SMatrix::InvBind { inv_bind_idx }) =>
                 ctx.model.inv_binds[inv_bind_idx as usize],
             Transform::SMatrix(SMatrix::Uninitialized { .. }) =>
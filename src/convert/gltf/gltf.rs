@@ -27,6 +27,13 @@ impl GlTF {
         }
     }
 
+    /// Adds an extension to extensionsUsed (if it isn't there already).
+    pub fn use_extension(&mut self, name: &str) {
+        if !self.json["extensionsUsed"].members().any(|x| x == name) {
+            self.json["extensionsUsed"].push(name).unwrap();
+        }
+    }
+
     /// Remove empty top-level collections from the glTF JSON.
     pub fn cleanup(&mut self) {
         for &key in &["accessors", "bufferViews", "extensionsUsed", "extensionsRequired"] {
@@ -37,7 +44,7 @@ impl GlTF {
     }
 
     /// Updates all the buffer views to point to the correct location when the
-    /// buffers are all joined into one single json["buffers"][0]. Returns the
+    /// buffers are all joined into one single `json["buffers"][0]`. Returns the
     /// size the joined buffer will have.
     pub fn update_buffer_views(&mut self) -> usize {
         // Record the offset to the start of each buffer when they are all laid
@@ -2,26 +2,46 @@ mod gltf;
 mod object_trs;
 mod curve;
 mod primitive;
+mod texture_transform;
+pub mod scene;
 
 use crate::nitro::Model;
 use crate::db::{Database, ModelId};
 use crate::connection::Connection;
 use crate::primitives::{Primitives, PolyType, DynamicState};
 use crate::skeleton::{Skeleton, Transform, SMatrix};
-use super::image_namer::ImageNamer;
-use cgmath::Matrix4;
+use super::image_namer::{ImageNamer, ImageId};
+use super::atlas::Atlas;
+use super::pose::Pose;
+use cgmath::{Matrix4, One};
 use json::JsonValue;
-use self::gltf::{GlTF, Buffer, ByteVec, VecExt};
+pub use self::gltf::GlTF;
+use self::gltf::{Buffer, ByteVec, VecExt};
 use self::object_trs::ObjectTRSes;
 use crate::util::{BiVec, BiMap};
+use crate::util::angle::unwrap_angles;
 use self::curve::{GlTFObjectCurves, CurveDomain};
 use crate::nitro::animation::Curve;
 use std::collections::HashMap;
 use self::primitive::encode_ngons;
+use self::texture_transform::TextureTransform;
 use crate::nds::Alpha;
 
 static FRAME_LENGTH: f32 = 1.0 / 60.0; // 60 fps
 
+/// How to export pattern animations, which glTF has no direct way to do.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum PatternMode {
+    /// Don't export them.
+    None,
+    /// Put all the images a material uses in a texture atlas and animate its
+    /// KHR_texture_transform offset (see `convert::atlas`).
+    Atlas,
+    /// Make a KHR_materials_variants variant for every keyframe. These can be
+    /// switched between but don't play.
+    Variants,
+}
+
 struct Ctx<'a> {
     model_id: ModelId,
     model: &'a Model,
@@ -31,6 +51,21 @@ struct Ctx<'a> {
     rest_trses: ObjectTRSes,
     prims: &'a Primitives,
     skel: &'a Skeleton,
+    pattern_mode: PatternMode,
+    /// Atlas for each material (PatternMode::Atlas only).
+    atlases: Vec<Option<Atlas>>,
+    /// Rest KHR_texture_transform for each material, or None if its texture
+    /// matrix is baked into the texcoords instead.
+    tex_transforms: Vec<Option<TextureTransform>>,
+    /// Whether the mesh is baked into a --pose (so it has no skin).
+    posed: bool,
+    /// The glTF mesh each draw call goes in. Pieces whose visibility can
+    /// change get a mesh (and node) of their own, so they can be hidden
+    /// without hiding anything else.
+    call_meshes: Vec<usize>,
+    /// For each mesh, the object controlling its visibility, if that can
+    /// change.
+    mesh_vis_objects: Vec<Option<u8>>,
 }
 
 pub fn to_gltf(
@@ -38,29 +73,96 @@ pub fn to_gltf(
     conn: &Connection,
     image_namer: &ImageNamer,
     model_id: ModelId,
+    pattern_mode: PatternMode,
+    pose: Option<&Pose>,
 ) -> GlTF {
     let model = &db.models[model_id];
+    let posed = pose.is_some();
 
     let rest_trses = ObjectTRSes::for_model_at_rest(model);
-    let objects = rest_trses.objects.iter()
-        .map(Matrix4::from)
+    let objects = match pose {
+        Some(pose) => pose.objects.clone(),
+        None => rest_trses.objects.iter().map(Matrix4::from).collect(),
+    };
+
+    let atlases = (0..model.materials.len())
+        .map(|material_idx| {
+            if pattern_mode != PatternMode::Atlas { return None }
+            Atlas::for_material(db, conn, model_id, material_idx)
+        })
+        .collect::<Vec<_>>();
+
+    // Texture matrices are exported as a KHR_texture_transform so they can be
+    // animated, except when the texcoords are generated from normals/positions
+    // or an atlas already needs the texture transform, or the model is posed.
+    // Then they're baked into the texcoords.
+    let tex_transforms = model.materials.iter().zip(&atlases)
+        .map(|(mat, atlas)| {
+            let mode = mat.params.texcoord_transform_mode();
+            if mode > 1 || atlas.is_some() || posed { return None }
+            Some(TextureTransform::from_texel_matrix(&mat.texture_mat, (mat.width, mat.height)))
+        })
         .collect::<Vec<_>>();
-    let uv_mats = model.materials.iter()
-        .map(|mat| mat.texture_mat)
-        .collect::<Vec<Matrix4<f64>>>();
-    let state = DynamicState { objects: &objects, uv_mats: &uv_mats };
-    let prims = Primitives::build(model, PolyType::TrisAndQuads, state);
+    let uv_mats = match pose {
+        Some(pose) => pose.uv_mats.clone(),
+        None => model.materials.iter().zip(&tex_transforms)
+            .map(|(mat, tt)| if tt.is_some() { Matrix4::one() } else { mat.texture_mat })
+            .collect::<Vec<Matrix4<f64>>>(),
+    };
+    // Pieces under hidden objects are included in the mesh; they're hidden
+    // with KHR_node_visibility instead. A posed mesh has no nodes to hide, so
+    // they're left out.
+    let visibility = match pose {
+        Some(_) => model.objects.iter().map(|o| o.visible).collect(),
+        None => vec![true; model.objects.len()],
+    };
+    let state = DynamicState { objects: &objects, uv_mats: &uv_mats, visibility: &visibility };
+    let mut prims = Primitives::build(model, PolyType::TrisAndQuads, state);
+    prims.remove_hidden_draw_calls();
     let prims = &encode_ngons(prims);
     let skel = &Skeleton::build(model, &objects);
 
-    let ctx = Ctx { model_id, model, db, conn, image_namer, rest_trses, prims, skel };
+    // Objects that are hidden at rest or have their visibility animated
+    let vis_anims = &conn.models[model_id].vis_anims;
+    let vis_changes = model.objects.iter().enumerate()
+        .map(|(object_idx, obj)| {
+            !posed && (!obj.visible || vis_anims.iter().any(|&id| {
+                db.vis_anims[id].animates(object_idx, obj.visible)
+            }))
+        })
+        .collect::<Vec<bool>>();
+    let mut call_meshes = vec![];
+    let mut mesh_vis_objects = vec![];
+    let mut main_mesh = None;
+    for call in &prims.draw_calls {
+        let vis_object = call.vis_object.filter(|&obj| vis_changes[obj as usize]);
+        let mesh = match vis_object {
+            None => *main_mesh.get_or_insert_with(|| {
+                mesh_vis_objects.push(None);
+                mesh_vis_objects.len() - 1
+            }),
+            Some(_) => {
+                mesh_vis_objects.push(vis_object);
+                mesh_vis_objects.len() - 1
+            }
+        };
+        call_meshes.push(mesh);
+    }
+
+    let ctx = Ctx {
+        model_id, model, db, conn, image_namer, rest_trses, prims, skel,
+        pattern_mode, atlases, tex_transforms, posed, call_meshes,
+        mesh_vis_objects,
+    };
 
     let mut gltf = GlTF::new();
 
     if !ctx.prims.vertices.is_empty() {
         mesh(&ctx, &mut gltf);
         nodes(&ctx, &mut gltf);
-        animations(&ctx, &mut gltf);
+        if !ctx.posed {
+            animations(&ctx, &mut gltf);
+        }
     }
     materials(&ctx, &mut gltf);
 
@@ -79,8 +181,8 @@ fn mesh(ctx: &Ctx, gltf: &mut GlTF) {
 
     // Positions
     // glTF wants the min/max, so compute that first
-    let mut min = verts[0].position.clone();
-    let mut max = verts[0].position.clone();
+    let mut min = verts[0].position;
+    let mut max = verts[0].position;
     for v in verts {
         for i in 0..3 {
             min[i] = min[i].min(v.position[i]);
@@ -143,7 +245,7 @@ fn mesh(ctx: &Ctx, gltf: &mut GlTF) {
     let color_accessor = if has_colors {
         let buf = gltf.buffers.add(Buffer {
             alignment: 4,
-            bytes: Vec::with_capacity(4 * verts.len() * 1),
+            bytes: Vec::with_capacity(4 * verts.len()),
         });
         let dat = &mut gltf.buffers[buf].bytes;
         // Is the DS in sRGB??
@@ -209,17 +311,17 @@ fn mesh(ctx: &Ctx, gltf: &mut GlTF) {
 
     // glTF gives joint/weight influences in sets of 4 (JOINT_0 is a VEC4
     // accessor with the first four joints, JOINTS_1 has the next four, etc).
-    // Find out how many sets we need.
-    let num_sets = ((ctx.skel.max_num_weights + 3) / 4) as usize;
+    // Find out how many sets we need. A posed mesh has no skin, so none.
+    let num_sets = if ctx.posed { 0 } else { ctx.skel.max_num_weights.div_ceil(4) as usize };
 
     // Make sure joints fit in a byte
-    assert!(ctx.skel.tree.node_count() <= 255);
+    assert!(num_sets == 0 || ctx.skel.tree.node_count() <= 255);
 
     // Joints
     let joints_accessors = {
         let buf = gltf.buffers.add(Buffer {
             alignment: 4,
-            bytes: Vec::with_capacity(4 * num_sets * verts.len() * 1),
+            bytes: Vec::with_capacity(4 * num_sets * verts.len()),
         });
         let dat_len = {
             let dat = &mut gltf.buffers[buf].bytes;
@@ -255,7 +357,7 @@ fn mesh(ctx: &Ctx, gltf: &mut GlTF) {
     let weights_accessors = {
         let buf = gltf.buffers.add(Buffer {
             alignment: 4,
-            bytes: Vec::with_capacity(4 * num_sets * verts.len() * 1),
+            bytes: Vec::with_capacity(4 * num_sets * verts.len()),
         });
         let dat_len = {
             let dat = &mut gltf.buffers[buf].bytes;
@@ -341,13 +443,19 @@ fn mesh(ctx: &Ctx, gltf: &mut GlTF) {
         primitive
     }).collect::<Vec<JsonValue>>();
 
-    let mesh = object!(
-        "primitives" => primitives,
-        "name" => ctx.model.name.to_string(),
-    );
-    gltf.json["meshes"] = array!(mesh);
+    let mut meshes = ctx.mesh_vis_objects.iter().map(|_| array!()).collect::<Vec<JsonValue>>();
+    for (&mesh, primitive) in ctx.call_meshes.iter().zip(primitives) {
+        meshes[mesh].push(primitive).unwrap();
+    }
+    gltf.json["meshes"] = meshes.into_iter().enumerate()
+        .map(|(mesh, primitives)| object!(
+            "primitives" => primitives,
+            "name" => mesh_name(ctx, mesh),
+        ))
+        .collect::<Vec<JsonValue>>()
+        .into();
 
-    gltf.json["extensionsUsed"].push("FB_ngon_encoding").unwrap();
+    gltf.use_extension("FB_ngon_encoding");
 }
 
 fn nodes(ctx: &Ctx, gltf: &mut GlTF) {
@@ -355,6 +463,20 @@ fn nodes(ctx: &Ctx, gltf: &mut GlTF) {
         return;
     }
 
+    if ctx.posed {
+        // Just the mesh, with the pose already baked into it.
+        gltf.json["nodes"] = array!(object!(
+            "mesh" => 0,
+            "name" => ctx.model.name.to_string(),
+        ));
+        gltf.json["scenes"] = array!(object!(
+            "nodes" => array!(0),
+            "name" => ctx.model.name.to_string(),
+        ));
+        gltf.json["scene"] = 0.into();
+        return;
+    }
+
     // Make a node tree from the skeleton tree. The NodeIndices for skel.tree
     // are the same as the indices into the glTF nodes array.
     gltf.json["nodes"] = ctx.skel.tree.node_idxs().map(|idx| {
@@ -402,13 +524,44 @@ fn nodes(ctx: &Ctx, gltf: &mut GlTF) {
 
     // Add another node above the skeleton root to instantiate the mesh at
     // (glTF-Blender-IO doesn't like it when we instantiate a mesh on a node
-    // that's also used as a joint).
-    gltf.json["nodes"].push(object!(
-        "mesh" => 0,
-        "skin" => 0,
+    // that's also used as a joint). Meshes whose visibility can change get
+    // their own nodes under it.
+    let mesh_nodes = mesh_nodes(ctx);
+    let top = ctx.skel.tree.node_count();
+    let mut top_node = object!(
         "name" => ctx.model.name.to_string(),
         "children" => array!(ctx.skel.root),
-    )).unwrap();
+    );
+    let mut vis_nodes = vec![];
+    for (mesh, &node_idx) in mesh_nodes.iter().enumerate() {
+        match ctx.mesh_vis_objects[mesh] {
+            None => {
+                top_node["mesh"] = mesh.into();
+                top_node["skin"] = 0.into();
+            }
+            Some(object_idx) => {
+                top_node["children"].push(node_idx).unwrap();
+                let visible = ctx.model.objects[object_idx as usize].visible;
+                vis_nodes.push(object!(
+                    "mesh" => mesh,
+                    "skin" => 0,
+                    "name" => mesh_name(ctx, mesh),
+                    "extensions" => object!(
+                        "KHR_node_visibility" => object!(
+                            "visible" => visible,
+                        ),
+                    ),
+                ));
+            }
+        }
+    }
+    gltf.json["nodes"].push(top_node).unwrap();
+    for node in vis_nodes {
+        gltf.json["nodes"].push(node).unwrap();
+    }
+    if !ctx.mesh_vis_objects.iter().all(Option::is_none) {
+        gltf.use_extension("KHR_node_visibility");
+    }
 
     // Make the skin
 
@@ -449,18 +602,49 @@ fn nodes(ctx: &Ctx, gltf: &mut GlTF) {
 
     gltf.json["scenes"] = array!(
         object!(
-            "nodes" => array!(skel.tree.node_count()),
+            "nodes" => array!(top),
             "name" => ctx.model.name.to_string(),
         )
     );
     gltf.json["scene"] = 0.into();
 }
 
+/// Name for a glTF mesh (and its node): the model name, or for a piece that
+/// has a mesh of its own, the model and piece names.
+fn mesh_name(ctx: &Ctx, mesh: usize) -> String {
+    if ctx.mesh_vis_objects[mesh].is_none() {
+        return ctx.model.name.to_string();
+    }
+    let call_idx = ctx.call_meshes.iter().position(|&m| m == mesh).unwrap();
+    let piece_id = ctx.prims.draw_calls[call_idx].piece_id;
+    format!("{}.{}", ctx.model.name, ctx.model.pieces[piece_id as usize].name)
+}
+
+/// The node each mesh is instanced on. The one without a visibility object
+/// is on the node above the skeleton, and the rest are on the nodes after
+/// it.
+fn mesh_nodes(ctx: &Ctx) -> Vec<usize> {
+    let top = ctx.skel.tree.node_count();
+    let mut next = top + 1;
+    ctx.mesh_vis_objects.iter()
+        .map(|vis_object| match vis_object {
+            None => top,
+            Some(_) => { next += 1; next - 1 }
+        })
+        .collect()
+}
+
 fn animations(ctx: &Ctx, gltf: &mut GlTF) {
     let models_animations = &ctx.conn.models[ctx.model_id].animations;
     let mat_animations = &ctx.conn.models[ctx.model_id].mat_anims;
+    let vis_animations = &ctx.conn.models[ctx.model_id].vis_anims;
+    let pat_animations = match ctx.pattern_mode {
+        PatternMode::Atlas => &ctx.conn.models[ctx.model_id].patterns[..],
+        _ => &[],
+    };
 
-    if models_animations.is_empty() && mat_animations.is_empty() {
+    if models_animations.is_empty() && mat_animations.is_empty() &&
+        vis_animations.is_empty() && pat_animations.is_empty() {
         return;
     }
 
@@ -493,7 +677,7 @@ fn animations(ctx: &Ctx, gltf: &mut GlTF) {
 
             let object_curves =
                 anim.objects_curves.iter()
-                .map(|c| GlTFObjectCurves::for_trs_curves(c))
+                .map(GlTFObjectCurves::for_trs_curves)
                 .collect::<Vec<GlTFObjectCurves>>();
 
             #[derive(Hash, Clone, Copy, PartialEq, Eq)]
@@ -522,7 +706,11 @@ fn animations(ctx: &Ctx, gltf: &mut GlTF) {
                     _ => continue,
                 };
 
-                let curves = &object_curves[object_idx as usize];
+                // The animation may have fewer objects than the model
+                let curves = match object_curves.get(object_idx as usize) {
+                    Some(curves) => curves,
+                    None => continue,
+                };
 
                 // Add channels for any of the TRSs that are animated for this
                 // object
@@ -671,29 +859,31 @@ fn animations(ctx: &Ctx, gltf: &mut GlTF) {
         })
         .collect::<Vec<JsonValue>>();
 
-    // Now material animations
-    let model = &ctx.db.models[ctx.model_id];
-    let mut had_mat_anims = false;
-    for mat_anim_conn in mat_animations {
-        let mat_anim = &ctx.db.mat_anims[mat_anim_conn.mat_anim_id];
+    // Now visibility animations. Pieces whose visibility can change are on
+    // nodes of their own, which are shown and hidden with KHR_node_visibility.
+    let mesh_nodes = mesh_nodes(ctx);
+    let mut had_vis_anims = false;
+    for &vis_anim_id in vis_animations {
+        let vis_anim = &ctx.db.vis_anims[vis_anim_id];
+
+        let timeline_descriptor = TimelineDescriptor {
+            start_frame: 0,
+            end_frame: vis_anim.num_frames,
+            sampling_rate: 1,
+        };
 
         let mut channels: Vec<JsonValue> = vec![];
         let mut samplers: Vec<JsonValue> = vec![];
 
-        for track in &mat_anim.tracks {
-            let u_off_curve = &track.channels[3].curve;
-            let v_off_curve = &track.channels[4].curve;
-
-            // Get common domain
-            let domain = u_off_curve.domain().union(v_off_curve.domain());
-            let (start_frame, end_frame, sampling_rate) = match domain {
-                CurveDomain::None => continue,
-                CurveDomain::Sampled { start_frame, end_frame, sampling_rate } =>
-                    (start_frame, end_frame, sampling_rate),
-            };
-            let timeline_descriptor = TimelineDescriptor {
-                start_frame, end_frame, sampling_rate,
+        for (&vis_object, &node_idx) in ctx.mesh_vis_objects.iter().zip(&mesh_nodes) {
+            let object_idx = match vis_object {
+                Some(object_idx) => object_idx as usize,
+                None => continue,
             };
+            let rest_visible = ctx.model.objects[object_idx].visible;
+            if !vis_anim.animates(object_idx, rest_visible) {
+                continue;
+            }
 
             // Reserve the input accessor
             if !timeline_descs.right_contains(&timeline_descriptor) {
@@ -704,77 +894,265 @@ fn animations(ctx: &Ctx, gltf: &mut GlTF) {
             };
             let &input = timeline_descs.backward(&timeline_descriptor);
 
-            // Find the target
-            let material_idx = model.materials.iter().position(|mat| mat.name == track.name).unwrap();
-            let target = format!(
-                "/materials/{}/pbrMetallicRoughness/baseColorTexture/extensions/KHR_texture_transform/offset",
-                material_idx,
-            );
-
-            // Find texture dimensions
-            let (w, h) = (model.materials[material_idx].width as f64, model.materials[material_idx].height as f64);
-
+            // Booleans are animated as unsigned bytes
             let data = &mut gltf.buffers[data_buffer].bytes;
             let byte_offset = data.len();
-            let num_samples = (end_frame - start_frame) / sampling_rate ;
-            data.reserve(4 * 2 * num_samples as usize);
-            let mut frame = start_frame;
-            while frame < end_frame {
-                let u_off = u_off_curve.sample_at(0.0, frame);
-                let v_off = v_off_curve.sample_at(0.0, frame);
+            for frame in 0..vis_anim.num_frames {
+                data.push(vis_anim.is_visible(frame, object_idx) as u8);
+            }
+            data.resize(data.len().next_multiple_of(4), 0);
+            let output = gltf.json["accessors"].add(object!(
+                "bufferView" => data_buf_view,
+                "type" => "SCALAR",
+                "componentType" => UNSIGNED_BYTE,
+                "byteOffset" => byte_offset,
+                "count" => vis_anim.num_frames,
+            ));
+
+            let sampler = samplers.add(object!(
+                "input" => input,
+                "output" => output,
+                "interpolation" => "STEP",
+            ));
 
-                // Convert to glTF texture space
-                let u_off = u_off / w;
-                let v_off = v_off / h;
+            let pointer = format!("/nodes/{}/extensions/KHR_node_visibility/visible", node_idx);
+            channels.push(object!(
+                "sampler" => sampler,
+                "target" => object!(
+                    "path" => "pointer",
+                    "extensions" => object!(
+                        "KHR_animation_pointer" => object!(
+                            "pointer" => pointer,
+                        ),
+                    ),
+                ),
+            ));
+        }
 
-                data.push_f32(u_off as f32);
-                data.push_f32(v_off as f32);
+        if channels.is_empty() { continue }
 
-                frame += sampling_rate;
+        animations.push(object!(
+            "name" => vis_anim.name.to_string(),
+            "samplers" => samplers,
+            "channels" => channels,
+        ));
+
+        had_vis_anims = true;
+    }
+
+    if had_vis_anims {
+        gltf.use_extension("KHR_animation_pointer");
+    }
+
+    // Now pattern animations. Move the texture transform to the atlas cell
+    // for each keyframe's image.
+    let model = &ctx.db.models[ctx.model_id];
+    let mut had_pat_anims = false;
+    for pat_conn in pat_animations {
+        let pat = &ctx.db.patterns[pat_conn.pattern_id];
+
+        let mut channels: Vec<JsonValue> = vec![];
+        let mut samplers: Vec<JsonValue> = vec![];
+
+        for track in &pat.material_tracks {
+            let material_idx = model.materials.iter().position(|mat| mat.name == track.name).unwrap();
+            let atlas = match ctx.atlases[material_idx] {
+                Some(ref atlas) => atlas,
+                None => continue,
+            };
+
+            let mut keys: Vec<(u16, [f64; 2])> = vec![];
+            for keyframe in &track.keyframes {
+                let image_id = pat_conn.image_id(keyframe.texture_idx, keyframe.palette_idx);
+                let offset = match image_id.and_then(|id| atlas.offset(id)) {
+                    Some(offset) => offset,
+                    None => continue,
+                };
+                // Times must be strictly increasing
+                if keys.last().map(|&(frame, _)| frame >= keyframe.frame).unwrap_or(false) {
+                    continue;
+                }
+                keys.push((keyframe.frame, offset));
             }
+            if keys.is_empty() { continue }
+
+            let data = &mut gltf.buffers[data_buffer].bytes;
+            let input_offset = data.len();
+            for &(frame, _) in &keys {
+                data.push_f32(frame as f32 * FRAME_LENGTH);
+            }
+            let output_offset = data.len();
+            for &(_, offset) in &keys {
+                data.push_f32(offset[0] as f32);
+                data.push_f32(offset[1] as f32);
+            }
+            let input = gltf.json["accessors"].add(object!(
+                "bufferView" => data_buf_view,
+                "type" => "SCALAR",
+                "componentType" => FLOAT,
+                "byteOffset" => input_offset,
+                "count" => keys.len(),
+                "min" => array!(keys[0].0 as f32 * FRAME_LENGTH),
+                "max" => array!(keys[keys.len() - 1].0 as f32 * FRAME_LENGTH),
+            ));
             let output = gltf.json["accessors"].add(object!(
                 "bufferView" => data_buf_view,
                 "type" => "VEC2",
                 "componentType" => FLOAT,
-                "byteOffset" => byte_offset,
-                "count" => num_samples,
+                "byteOffset" => output_offset,
+                "count" => keys.len(),
             ));
 
             let sampler = samplers.add(object!(
                 "input" => input,
                 "output" => output,
+                "interpolation" => "STEP",
             ));
 
+            let pointer = format!(
+                "/materials/{}/pbrMetallicRoughness/baseColorTexture/extensions/KHR_texture_transform/offset",
+                material_idx,
+            );
             channels.push(object!(
-                "target" => target,
                 "sampler" => sampler,
+                "target" => object!(
+                    "path" => "pointer",
+                    "extensions" => object!(
+                        "KHR_animation_pointer" => object!(
+                            "pointer" => pointer,
+                        ),
+                    ),
+                ),
             ));
         }
 
-        if samplers.is_empty() { continue }
+        if channels.is_empty() { continue }
+
+        animations.push(object!(
+            "name" => pat.name.to_string(),
+            "samplers" => samplers,
+            "channels" => channels,
+        ));
+
+        had_pat_anims = true;
+    }
+
+    if had_pat_anims {
+        gltf.use_extension("KHR_animation_pointer");
+    }
+
+    // Now material animations. Animate the KHR_texture_transform for each
+    // material with KHR_animation_pointer.
+    let mut had_mat_anims = false;
+    for mat_anim_conn in mat_animations {
+        let mat_anim = &ctx.db.mat_anims[mat_anim_conn.mat_anim_id];
+
+        let mut channels: Vec<JsonValue> = vec![];
+        let mut samplers: Vec<JsonValue> = vec![];
+
+        for track in &mat_anim.tracks {
+            // Find the target
+            let material_idx = model.materials.iter().position(|mat| mat.name == track.name).unwrap();
+            if ctx.tex_transforms[material_idx].is_none() {
+                continue;
+            }
+            let material = &model.materials[material_idx];
+
+            // Get common domain
+            let domain = track.channels.iter()
+                .fold(CurveDomain::None, |d, channel| d.union(channel.curve.domain()));
+            let (start_frame, end_frame, sampling_rate) = match domain {
+                CurveDomain::None => continue,
+                CurveDomain::Sampled { start_frame, end_frame, sampling_rate } =>
+                    (start_frame, end_frame, sampling_rate),
+            };
+            let timeline_descriptor = TimelineDescriptor {
+                start_frame, end_frame, sampling_rate,
+            };
+
+            // Reserve the input accessor
+            if !timeline_descs.right_contains(&timeline_descriptor) {
+                let accessor = gltf.json["accessors"].add(
+                    JsonValue::new_object()
+                );
+                timeline_descs.insert((accessor, timeline_descriptor));
+            };
+            let &input = timeline_descs.backward(&timeline_descriptor);
+
+            let mut transforms = vec![];
+            let mut frame = start_frame;
+            while frame < end_frame {
+                let mat = track.eval_uv_mat(frame, model.tex_mtx_mode, (material.width, material.height));
+                transforms.push(TextureTransform::from_texel_matrix(&mat, (material.width, material.height)));
+                frame += sampling_rate;
+            }
+            unwrap_angles(transforms.iter_mut().map(|tt| &mut tt.rotation));
+
+            let data = &mut gltf.buffers[data_buffer].bytes;
+            let offset_byte_offset = data.len();
+            for tt in &transforms {
+                data.push_f32(tt.offset[0] as f32);
+                data.push_f32(tt.offset[1] as f32);
+            }
+            let rotation_byte_offset = data.len();
+            for tt in &transforms {
+                data.push_f32(tt.rotation as f32);
+            }
+            let scale_byte_offset = data.len();
+            for tt in &transforms {
+                data.push_f32(tt.scale[0] as f32);
+                data.push_f32(tt.scale[1] as f32);
+            }
+
+            let properties = [
+                ("offset", "VEC2", offset_byte_offset),
+                ("rotation", "SCALAR", rotation_byte_offset),
+                ("scale", "VEC2", scale_byte_offset),
+            ];
+            for &(property, ty, byte_offset) in &properties {
+                let output = gltf.json["accessors"].add(object!(
+                    "bufferView" => data_buf_view,
+                    "type" => ty,
+                    "componentType" => FLOAT,
+                    "byteOffset" => byte_offset,
+                    "count" => transforms.len(),
+                ));
+                let sampler = samplers.add(object!(
+                    "input" => input,
+                    "output" => output,
+                ));
+                let pointer = format!(
+                    "/materials/{}/pbrMetallicRoughness/baseColorTexture/extensions/KHR_texture_transform/{}",
+                    material_idx, property,
+                );
+                channels.push(object!(
+                    "sampler" => sampler,
+                    "target" => object!(
+                        "path" => "pointer",
+                        "extensions" => object!(
+                            "KHR_animation_pointer" => object!(
+                                "pointer" => pointer,
+                            ),
+                        ),
+                    ),
+                ));
+            }
+        }
+
+        if channels.is_empty() { continue }
 
         animations.push(object!(
             "name" => mat_anim.name.to_string(),
             "samplers" => samplers,
-            // glTF requires this be non-empty, so we add a channel that does
-            // nothing.
-            "channels" => vec![object!(
-                "target" => object!("path" => "scale"),
-                "sampler" => 0,
-            )],
-            "extensions" => object!(
-                "EXT_property_animation" => object!(
-                    "channels" => channels,
-                )
-            )
+            "channels" => channels,
         ));
 
         had_mat_anims = true;
     }
 
     if had_mat_anims {
-        gltf.json["extensionsUsed"].push("KHR_texture_transform").unwrap();
-        gltf.json["extensionsUsed"].push("EXT_property_animation").unwrap();
+        gltf.use_extension("KHR_texture_transform");
+        gltf.use_extension("KHR_animation_pointer");
     }
 
     gltf.json["bufferViews"][data_buf_view]["byteLength"] =
@@ -885,8 +1263,22 @@ fn materials(ctx: &Ctx, gltf: &mut GlTF) {
     // Maps a texture index to the sampler and image it will use.
     let mut texture_descs = BiVec::<TextureDescriptor>::new();
 
-    let materials = ctx.model.materials.iter().enumerate()
-        .map(|(material_idx, material)| {
+    // Materials with a material animation
+    let mut animated_materials = vec![false; ctx.model.materials.len()];
+    for mat_anim_conn in &ctx.conn.models[ctx.model_id].mat_anims {
+        for track in &ctx.db.mat_anims[mat_anim_conn.mat_anim_id].tracks {
+            let pos = ctx.model.materials.iter().position(|mat| mat.name == track.name);
+            if let Some(material_idx) = pos {
+                animated_materials[material_idx] = true;
+            }
+        }
+    }
+    let mut uses_tex_transform = false;
+
+    // Makes the material for model.materials[material_idx] showing the given
+    // image.
+    let mut make_material = |material_idx: usize, image_id: Option<ImageId>| {
+        let material = &ctx.model.materials[material_idx];
         let mut mat = object!(
             "name" => material.name.to_string(),
             "pbrMetallicRoughness" => JsonValue::new_object(),
@@ -895,46 +1287,67 @@ fn materials(ctx: &Ctx, gltf: &mut GlTF) {
             )
         );
 
-        let image_id =
-            ctx.conn.models[ctx.model_id]
-            .materials[material_idx].image_id();
-        match image_id {
-            Ok(Some(image_id)) => {
-                let params = ctx.db.textures[image_id.0].params;
-                match params.format().alpha_type(params) {
-                    Alpha::Opaque => (),
-                    Alpha::Transparent =>
-                        mat["alphaMode"] = "MASK".into(),
-                    Alpha::Translucent =>
-                        mat["alphaMode"] = "BLEND".into(),
-                }
-
-                let wrap = |repeat, mirror| {
-                    match (repeat, mirror) {
-                        (false, _) => WrapMode::Clamp,
-                        (true, false) => WrapMode::Repeat,
-                        (true, true) => WrapMode::MirroredRepeat,
-                    }
-                };
-                let params = material.params;
-                let sampler_desc = SamplerDescriptor {
-                    wrap_s: wrap(params.repeat_s(), params.mirror_s()),
-                    wrap_t: wrap(params.repeat_t(), params.mirror_t()),
-                };
-                let sampler = sampler_descs.push(sampler_desc);
-
-                let image_name = &ctx.image_namer.names[&image_id];
-                let image = image_descs.push(image_name.clone());
-
-                let texture_desc = TextureDescriptor { sampler, image };
-                let texture = texture_descs.push(texture_desc);
+        if let Some(image_id) = image_id {
+            let params = ctx.db.textures[image_id.0].params;
+            match params.format().alpha_type(params) {
+                Alpha::Opaque => (),
+                Alpha::Transparent =>
+                    mat["alphaMode"] = "MASK".into(),
+                Alpha::Translucent =>
+                    mat["alphaMode"] = "BLEND".into(),
+            }
 
-                mat["pbrMetallicRoughness"]["baseColorTexture"] =
-                    object!("index" => texture);
-                mat["pbrMetallicRoughness"]["metallicFactor"] = 0.into();
+            let wrap = |repeat, mirror| {
+                match (repeat, mirror) {
+                    (false, _) => WrapMode::Clamp,
+                    (true, false) => WrapMode::Repeat,
+                    (true, true) => WrapMode::MirroredRepeat,
+                }
+            };
+            let params = material.params;
+            let sampler_desc = SamplerDescriptor {
+                wrap_s: wrap(params.repeat_s(), params.mirror_s()),
+                wrap_t: wrap(params.repeat_t(), params.mirror_t()),
+            };
+            let sampler = sampler_descs.push(sampler_desc);
 
+            let atlas = ctx.atlases.get(material_idx).and_then(|a| a.as_ref());
+            let image_name = match atlas {
+                Some(atlas) => &ctx.image_namer.atlas_names[&atlas.images],
+                None => &ctx.image_namer.names[&image_id],
+            };
+            let image = image_descs.push(image_name.clone());
+
+            let texture_desc = TextureDescriptor { sampler, image };
+            let texture = texture_descs.push(texture_desc);
+
+            mat["pbrMetallicRoughness"]["baseColorTexture"] =
+                object!("index" => texture);
+            let tex_transform = ctx.tex_transforms.get(material_idx).and_then(|tt| tt.as_ref());
+            if let Some(tt) = tex_transform {
+                if !tt.is_identity() || animated_materials[material_idx] {
+                    mat["pbrMetallicRoughness"]["baseColorTexture"]["extensions"] = object!(
+                        "KHR_texture_transform" => object!(
+                            "offset" => tt.offset.to_vec(),
+                            "rotation" => tt.rotation,
+                            "scale" => tt.scale.to_vec(),
+                        ),
+                    );
+                    uses_tex_transform = true;
+                }
+            }
+            if let Some(atlas) = atlas {
+                // Select the cell for this image
+                let offset = atlas.offset(image_id).unwrap();
+                let scale = atlas.scale(ctx.db, (material.width, material.height));
+                mat["pbrMetallicRoughness"]["baseColorTexture"]["extensions"] = object!(
+                    "KHR_texture_transform" => object!(
+                        "offset" => offset.to_vec(),
+                        "scale" => scale.to_vec(),
+                    ),
+                );
             }
-            _ => (),
+            mat["pbrMetallicRoughness"]["metallicFactor"] = 0.into();
         }
 
         let has_diffuse =
@@ -970,7 +1383,24 @@ fn materials(ctx: &Ctx, gltf: &mut GlTF) {
         }
 
         mat
-    }).collect::<Vec<JsonValue>>();
+    };
+
+    let mut materials = (0..ctx.model.materials.len())
+        .map(|material_idx| {
+            let image_id = ctx.conn.models[ctx.model_id]
+                .materials[material_idx].image_id()
+                .unwrap_or(None);
+            // A material with an atlas but no image of its own starts out
+            // showing the first image in the atlas.
+            let atlas = ctx.atlases.get(material_idx).and_then(|a| a.as_ref());
+            let image_id = image_id.or_else(|| atlas.map(|atlas| atlas.images[0]));
+            make_material(material_idx, image_id)
+        })
+        .collect::<Vec<JsonValue>>();
+
+    if ctx.pattern_mode == PatternMode::Variants {
+        variants(ctx, gltf, &mut materials, &mut make_material);
+    }
 
     let wrap = |wrap_mode| {
         match wrap_mode {
@@ -1009,6 +1439,188 @@ fn materials(ctx: &Ctx, gltf: &mut GlTF) {
     if gltf.json["materials"].is_empty() { gltf.json.remove("materials"); }
 
     if gltf.json.has_key("materials") {
-        gltf.json["extensionsUsed"].push("KHR_materials_unlit").unwrap();
+        gltf.use_extension("KHR_materials_unlit");
+    }
+    if uses_tex_transform || ctx.atlases.iter().any(|atlas| atlas.is_some()) {
+        gltf.use_extension("KHR_texture_transform");
     }
 }
+
+/// Makes a KHR_materials_variants variant for every keyframe of every pattern,
+/// with a copy of each material showing the image for that keyframe.
+fn variants(
+    ctx: &Ctx,
+    gltf: &mut GlTF,
+    materials: &mut Vec<JsonValue>,
+    make_material: &mut dyn FnMut(usize, Option<ImageId>) -> JsonValue,
+) {
+    let mut variants: Vec<JsonValue> = vec![];
+    // Material copies, keyed by the material they copy and the image they show
+    let mut copies = HashMap::<(usize, ImageId), usize>::new();
+    // For each material, the copies to use and the variants to use them in
+    let mut mappings: Vec<Vec<(usize, Vec<usize>)>> = vec![vec![]; ctx.model.materials.len()];
+
+    for pat_conn in &ctx.conn.models[ctx.model_id].patterns {
+        let pat = &ctx.db.patterns[pat_conn.pattern_id];
+
+        let mut frames = pat.material_tracks.iter()
+            .flat_map(|track| track.keyframes.iter().map(|key| key.frame))
+            .collect::<Vec<u16>>();
+        frames.sort();
+        frames.dedup();
+
+        for frame in frames {
+            let variant = variants.add(object!(
+                "name" => format!("{}@{}", pat.name, frame),
+            ));
+
+            for track in &pat.material_tracks {
+                let material_idx = ctx.model.materials.iter()
+                    .position(|mat| mat.name == track.name)
+                    .unwrap();
+                let (texture_idx, palette_idx) = track.sample(frame);
+                let image_id = match pat_conn.image_id(texture_idx, palette_idx) {
+                    Some(image_id) => image_id,
+                    None => continue,
+                };
+
+                let copy = *copies.entry((material_idx, image_id)).or_insert_with(|| {
+                    materials.add(make_material(material_idx, Some(image_id)))
+                });
+
+                let mapping = &mut mappings[material_idx];
+                match mapping.iter_mut().find(|(mat, _)| *mat == copy) {
+                    Some((_, variants)) => variants.push(variant),
+                    None => mapping.push((copy, vec![variant])),
+                }
+            }
+        }
+    }
+
+    if variants.is_empty() || !gltf.json.has_key("meshes") {
+        return;
+    }
+
+    // Primitives are in the same order as the draw calls in each mesh
+    let mut next_primitive = vec![0; ctx.mesh_vis_objects.len()];
+    for (call, &mesh) in ctx.prims.draw_calls.iter().zip(&ctx.call_meshes) {
+        let primitive = &mut gltf.json["meshes"][mesh]["primitives"][next_primitive[mesh]];
+        next_primitive[mesh] += 1;
+        let mapping = &mappings[call.mat_id as usize];
+        if mapping.is_empty() { continue }
+        primitive["extensions"]["KHR_materials_variants"] = object!(
+            "mappings" => mapping.iter().map(|(material, variants)| {
+                object!(
+                    "material" => *material,
+                    "variants" => variants.clone(),
+                )
+            }).collect::<Vec<JsonValue>>(),
+        );
+    }
+
+    gltf.json["extensions"]["KHR_materials_variants"] = object!(
+        "variants" => variants,
+    );
+    gltf.use_extension("KHR_materials_variants");
+}
+
+#[test]
+fn test_animation_with_fewer_objects() {
+    use crate::connection::ConnectionOptions;
+    use crate::nitro::{Animation, Name};
+    use crate::nitro::animation::TRSCurves;
+    use crate::test_util::{add_model, db_with_files, ModelBuilder};
+
+    let mut db = db_with_files(&["hero.nsbmd"]);
+    let model = ModelBuilder::new("hero")
+        .chain(&["hip", "arm"])
+        .material("default")
+        .triangle(1, 0)
+        .build();
+    add_model(&mut db, model, 0);
+    // Only animates the first object
+    db.animations.push(Animation {
+        name: Name::from_str_truncated("hero_wave"),
+        num_frames: 2,
+        objects_curves: vec![TRSCurves {
+            trans: [
+                Curve::Samples { start_frame: 0, end_frame: 2, values: vec![0.0, 1.0] },
+                Curve::None,
+                Curve::None,
+            ],
+            rotation: Curve::None,
+            scale: [Curve::None, Curve::None, Curve::None],
+        }],
+    });
+    db.animations_found_in.push(0);
+
+    let options = ConnectionOptions { all_animations: true, ..ConnectionOptions::default() };
+    let conn = Connection::build(&db, options);
+    let image_namer = ImageNamer::build(&db, &conn, &[0]);
+    let gltf = to_gltf(&db, &conn, &image_namer, 0, PatternMode::None, None);
+    assert_eq!(gltf.json["animations"].len(), 1);
+    assert_eq!(gltf.json["animations"][0]["channels"].len(), 1);
+}
+
+#[test]
+fn test_visibility() {
+    use crate::connection::ConnectionOptions;
+    use crate::nitro::Name;
+    use crate::nitro::visibility_animation::read_vis_anim;
+    use crate::test_util::{add_model, db_with_files, ModelBuilder};
+    use crate::util::cur::Cur;
+
+    let mut db = db_with_files(&["hero.nsbmd"]);
+    // The arm's piece is hidden at rest
+    let model = ModelBuilder::new("hero")
+        .chain(&["hip", "arm"])
+        .hide(1)
+        .material("default")
+        .triangle(0, 0)
+        .triangle(1, 0)
+        .build();
+    add_model(&mut db, model, 0);
+    // Two frames; the arm is shown on the second
+    let buf = [
+        b'V', 0, b'A', b'V', 2, 0, 2, 0, 0, 0, 0, 0,
+        0b1101, 0, 0, 0,
+    ];
+    db.vis_anims.push(read_vis_anim(Cur::new(&buf), Name::from_str_truncated("wave")).unwrap());
+    db.vis_anims_found_in.push(0);
+
+    let conn = Connection::build(&db, ConnectionOptions::default());
+    let image_namer = ImageNamer::build(&db, &conn, &[0]);
+    let gltf = to_gltf(&db, &conn, &image_namer, 0, PatternMode::None, None);
+    let json = &gltf.json;
+
+    // The arm's piece gets a mesh and node of its own, hidden at rest; the
+    // joints aren't touched
+    assert_eq!(json["meshes"].len(), 2);
+    assert_eq!(json["meshes"][1]["name"], "hero.polygon1");
+    let node_idx = json["nodes"].members()
+        .position(|node| node["name"] == "hero.polygon1")
+        .unwrap();
+    let node = &json["nodes"][node_idx];
+    assert_eq!(node["mesh"], 1);
+    assert_eq!(node["skin"], 0);
+    assert_eq!(node["extensions"]["KHR_node_visibility"]["visible"], false);
+    assert!(json["nodes"].members().all(|node| node["scale"].is_null()));
+    let top = &json["nodes"][json["scenes"][0]["nodes"][0].as_usize().unwrap()];
+    assert_eq!(top["mesh"], 0);
+    assert!(top["children"].members().any(|child| *child == node_idx));
+
+    let anim = &json["animations"][0];
+    assert_eq!(anim["name"], "wave");
+    assert_eq!(anim["channels"].len(), 1);
+    assert_eq!(
+        anim["channels"][0]["target"]["extensions"]["KHR_animation_pointer"]["pointer"],
+        format!("/nodes/{}/extensions/KHR_node_visibility/visible", node_idx),
+    );
+    let output = &json["accessors"][anim["samplers"][0]["output"].as_usize().unwrap()];
+    assert_eq!(output["componentType"], UNSIGNED_BYTE);
+    assert_eq!(output["count"], 2);
+    let view = &json["bufferViews"][output["bufferView"].as_usize().unwrap()];
+    let bytes = &gltf.buffers[view["buffer"].as_usize().unwrap()].bytes;
+    let offset = output["byteOffset"].as_usize().unwrap();
+    assert_eq!(bytes[offset..offset + 2], [0, 1]);
+}
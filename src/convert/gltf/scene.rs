@@ -0,0 +1,275 @@
+//! Merging the glTFs for several models into one scene (convert --scene).
+//!
+//! Each model is converted on its own, exactly as for a single-model file,
+//! and then its glTF is appended to the scene with all its indices shifted.
+//! Images, samplers, and textures that are the same in several models are
+//! only included once, and animations (and material variants) with the same
+//! name are merged, so eg. a "loop" animation plays on every model in a stage
+//! at once.
+
+use super::gltf::GlTF;
+use json::JsonValue;
+
+/// Merges the glTFs into one scene with the given name. The top-level node of
+/// each glTF becomes a top-level node of the scene.
+pub fn merge(name: &str, gltfs: Vec<GlTF>) -> GlTF {
+    let mut out = GlTF::new();
+    for &key in &["meshes", "nodes", "skins", "materials", "textures", "samplers", "images", "animations"] {
+        out.json[key] = array!();
+    }
+    let mut scene_nodes = vec![];
+    let mut variants = vec![];
+
+    for gltf in gltfs {
+        append(&mut out, gltf, &mut scene_nodes, &mut variants);
+    }
+
+    if !variants.is_empty() {
+        out.json["extensions"]["KHR_materials_variants"] = object!(
+            "variants" => variants,
+        );
+    }
+    if !scene_nodes.is_empty() {
+        let scene = object!(
+            "nodes" => scene_nodes,
+            "name" => name,
+        );
+        out.json["scenes"] = array!(scene);
+        out.json["scene"] = 0.into();
+    }
+
+    for &key in &["meshes", "nodes", "skins", "materials", "textures", "samplers", "images", "animations"] {
+        if out.json[key].is_empty() {
+            out.json.remove(key);
+        }
+    }
+    out.cleanup();
+    out
+}
+
+fn append(
+    out: &mut GlTF,
+    gltf: GlTF,
+    scene_nodes: &mut Vec<usize>,
+    variants: &mut Vec<JsonValue>,
+) {
+    let GlTF { buffers, mut json } = gltf;
+
+    let buffer_base = out.buffers.len();
+    let view_base = out.json["bufferViews"].len();
+    let accessor_base = out.json["accessors"].len();
+    let mesh_base = out.json["meshes"].len();
+    let node_base = out.json["nodes"].len();
+    let skin_base = out.json["skins"].len();
+    let material_base = out.json["materials"].len();
+    let anim_base = out.json["animations"].len();
+
+    out.buffers.extend(buffers);
+
+    for mut view in take_members(&mut json["bufferViews"]) {
+        shift(&mut view, &["buffer"], buffer_base);
+        out.json["bufferViews"].push(view).unwrap();
+    }
+
+    for mut accessor in take_members(&mut json["accessors"]) {
+        shift(&mut accessor, &["bufferView"], view_base);
+        out.json["accessors"].push(accessor).unwrap();
+    }
+
+    // Shared things; map our indices to the (possibly existing) ones in out
+    let sampler_map = take_members(&mut json["samplers"]).into_iter()
+        .map(|sampler| find_or_add(&mut out.json["samplers"], sampler))
+        .collect::<Vec<_>>();
+    let image_map = take_members(&mut json["images"]).into_iter()
+        .map(|image| find_or_add(&mut out.json["images"], image))
+        .collect::<Vec<_>>();
+    let texture_map = take_members(&mut json["textures"]).into_iter()
+        .map(|mut texture| {
+            remap(&mut texture, &["source"], &image_map);
+            remap(&mut texture, &["sampler"], &sampler_map);
+            find_or_add(&mut out.json["textures"], texture)
+        })
+        .collect::<Vec<_>>();
+    let variant_map = field(&mut json, &["extensions", "KHR_materials_variants", "variants"])
+        .map(take_members)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|variant| {
+            match variants.iter().position(|v| v["name"] == variant["name"]) {
+                Some(idx) => idx,
+                None => { variants.push(variant); variants.len() - 1 }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    for mut material in take_members(&mut json["materials"]) {
+        remap(&mut material, &["pbrMetallicRoughness", "baseColorTexture", "index"], &texture_map);
+        out.json["materials"].push(material).unwrap();
+    }
+
+    for mut mesh in take_members(&mut json["meshes"]) {
+        for primitive in members(&mut mesh, &["primitives"]) {
+            if let Some(attributes) = field(primitive, &["attributes"]) {
+                for (_, accessor) in attributes.entries_mut() {
+                    shift(accessor, &[], accessor_base);
+                }
+            }
+            shift(primitive, &["indices"], accessor_base);
+            shift(primitive, &["material"], material_base);
+            for mapping in members(primitive, &["extensions", "KHR_materials_variants", "mappings"]) {
+                shift(mapping, &["material"], material_base);
+                for variant in members(mapping, &["variants"]) {
+                    remap(variant, &[], &variant_map);
+                }
+            }
+        }
+        out.json["meshes"].push(mesh).unwrap();
+    }
+
+    for mut node in take_members(&mut json["nodes"]) {
+        for child in members(&mut node, &["children"]) {
+            shift(child, &[], node_base);
+        }
+        shift(&mut node, &["mesh"], mesh_base);
+        shift(&mut node, &["skin"], skin_base);
+        out.json["nodes"].push(node).unwrap();
+    }
+
+    for mut skin in take_members(&mut json["skins"]) {
+        shift(&mut skin, &["skeleton"], node_base);
+        for joint in members(&mut skin, &["joints"]) {
+            shift(joint, &[], node_base);
+        }
+        shift(&mut skin, &["inverseBindMatrices"], accessor_base);
+        out.json["skins"].push(skin).unwrap();
+    }
+
+    for mut anim in take_members(&mut json["animations"]) {
+        for sampler in members(&mut anim, &["samplers"]) {
+            shift(sampler, &["input"], accessor_base);
+            shift(sampler, &["output"], accessor_base);
+        }
+        for channel in members(&mut anim, &["channels"]) {
+            shift(channel, &["target", "node"], node_base);
+            let path = ["target", "extensions", "KHR_animation_pointer", "pointer"];
+            if let Some(pointer) = field(channel, &path) {
+                let shifted = pointer.as_str().map(|p| shift_pointer(p, node_base, material_base));
+                if let Some(shifted) = shifted {
+                    *pointer = shifted.into();
+                }
+            }
+        }
+
+        // Merge with an animation of the same name from an earlier model (not
+        // from this one, since two of those could animate the same thing).
+        let existing = out.json["animations"].members()
+            .take(anim_base)
+            .position(|a| a["name"] == anim["name"]);
+        match existing {
+            Some(idx) => {
+                let dest = &mut out.json["animations"][idx];
+                let sampler_base = dest["samplers"].len();
+                for sampler in take_members(&mut anim["samplers"]) {
+                    dest["samplers"].push(sampler).unwrap();
+                }
+                for mut channel in take_members(&mut anim["channels"]) {
+                    shift(&mut channel, &["sampler"], sampler_base);
+                    dest["channels"].push(channel).unwrap();
+                }
+            }
+            None => out.json["animations"].push(anim).unwrap(),
+        }
+    }
+
+    for node in json["scenes"][0]["nodes"].members() {
+        scene_nodes.push(node.as_usize().unwrap() + node_base);
+    }
+
+    for &key in &["extensionsUsed", "extensionsRequired"] {
+        for ext in json[key].members() {
+            if !out.json[key].members().any(|x| x == ext) {
+                out.json[key].push(ext.clone()).unwrap();
+            }
+        }
+    }
+}
+
+/// Takes the members out of a JSON array (or nothing if it isn't one).
+fn take_members(v: &mut JsonValue) -> Vec<JsonValue> {
+    match v.take() {
+        JsonValue::Array(members) => members,
+        _ => vec![],
+    }
+}
+
+/// The value at the path of keys under v, if it's there. (Indexing a
+/// JsonValue mutably would insert it instead.)
+fn field<'a>(v: &'a mut JsonValue, path: &[&str]) -> Option<&'a mut JsonValue> {
+    let mut v = v;
+    for &key in path {
+        if !v.has_key(key) {
+            return None;
+        }
+        v = &mut v[key];
+    }
+    Some(v)
+}
+
+/// The members of the array at the path under v (none if it isn't there).
+fn members<'a>(v: &'a mut JsonValue, path: &[&str]) -> impl Iterator<Item = &'a mut JsonValue> {
+    field(v, path).into_iter().flat_map(|array| array.members_mut())
+}
+
+/// Adds base to the index at the path under v, if it's there.
+fn shift(v: &mut JsonValue, path: &[&str], base: usize) {
+    if let Some(v) = field(v, path) {
+        if let Some(idx) = v.as_usize() {
+            *v = (idx + base).into();
+        }
+    }
+}
+
+/// Replaces the index at the path under v with what it maps to, if it's there.
+fn remap(v: &mut JsonValue, path: &[&str], map: &[usize]) {
+    if let Some(v) = field(v, path) {
+        if let Some(idx) = v.as_usize() {
+            *v = map[idx].into();
+        }
+    }
+}
+
+/// Index of x in the array, adding it if it isn't there.
+fn find_or_add(array: &mut JsonValue, x: JsonValue) -> usize {
+    match array.members().position(|y| *y == x) {
+        Some(idx) => idx,
+        None => {
+            array.push(x).unwrap();
+            array.len() - 1
+        }
+    }
+}
+
+/// Shifts the index in a KHR_animation_pointer pointer like "/nodes/3/scale".
+fn shift_pointer(pointer: &str, node_base: usize, material_base: usize) -> String {
+    let mut parts = pointer.splitn(4, '/').map(|s| s.to_string()).collect::<Vec<_>>();
+    if parts.len() >= 3 {
+        let base = match parts[1].as_str() {
+            "nodes" => node_base,
+            "materials" => material_base,
+            _ => 0,
+        };
+        if let Ok(idx) = parts[2].parse::<usize>() {
+            parts[2] = (idx + base).to_string();
+        }
+    }
+    parts.join("/")
+}
+
+#[test]
+fn test_shift_pointer() {
+    assert_eq!(shift_pointer("/nodes/3/scale", 10, 20), "/nodes/13/scale");
+    assert_eq!(
+        shift_pointer("/materials/0/pbrMetallicRoughness/baseColorTexture/extensions/KHR_texture_transform/offset", 10, 20),
+        "/materials/20/pbrMetallicRoughness/baseColorTexture/extensions/KHR_texture_transform/offset",
+    );
+}
@@ -0,0 +1,90 @@
+//! Converts Nitro texture matrices to KHR_texture_transform.
+//!
+//! KHR_texture_transform works in glTF UV space (v down, one unit = one copy of
+//! the texture) and applies uv' = offset + rotate(rotation) * scale * uv. A
+//! texture matrix is turned into one by moving it into UV space and reading off
+//! the offset, then splitting the linear part into a rotation and a scale.
+//! Matrices with shear (Max3DS with non-uniform scale and a rotation) can't be
+//! represented exactly; we keep the first axis right in that case.
+
+use cgmath::Matrix4;
+
+#[derive(Copy, Clone, Debug)]
+pub struct TextureTransform {
+    pub offset: [f64; 2],
+    pub rotation: f64,
+    pub scale: [f64; 2],
+}
+
+impl TextureTransform {
+    /// The transform for a texture matrix acting in texel space on a texture
+    /// of the given dimensions.
+    pub fn from_texel_matrix(m: &Matrix4<f64>, (width, height): (u16, u16)) -> TextureTransform {
+        // glTF UV is just texel coords divided by the dimensions
+        let (w, h) = (width.max(1) as f64, height.max(1) as f64);
+        let m =
+            Matrix4::from_nonuniform_scale(1.0 / w, 1.0 / h, 1.0) *
+            m *
+            Matrix4::from_nonuniform_scale(w, h, 1.0);
+
+        let offset = [m.w.x, m.w.y];
+
+        // The linear part is
+        //
+        //   [ c  s ] [ sx  0 ]   [  sx c  sy s ]
+        //   [-s  c ] [  0 sy ] = [ -sx s  sy c ]
+        let sx = (m.x.x * m.x.x + m.x.y * m.x.y).sqrt();
+        let rotation = (-m.x.y).atan2(m.x.x);
+        let (s, c) = rotation.sin_cos();
+        let sy = m.y.x * s + m.y.y * c;
+
+        TextureTransform { offset, rotation, scale: [sx, sy] }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-6;
+        close(self.offset[0], 0.0) && close(self.offset[1], 0.0) &&
+        close(self.rotation, 0.0) &&
+        close(self.scale[0], 1.0) && close(self.scale[1], 1.0)
+    }
+
+    /// The matrix this transform stands for (in glTF UV space).
+    #[cfg(test)]
+    fn matrix(&self) -> Matrix4<f64> {
+        use cgmath::vec3;
+        let (s, c) = self.rotation.sin_cos();
+        let rotate = Matrix4::new(
+            c, -s, 0.0, 0.0,
+            s, c, 0.0, 0.0,
+            0.0, 0.0, 1.0, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+        Matrix4::from_translation(vec3(self.offset[0], self.offset[1], 0.0)) *
+        rotate *
+        Matrix4::from_nonuniform_scale(self.scale[0], self.scale[1], 1.0)
+    }
+}
+
+#[test]
+fn test_from_texel_matrix() {
+    use crate::nitro::texture_matrix::{TextureSrt, TexMtxMode};
+    use cgmath::vec4;
+
+    let srt = TextureSrt {
+        scale: (2.0, -0.5),
+        rotation: 0.3,
+        translation: (0.25, 0.125),
+    };
+    let dim = (32, 16);
+    let texel_mat = srt.matrix(TexMtxMode::Maya, dim);
+    let tt = TextureTransform::from_texel_matrix(&texel_mat, dim);
+
+    // Check it does the same thing to some points
+    let uv_mat = tt.matrix();
+    for &(u, v) in &[(0.0, 0.0), (1.0, 0.0), (0.3, 0.7)] {
+        let a = uv_mat * vec4(u, v, 0.0, 1.0);
+        let b = texel_mat * vec4(u * 32.0, v * 16.0, 0.0, 1.0);
+        assert!((a.x - b.x / 32.0).abs() < 1e-9);
+        assert!((a.y - b.y / 16.0).abs() < 1e-9);
+    }
+}
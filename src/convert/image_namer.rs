@@ -1,31 +1,37 @@
 //! Discovers images in a Connection and assigns them names. We use these for
 //! image filenames so that models know what the path to a specific image it
 //! uses will be.
-use crate::db::{Database, TextureId, PaletteId};
+use crate::db::{Database, ModelId, TextureId, PaletteId};
 use crate::nitro::Name;
 use std::collections::HashMap;
 use crate::util::namers::UniqueNamer;
 use crate::connection::Connection;
+use crate::convert::atlas::Atlas;
 
-type ImageId = (TextureId, Option<PaletteId>);
+pub type ImageId = (TextureId, Option<PaletteId>);
 
 pub struct ImageNamer {
     pub namer: UniqueNamer,
     pub names: HashMap<ImageId, String>,
     pub used_texture_ids: Vec<bool>, // TODO: BitVec
+    /// Names for texture atlases (see `convert::atlas`), keyed by the images
+    /// in them.
+    pub atlas_names: HashMap<Vec<ImageId>, String>,
 }
 
 impl ImageNamer {
-    pub fn build(db: &Database, conn: &Connection) -> ImageNamer {
+    /// Discovers the images used by the given models.
+    pub fn build(db: &Database, conn: &Connection, model_ids: &[ModelId]) -> ImageNamer {
         let mut image_namer = ImageNamer {
             namer: UniqueNamer::new(),
             names: HashMap::new(),
             used_texture_ids: vec![false; db.textures.len()],
+            atlas_names: HashMap::new(),
         };
 
         // Discovery images from model materials
-        for mdl_conn in &conn.models {
-            for mat_conn in &mdl_conn.materials {
+        for &model_id in model_ids {
+            for mat_conn in &conn.models[model_id].materials {
                 match mat_conn.image_id() {
                     Ok(Some(image_id)) =>
                         image_namer.insert_image_id(db, image_id),
@@ -35,23 +41,15 @@ impl ImageNamer {
         }
 
         // Discover images from pattern animations
-        for mdl_conn in &conn.models {
-            for pat_conn in &mdl_conn.patterns {
+        for &model_id in model_ids {
+            for pat_conn in &conn.models[model_id].patterns {
                 let pat = &db.patterns[pat_conn.pattern_id];
                 for track in &pat.material_tracks {
                     for keyframe in &track.keyframes {
-                        let tex_idx = keyframe.texture_idx as usize;
-                        let texture_id = match pat_conn.texture_ids[tex_idx] {
-                            Some(id) => id,
-                            None => continue,
-                        };
-                        let pal_idx = keyframe.palette_idx as usize;
-                        let palette_id = match pat_conn.palette_ids[pal_idx] {
-                            Some(id) => id,
-                            None => continue,
-                        };
-                        let image_id = (texture_id, Some(palette_id));
-                        image_namer.insert_image_id(db, image_id);
+                        let image_id = pat_conn.image_id(keyframe.texture_idx, keyframe.palette_idx);
+                        if let Some(image_id) = image_id {
+                            image_namer.insert_image_id(db, image_id);
+                        }
                     }
                 }
             }
@@ -69,6 +67,23 @@ impl ImageNamer {
         self.used_texture_ids[image_id.0] = true;
     }
 
+    /// Discover the atlases needed to export pattern animations.
+    pub fn add_atlases(&mut self, db: &Database, conn: &Connection, model_ids: &[ModelId]) {
+        for &model_id in model_ids {
+            for material_idx in 0..db.models[model_id].materials.len() {
+                let atlas = match Atlas::for_material(db, conn, model_id, material_idx) {
+                    Some(atlas) => atlas,
+                    None => continue,
+                };
+                let texture_name = db.textures[atlas.images[0].0].name;
+                let namer = &mut self.namer;
+                self.atlas_names.entry(atlas.images).or_insert_with(|| {
+                    namer.get_fresh_name(format!("{}_atlas", texture_name.print_safe()))
+                });
+            }
+        }
+    }
+
     /// Discover even more images by guessing, based on their names, which
     /// palettes go with which textures.
     pub fn add_more_images(&mut self, db: &Database) {
@@ -119,7 +134,7 @@ impl ImageNamer {
 }
 
 /// Append "_pl" to the end of a name.
-fn append_pl(name: &Name) -> Name {
+pub fn append_pl(name: &Name) -> Name {
     let mut res = name.clone();
 
     // Find the index of the first NUL byte in the suffix of NUL bytes.
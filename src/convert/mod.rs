@@ -1,19 +1,26 @@
-mod collada;
-mod image_namer;
-mod gltf;
-
-use crate::cli::Args;
+pub mod collada;
+pub mod image_namer;
+pub mod atlas;
+pub mod gltf;
+pub mod obj;
+pub mod pose;
+
+use crate::cli::{Args, parse_pose};
 use crate::errors::Result;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use crate::util::namers::UniqueNamer;
 use crate::util::OutDir;
 use crate::db::Database;
 use crate::convert::image_namer::ImageNamer;
 use crate::connection::{Connection, ConnectionOptions};
+use crate::convert::atlas::Atlas;
+use crate::convert::gltf::PatternMode;
+use crate::convert::pose::Pose;
+use crate::select::{NamePattern, Selection};
 
-pub fn main(args: &Args) -> Result<()> {
+pub(crate) fn main(args: &Args) -> Result<()> {
     let out_dir_path = PathBuf::from(args.get_opt("output").unwrap());
     let mut out_dir = OutDir::new(out_dir_path)?;
 
@@ -21,16 +28,44 @@ pub fn main(args: &Args) -> Result<()> {
 
     db.print_status();
 
-    let conn_options = ConnectionOptions::from_cli_args(args);
-    let conn = Connection::build(&db, conn_options);
+    let conn_options = ConnectionOptions::from_cli_args(args)?;
+    let mut conn = Connection::build(&db, conn_options);
+
+    let selection = Selection::from_cli_args(args);
+    selection.filter_animations(&db, &mut conn);
+    let model_ids = selection.model_ids(&db);
 
     let format = args.get_opt("format").map(|s| s.to_str().unwrap())
         .unwrap_or("dae");
 
-    let mut image_namer = ImageNamer::build(&db, &conn);
+    let pattern_mode = match args.get_opt("patterns").map(|s| s.to_str().unwrap()) {
+        None => PatternMode::None,
+        Some("atlas") => PatternMode::Atlas,
+        Some("variants") => PatternMode::Variants,
+        _ => unreachable!(),
+    };
+    let is_gltf = format == "glb" || format == "gltf";
+    if pattern_mode != PatternMode::None && !is_gltf {
+        warn!("--patterns only applies to glTF; pattern animations won't be exported");
+    }
+    let scene = args.flags.contains(&"scene");
+    if scene && !is_gltf {
+        warn!("--scene only applies to glTF; writing a file for each model");
+    }
+    let scene = scene && is_gltf;
+
+    let pose = args.get_opt("pose")
+        .and_then(|s| s.to_str())
+        .and_then(parse_pose)
+        .map(|(name, frame)| (NamePattern::new(name), frame));
+
+    let mut image_namer = ImageNamer::build(&db, &conn, &model_ids);
     if args.flags.contains(&"more-textures") {
         image_namer.add_more_images(&db);
     }
+    if pattern_mode == PatternMode::Atlas && is_gltf {
+        image_namer.add_atlases(&db, &conn, &model_ids);
+    }
 
     let mut models_written = 0;
     let mut pngs_written = 0;
@@ -38,17 +73,44 @@ pub fn main(args: &Args) -> Result<()> {
     // Gives unique names to each model file to avoid name clashes.
     let mut model_file_namer = UniqueNamer::new();
 
-    for (model_id, model) in db.models.iter().enumerate() {
+    // With --scene, the glTFs for the models in each file, to be merged into
+    // one scene for the file.
+    let mut scene_gltfs: Vec<(usize, Vec<gltf::GlTF>)> = vec![];
+
+    for &model_id in &model_ids {
+        let model = &db.models[model_id];
         debug!("Converting model {} ({})...", model.name, model_id);
 
+        let pose = match pose {
+            None => None,
+            Some((ref pattern, frame)) => {
+                let pose = Pose::for_model(&db, &conn, model_id, pattern, frame);
+                if pose.is_none() {
+                    warn!("no animation matching {} goes with model {}, exporting it unposed",
+                        pattern, model.name);
+                }
+                pose
+            }
+        };
+
+        if scene {
+            let gltf = gltf::to_gltf(&db, &conn, &image_namer, model_id, pattern_mode, pose.as_ref());
+            let file_id = db.models_found_in[model_id];
+            match scene_gltfs.last_mut() {
+                Some((id, gltfs)) if *id == file_id => gltfs.push(gltf),
+                _ => scene_gltfs.push((file_id, vec![gltf])),
+            }
+            continue;
+        }
+
         let name = model_file_namer.get_fresh_name(format!("{}", model.name.print_safe()));
         let mut f = out_dir.create_file(&format!("{}.{}", name, format))?;
 
         let res = if format == "dae" {
-            let s = collada::write(&db, &conn, &image_namer, model_id);
+            let s = collada::write(&db, &conn, &image_namer, model_id, pose.as_ref());
             f.write_all(s.as_bytes()).and_then(|_| f.flush())
-        } else if format == "glb" || format == "gltf" {
-            let gltf = gltf::to_gltf(&db, &conn, &image_namer, model_id);
+        } else if is_gltf {
+            let gltf = gltf::to_gltf(&db, &conn, &image_namer, model_id, pattern_mode, pose.as_ref());
             if format == "glb" {
                 gltf.write_glb(&mut f)
             } else {
@@ -56,6 +118,13 @@ pub fn main(args: &Args) -> Result<()> {
                 let mut bin_f = out_dir.create_file(&bin_file_name)?;
                 gltf.write_gltf_bin(&mut f, &mut bin_f, &bin_file_name)
             }
+        } else if format == "obj" {
+            let mtl_file_name = format!("{}.mtl", name);
+            let mut mtl_f = out_dir.create_file(&mtl_file_name)?;
+            let (obj, mtl) = obj::write(&db, &conn, &image_namer, model_id, &mtl_file_name, pose.as_ref());
+            f.write_all(obj.as_bytes()).and_then(|_| f.flush())
+                .and_then(|_| mtl_f.write_all(mtl.as_bytes()))
+                .and_then(|_| mtl_f.flush())
         } else {
             unreachable!()
         };
@@ -66,6 +135,30 @@ pub fn main(args: &Args) -> Result<()> {
         }
     }
 
+    for (file_id, gltfs) in scene_gltfs {
+        let stem = db.file_paths[file_id].file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "scene".to_string());
+        debug!("Writing scene {} ({} models)...", stem, gltfs.len());
+
+        let name = model_file_namer.get_fresh_name(stem);
+        let mut f = out_dir.create_file(&format!("{}.{}", name, format))?;
+
+        let gltf = gltf::scene::merge(&name, gltfs);
+        let res = if format == "glb" {
+            gltf.write_glb(&mut f)
+        } else {
+            let bin_file_name = format!("{}.bin", name);
+            let mut bin_f = out_dir.create_file(&bin_file_name)?;
+            gltf.write_gltf_bin(&mut f, &mut bin_f, &bin_file_name)
+        };
+
+        match res {
+            Ok(()) => { models_written += 1; },
+            Err(e) => error!("failed to write {}: {}", name, e),
+        }
+    }
+
     // Save PNGs for all the images
     for ((texture_id, palette_id), image_name) in image_namer.names.drain() {
         let texture = &db.textures[texture_id];
@@ -88,12 +181,32 @@ pub fn main(args: &Args) -> Result<()> {
         }
     }
 
+    // Save PNGs for all the atlases
+    for (images, atlas_name) in image_namer.atlas_names.drain() {
+        let atlas = Atlas { images };
+        let rgba = match atlas.rgba(&db) {
+            Ok(rgba) => rgba,
+            Err(e) => {
+                error!("error generating atlas {}, error: {}", atlas_name, e);
+                continue;
+            }
+        };
+
+        let dim = atlas.dim(&db);
+        let mut png_file = out_dir.create_file(&format!("{}.png", atlas_name))?;
+        match write_rgba(&mut png_file, &rgba[..], dim) {
+            Ok(()) => { pngs_written += 1; }
+            Err(e) => error!("failed writing PNG: {}", e),
+        }
+    }
+
     // Print results
     let plural = |x| if x != 1 { "s" } else { "" };
     let model_file_name = match format {
         "dae" => "DAE",
         "glb" => "GLB",
         "gltf" => "glTF",
+        "obj" => "OBJ",
         _ => unreachable!(),
     };
     println!("Wrote {} {}{}, {} PNG{}.",
@@ -103,6 +216,29 @@ pub fn main(args: &Args) -> Result<()> {
     Ok(())
 }
 
+/// Reads a PNG file into RGBA8888 pixels.
+pub fn read_rgba(path: &Path) -> Result<(Vec<u8>, (u32, u32))> {
+    use png::{ColorType, Decoder, Transformations};
+
+    let mut decoder = Decoder::new(File::open(path)?);
+    // Expand palettes, low bit depths, and tRNS; cut 16-bit channels to 8
+    decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
+
+    let rgba = match info.color_type {
+        ColorType::Rgba => buf,
+        ColorType::Rgb => buf.chunks(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        ColorType::GrayscaleAlpha => buf.chunks(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        ColorType::Grayscale => buf.iter().flat_map(|&x| [x, x, x, 255]).collect(),
+        ColorType::Indexed => bail!("unexpected indexed PNG after expanding"),
+    };
+
+    Ok((rgba, (info.width, info.height)))
+}
+
 pub fn write_rgba(f: &mut File, rgba: &[u8], dim: (u32, u32)) -> Result<()> {
     use png::{Encoder, ColorType, BitDepth};
 
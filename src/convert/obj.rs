@@ -0,0 +1,229 @@
+//! Wavefront OBJ/MTL writer.
+//!
+//! OBJ only holds static geometry, so this writes the model in its rest pose
+//! (or the --pose frame) with no skeleton or animations. Every vertex gets a
+//! `v`. If any draw call used texcoords or normals, every vertex also gets a
+//! `vt` or `vn`, but faces only refer to them for the draw calls that used
+//! them.
+//! Vertex colors are written after the position (`v x y z r g b`), which most
+//! importers understand.
+
+use crate::connection::Connection;
+use crate::convert::image_namer::ImageNamer;
+use crate::convert::pose::Pose;
+use crate::db::{Database, ModelId};
+use crate::primitives::{self, DynamicState, Primitives};
+use crate::util::namers::UniqueNamer;
+use std::fmt::Write;
+
+/// Returns the text of the OBJ file and the MTL file. The OBJ file refers to
+/// the MTL file by `mtl_file_name`.
+pub fn write(
+    db: &Database,
+    conn: &Connection,
+    image_namer: &ImageNamer,
+    model_id: ModelId,
+    mtl_file_name: &str,
+    pose: Option<&Pose>,
+) -> (String, String) {
+    let model = &db.models[model_id];
+
+    let (objects, uv_mats) = match pose {
+        Some(pose) => (pose.objects.clone(), pose.uv_mats.clone()),
+        None => (
+            model.objects.iter().map(|o| o.matrix).collect(),
+            model.materials.iter().map(|mat| mat.texture_mat).collect(),
+        ),
+    };
+    let visibility = &model.objects.iter()
+        .map(|o| o.visible)
+        .collect::<Vec<_>>();
+    let state = DynamicState { objects: &objects, uv_mats: &uv_mats, visibility };
+    let mut prims = Primitives::build(model, primitives::PolyType::TrisAndQuads, state);
+    // OBJ has no way to hide anything, so pieces under hidden objects are
+    // left out instead of exported collapsed to the origin.
+    prims.remove_hidden_draw_calls();
+
+    // OBJ names can't have spaces, etc. and need to be unique.
+    let mut namer = UniqueNamer::new();
+    let material_names = model.materials.iter()
+        .map(|mat| namer.get_fresh_name(mat.name.print_safe().to_string()))
+        .collect::<Vec<_>>();
+
+    let obj = write_obj(&prims, &model.name.print_safe().to_string(), &material_names, mtl_file_name);
+    let mtl = write_mtl(db, conn, image_namer, model_id, &material_names);
+    (obj, mtl)
+}
+
+fn write_obj(
+    prims: &Primitives,
+    model_name: &str,
+    material_names: &[String],
+    mtl_file_name: &str,
+) -> String {
+    let mut s = String::with_capacity(1024 * 1024); // 1MiB
+
+    writeln!(s, "mtllib {}", mtl_file_name).unwrap();
+    writeln!(s, "o {}", model_name).unwrap();
+
+    let use_colors = prims.draw_calls.iter().any(|call| call.used_vertex_color);
+    for v in &prims.vertices {
+        let p = v.position;
+        if use_colors {
+            let c = v.color;
+            writeln!(s, "v {} {} {} {} {} {}", p[0], p[1], p[2], c[0], c[1], c[2]).unwrap();
+        } else {
+            writeln!(s, "v {} {} {}", p[0], p[1], p[2]).unwrap();
+        }
+    }
+    let use_texcoords = prims.draw_calls.iter().any(|call| call.used_texcoords);
+    if use_texcoords {
+        for v in &prims.vertices {
+            writeln!(s, "vt {} {}", v.texcoord[0], v.texcoord[1]).unwrap();
+        }
+    }
+    let use_normals = prims.draw_calls.iter().any(|call| call.used_normals);
+    if use_normals {
+        for v in &prims.vertices {
+            let n = v.normal;
+            writeln!(s, "vn {} {} {}", n[0], n[1], n[2]).unwrap();
+        }
+    }
+
+    let mut cur_material = None;
+    for call in &prims.draw_calls {
+        if cur_material != Some(call.mat_id) {
+            writeln!(s, "usemtl {}", material_names[call.mat_id as usize]).unwrap();
+            cur_material = Some(call.mat_id);
+        }
+
+        let indices = &prims.indices[call.index_range.clone()];
+        for poly in indices.chunks(4) {
+            let poly = if poly[3] == 0xffff { &poly[0..3] } else { poly };
+
+            s.push('f');
+            for &i in poly {
+                // OBJ indices are 1-based
+                let i = i as u32 + 1;
+                match (call.used_texcoords, call.used_normals) {
+                    (false, false) => write!(s, " {}", i).unwrap(),
+                    (true, false) => write!(s, " {}/{}", i, i).unwrap(),
+                    (false, true) => write!(s, " {}//{}", i, i).unwrap(),
+                    (true, true) => write!(s, " {}/{}/{}", i, i, i).unwrap(),
+                }
+            }
+            s.push('\n');
+        }
+    }
+
+    s
+}
+
+fn write_mtl(
+    db: &Database,
+    conn: &Connection,
+    image_namer: &ImageNamer,
+    model_id: ModelId,
+    material_names: &[String],
+) -> String {
+    let mut s = String::new();
+
+    let model = &db.models[model_id];
+    for (material_id, mat) in model.materials.iter().enumerate() {
+        let rgb = |c: [f32; 3]| format!("{} {} {}", c[0], c[1], c[2]);
+
+        writeln!(s, "newmtl {}", material_names[material_id]).unwrap();
+        writeln!(s, "Ka {}", rgb(mat.ambient)).unwrap();
+        writeln!(s, "Kd {}", rgb(mat.diffuse)).unwrap();
+        writeln!(s, "Ks {}", rgb(mat.specular)).unwrap();
+        writeln!(s, "Ke {}", rgb(mat.emission)).unwrap();
+        writeln!(s, "d {}", mat.alpha).unwrap();
+
+        let mat_conn = &conn.models[model_id].materials[material_id];
+        let image_name = match mat_conn.image_id() {
+            Ok(Some(image_id)) => image_namer.names.get(&image_id),
+            _ => None,
+        };
+        if let Some(image_name) = image_name {
+            writeln!(s, "map_Kd {}.png", image_name).unwrap();
+        }
+
+        s.push('\n');
+    }
+
+    s
+}
+
+#[test]
+fn test_write() {
+    use cgmath::vec3;
+    use crate::connection::ConnectionOptions;
+    use crate::test_util::{add_model, db_with_files, ModelBuilder};
+
+    let mut db = db_with_files(&["tri.nsbmd"]);
+    // A triangle under each object
+    let model = ModelBuilder::new("tri")
+        .chain(&["root", "arm"])
+        .material("default")
+        .triangle(0, 0)
+        .triangle(1, 0)
+        .build();
+    add_model(&mut db, model, 0);
+    let conn = Connection::build(&db, ConnectionOptions::default());
+    let image_namer = ImageNamer::build(&db, &conn, &[0]);
+
+    let (obj, mtl) = write(&db, &conn, &image_namer, 0, "tri.mtl", None);
+    assert_eq!(obj, "\
+mtllib tri.mtl
+o tri
+v 1 0 0
+v 2 0 0
+v 1 1 0
+v 2 0 0
+v 3 0 0
+v 2 1 0
+usemtl default
+f 1 2 3
+f 4 5 6
+");
+    assert_eq!(mtl, "\
+newmtl default
+Ka 0.5 0.5 0.5
+Kd 1 1 1
+Ks 0 0 0
+Ke 0 0 0
+d 1
+
+");
+
+    // Hide the root; the face drawn under it is left out
+    db.models[0] = ModelBuilder::new("tri")
+        .chain(&["root", "arm"])
+        .hide(0)
+        .material("default")
+        .triangle(0, 0)
+        .triangle(1, 0)
+        .build();
+    let (obj, _) = write(&db, &conn, &image_namer, 0, "tri.mtl", None);
+    assert_eq!(obj.lines().filter(|l| l.starts_with("f ")).collect::<Vec<_>>(), ["f 4 5 6"]);
+
+    // Texcoords and normals are only referenced by the faces that have them
+    let mut model = ModelBuilder::new("tri")
+        .chain(&["root", "arm"])
+        .material("default")
+        .triangle(0, 0)
+        .triangle_with(1, 0, Some([[0.0, 0.0], [8.0, 0.0], [0.0, 8.0]]), None)
+        .triangle_with(1, 0, None, Some(vec3(0.0, 0.0, 1.0)))
+        .build();
+    model.materials[0].width = 8;
+    model.materials[0].height = 8;
+    db.models[0] = model;
+    let (obj, _) = write(&db, &conn, &image_namer, 0, "tri.mtl", None);
+    let lines = |prefix| obj.lines().filter(|l| l.starts_with(prefix)).collect::<Vec<_>>();
+    assert_eq!(lines("v ").len(), 9);
+    assert_eq!(lines("vt ")[3..6], ["vt 0 1", "vt 1 1", "vt 0 0"]);
+    assert_eq!(lines("vt ").len(), 9);
+    assert_eq!(lines("vn ")[6..9], ["vn 0 0 1", "vn 0 0 1", "vn 0 0 1"]);
+    assert_eq!(lines("vn ").len(), 9);
+    assert_eq!(lines("f "), ["f 1 2 3", "f 4/4 5/5 6/6", "f 7//7 8//8 9//9"]);
+}
@@ -0,0 +1,161 @@
+//! Baking one frame of an animation into the exported mesh (--pose).
+//!
+//! The posed model is exported as a static mesh: no skin, no joints, and no
+//! animations, since those would all be relative to the rest pose.
+
+use cgmath::Matrix4;
+use crate::connection::Connection;
+use crate::db::{Database, ModelId};
+use crate::nitro::Name;
+use crate::select::NamePattern;
+
+/// The matrices for a model at one animation frame.
+pub struct Pose {
+    /// Object matrices, as in DynamicState.
+    pub objects: Vec<Matrix4<f64>>,
+    /// Texture matrix for each material, as in DynamicState.
+    pub uv_mats: Vec<Matrix4<f64>>,
+}
+
+impl Pose {
+    /// Poses the model with the first connected joint animation and the first
+    /// connected material animation matching the pattern. Objects and
+    /// materials they don't animate keep their rest values. None if neither
+    /// kind of animation matches.
+    pub fn for_model(
+        db: &Database,
+        conn: &Connection,
+        model_id: ModelId,
+        pattern: &NamePattern,
+        frame: u16,
+    ) -> Option<Pose> {
+        let model = &db.models[model_id];
+        let mdl_conn = &conn.models[model_id];
+
+        let anim_id = mdl_conn.animations.iter()
+            .cloned()
+            .find(|&id| pattern.matches(id, &db.animations[id].name));
+        let mat_anim_id = mdl_conn.mat_anims.iter()
+            .map(|x| x.mat_anim_id)
+            .find(|&id| pattern.matches(id, &db.mat_anims[id].name));
+        if anim_id.is_none() && mat_anim_id.is_none() {
+            return None;
+        }
+
+        let mut objects = model.objects.iter()
+            .map(|obj| obj.matrix)
+            .collect::<Vec<_>>();
+        if let Some(anim_id) = anim_id {
+            let anim = &db.animations[anim_id];
+            let frame = clamp_frame(&anim.name, frame, anim.num_frames);
+            for (i, curves) in anim.objects_curves.iter().enumerate().take(objects.len()) {
+                objects[i] = curves.sample_at(frame);
+            }
+        }
+
+        let mut uv_mats = model.materials.iter()
+            .map(|mat| mat.texture_mat)
+            .collect::<Vec<_>>();
+        if let Some(mat_anim_id) = mat_anim_id {
+            let mat_anim = &db.mat_anims[mat_anim_id];
+            let frame = clamp_frame(&mat_anim.name, frame, mat_anim.num_frames);
+            for track in &mat_anim.tracks {
+                for (i, mat) in model.materials.iter().enumerate() {
+                    if mat.name == track.name {
+                        uv_mats[i] = track.eval_uv_mat(
+                            frame,
+                            model.tex_mtx_mode,
+                            (mat.width, mat.height),
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+
+        Some(Pose { objects, uv_mats })
+    }
+}
+
+fn clamp_frame(name: &Name, frame: u16, num_frames: u16) -> u16 {
+    if frame >= num_frames {
+        warn!("animation {} only has {} frames", name, num_frames);
+    }
+    frame.min(num_frames.saturating_sub(1))
+}
+
+#[test]
+fn test_for_model() {
+    use cgmath::{vec4, Vector4};
+    use crate::connection::ConnectionOptions;
+    use crate::nitro::animation::{Animation, Curve, TRSCurves};
+    use crate::nitro::material_animation::{MaterialAnimation, MaterialTrack, MaterialChannel, MatChannelTarget};
+    use crate::nitro::texture_matrix::TexMtxMode;
+    use crate::test_util::{add_model, db_with_files, ModelBuilder};
+
+    let name = Name::from_str_truncated;
+    // The curves have three samples, but the animations are only two frames
+    // long, so the last sample is only reached if the frame isn't clamped.
+    let samples = |values: Vec<f64>| Curve::Samples { start_frame: 0, end_frame: 3, values };
+    let still = || TRSCurves {
+        trans: [Curve::None, Curve::None, Curve::None],
+        rotation: Curve::None,
+        scale: [Curve::None, Curve::None, Curve::None],
+    };
+    let channel = |target, curve| MaterialChannel { num_frames: 2, target, curve };
+
+    let mut db = db_with_files(&["hero.nsbmd"]);
+    let mut model = ModelBuilder::new("hero")
+        .chain(&["hip", "arm"])
+        .material("default")
+        .build();
+    model.tex_mtx_mode = TexMtxMode::SoftimageXSI;
+    model.materials[0].width = 32;
+    model.materials[0].height = 16;
+    add_model(&mut db, model, 0);
+    db.animations.push(Animation {
+        name: name("walk"),
+        num_frames: 2,
+        objects_curves: vec![
+            TRSCurves {
+                trans: [samples(vec![0.0, 10.0, 20.0]), Curve::None, Curve::None],
+                ..still()
+            },
+            still(),
+        ],
+    });
+    db.animations_found_in.push(0);
+    db.mat_anims.push(MaterialAnimation {
+        name: name("scroll"),
+        num_frames: 2,
+        tracks: vec![MaterialTrack {
+            name: name("default"),
+            channels: [
+                channel(MatChannelTarget::ScaleU, Curve::None),
+                channel(MatChannelTarget::ScaleV, Curve::None),
+                channel(MatChannelTarget::Rotation, Curve::None),
+                channel(MatChannelTarget::TranslationU, samples(vec![0.0, 0.25, 0.5])),
+                channel(MatChannelTarget::TranslationV, Curve::None),
+            ],
+        }],
+    });
+    db.mat_anims_found_in.push(0);
+    let conn = Connection::build(&db, ConnectionOptions::default());
+
+    let origin = vec4(0.0, 0.0, 0.0, 1.0);
+    let check = |frame, x: f64, s: f64| {
+        let pose = Pose::for_model(&db, &conn, 0, &NamePattern::new(".*"), frame).unwrap();
+        assert_eq!(pose.objects.len(), 2);
+        assert_eq!(pose.objects[0] * origin, vec4(x, 0.0, 0.0, 1.0));
+        assert_eq!(pose.objects[1] * origin, origin);
+        // A translation of u on a 32-wide texture moves s by 32u texels
+        let st: Vector4<f64> = pose.uv_mats[0] * origin;
+        assert!((st.x - s).abs() < 1e-9 && st.y.abs() < 1e-9);
+    };
+    check(0, 0.0, 0.0);
+    check(1, 10.0, 8.0);
+    // Past the end; clamped to the last frame
+    check(5, 10.0, 8.0);
+
+    assert!(Pose::for_model(&db, &conn, 0, &NamePattern::new("run"), 0).is_none());
+}
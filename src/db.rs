@@ -1,13 +1,22 @@
 use crate::cli::Args;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+use memmap2::Mmap;
 use std::collections::HashMap;
 use crate::nitro::{
     Name, Model, Texture, Palette, Animation, Pattern,
-    MaterialAnimation, Container
+    MaterialAnimation, MaterialColorAnimation, VisibilityAnimation,
+    Container,
 };
 use crate::errors::Result;
 use crate::util::cur::Cur;
+use crate::util::glob::Glob;
+use crate::util::SharedBytes;
 
 pub type FileId = usize;
 pub type ModelId = usize;
@@ -16,6 +25,8 @@ pub type PaletteId = usize;
 pub type AnimationId = usize;
 pub type PatternId = usize;
 pub type MatAnimId = usize;
+pub type MatColorAnimId = usize;
+pub type VisAnimId = usize;
 
 #[derive(Default)]
 pub struct Database {
@@ -27,6 +38,8 @@ pub struct Database {
     pub animations: Vec<Animation>,
     pub patterns: Vec<Pattern>,
     pub mat_anims: Vec<MaterialAnimation>,
+    pub mat_color_anims: Vec<MaterialColorAnimation>,
+    pub vis_anims: Vec<VisibilityAnimation>,
 
     pub models_found_in: Vec<FileId>,
     pub textures_found_in: Vec<FileId>,
@@ -34,17 +47,102 @@ pub struct Database {
     pub animations_found_in: Vec<FileId>,
     pub patterns_found_in: Vec<FileId>,
     pub mat_anims_found_in: Vec<FileId>,
+    pub mat_color_anims_found_in: Vec<FileId>,
+    pub vis_anims_found_in: Vec<FileId>,
 
     pub textures_by_name: HashMap<Name, Vec<TextureId>>,
     pub palettes_by_name: HashMap<Name, Vec<PaletteId>>,
 }
 
+/// Controls which files in the directories the user gives us are used. Files
+/// the user names directly are always used.
+#[derive(Default)]
+pub struct InputOptions {
+    /// Look in subdirectories too.
+    pub recursive: bool,
+    /// If non-empty, only use files matching one of these.
+    pub include: Vec<Glob>,
+    /// Don't use files matching any of these.
+    pub exclude: Vec<Glob>,
+    /// If non-empty, only use files with one of these extensions (lowercase,
+    /// without the dot).
+    pub extensions: Vec<String>,
+}
+
+impl InputOptions {
+    /// Creates an InputOptions from the CLI arguments.
+    pub(crate) fn from_cli_args(args: &Args) -> InputOptions {
+        let list = |long| {
+            args.get_opt(long)
+                .map(|s| s.to_string_lossy().into_owned())
+                .map(|s| {
+                    s.split(',')
+                        .map(|x| x.trim())
+                        .filter(|x| !x.is_empty())
+                        .map(|x| x.to_string())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+        InputOptions {
+            recursive: args.flags.contains(&"recursive"),
+            include: list("include").iter().map(|s| Glob::new(s)).collect(),
+            exclude: list("exclude").iter().map(|s| Glob::new(s)).collect(),
+            extensions: list("ext").iter()
+                .map(|s| s.trim_start_matches('.').to_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Whether any option is set. With none, every entry in a directory is
+    /// used, as it was before these options existed, so file IDs don't change.
+    fn is_filtering(&self) -> bool {
+        self.recursive ||
+            !self.include.is_empty() ||
+            !self.exclude.is_empty() ||
+            !self.extensions.is_empty()
+    }
+
+    /// Whether to use a file found in a directory. The path is relative to the
+    /// directory the user gave.
+    fn wants(&self, rel_path: &Path) -> bool {
+        if !self.extensions.is_empty() {
+            let ext = rel_path.extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase());
+            match ext {
+                Some(ext) if self.extensions.contains(&ext) => (),
+                _ => return false,
+            }
+        }
+        if !self.include.is_empty() && !self.include.iter().any(|g| g.matches(rel_path)) {
+            return false;
+        }
+        !self.exclude.iter().any(|g| g.matches(rel_path))
+    }
+}
+
 impl Database {
-    pub fn from_cli_args(args: &Args) -> Result<Database> {
+    pub(crate) fn from_cli_args(args: &Args) -> Result<Database> {
         let user_paths =
             args.free_args.iter()
             .map(PathBuf::from);
-        let file_paths = expand_directories(user_paths);
+        let options = InputOptions::from_cli_args(args);
+        Database::from_paths_with_options(user_paths, &options)
+    }
+
+    /// Loads all the Nitro files at the given paths. Directories are expanded
+    /// into the files in them (but not recursively). Files that can't be read
+    /// or parsed are logged and skipped.
+    pub fn from_paths<I: IntoIterator<Item=PathBuf>>(paths: I) -> Result<Database> {
+        Database::from_paths_with_options(paths, &InputOptions::default())
+    }
+
+    /// Like from_paths, but with options for how to expand directories. When
+    /// any option is set, files in directories that don't start like a Nitro
+    /// file are quietly skipped.
+    pub fn from_paths_with_options<I: IntoIterator<Item=PathBuf>>(paths: I, options: &InputOptions)
+    -> Result<Database> {
+        let file_paths = expand_directories(paths.into_iter(), options);
 
         let mut db: Database = Default::default();
         db.build(file_paths)?;
@@ -58,16 +156,20 @@ impl Database {
         let num_animations = self.animations.len();
         let num_patterns = self.patterns.len();
         let num_mat_anims = self.mat_anims.len();
+        let num_mat_color_anims = self.mat_color_anims.len();
+        let num_vis_anims = self.vis_anims.len();
 
         let plural = |x| if x != 1 { "s" } else { "" };
         println!(
-            "Got {} model{}, {} texture{}, {} palette{}, {} animation{}, {} pattern animation{}, {} material animation{}.",
+            "Got {} model{}, {} texture{}, {} palette{}, {} animation{}, {} pattern animation{}, {} material animation{}, {} material color animation{}, {} visibility animation{}.",
             num_models, plural(num_models),
             num_textures, plural(num_textures),
             num_palettes, plural(num_palettes),
             num_animations, plural(num_animations),
             num_patterns, plural(num_patterns),
             num_mat_anims, plural(num_mat_anims),
+            num_mat_color_anims, plural(num_mat_color_anims),
+            num_vis_anims, plural(num_vis_anims),
         );
 
         if num_mat_anims > 0 {
@@ -80,28 +182,15 @@ impl Database {
 
         debug!("Building database...");
 
-        for file_id in 0..self.file_paths.len() {
-            debug!("Processing {:?}...", self.file_paths[file_id]);
-
-            let buf = match std::fs::read(&self.file_paths[file_id]) {
-                Ok(buf) => buf,
-                Err(e) =>{
-                    error!("file-system error reading {}: {}",
-                        self.file_paths[file_id].to_string_lossy(), e,
-                    );
-                    continue;
-                }
-            };
-
-            use crate::nitro::container::read_container;
-            match read_container(Cur::new(&buf)) {
-                Ok(cont) => {
-                    self.add_container(file_id, cont);
-                }
-                Err(e) => {
-                    error!("couldn't parse as a Nitro file: {}: {}",
-                        self.file_paths[file_id].to_string_lossy(), e);
-                }
+        // Files are parsed in parallel, but added in order, so the IDs are
+        // the same as if we'd gone one by one.
+        let num_threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        let containers = load_files(&self.file_paths, num_threads);
+        for (file_id, conts) in containers.into_iter().enumerate() {
+            for cont in conts {
+                self.add_container(file_id, cont);
             }
         }
 
@@ -126,6 +215,8 @@ impl Database {
         move_from_cont!(animations, animations_found_in);
         move_from_cont!(patterns, patterns_found_in);
         move_from_cont!(mat_anims, mat_anims_found_in);
+        move_from_cont!(mat_color_anims, mat_color_anims_found_in);
+        move_from_cont!(vis_anims, vis_anims_found_in);
     }
 
     /// Fill out `textures_by_name` and `palettes_by_name`.
@@ -144,34 +235,308 @@ impl Database {
     }
 }
 
+/// Reads and parses all the files, on up to num_threads threads. Returns the
+/// containers in each file, in the same order as the paths.
+fn load_files(paths: &[PathBuf], num_threads: usize) -> Vec<Vec<Container>> {
+    let num_threads = num_threads.min(paths.len()).max(1);
+
+    let mut containers: Vec<Vec<Container>> = paths.iter().map(|_| vec![]).collect();
+    let next_file = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..num_threads {
+            let tx = tx.clone();
+            let next_file = &next_file;
+            scope.spawn(move || loop {
+                let file_id = next_file.fetch_add(1, Ordering::Relaxed);
+                if file_id >= paths.len() {
+                    break;
+                }
+                let conts = load_file(&paths[file_id]);
+                if tx.send((file_id, conts)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        let start = Instant::now();
+        let mut last_report = start;
+        for (num_done, (file_id, conts)) in rx.into_iter().enumerate() {
+            containers[file_id] = conts;
+
+            // Report progress about once a second for slow loads
+            let now = Instant::now();
+            if now.duration_since(last_report).as_secs() >= 1 {
+                info!("loaded {}/{} files", num_done + 1, paths.len());
+                last_report = now;
+            }
+        }
+    });
+
+    containers
+}
+
+/// Reads and parses one file. Errors are logged.
+fn load_file(path: &Path) -> Vec<Container> {
+    debug!("Processing {:?}...", path);
+
+    let mut conts = vec![];
+    match read_file_bytes(path) {
+        Ok(buf) => {
+            if let Err(e) = read_file(&buf, &mut conts) {
+                error!("couldn't parse as a Nitro file: {}: {}",
+                    path.to_string_lossy(), e);
+            }
+        }
+        Err(e) => {
+            error!("file-system error reading {}: {}", path.to_string_lossy(), e);
+        }
+    }
+    conts
+}
+
+/// Reads the contents of the file. Big files (like ROMs) are memory-mapped so
+/// they don't have to be read into memory. For small ones, mapping costs more
+/// than it saves.
+fn read_file_bytes(path: &Path) -> std::io::Result<SharedBytes> {
+    const MIN_MAP_SIZE: u64 = 1 << 20;
+
+    let mut file = fs::File::open(path)?;
+    if file.metadata()?.len() >= MIN_MAP_SIZE {
+        // SAFETY: the map is only unsound if the file is changed while it's
+        // mapped. Nothing should be writing to the Nitro files while we read
+        // them.
+        if let Ok(map) = unsafe { Mmap::map(&file) } {
+            return Ok(SharedBytes::new(map));
+        }
+    }
+    let mut buf = vec![];
+    file.read_to_end(&mut buf)?;
+    Ok(SharedBytes::from(buf))
+}
+
+/// Reads the Nitro file in `buf`. Also handles NARCs, reading all the Nitro
+/// files inside, and compressed files. The textures in the containers share
+/// the data in `buf`.
+fn read_file(buf: &SharedBytes, conts: &mut Vec<Container>) -> Result<()> {
+    use crate::nitro::container::read_shared_container;
+    use crate::nitro::narc;
+    use crate::decompress::decompress;
+
+    if narc::is_narc(buf) {
+        let narc = narc::read_narc(buf)?;
+        for file in &narc.files {
+            if let Err(e) = read_file(&buf.slice_of(file.data), conts) {
+                debug!("skipping NARC member {}: {}",
+                    file.name.as_deref().unwrap_or("(unnamed)"), e);
+            }
+        }
+        return Ok(());
+    }
+
+    match read_shared_container(buf) {
+        Ok(cont) => {
+            conts.push(cont);
+            Ok(())
+        }
+        Err(e) => {
+            match decompress(Cur::new(buf)) {
+                Ok(res) => read_file(&SharedBytes::from(res.data), conts),
+                Err(_) => Err(e),
+            }
+        }
+    }
+}
+
 /// Collects the user's provided paths, expanding any that are directories into
-/// their children (only expand once, not recursively).
-fn expand_directories<I: Iterator<Item=PathBuf>>(paths: I) -> Vec<PathBuf> {
+/// the files in them (recursively, if the options say so).
+fn expand_directories<I: Iterator<Item=PathBuf>>(paths: I, options: &InputOptions) -> Vec<PathBuf> {
     let mut file_paths = vec![];
     for path in paths {
         if path.is_dir() {
-            let start_idx = file_paths.len();
-
-            let entries = match fs::read_dir(path) {
-                Ok(entries) => entries,
-                Err(e) => {
-                    warn!("error reading directory: {}", e);
-                    continue;
-                }
-            };
-            for entry in entries {
-                let entry = match entry {
-                    Ok(entry) => entry,
-                    Err(_) => continue,
-                };
-                file_paths.push(entry.path());
+            let num_before = file_paths.len();
+            walk_directory(&path, &path, options, &mut file_paths);
+            if options.recursive {
+                info!("found {} Nitro files under {}",
+                    file_paths.len() - num_before, path.display());
             }
-
-            let end_idx = file_paths.len();
-            file_paths[start_idx..end_idx].sort();
         } else {
             file_paths.push(path);
         }
     }
     file_paths
 }
+
+fn walk_directory(root: &Path, dir: &Path, options: &InputOptions, file_paths: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("error reading directory {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    let mut entries = entries
+        .filter_map(|entry| {
+            entry.map_err(|e| debug!("error reading entry in {}: {}", dir.display(), e)).ok()
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.path());
+
+    for entry in entries {
+        let path = entry.path();
+        if !options.is_filtering() {
+            file_paths.push(path);
+            continue;
+        }
+        // Don't follow symlinks to directories, so we can't loop
+        let is_dir = entry.file_type().map(|ty| ty.is_dir()).unwrap_or(false);
+        if is_dir {
+            if options.recursive {
+                walk_directory(root, &path, options, file_paths);
+            }
+            continue;
+        }
+
+        let rel_path = path.strip_prefix(root).unwrap_or(&path);
+        if !options.wants(rel_path) {
+            continue;
+        }
+        if !has_nitro_stamp(&path) {
+            trace!("skipping {}: not a Nitro file", path.display());
+            continue;
+        }
+        file_paths.push(path);
+    }
+}
+
+/// Whether the file starts like something read_file could read: a Nitro file,
+/// a NARC, or compressed data that decompresses to one of those.
+fn has_nitro_stamp(path: &Path) -> bool {
+    // Enough to hold the compression header, a Huffman tree, and the first
+    // few compressed bytes
+    let mut start = Vec::with_capacity(0x1000);
+    let res = fs::File::open(path)
+        .and_then(|f| f.take(0x1000).read_to_end(&mut start));
+    if let Err(e) = res {
+        debug!("couldn't read {}: {}", path.display(), e);
+        return false;
+    }
+    starts_with_nitro_stamp(&start)
+}
+
+fn starts_with_nitro_stamp(bytes: &[u8]) -> bool {
+    use crate::nitro::container::STAMPS;
+    use crate::decompress::{decompress_up_to, COMPRESSION_START_BYTES};
+
+    let is_stamp = |b: &[u8]| {
+        b.len() >= 4 && (STAMPS.iter().any(|s| *s == &b[..4]) || &b[..4] == b"NARC")
+    };
+
+    if is_stamp(bytes) {
+        return true;
+    }
+    // Lots of files start with one of these bytes by chance, so check that
+    // the data really decompresses to a Nitro file.
+    if bytes.first().map(|b| COMPRESSION_START_BYTES.contains(b)) == Some(true) {
+        if let Ok(res) = decompress_up_to(Cur::new(bytes), 4) {
+            return is_stamp(&res.data);
+        }
+    }
+    false
+}
+
+#[test]
+fn test_threaded_load_order() {
+    use crate::nds::TextureParams;
+    use crate::nitro::write_container;
+
+    // Files with different numbers of textures, so a mix-up would show
+    let dir = std::env::temp_dir().join(format!("apicula-load-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let paths = (0..8u8).map(|i| {
+        let textures = (0..i % 3 + 1).map(|j| Texture {
+            name: Name::from_str_truncated(&format!("tex{}_{}", i, j)),
+            // 4-color 8x8
+            params: TextureParams(2 << 26),
+            data1: vec![i * 16 + j; 16].into(),
+            data2: Default::default(),
+            unknown: 0,
+        }).collect();
+        let cont = Container {
+            stamp: b"BTX0", version: 1, file_size: 0, models: vec![], textures,
+            palettes: vec![], animations: vec![], patterns: vec![],
+            mat_anims: vec![], mat_color_anims: vec![], vis_anims: vec![],
+        };
+        let path = dir.join(format!("{}.nsbtx", i));
+        fs::write(&path, write_container(&cont).unwrap()).unwrap();
+        path
+    }).collect::<Vec<_>>();
+
+    let summarize = |containers: Vec<Vec<Container>>| {
+        containers.into_iter().enumerate()
+            .flat_map(|(file_id, conts)| conts.into_iter().map(move |cont| (file_id, cont)))
+            .flat_map(|(file_id, cont)| {
+                cont.textures.into_iter()
+                    .map(move |tex| (file_id, tex.name, tex.data1.to_vec()))
+            })
+            .collect::<Vec<_>>()
+    };
+    let sequential = summarize(load_files(&paths, 1));
+    let threaded = summarize(load_files(&paths, 4));
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(sequential.len(), 15);
+    assert!(sequential == threaded);
+    assert_eq!(sequential[1], (1, Name::from_str_truncated("tex1_0"), vec![16; 16]));
+}
+
+#[test]
+fn test_starts_with_nitro_stamp() {
+    assert!(starts_with_nitro_stamp(b"BMD0\xff\xfe\x02\x00"));
+    assert!(starts_with_nitro_stamp(b"NARC\xfe\xff\x00\x01"));
+    assert!(!starts_with_nitro_stamp(b"BMD"));
+    assert!(!starts_with_nitro_stamp(b"PNG\x00"));
+
+    // LZ77 with a literal block holding a BTX0 stamp
+    let mut compressed = vec![0x10, 40, 0, 0, 0x00];
+    compressed.extend_from_slice(b"BTX0\xff\xfe\x01\x00");
+    assert!(starts_with_nitro_stamp(&compressed));
+
+    // Same, but the decompressed data isn't a Nitro file
+    let mut compressed = vec![0x10, 40, 0, 0, 0x00];
+    compressed.extend_from_slice(b"text fil");
+    assert!(!starts_with_nitro_stamp(&compressed));
+
+    // Starts with a compression byte, but the size is implausible
+    assert!(!starts_with_nitro_stamp(b"\x10\x00\x00\x00\x00\x00\x00\x00BMD0"));
+}
+
+#[test]
+fn test_expand_directories() {
+    let dir = std::env::temp_dir().join(format!("apicula-expand-{}", std::process::id()));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("a.nsbtx"), b"BTX0\xff\xfe\x01\x00").unwrap();
+    fs::write(dir.join("b.txt"), b"text").unwrap();
+    fs::write(dir.join("sub/c.nsbmd"), b"BMD0\xff\xfe\x02\x00").unwrap();
+    let expand = |options: &InputOptions| {
+        expand_directories(std::iter::once(dir.clone()), options).iter()
+            .map(|path| path.strip_prefix(&dir).unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+    };
+
+    // Without options, every entry gets a file ID, like always
+    let plain = expand(&InputOptions::default());
+    let recursive = expand(&InputOptions { recursive: true, ..InputOptions::default() });
+    let nsbmd_only = expand(&InputOptions {
+        recursive: true,
+        extensions: vec!["nsbmd".to_string()],
+        ..InputOptions::default()
+    });
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(plain, ["a.nsbtx", "b.txt", "sub"]);
+    assert_eq!(recursive, ["a.nsbtx", "sub/c.nsbmd"]);
+    assert_eq!(nsbmd_only, ["sub/c.nsbmd"]);
+}
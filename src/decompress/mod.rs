@@ -1,34 +1,49 @@
 //! Decompress NDS data.
 //!
-//! The compressions methods are the LZ77 methods included in the NDS bios.
+//! The compressions methods are the ones included in the NDS bios (LZ77,
+//! Huffman, and RLE), the LZ77 variant with type 0x40 used by some games, and
+//! the backwards LZ77 used for ARM9 overlays.
 //!
-//! See: http://problemkaputt.de/gbatek.htm#biosdecompressionfunctions
-//! See: DSDecmp (https://github.com/Barubary/dsdecmp)
+//! See: <http://problemkaputt.de/gbatek.htm#biosdecompressionfunctions>
+//! See: DSDecmp (<https://github.com/Barubary/dsdecmp>)
 
 use std::{fmt, error, result};
 use crate::util::bits::BitField;
 use crate::util::cur::{self, Cur};
 
-pub struct DecompressResult<'a> {
+pub struct DecompressResult {
     /// The decompressed data.
     pub data: Vec<u8>,
-    /// A cursor at the end of the compressed data stream.
-    pub end_cur: Cur<'a>,
 }
 
+/// The first bytes of data compressed with one of the formats `decompress`
+/// understands.
+pub const COMPRESSION_START_BYTES: [u8; 6] = [0x10, 0x11, 0x24, 0x28, 0x30, 0x40];
+
 /// Try to decompress data at `cur`.
 pub fn decompress(cur: Cur) -> Result<DecompressResult> {
+    decompress_up_to(cur, usize::MAX)
+}
+
+/// Like `decompress`, but stops after producing (at least) `limit` bytes. Can
+/// be used to cheaply peek at the start of the decompressed data.
+pub fn decompress_up_to(cur: Cur, limit: usize) -> Result<DecompressResult> {
     match cur.peek::<u8>() {
-        Ok(0x10) => de_lz77_0x10(cur),
-        Ok(0x11) => de_lz77_0x11(cur),
+        Ok(0x10) => de_lz77_0x10(cur, limit),
+        Ok(0x11) => de_lz77_0x11(cur, limit),
+        Ok(0x24) | Ok(0x28) => de_huffman(cur, limit),
+        Ok(0x30) => de_rle(cur, limit),
+        Ok(0x40) => de_lz77_0x40(cur, limit),
         _ => Err(Error::DecompressFailed),
     }
 }
 
-fn de_lz77_0x10(mut cur: Cur) -> Result<DecompressResult> {
+/// Reads the header at the start of compressed data, returning the
+/// decompressed size.
+fn read_header(cur: &mut Cur, expected_ty: u8) -> Result<usize> {
     let header = cur.next::<u32>()?;
     let ty = header.bits(0,8);
-    if ty != 0x10 {
+    if ty != expected_ty as u32 {
         return Err(Error::DecompressFailed);
     }
     let mut decompressed_size = header.bits(8, 32) as usize;
@@ -45,9 +60,16 @@ fn de_lz77_0x10(mut cur: Cur) -> Result<DecompressResult> {
         return Err(Error::DecompressFailed);
     }
 
-    let mut out = Vec::with_capacity(decompressed_size);
+    Ok(decompressed_size)
+}
+
+fn de_lz77_0x10(mut cur: Cur, limit: usize) -> Result<DecompressResult> {
+    let decompressed_size = read_header(&mut cur, 0x10)?;
+    let end = decompressed_size.min(limit);
 
-    while out.len() < decompressed_size {
+    let mut out = Vec::with_capacity(end);
+
+    while out.len() < end {
         let mut flags = cur.next::<u8>()?;
         for _ in 0..8 {
             let compressed = flags & 0x80 != 0;
@@ -76,41 +98,22 @@ fn de_lz77_0x10(mut cur: Cur) -> Result<DecompressResult> {
                 }
             }
 
-            if out.len() >= decompressed_size {
+            if out.len() >= end {
                 break;
             }
         }
     }
 
-    Ok(DecompressResult {
-        data: out,
-        end_cur: cur,
-    })
+    Ok(DecompressResult { data: out })
 }
 
-fn de_lz77_0x11(mut cur: Cur) -> Result<DecompressResult> {
-    let header = cur.next::<u32>()?;
-    let ty = header.bits(0,8);
-    if ty != 0x11 {
-        return Err(Error::DecompressFailed);
-    }
-    let mut decompressed_size = header.bits(8, 32) as usize;
-    if decompressed_size == 0 {
-        decompressed_size = cur.next::<u32>()? as usize;
-    }
+fn de_lz77_0x11(mut cur: Cur, limit: usize) -> Result<DecompressResult> {
+    let decompressed_size = read_header(&mut cur, 0x11)?;
+    let end = decompressed_size.min(limit);
 
-    // Too short to contain anything interesting
-    if decompressed_size < 40 {
-        return Err(Error::DecompressFailed);
-    }
-    // Too big (> 4 MiB)
-    if decompressed_size > (1 << 19) * 4 {
-        return Err(Error::DecompressFailed);
-    }
-
-    let mut out = Vec::with_capacity(decompressed_size);
+    let mut out = Vec::with_capacity(end);
 
-    while out.len() < decompressed_size {
+    while out.len() < end {
         let mut flags = cur.next::<u8>()?;
         for _ in 0..8 {
             let compressed = flags & 0x80 != 0;
@@ -172,16 +175,240 @@ fn de_lz77_0x11(mut cur: Cur) -> Result<DecompressResult> {
                 }
             }
 
-            if out.len() >= decompressed_size {
+            if out.len() >= end {
+                break;
+            }
+        }
+    }
+
+    Ok(DecompressResult { data: out })
+}
+
+
+/// LZ77 with type 0x40. Like 0x11, but backreferences are little-endian and
+/// their offsets aren't biased by one.
+fn de_lz77_0x40(mut cur: Cur, limit: usize) -> Result<DecompressResult> {
+    let decompressed_size = read_header(&mut cur, 0x40)?;
+    let end = decompressed_size.min(limit);
+
+    let mut out = Vec::with_capacity(end);
+
+    while out.len() < end {
+        let mut flags = cur.next::<u8>()?;
+        for _ in 0..8 {
+            let compressed = flags & 0x80 != 0;
+            flags <<= 1;
+            if !compressed {
+                // Uncompressed byte
+                out.push(cur.next::<u8>()?);
+            } else {
+                // x = ofs << 4 | n
+                // n = 0 => n = next byte + 0x10
+                // n = 1 => n = next u16 + 0x110
+                let x = cur.next::<u16>()?;
+                let ofs = x.bits(4, 16) as usize;
+                let n = match x.bits(0, 4) {
+                    0 => cur.next::<u8>()? as usize + 0x10,
+                    1 => cur.next::<u16>()? as usize + 0x110,
+                    n => n as usize,
+                };
+
+                if out.len() + n > decompressed_size { // too much data
+                    return Err(Error::DecompressFailed);
+                }
+                if ofs == 0 || out.len() < ofs { // not enough data
+                    return Err(Error::DecompressFailed);
+                }
+
+                for _ in 0 .. n {
+                    let x = out[out.len() - ofs];
+                    out.push(x);
+                }
+            }
+
+            if out.len() >= end {
                 break;
             }
         }
     }
 
-    Ok(DecompressResult {
-        data: out,
-        end_cur: cur,
-    })
+    Ok(DecompressResult { data: out })
+}
+
+fn de_huffman(mut cur: Cur, limit: usize) -> Result<DecompressResult> {
+    let data_bits = cur.peek::<u8>()? & 0xf;
+    let decompressed_size = read_header(&mut cur, 0x20 | data_bits)?;
+    let end = decompressed_size.min(limit);
+
+    // The tree is stored as an array of nodes. Each (non-root) node is
+    // either a data value or an internal node. An internal node's low six
+    // bits give the offset to its pair of children, and its top two bits
+    // tell whether the left and right children are data.
+    let tree_size = cur.peek::<u8>()? as usize;
+    let tree = cur.next_n_u8s((tree_size + 1) * 2)?;
+    const ROOT: usize = 1;
+
+    let mut out = Vec::with_capacity(end);
+    let mut node_pos = ROOT;
+    // Holds the first nibble for 4-bit data
+    let mut half_byte: Option<u8> = None;
+
+    'outer: while out.len() < end {
+        // The bitstream is stored in little-endian words, read MSB first
+        let word = cur.next::<u32>()?;
+        for i in (0..32).rev() {
+            let bit = (word >> i) & 1;
+            let node = tree[node_pos];
+            let child_pos = (node_pos & !1) + (node & 0x3f) as usize * 2 + 2 + bit as usize;
+            if child_pos >= tree.len() {
+                return Err(Error::DecompressFailed);
+            }
+            let child_is_data = node & (0x80 >> bit) != 0;
+
+            if !child_is_data {
+                node_pos = child_pos;
+                continue;
+            }
+
+            let value = tree[child_pos];
+            node_pos = ROOT;
+            if data_bits == 8 {
+                out.push(value);
+            } else {
+                // Low nibble first
+                match half_byte.take() {
+                    None => half_byte = Some(value & 0xf),
+                    Some(lo) => out.push(lo | (value & 0xf) << 4),
+                }
+            }
+
+            if out.len() >= end {
+                break 'outer;
+            }
+        }
+    }
+
+    Ok(DecompressResult { data: out })
+}
+
+fn de_rle(mut cur: Cur, limit: usize) -> Result<DecompressResult> {
+    let decompressed_size = read_header(&mut cur, 0x30)?;
+    let end = decompressed_size.min(limit);
+
+    let mut out = Vec::with_capacity(end);
+
+    while out.len() < end {
+        let flag = cur.next::<u8>()?;
+        if flag & 0x80 == 0 {
+            // Uncompressed run
+            let n = (flag & 0x7f) as usize + 1;
+            out.extend_from_slice(cur.next_n_u8s(n)?);
+        } else {
+            // Run of one repeated byte
+            let n = (flag & 0x7f) as usize + 3;
+            let x = cur.next::<u8>()?;
+            out.extend(std::iter::repeat_n(x, n));
+        }
+    }
+
+    if out.len() > decompressed_size { // too much data
+        return Err(Error::DecompressFailed);
+    }
+
+    Ok(DecompressResult { data: out })
+}
+
+/// Decompress backwards LZ77, used for ARM9 overlays (and sometimes the ARM9
+/// binary itself). Unlike the other formats, this is read from the end of
+/// `buf` backwards.
+///
+/// The footer at the end of `buf` gives the length of the compressed part at
+/// the end of buf (anything before it is uncompressed) and how much larger
+/// the decompressed data is.
+pub fn decompress_backwards(buf: &[u8]) -> Result<DecompressResult> {
+    let len = buf.len();
+    if len < 8 {
+        return Err(Error::DecompressFailed);
+    }
+    let footer = Cur::from_buf_pos(buf, len - 8);
+    let (compressed_len_and_header_len, extra_size) = footer.nth::<(u32, u32)>(0)?;
+    let compressed_len = compressed_len_and_header_len.bits(0, 24) as usize;
+    let header_len = compressed_len_and_header_len.bits(24, 32) as usize;
+    let extra_size = extra_size as usize;
+
+    if extra_size == 0 || header_len < 8 || header_len > compressed_len || compressed_len > len {
+        return Err(Error::DecompressFailed);
+    }
+    // Too big (> 4 MiB)
+    if len + extra_size > (1 << 19) * 4 {
+        return Err(Error::DecompressFailed);
+    }
+
+    // Everything before the compressed part is copied as-is
+    let start = len - compressed_len;
+    let mut out = vec![0; len + extra_size];
+    out[..start].copy_from_slice(&buf[..start]);
+
+    // Positions just past the next byte to read/write
+    let mut read_pos = len - header_len;
+    let mut write_pos = out.len();
+
+    macro_rules! read_byte {
+        () => {{
+            if read_pos <= start {
+                return Err(Error::DecompressFailed);
+            }
+            read_pos -= 1;
+            buf[read_pos]
+        }};
+    }
+
+    'outer: while read_pos > start {
+        let mut flags = read_byte!();
+        for _ in 0..8 {
+            let compressed = flags & 0x80 != 0;
+            flags <<= 1;
+            if !compressed {
+                // Uncompressed byte
+                if write_pos <= start {
+                    return Err(Error::DecompressFailed);
+                }
+                write_pos -= 1;
+                out[write_pos] = read_byte!();
+            } else {
+                // ab cd (read backwards)
+                // =>
+                // n = a + 3
+                // ofs = bcd + 3
+                let ab = read_byte!();
+                let cd = read_byte!();
+                let n = (ab >> 4) as usize + 3;
+                let ofs = (((ab & 0xf) as usize) << 8 | cd as usize) + 3;
+
+                if write_pos < start + n { // too much data
+                    return Err(Error::DecompressFailed);
+                }
+                if write_pos - 1 + ofs >= out.len() { // not enough data
+                    return Err(Error::DecompressFailed);
+                }
+
+                for _ in 0..n {
+                    write_pos -= 1;
+                    out[write_pos] = out[write_pos + ofs];
+                }
+            }
+
+            if read_pos <= start {
+                break 'outer;
+            }
+        }
+    }
+
+    if write_pos != start {
+        return Err(Error::DecompressFailed);
+    }
+
+    Ok(DecompressResult { data: out })
 }
 
 
@@ -204,3 +431,61 @@ impl error::Error for Error {}
 impl From<cur::Error> for Error {
     fn from(_: cur::Error) -> Error { Error::DecompressFailed }
 }
+
+#[test]
+fn test_rle() {
+    // Run of 40 'A's
+    let data = [0x30, 40, 0, 0, 0x80 | (40 - 3), b'A'];
+    let res = decompress(Cur::new(&data)).unwrap();
+    assert_eq!(res.data, vec![b'A'; 40]);
+}
+
+#[test]
+fn test_huffman() {
+    // Tree with a root whose children are the data 'a' (0) and 'b' (1)
+    let mut data = vec![0x28, 40, 0, 0, 1, 0xc0, b'a', b'b'];
+    data.extend_from_slice(&[0x55; 8]);
+    let res = decompress(Cur::new(&data)).unwrap();
+    assert_eq!(res.data, b"ab".repeat(20));
+}
+
+#[test]
+fn test_huffman_4bit() {
+    // Tree: the root's left child is a node with the data 0xa (00) and 0x3
+    // (01), and its right child is the data 0x5 (1). The data is the bytes
+    // a5 35 repeated; nibbles come low first, so the codes are 1 00 1 01.
+    let mut data = vec![0x24, 40, 0, 0, 2, 0x40, 0xc0, 0x5, 0xa, 0x3];
+    data.extend_from_slice(&[
+        0x96, 0x65, 0x59, 0x96, 0x59, 0x96, 0x65, 0x59,
+        0x65, 0x59, 0x96, 0x65, 0x00, 0x65, 0x59, 0x96,
+    ]);
+    let res = decompress(Cur::new(&data)).unwrap();
+    assert_eq!(res.data, [0xa5, 0x35].repeat(20));
+}
+
+#[test]
+fn test_lz77_0x40() {
+    // Three literals, then backreferences with a length in the first byte, a
+    // length in the next byte, and a length in the next u16
+    let data = [
+        0x40, 0x2e, 0x01, 0x00, 0x1c, b'A', b'B', b'C',
+        0x35, 0x00, 0x40, 0x00, 0x04, 0x21, 0x00, 0x02,
+        0x00,
+    ];
+    let res = decompress(Cur::new(&data)).unwrap();
+    let mut expected = b"ABCABCAB".to_vec();
+    expected.extend_from_slice(&b"BCAB".repeat(5));
+    expected.extend_from_slice(&b"AB".repeat(137));
+    assert_eq!(res.data, expected);
+}
+
+#[test]
+fn test_decompress_backwards() {
+    // Three literals, then two backreferences
+    let data = [
+        0x00, 0x00, 0x00, 0xf0, b'A', b'B', b'C', 0x18,
+        16, 0, 0, 8, 8, 0, 0, 0,
+    ];
+    let res = decompress_backwards(&data).unwrap();
+    assert_eq!(res.data, b"ABC".repeat(8));
+}
@@ -0,0 +1,154 @@
+//! Encode PNGs as NDS textures.
+//!
+//! Makes a new .nsbtx holding the textures, or with --into, replaces the
+//! textures with the same names in an existing .nsbmd/.nsbtx.
+
+use crate::cli::Args;
+use crate::convert::read_rgba;
+use crate::errors::Result;
+use crate::nds::{encode_texture, TextureFormat, TextureParams};
+use crate::nitro::{read_container, write_container, Container, Name, Palette, Texture};
+use crate::util::cur::Cur;
+use crate::util::OutDir;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub fn main(args: &Args) -> Result<()> {
+    let out_dir_path = PathBuf::from(args.get_opt("output").unwrap());
+    let mut out_dir = OutDir::new(out_dir_path)?;
+
+    let format = args.get_opt("format")
+        .and_then(|s| s.to_str())
+        .and_then(TextureFormat::from_short_name)
+        .unwrap();
+
+    let mut textures = vec![];
+    let mut palettes = vec![];
+    for input in &args.free_args {
+        let path = Path::new(input);
+        let (rgba, dim) = read_rgba(path)?;
+        let enc = encode_texture(&rgba, dim, format)
+            .map_err(|e| errmsg!("{}: {}", path.display(), e))?;
+
+        let stem = path.file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if stem.len() > 16 {
+            warn!("{} is too long for a texture name; it will be cut to 16 bytes", stem);
+        }
+        let name = Name::from_str_truncated(&stem);
+
+        info!("encoded {} as {}x{} {}", path.display(), dim.0, dim.1, format.desc().name);
+
+        if !enc.palette.is_empty() {
+            palettes.push(Palette {
+                name: palette_name(&stem),
+                off: 0,
+                pal_block: Arc::new(enc.palette.into_boxed_slice()),
+                unknown: 0,
+            });
+        }
+        textures.push(Texture {
+            name,
+            params: enc.params,
+            data1: enc.data1.into(),
+            data2: enc.data2.into(),
+            unknown: 0,
+        });
+    }
+
+    let (cont, file_name) = match args.get_opt("into") {
+        Some(target) => {
+            let target = Path::new(target);
+            let buf = std::fs::read(target)?;
+            let mut cont = read_container(Cur::new(&buf))
+                .map_err(|e| errmsg!("{}: {}", target.display(), e))?;
+            replace_textures(&mut cont, textures, palettes);
+            let file_name = target.file_name().unwrap().to_string_lossy().into_owned();
+            (cont, file_name)
+        }
+        None => {
+            let file_name = if args.free_args.len() == 1 {
+                let stem = Path::new(&args.free_args[0]).file_stem().unwrap();
+                format!("{}.nsbtx", stem.to_string_lossy())
+            } else {
+                "textures.nsbtx".to_string()
+            };
+            let cont = Container {
+                stamp: b"BTX0",
+                version: 1,
+                file_size: 0,
+                models: vec![],
+                textures,
+                palettes,
+                animations: vec![],
+                patterns: vec![],
+                mat_anims: vec![],
+                mat_color_anims: vec![],
+                vis_anims: vec![],
+            };
+            (cont, file_name)
+        }
+    };
+
+    let buf = write_container(&cont)?;
+    let mut f = out_dir.create_file(&file_name)?;
+    f.write_all(&buf)?;
+    f.flush()?;
+
+    info!("wrote {}", file_name);
+
+    Ok(())
+}
+
+/// The usual name for a texture's palette: the texture name plus "_pl".
+fn palette_name(texture_name: &str) -> Name {
+    let mut len = texture_name.len().min(13);
+    while !texture_name.is_char_boundary(len) {
+        len -= 1;
+    }
+    Name::from_str_truncated(&format!("{}_pl", &texture_name[..len]))
+}
+
+/// Puts the new textures and palettes in the container, replacing ones with
+/// the same names.
+fn replace_textures(cont: &mut Container, textures: Vec<Texture>, palettes: Vec<Palette>) {
+    for mut tex in textures {
+        match cont.textures.iter_mut().find(|t| t.name == tex.name) {
+            Some(old) => {
+                if old.params.dim() != tex.params.dim() {
+                    // Texcoords are in texels, so they'd cover a different
+                    // part of the new texture.
+                    warn!("texture {} changed size from {}x{} to {}x{}; models using it \
+                        will map it differently",
+                        tex.name, old.params.width(), old.params.height(),
+                        tex.params.width(), tex.params.height());
+                }
+                // Keep the repeat/mirror and texcoord transform bits
+                let keep = 0xc00f_0000;
+                tex.params = TextureParams((tex.params.0 & !keep) | (old.params.0 & keep));
+                tex.unknown = old.unknown;
+                info!("replaced texture {}", tex.name);
+                *old = tex;
+            }
+            None => {
+                info!("added texture {}", tex.name);
+                cont.textures.push(tex);
+            }
+        }
+    }
+
+    for pal in palettes {
+        match cont.palettes.iter_mut().find(|p| p.name == pal.name) {
+            Some(old) => {
+                info!("replaced palette {}", pal.name);
+                *old = pal;
+            }
+            None => {
+                info!("added palette {}", pal.name);
+                cont.palettes.push(pal);
+            }
+        }
+    }
+}
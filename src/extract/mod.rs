@@ -1,10 +1,13 @@
 //! Extract recognized container files from ROMs or other packed files.
 
+mod nds_rom;
+
 use crate::cli::Args;
 use crate::decompress;
 use crate::errors::Result;
 use crate::nitro::Container;
 use crate::nitro::container::read_container;
+use crate::nitro::narc::{self, Narc};
 use std::collections::HashSet;
 use std::fs;
 use std::io::Write;
@@ -22,66 +25,186 @@ pub fn main(args: &Args) -> Result<()> {
     let out_dir = OutDir::new(out_dir_path)?;
     let mut output = ExtractOutput::new(out_dir);
 
-    scan_for_nitro_files(&mut output, cur);
-    scan_for_compressed_nitro_files(&mut output, cur);
+    let mut extracted_from_fs = false;
+    if nds_rom::is_rom(&input) {
+        match nds_rom::read_rom(&input) {
+            Ok(rom) => {
+                extract_from_rom(&mut output, &rom);
+                extracted_from_fs = true;
+            }
+            Err(e) => {
+                warn!("couldn't read the ROM filesystem: {}", e);
+                info!("falling back to scanning the whole ROM");
+            }
+        }
+    }
+    if !extracted_from_fs {
+        // The members of a NARC (possibly compressed) are extracted to the
+        // top level of the output directory
+        let decompressed = decompress::decompress(cur).ok().map(|res| res.data);
+        let narc_buf = decompressed.as_deref().unwrap_or(&input);
+        if let Ok(narc) = narc::read_narc(narc_buf) {
+            extract_from_narc(&mut output, &narc, "");
+            extracted_from_fs = true;
+        }
+    }
+    if !extracted_from_fs {
+        scan_for_nitro_files(&mut output, cur, "");
+        scan_for_compressed_nitro_files(&mut output, cur, "");
+        scan_for_backwards_compressed_nitro_files(&mut output, &input, "");
+    }
 
     output.print_report();
 
     Ok(())
 }
 
-fn scan_for_nitro_files(output: &mut ExtractOutput, mut cur: Cur) {
+/// Extracts the files in an NDS ROM's filesystem, keeping their original
+/// paths.
+fn extract_from_rom(output: &mut ExtractOutput, rom: &nds_rom::Rom) {
+    for file in &rom.files {
+        extract_named_file(output, file.data, &file.path, false);
+    }
+
+    for &data in &rom.unnamed_data {
+        scan_for_nitro_files(output, Cur::new(data), "");
+        scan_for_compressed_nitro_files(output, Cur::new(data), "");
+        scan_for_backwards_compressed_nitro_files(output, data, "");
+    }
+}
+
+/// Extracts the members of a NARC into the directory `dir`, keeping their
+/// names. Members without names are named by their index.
+fn extract_from_narc(output: &mut ExtractOutput, narc: &Narc, dir: &str) {
+    for file in &narc.files {
+        match file.name {
+            Some(ref name) => {
+                let path = format!("{}{}", dir, name);
+                extract_named_file(output, file.data, &path, false);
+            }
+            None => {
+                let path = format!("{}{:04}", dir, file.index);
+                extract_named_file(output, file.data, &path, true);
+            }
+        }
+    }
+}
+
+/// Extracts `data`, a file from a filesystem or archive, to `path`. Nitro files
+/// are saved as-is (after decompressing), and NARCs are unpacked into a
+/// directory named after them. If `add_ext` is set, the appropriate extension
+/// is added to the name of a Nitro file.
+fn extract_named_file(output: &mut ExtractOutput, data: &[u8], path: &str, add_ext: bool) {
+    if try_extract_named_file(output, data, path, add_ext) {
+        return;
+    }
+    if let Ok(result) = decompress::decompress(Cur::new(data)) {
+        if try_extract_named_file(output, &result.data, path, add_ext) {
+            return;
+        }
+    }
+
+    // Not a Nitro file itself; maybe there are some inside it. Put any we
+    // find in a directory named after the file.
+    let dir = format!("{}/", path);
+    scan_for_nitro_files(output, Cur::new(data), &dir);
+    scan_for_compressed_nitro_files(output, Cur::new(data), &dir);
+}
+
+fn try_extract_named_file(output: &mut ExtractOutput, data: &[u8], path: &str, add_ext: bool) -> bool {
+    if let Ok(cont) = read_container(Cur::new(data)) {
+        if cont.file_size as usize <= data.len() {
+            let bytes = &data[..cont.file_size as usize];
+            let save_path =
+                if add_ext {
+                    format!("{}.{}", path, file_extension(&cont))
+                } else {
+                    path.to_string()
+                };
+            output.save_file_at(save_path, bytes, &cont);
+            return true;
+        }
+    }
+    if let Ok(narc) = narc::read_narc(data) {
+        extract_from_narc(output, &narc, &format!("{}/", path));
+        return true;
+    }
+    false
+}
+
+/// Scans for Nitro files, saving the ones found in the output directory `dir`
+/// (which is either empty or ends in a slash).
+fn scan_for_nitro_files(output: &mut ExtractOutput, mut cur: Cur, dir: &str) {
     while let Some(start_idx) = find_next_stamp(cur.slice_from_cur_to_end()) {
         cur.jump_forward(start_idx);
-        scan_for_file_at(output, &mut cur);
+        scan_for_file_at(output, &mut cur, dir);
     }
 }
 
 /// Scans for a Nitro file at cur and outputs it if it exists.
 /// Also moves cur to where you should resume searching from.
-fn scan_for_file_at(output: &mut ExtractOutput, cur: &mut Cur) {
+fn scan_for_file_at(output: &mut ExtractOutput, cur: &mut Cur, dir: &str) {
     if let Ok(cont) = read_container(*cur) {
         if let Ok(file_bytes) = cur.next_n_u8s(cont.file_size as usize) {
-            output.save_file(file_bytes, &cont);
+            output.save_file(dir, file_bytes, &cont);
             return;
         }
     }
     cur.jump_forward(1);
 }
 
-fn scan_for_compressed_nitro_files(output: &mut ExtractOutput, mut cur: Cur) {
+fn scan_for_compressed_nitro_files(output: &mut ExtractOutput, mut cur: Cur, dir: &str) {
     while let Some(start_idx) = find_next_compression_start_byte(cur.slice_from_cur_to_end()) {
         cur.jump_forward(start_idx);
-        if let Ok(result) = decompress::decompress(cur) {
-            scan_for_nitro_files(output, Cur::new(&result.data));
+        if worth_decompressing(cur) {
+            if let Ok(result) = decompress::decompress(cur) {
+                scan_for_nitro_files(output, Cur::new(&result.data), dir);
+            }
         }
         cur.jump_forward(1);
     }
 }
 
-fn find_next_stamp(bytes: &[u8]) -> Option<usize> {
-    // find BMD0|BTX0|BCA0|BTP0|BTA0
-    let mut i = 0;
-    while i + 3 < bytes.len() {
-        if bytes[i] == b'B' && bytes[i+3] == b'0' {
-            if (bytes[i+1] == b'M' && bytes[i+2] == b'D') ||
-               (bytes[i+1] == b'T' && bytes[i+2] == b'X') ||
-               (bytes[i+1] == b'C' && bytes[i+2] == b'A') ||
-               (bytes[i+1] == b'T' && bytes[i+2] == b'P') ||
-               (bytes[i+1] == b'T' && bytes[i+2] == b'A') {
-                return Some(i);
+/// Whether to try decompressing the data at `cur` while scanning.
+///
+/// Almost any bytes are valid RLE or Huffman data, so trying to decompress
+/// every candidate fully would be slow. For formats other than the LZ77 ones,
+/// only the ones that start with a Nitro file or NARC are accepted.
+fn worth_decompressing(cur: Cur) -> bool {
+    match cur.peek::<u8>() {
+        Ok(0x10) | Ok(0x11) => true,
+        _ => {
+            match decompress::decompress_up_to(cur, 4) {
+                Ok(result) => {
+                    let head = &result.data[..result.data.len().min(4)];
+                    find_next_stamp(head) == Some(0) || head == b"NARC"
+                }
+                Err(_) => false,
             }
         }
-        i += 1;
     }
-    None
 }
 
-fn find_next_compression_start_byte(bytes: &[u8]) -> Option<usize> {
-    // find 0x10|0x11
+/// Scans for Nitro files inside backwards-compressed data (eg. an overlay).
+fn scan_for_backwards_compressed_nitro_files(output: &mut ExtractOutput, data: &[u8], dir: &str) {
+    if let Ok(result) = decompress::decompress_backwards(data) {
+        scan_for_nitro_files(output, Cur::new(&result.data), dir);
+    }
+}
+
+fn find_next_stamp(bytes: &[u8]) -> Option<usize> {
+    // find BMD0|BTX0|BCA0|BTP0|BTA0|BMA0|BVA0
     let mut i = 0;
-    while i != bytes.len() {
-        if bytes[i] == 0x10 || bytes[i] == 0x11 {
+    while i + 3 < bytes.len() {
+        if bytes[i] == b'B' && bytes[i+3] == b'0' && (
+            (bytes[i+1] == b'M' && bytes[i+2] == b'D') ||
+            (bytes[i+1] == b'T' && bytes[i+2] == b'X') ||
+            (bytes[i+1] == b'C' && bytes[i+2] == b'A') ||
+            (bytes[i+1] == b'T' && bytes[i+2] == b'P') ||
+            (bytes[i+1] == b'T' && bytes[i+2] == b'A') ||
+            (bytes[i+1] == b'M' && bytes[i+2] == b'A') ||
+            (bytes[i+1] == b'V' && bytes[i+2] == b'A')
+        ) {
             return Some(i);
         }
         i += 1;
@@ -89,6 +212,10 @@ fn find_next_compression_start_byte(bytes: &[u8]) -> Option<usize> {
     None
 }
 
+fn find_next_compression_start_byte(bytes: &[u8]) -> Option<usize> {
+    bytes.iter().position(|b| decompress::COMPRESSION_START_BYTES.contains(b))
+}
+
 struct ExtractOutput {
     taken_file_names: HashSet<String>,
     out_dir: OutDir,
@@ -97,6 +224,8 @@ struct ExtractOutput {
     num_bcas: u32,
     num_btps: u32,
     num_btas: u32,
+    num_bmas: u32,
+    num_bvas: u32,
 }
 
 impl ExtractOutput {
@@ -109,33 +238,31 @@ impl ExtractOutput {
             num_bcas: 0,
             num_btps: 0,
             num_btas: 0,
+            num_bmas: 0,
+            num_bvas: 0,
         }
     }
 
     /// Print report on extraction results.
     fn print_report(&self) {
         let plural = |x| if x != 1 { "s" } else { "" };
-        println!("Found {} BMD{}, {} BTX{}, {} BCA{}, {} BTP{}, {} BTA{}.",
+        println!("Found {} BMD{}, {} BTX{}, {} BCA{}, {} BTP{}, {} BTA{}, {} BMA{}, {} BVA{}.",
             self.num_bmds, plural(self.num_bmds),
             self.num_btxs, plural(self.num_btxs),
             self.num_bcas, plural(self.num_bcas),
             self.num_btps, plural(self.num_btps),
             self.num_btas, plural(self.num_btas),
+            self.num_bmas, plural(self.num_bmas),
+            self.num_bvas, plural(self.num_bvas),
         );
     }
 
     /// Given the slice `bytes` that successfully parsed as the Nitro container
-    /// `container`, save the slice to a file in the output directory.
-    fn save_file(&mut self, bytes: &[u8], container: &Container) {
-        let file_name = guess_container_name(container);
-        let file_extension = match container.stamp {
-            b"BMD0" => "nsbmd",
-            b"BTX0" => "nsbtx",
-            b"BCA0" => "nsbca",
-            b"BTP0" => "nsbtp",
-            b"BTA0" => "nsbta",
-            _ => "nsbxx",
-        };
+    /// `container`, save the slice to a file in the directory `dir` of the
+    /// output directory, guessing a name for it.
+    fn save_file(&mut self, dir: &str, bytes: &[u8], container: &Container) {
+        let file_name = format!("{}{}", dir, guess_container_name(container));
+        let file_extension = file_extension(container);
 
         // Find an available filename
         let mut save_path = format!("{}.{}", file_name, file_extension);
@@ -146,6 +273,13 @@ impl ExtractOutput {
             write!(save_path, "{}.{:03}.{}", file_name, cntr, file_extension).unwrap();
             cntr += 1;
         }
+
+        self.save_file_at(save_path, bytes, container);
+    }
+
+    /// Like `save_file`, but saves to the given path (relative to the output
+    /// directory) instead of guessing one.
+    fn save_file_at(&mut self, save_path: String, bytes: &[u8], container: &Container) {
         self.taken_file_names.insert(save_path.clone());
 
         let result = self.out_dir
@@ -159,16 +293,31 @@ impl ExtractOutput {
                     b"BCA0" => self.num_bcas += 1,
                     b"BTP0" => self.num_btps += 1,
                     b"BTA0" => self.num_btas += 1,
+                    b"BMA0" => self.num_bmas += 1,
+                    b"BVA0" => self.num_bvas += 1,
                     _ => (),
                 }
             }
             Err(e) => {
-                error!("failed to write {}: {:?}", file_name, e);
+                error!("failed to write {}: {:?}", save_path, e);
             }
         }
     }
 }
 
+fn file_extension(cont: &Container) -> &'static str {
+    match cont.stamp {
+        b"BMD0" => "nsbmd",
+        b"BTX0" => "nsbtx",
+        b"BCA0" => "nsbca",
+        b"BTP0" => "nsbtp",
+        b"BTA0" => "nsbta",
+        b"BMA0" => "nsbma",
+        b"BVA0" => "nsbva",
+        _ => "nsbxx",
+    }
+}
+
 /// Guess a name for `cont` using the name of its first item.
 fn guess_container_name(cont: &Container) -> String {
     if !cont.models.is_empty() {
@@ -183,6 +332,10 @@ fn guess_container_name(cont: &Container) -> String {
         format!("{}", cont.patterns[0].name.print_safe())
     } else if !cont.mat_anims.is_empty() {
         format!("{}", cont.mat_anims[0].name.print_safe())
+    } else if !cont.mat_color_anims.is_empty() {
+        format!("{}", cont.mat_color_anims[0].name.print_safe())
+    } else if !cont.vis_anims.is_empty() {
+        format!("{}", cont.vis_anims[0].name.print_safe())
     } else {
         match cont.stamp {
             b"BMD0" => "empty_model_file",
@@ -190,6 +343,8 @@ fn guess_container_name(cont: &Container) -> String {
             b"BCA0" => "empty_animation_file",
             b"BTP0" => "empty_pattern_file",
             b"BTA0" => "empty_material_anim_file",
+            b"BMA0" => "empty_material_color_anim_file",
+            b"BVA0" => "empty_visibility_anim_file",
             _ => "empty_unknown_file",
         }.to_string()
     }
@@ -0,0 +1,107 @@
+//! Read the filesystem of an NDS ROM.
+//!
+//! A ROM stores its files in one flat array (the File Allocation Table, FAT)
+//! and gives names to some of them with a File Name Table (see `nds::fnt`).
+//! Files that aren't in the FNT are usually overlays for the ARM9.
+//!
+//! See: <http://problemkaputt.de/gbatek.htm#dscartridgenitroromandnitroarcfilesystems>
+
+use crate::errors::Result;
+use crate::nds::fnt::read_fnt;
+use crate::util::cur::Cur;
+
+/// The contents of an NDS ROM.
+pub struct Rom<'a> {
+    /// Files that have a name in the FNT.
+    pub files: Vec<RomFile<'a>>,
+    /// Data in the ROM that isn't a named file, eg. the ARM9/ARM7 binaries
+    /// and overlays.
+    pub unnamed_data: Vec<&'a [u8]>,
+}
+
+pub struct RomFile<'a> {
+    /// Path of the file in the ROM filesystem, eg. "data/pokemon/p001.nsbmd".
+    pub path: String,
+    pub data: &'a [u8],
+}
+
+/// Whether `buf` starts with a valid NDS ROM header.
+pub fn is_rom(buf: &[u8]) -> bool {
+    if buf.len() < 0x200 {
+        return false;
+    }
+    let header_crc = Cur::from_buf_pos(buf, 0x15e).next::<u16>().unwrap();
+    crc16(&buf[..0x15e]) == header_crc
+}
+
+pub fn read_rom(buf: &[u8]) -> Result<Rom<'_>> {
+    let cur = Cur::new(buf);
+    fields!(cur + 0x20usize, rom_header {
+        arm9_off: u32,
+        _arm9_entry: u32,
+        _arm9_load_addr: u32,
+        arm9_size: u32,
+        arm7_off: u32,
+        _arm7_entry: u32,
+        _arm7_load_addr: u32,
+        arm7_size: u32,
+        fnt_off: u32,
+        _fnt_size: u32,
+        fat_off: u32,
+        fat_size: u32,
+    });
+
+    // Read the FAT
+    let fat = (cur + fat_off)
+        .next_n::<(u32, u32)>(fat_size as usize / 8)?
+        .map(|(start, end)| {
+            let (start, end) = (start as usize, end as usize);
+            if start <= end && end <= buf.len() {
+                Some(&buf[start..end])
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<Option<&[u8]>>>();
+
+    // Find the path to every file the FNT names
+    let mut paths = read_fnt(cur + fnt_off, fat.len())?;
+
+    let mut rom = Rom { files: vec![], unnamed_data: vec![] };
+    for (file_id, data) in fat.into_iter().enumerate() {
+        let data = match data {
+            Some(data) => data,
+            None => continue,
+        };
+        match paths[file_id].take() {
+            Some(path) => rom.files.push(RomFile { path, data }),
+            None => rom.unnamed_data.push(data),
+        }
+    }
+
+    let region = |off: u32, size: u32| {
+        let (start, end) = (off as usize, off as usize + size as usize);
+        if start <= end && end <= buf.len() { Some(&buf[start..end]) } else { None }
+    };
+    rom.unnamed_data.extend(region(arm9_off, arm9_size));
+    rom.unnamed_data.extend(region(arm7_off, arm7_size));
+
+    Ok(rom)
+}
+
+/// CRC-16 used in the ROM header.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for &b in data {
+        crc ^= b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xa001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+#[test]
+fn test_crc16() {
+    assert_eq!(crc16(b"123456789"), 0x4b37);
+}
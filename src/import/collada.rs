@@ -0,0 +1,932 @@
+//! Loads the parts of a COLLADA file (.dae) that import uses, into the same
+//! Scene the glTF loader produces.
+//!
+//! Only meshes made of polygons are read, along with the skins on them (from
+//! controllers), the node hierarchy of the visual scene, and the diffuse
+//! color/texture of materials. Animations, cameras, lights, etc. are ignored.
+//!
+//! The differences from glTF are fixed up here, so compile doesn't have to
+//! care where the scene came from:
+//!
+//! * each primitive input has its own index in COLLADA; vertices are made for
+//!   each distinct combination of indices
+//! * texcoords are flipped, since COLLADA has the origin at the bottom-left
+//! * a skin's bind shape matrix is applied to the mesh
+//! * Z_UP and X_UP scenes are rotated to be Y_UP
+
+use cgmath::{Matrix3, Matrix4, Point3, vec3, Deg, InnerSpace, SquareMatrix, Transform};
+use roxmltree::{Document, Node as XmlNode};
+use std::collections::HashMap;
+use std::path::Path;
+use crate::errors::Result;
+use super::compile::normal_matrix;
+use super::gltf::{
+    decompose, percent_decode, Image, Material, Mesh, Node, Primitive, Scene, Skin,
+    REPEAT, MIRRORED_REPEAT,
+};
+
+/// glTF's CLAMP_TO_EDGE wrap mode.
+const CLAMP_TO_EDGE: u32 = 33071;
+
+pub fn load(path: &Path) -> Result<Scene> {
+    let text = std::fs::read_to_string(path)?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    parse(&text, base_dir)
+}
+
+fn parse(text: &str, base_dir: &Path) -> Result<Scene> {
+    let doc = match Document::parse(text) {
+        Ok(doc) => doc,
+        Err(e) => bail!("couldn't parse COLLADA XML: {}", e),
+    };
+    let root = doc.root_element();
+    if root.tag_name().name() != "COLLADA" {
+        bail!("not a COLLADA file");
+    }
+
+    let ids = doc.descendants()
+        .filter_map(|x| Some((x.attribute("id")?, x)))
+        .collect::<HashMap<_, _>>();
+    let mut ctx = Ctx {
+        ids,
+        base_dir,
+        scene: Scene {
+            nodes: vec![],
+            roots: vec![],
+            meshes: vec![],
+            skins: vec![],
+            materials: vec![],
+            images: vec![],
+        },
+        xml_nodes: vec![],
+        material_ids: HashMap::new(),
+        image_ids: HashMap::new(),
+    };
+
+    let visual_scene = child(root, "scene")
+        .and_then(|scene| child(scene, "instance_visual_scene"))
+        .and_then(|inst| ctx.lookup(inst.attribute("url")?))
+        .or_else(|| {
+            let lib = child(root, "library_visual_scenes")?;
+            child(lib, "visual_scene")
+        });
+    let visual_scene = match visual_scene {
+        Some(x) => x,
+        None => bail!("COLLADA file has no visual scene"),
+    };
+
+    // Make all the nodes first, so skins can find their joints anywhere
+    for xml in children(visual_scene, "node") {
+        let idx = ctx.add_node(xml);
+        ctx.scene.roots.push(idx);
+    }
+    for idx in 0..ctx.xml_nodes.len() {
+        ctx.add_instances(idx)?;
+    }
+
+    let up_axis = child(root, "asset")
+        .and_then(|asset| child(asset, "up_axis"))
+        .and_then(|up| up.text())
+        .map(|up| up.trim());
+    let to_y_up = match up_axis {
+        Some("Z_UP") => Some(Matrix3::from_cols(vec3(1.0, 0.0, 0.0), vec3(0.0, 0.0, -1.0), vec3(0.0, 1.0, 0.0))),
+        Some("X_UP") => Some(Matrix3::from_cols(vec3(0.0, 1.0, 0.0), vec3(-1.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0))),
+        _ => None,
+    };
+    if let Some(r) = to_y_up {
+        // R * (T * Rot * S) = T' * (R * Rot) * S with t' = R t
+        for &root in &ctx.scene.roots {
+            let node = &mut ctx.scene.nodes[root];
+            node.trans = r * node.trans;
+            node.rot = r * node.rot;
+        }
+    }
+
+    Ok(ctx.scene)
+}
+
+struct Ctx<'a, 'input> {
+    ids: HashMap<&'a str, XmlNode<'a, 'input>>,
+    base_dir: &'a Path,
+    scene: Scene,
+    /// The `<node>` for each node in the scene.
+    xml_nodes: Vec<XmlNode<'a, 'input>>,
+    /// Maps COLLADA IDs to indices in the scene.
+    material_ids: HashMap<&'a str, usize>,
+    image_ids: HashMap<&'a str, usize>,
+}
+
+/// What the skin in a controller does to the vertices of its mesh.
+struct SkinData {
+    skin_idx: usize,
+    bind_shape: Matrix4<f64>,
+    /// Joint indices and weights for each position.
+    influences: Vec<Vec<(u32, f64)>>,
+}
+
+impl<'a, 'input> Ctx<'a, 'input> {
+    /// Finds the element a URL like "#id" refers to.
+    fn lookup(&self, url: &str) -> Option<XmlNode<'a, 'input>> {
+        self.ids.get(url.trim_start_matches('#')).cloned()
+    }
+
+    /// Adds the `<node>` and its child nodes. Returns its index.
+    fn add_node(&mut self, xml: XmlNode<'a, 'input>) -> usize {
+        let name = xml.attribute("name")
+            .or_else(|| xml.attribute("id"))
+            .or_else(|| xml.attribute("sid"))
+            .unwrap_or("")
+            .to_string();
+        let (trans, rot, scale) = decompose(&node_matrix(xml));
+
+        let idx = self.scene.nodes.len();
+        self.scene.nodes.push(Node {
+            name, children: vec![], trans, rot, scale, mesh: None, skin: None,
+        });
+        self.xml_nodes.push(xml);
+
+        if children(xml, "instance_node").next().is_some() {
+            warn!("COLLADA instance_node isn't supported; skipping it");
+        }
+        for child_xml in children(xml, "node") {
+            let child_idx = self.add_node(child_xml);
+            self.scene.nodes[idx].children.push(child_idx);
+        }
+        idx
+    }
+
+    /// Gives the node the mesh of its geometry or controller instance. A
+    /// node can only have one, so further ones go on new child nodes.
+    fn add_instances(&mut self, idx: usize) -> Result<()> {
+        let xml = self.xml_nodes[idx];
+        let instances = xml.children()
+            .filter(|x| matches!(x.tag_name().name(), "instance_geometry" | "instance_controller"));
+        for (i, inst) in instances.enumerate() {
+            let (mesh, skin) = match self.instance(inst)? {
+                Some(x) => x,
+                None => continue,
+            };
+            let node_idx = if i == 0 {
+                idx
+            } else {
+                let new_idx = self.scene.nodes.len();
+                self.scene.nodes.push(Node {
+                    name: format!("{}.{}", self.scene.nodes[idx].name, i),
+                    children: vec![],
+                    trans: vec3(0.0, 0.0, 0.0),
+                    rot: Matrix3::identity(),
+                    scale: vec3(1.0, 1.0, 1.0),
+                    mesh: None,
+                    skin: None,
+                });
+                self.scene.nodes[idx].children.push(new_idx);
+                new_idx
+            };
+            self.scene.nodes[node_idx].mesh = Some(mesh);
+            self.scene.nodes[node_idx].skin = skin;
+        }
+        Ok(())
+    }
+
+    /// Makes the mesh (and skin) for an <instance_geometry> or
+    /// <instance_controller>.
+    fn instance(&mut self, inst: XmlNode<'a, 'input>) -> Result<Option<(usize, Option<usize>)>> {
+        let url = inst.attribute("url").unwrap_or("");
+        let target = match self.lookup(url) {
+            Some(x) => x,
+            None => {
+                warn!("COLLADA: can't find {}", url);
+                return Ok(None);
+            }
+        };
+
+        // Material symbols in the geometry -> materials
+        let mut bindings = HashMap::new();
+        let instance_materials = child(inst, "bind_material")
+            .and_then(|bind| child(bind, "technique_common"))
+            .into_iter()
+            .flat_map(|tech| children(tech, "instance_material"));
+        for inst_mat in instance_materials {
+            let symbol = inst_mat.attribute("symbol").unwrap_or("");
+            if let Some(mat) = inst_mat.attribute("target").and_then(|t| self.material(t)) {
+                bindings.insert(symbol, mat);
+            }
+        }
+
+        let (geometry, skin) = if inst.tag_name().name() == "instance_controller" {
+            let skin_xml = match child(target, "skin") {
+                Some(x) => x,
+                None => {
+                    warn!("COLLADA: only skin controllers are supported; skipping {}", url);
+                    return Ok(None);
+                }
+            };
+            let geometry = skin_xml.attribute("source").and_then(|src| self.lookup(src));
+            let skeletons = children(inst, "skeleton")
+                .filter_map(|s| self.node_by_url(s.text()?))
+                .collect::<Vec<_>>();
+            (geometry, Some(self.skin(skin_xml, &skeletons)?))
+        } else {
+            (Some(target), None)
+        };
+        let geometry = match geometry {
+            Some(x) => x,
+            None => {
+                warn!("COLLADA: can't find the geometry for {}", url);
+                return Ok(None);
+            }
+        };
+
+        let primitives = self.primitives(geometry, &bindings, skin.as_ref())?;
+        let mesh_idx = self.scene.meshes.len();
+        self.scene.meshes.push(Mesh { primitives });
+        Ok(Some((mesh_idx, skin.map(|s| s.skin_idx))))
+    }
+
+    fn node_by_url(&self, url: &str) -> Option<usize> {
+        let xml = self.lookup(url.trim())?;
+        self.xml_nodes.iter().position(|&x| x == xml)
+    }
+
+    /// Finds the node a skin's joint name refers to. Names are SIDs, looked
+    /// up under the skeleton roots first. Failing that, any node with that
+    /// SID, ID, or name will do.
+    fn joint_node(&self, joint: &str, skeletons: &[usize], is_idref: bool) -> Option<usize> {
+        if is_idref {
+            return self.node_by_url(joint);
+        }
+        // (Nodes added for extra instances have no <node>.)
+        let has_sid = |idx: &usize| {
+            self.xml_nodes.get(*idx).and_then(|x| x.attribute("sid")) == Some(joint)
+        };
+        for &skel in skeletons {
+            let mut stack = vec![skel];
+            while let Some(idx) = stack.pop() {
+                if has_sid(&idx) {
+                    return Some(idx);
+                }
+                stack.extend_from_slice(&self.scene.nodes[idx].children);
+            }
+        }
+        let num_nodes = self.xml_nodes.len();
+        (0..num_nodes).find(has_sid)
+            .or_else(|| (0..num_nodes).find(|&i| self.xml_nodes[i].attribute("id") == Some(joint)))
+            .or_else(|| (0..num_nodes).find(|&i| self.scene.nodes[i].name == joint))
+    }
+
+    fn skin(&mut self, skin: XmlNode<'a, 'input>, skeletons: &[usize]) -> Result<SkinData> {
+        let bind_shape = child(skin, "bind_shape_matrix")
+            .and_then(|x| x.text())
+            .map(|text| matrix_from_row_major(&floats(text)))
+            .unwrap_or_else(Matrix4::identity);
+
+        let joints_xml = match child(skin, "joints") {
+            Some(x) => x,
+            None => bail!("COLLADA skin has no joints"),
+        };
+        let mut joint_names = vec![];
+        let mut is_idref = false;
+        let mut inv_binds = vec![];
+        for input in children(joints_xml, "input") {
+            let source = match input.attribute("source").and_then(|s| self.lookup(s)) {
+                Some(x) => x,
+                None => continue,
+            };
+            match input.attribute("semantic") {
+                Some("JOINT") => {
+                    is_idref = child(source, "IDREF_array").is_some();
+                    joint_names = names(source);
+                }
+                Some("INV_BIND_MATRIX") => {
+                    let (data, stride) = read_source(source)?;
+                    check!(stride == 16)?;
+                    inv_binds = data.chunks(16).map(matrix_from_row_major).collect();
+                }
+                _ => (),
+            }
+        }
+        check!(inv_binds.len() >= joint_names.len())?;
+
+        let mut joints = vec![];
+        for name in &joint_names {
+            match self.joint_node(name, skeletons, is_idref) {
+                Some(node) => joints.push(node),
+                None => bail!("COLLADA skin joint {} isn't in the scene", name),
+            }
+        }
+
+        let weights_xml = match child(skin, "vertex_weights") {
+            Some(x) => x,
+            None => bail!("COLLADA skin has no vertex weights"),
+        };
+        let (mut joint_offset, mut weight_offset, mut weights) = (None, None, vec![]);
+        let mut stride = 0;
+        for input in children(weights_xml, "input") {
+            let offset = input.attribute("offset").and_then(|x| x.parse::<usize>().ok()).unwrap_or(0);
+            stride = stride.max(offset + 1);
+            match input.attribute("semantic") {
+                Some("JOINT") => joint_offset = Some(offset),
+                Some("WEIGHT") => {
+                    weight_offset = Some(offset);
+                    if let Some(source) = input.attribute("source").and_then(|s| self.lookup(s)) {
+                        weights = read_source(source)?.0;
+                    }
+                }
+                _ => (),
+            }
+        }
+        let (joint_offset, weight_offset) = match (joint_offset, weight_offset) {
+            (Some(j), Some(w)) => (j, w),
+            _ => bail!("COLLADA vertex weights need JOINT and WEIGHT inputs"),
+        };
+        let vcount = ints(child(weights_xml, "vcount").and_then(|x| x.text()).unwrap_or(""));
+        let v = ints(child(weights_xml, "v").and_then(|x| x.text()).unwrap_or(""));
+
+        let mut influences = Vec::with_capacity(vcount.len());
+        let mut pos = 0;
+        for &n in &vcount {
+            let n = n.max(0) as usize;
+            let end = pos + n * stride;
+            if end > v.len() {
+                bail!("COLLADA vertex weights out of range");
+            }
+            let mut infs = vec![];
+            for k in 0..n {
+                let joint = v[pos + k * stride + joint_offset];
+                let weight_idx = v[pos + k * stride + weight_offset];
+                // Joint -1 is the bind shape itself; not supported
+                if joint < 0 || joint as usize >= joints.len() {
+                    continue;
+                }
+                match weights.get(weight_idx.max(0) as usize) {
+                    Some(&weight) if weight_idx >= 0 => infs.push((joint as u32, weight)),
+                    _ => bail!("COLLADA weight index out of range"),
+                }
+            }
+            influences.push(infs);
+            pos = end;
+        }
+
+        let skin_idx = self.scene.skins.len();
+        inv_binds.truncate(joints.len());
+        self.scene.skins.push(Skin { joints, inv_binds });
+        Ok(SkinData { skin_idx, bind_shape, influences })
+    }
+
+    /// Reads the polygons of a `<geometry>` as triangle primitives.
+    fn primitives(
+        &mut self,
+        geometry: XmlNode<'a, 'input>,
+        bindings: &HashMap<&str, usize>,
+        skin: Option<&SkinData>,
+    ) -> Result<Vec<Primitive>> {
+        let mesh = match child(geometry, "mesh") {
+            Some(x) => x,
+            None => {
+                warn!("COLLADA: only meshes are supported; skipping geometry {}",
+                    geometry.attribute("id").unwrap_or(""));
+                return Ok(vec![]);
+            }
+        };
+
+        let mut prims = vec![];
+        for prim_xml in mesh.children().filter(|x| x.is_element()) {
+            let kind = prim_xml.tag_name().name();
+            match kind {
+                "triangles" | "polylist" | "polygons" | "tristrips" | "trifans" => (),
+                "lines" | "linestrips" => {
+                    warn!("skipping COLLADA {}", kind);
+                    continue;
+                }
+                _ => continue,
+            }
+            let mut prim = self.primitive(mesh, prim_xml, skin)?;
+            prim.material = prim_xml.attribute("material")
+                .and_then(|symbol| bindings.get(symbol).cloned());
+            prims.push(prim);
+        }
+        Ok(prims)
+    }
+
+    fn primitive(
+        &mut self,
+        mesh: XmlNode<'a, 'input>,
+        prim: XmlNode<'a, 'input>,
+        skin: Option<&SkinData>,
+    ) -> Result<Primitive> {
+        // (semantic, set, offset, source) for each input, with VERTEX expanded
+        // into the inputs of <vertices>.
+        let mut inputs = vec![];
+        let mut stride = 0;
+        for input in children(prim, "input") {
+            let semantic = input.attribute("semantic").unwrap_or("");
+            let offset = input.attribute("offset").and_then(|x| x.parse::<usize>().ok()).unwrap_or(0);
+            let set = input.attribute("set").and_then(|x| x.parse::<u32>().ok()).unwrap_or(0);
+            stride = stride.max(offset + 1);
+            let source = match input.attribute("source").and_then(|s| self.lookup(s)) {
+                Some(x) => x,
+                None => continue,
+            };
+            if semantic == "VERTEX" {
+                for vinput in children(source, "input") {
+                    let vsemantic = vinput.attribute("semantic").unwrap_or("");
+                    if let Some(vsource) = vinput.attribute("source").and_then(|s| self.lookup(s)) {
+                        inputs.push((vsemantic, 0, offset, vsource));
+                    }
+                }
+            } else {
+                inputs.push((semantic, set, offset, source));
+            }
+        }
+        // Use the lowest set of each semantic
+        inputs.sort_by_key(|&(_, set, _, _)| set);
+        let find = |semantic: &str| inputs.iter().find(|x| x.0 == semantic);
+
+        let position_input = match find("POSITION") {
+            Some(x) => x,
+            None => bail!("COLLADA {} in {} has no positions", prim.tag_name().name(),
+                mesh.parent().and_then(|g| g.attribute("id")).unwrap_or("")),
+        };
+        let read = |input: Option<&(&str, u32, usize, XmlNode)>| -> Result<Option<(usize, Vec<f64>, usize)>> {
+            match input {
+                Some(&(_, _, offset, source)) => {
+                    let (data, stride) = read_source(source)?;
+                    Ok(Some((offset, data, stride)))
+                }
+                None => Ok(None),
+            }
+        };
+        let positions_src = read(Some(position_input))?.unwrap();
+        let normals_src = read(find("NORMAL"))?;
+        let texcoords_src = read(find("TEXCOORD"))?;
+        let colors_src = read(find("COLOR"))?;
+
+        let polygons = polygons(prim, stride)?;
+
+        // Make a vertex for each distinct combination of indices
+        let mut vertex_of = HashMap::new();
+        let mut positions = vec![];
+        let mut normals = normals_src.as_ref().map(|_| vec![]);
+        let mut texcoords = texcoords_src.as_ref().map(|_| vec![]);
+        let mut colors = colors_src.as_ref().map(|_| vec![]);
+        let mut position_idxs = vec![];
+        let mut indices = vec![];
+
+        let bind_shape = skin.map(|s| s.bind_shape).unwrap_or_else(Matrix4::identity);
+        let bind_shape_normals = normal_matrix(&bind_shape);
+
+        for polygon in &polygons {
+            let mut verts = vec![];
+            for tuple in polygon.chunks(stride) {
+                if let Some(&vert) = vertex_of.get(tuple) {
+                    verts.push(vert);
+                    continue;
+                }
+                let vert = positions.len() as u32;
+
+                let p = element(&positions_src, tuple, 3)?;
+                let p = bind_shape.transform_point(Point3::new(p[0], p[1], p[2]));
+                positions.push([p.x, p.y, p.z]);
+                position_idxs.push(tuple[positions_src.0]);
+                if let (Some(src), Some(out)) = (&normals_src, &mut normals) {
+                    let n = element(src, tuple, 3)?;
+                    let n = bind_shape_normals * vec3(n[0], n[1], n[2]);
+                    out.push([n.x, n.y, n.z]);
+                }
+                if let (Some(src), Some(out)) = (&texcoords_src, &mut texcoords) {
+                    let t = element(src, tuple, 2)?;
+                    out.push([t[0], 1.0 - t[1]]);
+                }
+                if let (Some(src), Some(out)) = (&colors_src, &mut colors) {
+                    let c = element(src, tuple, 3)?;
+                    out.push([c[0], c[1], c[2]]);
+                }
+
+                vertex_of.insert(tuple, vert);
+                verts.push(vert);
+            }
+            match prim.tag_name().name() {
+                "tristrips" => {
+                    for i in 2..verts.len() {
+                        let (a, b, c) = (verts[i - 2], verts[i - 1], verts[i]);
+                        if i % 2 == 0 {
+                            indices.extend_from_slice(&[a, b, c]);
+                        } else {
+                            indices.extend_from_slice(&[b, a, c]);
+                        }
+                    }
+                }
+                // Everything else is convex polygons; triangulate as fans
+                _ => {
+                    for i in 2..verts.len() {
+                        indices.extend_from_slice(&[verts[0], verts[i - 1], verts[i]]);
+                    }
+                }
+            }
+        }
+
+        let (mut joints, mut weights) = (None, None);
+        if let Some(skin) = skin {
+            let mut js = Vec::with_capacity(positions.len());
+            let mut ws = Vec::with_capacity(positions.len());
+            for &pos_idx in &position_idxs {
+                let (j, w) = top_influences(skin.influences.get(pos_idx as usize).map(|x| &x[..]).unwrap_or(&[]));
+                js.push(j);
+                ws.push(w);
+            }
+            joints = Some(js);
+            weights = Some(ws);
+        }
+
+        Ok(Primitive {
+            positions, normals, texcoords, colors, joints, weights, indices,
+            material: None,
+        })
+    }
+
+    /// Index of the material with the given URL, adding it if needed.
+    fn material(&mut self, url: &str) -> Option<usize> {
+        let xml = self.lookup(url)?;
+        let id = xml.attribute("id")?;
+        if let Some(&idx) = self.material_ids.get(id) {
+            return Some(idx);
+        }
+
+        let name = xml.attribute("name").unwrap_or(id).to_string();
+        let mut mat = Material {
+            name,
+            base_color: [1.0; 4],
+            image: None,
+            wrap: (REPEAT, REPEAT),
+            double_sided: false,
+            blend: false,
+        };
+
+        let effect = child(xml, "instance_effect")
+            .and_then(|inst| self.lookup(inst.attribute("url")?));
+        let profile = effect.and_then(|effect| child(effect, "profile_COMMON"));
+        let shader = profile
+            .and_then(|profile| child(profile, "technique"))
+            .and_then(|tech| {
+                tech.children().find(|x| {
+                    matches!(x.tag_name().name(), "phong" | "lambert" | "blinn" | "constant")
+                })
+            });
+        if let (Some(profile), Some(shader)) = (profile, shader) {
+            let diffuse = child(shader, "diffuse")
+                .or_else(|| child(shader, "emission"));
+            if let Some(color) = diffuse.and_then(|d| child(d, "color")).and_then(|c| c.text()) {
+                for (i, x) in floats(color).into_iter().take(4).enumerate() {
+                    mat.base_color[i] = x;
+                }
+            }
+            if let Some(texture) = diffuse.and_then(|d| child(d, "texture")) {
+                self.texture(profile, texture, &mut mat);
+            }
+            let transparency = child(shader, "transparency")
+                .and_then(|t| child(t, "float"))
+                .and_then(|f| f.text())
+                .and_then(|text| text.trim().parse::<f64>().ok());
+            if let Some(transparency) = transparency {
+                mat.base_color[3] *= transparency;
+            }
+            mat.blend = mat.base_color[3] < 1.0;
+        }
+        mat.double_sided = effect
+            .map(|effect| effect.descendants().any(|x| {
+                x.tag_name().name() == "double_sided" && x.text().map(|t| t.trim()) == Some("1")
+            }))
+            .unwrap_or(false);
+
+        let idx = self.scene.materials.len();
+        self.scene.materials.push(mat);
+        self.material_ids.insert(id, idx);
+        Some(idx)
+    }
+
+    /// Fills in the image and wrap mode of a material from a `<texture>`.
+    fn texture(&mut self, profile: XmlNode<'a, 'input>, texture: XmlNode<'a, 'input>, mat: &mut Material) {
+        let newparam = |sid: &str| children(profile, "newparam").find(|p| p.attribute("sid") == Some(sid));
+        let sid = texture.attribute("texture").unwrap_or("");
+
+        // The texture is usually a sampler2D (whose source is a surface, which
+        // is initialized from an image), but some exporters put the image ID
+        // here directly.
+        let image_id = match newparam(sid).and_then(|p| child(p, "sampler2D")) {
+            Some(sampler) => {
+                let wrap = |tag| match child(sampler, tag).and_then(|x| x.text()).map(|t| t.trim()) {
+                    Some("MIRROR") => MIRRORED_REPEAT,
+                    Some("CLAMP") | Some("BORDER") => CLAMP_TO_EDGE,
+                    _ => REPEAT,
+                };
+                mat.wrap = (wrap("wrap_s"), wrap("wrap_t"));
+
+                let surface_sid = child(sampler, "source").and_then(|x| x.text()).unwrap_or("");
+                let from_surface = newparam(surface_sid.trim())
+                    .and_then(|p| child(p, "surface"))
+                    .and_then(|surface| child(surface, "init_from"))
+                    .and_then(|init| init.text());
+                // COLLADA 1.5 puts the image in the sampler
+                let from_sampler = child(sampler, "instance_image")
+                    .and_then(|inst| inst.attribute("url"));
+                from_surface.or(from_sampler)
+            }
+            None => Some(sid),
+        };
+        mat.image = image_id.and_then(|id| self.image(id.trim()));
+    }
+
+    /// Index of the image with the given ID, adding it if needed.
+    fn image(&mut self, id: &str) -> Option<usize> {
+        let xml = self.lookup(id)?;
+        let id = xml.attribute("id")?;
+        if let Some(&idx) = self.image_ids.get(id) {
+            return Some(idx);
+        }
+
+        let name = xml.attribute("name").unwrap_or(id).to_string();
+        let init_from = child(xml, "init_from")?;
+        // COLLADA 1.5 wraps the path in a <ref>
+        let uri = child(init_from, "ref").unwrap_or(init_from).text().unwrap_or("").trim();
+        let uri = percent_decode(uri.strip_prefix("file://").unwrap_or(uri));
+        let png = std::fs::read(self.base_dir.join(&uri)).ok()
+            .filter(|data| data.starts_with(b"\x89PNG"));
+        if png.is_none() {
+            warn!("couldn't load image {} (only PNGs are supported)", name);
+        }
+
+        let idx = self.scene.images.len();
+        self.scene.images.push(Image { name, png });
+        self.image_ids.insert(id, idx);
+        Some(idx)
+    }
+}
+
+fn child<'a, 'input>(xml: XmlNode<'a, 'input>, tag: &str) -> Option<XmlNode<'a, 'input>> {
+    xml.children().find(|x| x.tag_name().name() == tag)
+}
+
+fn children<'a, 'input: 'a>(xml: XmlNode<'a, 'input>, tag: &'a str)
+-> impl Iterator<Item = XmlNode<'a, 'input>> + 'a {
+    xml.children().filter(move |x| x.tag_name().name() == tag)
+}
+
+fn floats(text: &str) -> Vec<f64> {
+    text.split_ascii_whitespace()
+        .map(|x| x.parse::<f64>().unwrap_or(0.0))
+        .collect()
+}
+
+fn ints(text: &str) -> Vec<i64> {
+    text.split_ascii_whitespace()
+        .map(|x| x.parse::<i64>().unwrap_or(-1))
+        .collect()
+}
+
+fn names(source: XmlNode) -> Vec<String> {
+    child(source, "Name_array")
+        .or_else(|| child(source, "IDREF_array"))
+        .and_then(|x| x.text())
+        .map(|text| text.split_ascii_whitespace().map(|s| s.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Reads the floats in a <source>. Returns them and the number per element.
+fn read_source(source: XmlNode) -> Result<(Vec<f64>, usize)> {
+    let data = match child(source, "float_array").and_then(|x| x.text()) {
+        Some(text) => floats(text),
+        None => vec![],
+    };
+    let accessor = child(source, "technique_common").and_then(|t| child(t, "accessor"));
+    let attr = |name| accessor.and_then(|a| a.attribute(name)).and_then(|x| x.parse::<usize>().ok());
+    let stride = attr("stride").unwrap_or(1).max(1);
+    let offset = attr("offset").unwrap_or(0);
+    let count = attr("count").unwrap_or(data.len() / stride);
+    let end = offset + count * stride;
+    if end > data.len() {
+        bail!("COLLADA source {} is too short", source.attribute("id").unwrap_or(""));
+    }
+    Ok((data[offset..end].to_vec(), stride))
+}
+
+/// The first n components of the element of (offset, data, stride) that the
+/// index tuple refers to.
+fn element<'d>(src: &'d (usize, Vec<f64>, usize), tuple: &[u32], n: usize) -> Result<&'d [f64]> {
+    let (offset, ref data, stride) = *src;
+    if stride < n {
+        bail!("COLLADA source has too few components");
+    }
+    let start = tuple[offset] as usize * stride;
+    match data.get(start..start + n) {
+        Some(x) => Ok(x),
+        None => bail!("COLLADA index out of range"),
+    }
+}
+
+/// The index tuples of each polygon (or strip/fan) in a primitive.
+fn polygons(prim: XmlNode, stride: usize) -> Result<Vec<Vec<u32>>> {
+    let ps = children(prim, "p")
+        .map(|p| ints(p.text().unwrap_or("")))
+        .collect::<Vec<_>>();
+    if ps.iter().flatten().any(|&i| i < 0 || i > u32::MAX as i64) {
+        bail!("bad COLLADA index");
+    }
+    let to_u32 = |xs: &[i64]| xs.iter().map(|&x| x as u32).collect::<Vec<_>>();
+
+    let polys = match prim.tag_name().name() {
+        "triangles" => {
+            let p = ps.concat();
+            p.chunks_exact(3 * stride).map(to_u32).collect()
+        }
+        "polylist" => {
+            let p = ps.concat();
+            let vcount = ints(child(prim, "vcount").and_then(|x| x.text()).unwrap_or(""));
+            let mut polys = vec![];
+            let mut pos = 0;
+            for n in vcount {
+                let end = pos + n.max(0) as usize * stride;
+                if end > p.len() {
+                    bail!("COLLADA polylist is too short");
+                }
+                polys.push(to_u32(&p[pos..end]));
+                pos = end;
+            }
+            polys
+        }
+        // <polygons>, <tristrips>, and <trifans> have one <p> each
+        _ => ps.iter().map(|p| to_u32(&p[..p.len() / stride * stride])).collect(),
+    };
+    Ok(polys)
+}
+
+/// The transform of a `<node>`, from all its transformation elements in order.
+fn node_matrix(xml: XmlNode) -> Matrix4<f64> {
+    let mut m = Matrix4::identity();
+    for x in xml.children() {
+        let v = match x.text() {
+            Some(text) => floats(text),
+            None => continue,
+        };
+        let t = match (x.tag_name().name(), v.len()) {
+            ("matrix", 16) => matrix_from_row_major(&v),
+            ("translate", 3) => Matrix4::from_translation(vec3(v[0], v[1], v[2])),
+            ("rotate", 4) => Matrix4::from_axis_angle(
+                vec3(v[0], v[1], v[2]).normalize(),
+                Deg(v[3]),
+            ),
+            ("scale", 3) => Matrix4::from_nonuniform_scale(v[0], v[1], v[2]),
+            _ => continue,
+        };
+        m = m * t;
+    }
+    m
+}
+
+/// COLLADA matrices are written row by row.
+fn matrix_from_row_major(m: &[f64]) -> Matrix4<f64> {
+    if m.len() < 16 {
+        return Matrix4::identity();
+    }
+    Matrix4::new(
+        m[0], m[4], m[8], m[12],
+        m[1], m[5], m[9], m[13],
+        m[2], m[6], m[10], m[14],
+        m[3], m[7], m[11], m[15],
+    )
+}
+
+/// Keeps the four biggest influences, renormalized.
+fn top_influences(influences: &[(u32, f64)]) -> ([u32; 4], [f64; 4]) {
+    let mut infs = influences.to_vec();
+    infs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    infs.truncate(4);
+    let total: f64 = infs.iter().map(|x| x.1).sum();
+    let (mut joints, mut weights) = ([0; 4], [0.0; 4]);
+    for (i, &(joint, weight)) in infs.iter().enumerate() {
+        joints[i] = joint;
+        weights[i] = if total > 0.0 { weight / total } else { 0.0 };
+    }
+    (joints, weights)
+}
+
+#[test]
+fn test_parse() {
+    // A quad skinned to two joints, in a Z_UP scene
+    let dae = r##"<?xml version="1.0" encoding="utf-8"?>
+<COLLADA xmlns="http://www.collada.org/2005/11/COLLADASchema" version="1.4.1">
+  <asset><up_axis>Z_UP</up_axis></asset>
+  <library_images>
+    <image id="img" name="face"><init_from>face.png</init_from></image>
+  </library_images>
+  <library_effects>
+    <effect id="fx"><profile_COMMON>
+      <newparam sid="surf"><surface type="2D"><init_from>img</init_from></surface></newparam>
+      <newparam sid="samp"><sampler2D><source>surf</source><wrap_s>MIRROR</wrap_s></sampler2D></newparam>
+      <technique sid="common"><lambert>
+        <diffuse><texture texture="samp" texcoord="uv"/></diffuse>
+      </lambert></technique>
+    </profile_COMMON></effect>
+  </library_effects>
+  <library_materials>
+    <material id="mat" name="skin_mat"><instance_effect url="#fx"/></material>
+  </library_materials>
+  <library_geometries>
+    <geometry id="quad"><mesh>
+      <source id="pos">
+        <float_array id="pos-a" count="12">0 0 0  1 0 0  1 1 0  0 1 0</float_array>
+        <technique_common><accessor source="#pos-a" count="4" stride="3"/></technique_common>
+      </source>
+      <source id="nrm">
+        <float_array id="nrm-a" count="3">0 0 1</float_array>
+        <technique_common><accessor source="#nrm-a" count="1" stride="3"/></technique_common>
+      </source>
+      <source id="uv">
+        <float_array id="uv-a" count="8">0 0  1 0  1 1  0 1</float_array>
+        <technique_common><accessor source="#uv-a" count="4" stride="2"/></technique_common>
+      </source>
+      <vertices id="verts"><input semantic="POSITION" source="#pos"/></vertices>
+      <polylist material="m" count="1">
+        <input semantic="VERTEX" source="#verts" offset="0"/>
+        <input semantic="NORMAL" source="#nrm" offset="1"/>
+        <input semantic="TEXCOORD" source="#uv" offset="2" set="0"/>
+        <vcount>4</vcount>
+        <p>0 0 0  1 0 1  2 0 2  3 0 3</p>
+      </polylist>
+    </mesh></geometry>
+  </library_geometries>
+  <library_controllers>
+    <controller id="skin"><skin source="#quad">
+      <bind_shape_matrix>1 0 0 2  0 1 0 0  0 0 1 0  0 0 0 1</bind_shape_matrix>
+      <source id="joints">
+        <Name_array id="joints-a" count="2">root arm</Name_array>
+        <technique_common><accessor source="#joints-a" count="2" stride="1"/></technique_common>
+      </source>
+      <source id="binds">
+        <float_array id="binds-a" count="32">
+          1 0 0 0  0 1 0 0  0 0 1 0  0 0 0 1
+          1 0 0 0  0 1 0 0  0 0 1 0  0 0 0 1
+        </float_array>
+        <technique_common><accessor source="#binds-a" count="2" stride="16"/></technique_common>
+      </source>
+      <source id="weights">
+        <float_array id="weights-a" count="3">1 0.25 0.75</float_array>
+        <technique_common><accessor source="#weights-a" count="3" stride="1"/></technique_common>
+      </source>
+      <joints>
+        <input semantic="JOINT" source="#joints"/>
+        <input semantic="INV_BIND_MATRIX" source="#binds"/>
+      </joints>
+      <vertex_weights count="4">
+        <input semantic="JOINT" source="#joints" offset="0"/>
+        <input semantic="WEIGHT" source="#weights" offset="1"/>
+        <vcount>1 1 2 1</vcount>
+        <v>0 0  1 0  0 1 1 2  1 0</v>
+      </vertex_weights>
+    </skin></controller>
+  </library_controllers>
+  <library_visual_scenes>
+    <visual_scene id="scene">
+      <node id="root" sid="root" name="root" type="JOINT">
+        <translate>0 0 1</translate>
+        <node id="arm" sid="arm" name="arm" type="JOINT"><translate>1 0 0</translate></node>
+      </node>
+      <node id="body" name="body">
+        <instance_controller url="#skin">
+          <skeleton>#root</skeleton>
+          <bind_material><technique_common>
+            <instance_material symbol="m" target="#mat"/>
+          </technique_common></bind_material>
+        </instance_controller>
+      </node>
+    </visual_scene>
+  </library_visual_scenes>
+  <scene><instance_visual_scene url="#scene"/></scene>
+</COLLADA>"##;
+    let scene = parse(dae, Path::new(".")).unwrap();
+
+    let names = scene.nodes.iter().map(|n| &n.name[..]).collect::<Vec<_>>();
+    assert_eq!(names, ["root", "arm", "body"]);
+    assert_eq!(scene.roots, [0, 2]);
+    // Z up became Y up, only on the roots
+    assert_eq!(scene.nodes[0].trans, vec3(0.0, 1.0, 0.0));
+    assert_eq!(scene.nodes[1].trans, vec3(1.0, 0.0, 0.0));
+
+    assert_eq!(scene.nodes[2].mesh, Some(0));
+    assert_eq!(scene.skins[scene.nodes[2].skin.unwrap()].joints, [0, 1]);
+    let prim = &scene.meshes[0].primitives[0];
+    assert_eq!(prim.indices, [0, 1, 2, 0, 2, 3]);
+    // The bind shape matrix moved it along x
+    assert_eq!(prim.positions[1], [3.0, 0.0, 0.0]);
+    assert_eq!(prim.normals.as_ref().unwrap()[1], [0.0, 0.0, 1.0]);
+    // Flipped
+    assert_eq!(prim.texcoords.as_ref().unwrap()[3], [0.0, 0.0]);
+    assert_eq!(prim.joints.as_ref().unwrap()[2][..2], [1, 0]);
+    assert_eq!(prim.weights.as_ref().unwrap()[2], [0.75, 0.25, 0.0, 0.0]);
+
+    let mat = &scene.materials[prim.material.unwrap()];
+    assert_eq!(mat.name, "skin_mat");
+    assert_eq!(mat.wrap, (MIRRORED_REPEAT, REPEAT));
+    assert_eq!(scene.images[mat.image.unwrap()].name, "face");
+
+    super::compile::compile(&scene, "test").unwrap();
+}
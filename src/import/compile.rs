@@ -0,0 +1,893 @@
+//! Compiles an imported scene (from glTF or COLLADA) into a Nitro model.
+//!
+//! Every node in the scene becomes an object. Each vertex is drawn with
+//! either the matrix of a single object (for vertices of unskinned meshes, or
+//! skinned vertices with only one influence) or a skinning matrix blended from
+//! several objects. Those matrices are computed into the matrix stack by the
+//! render commands right before the pieces that use them are drawn.
+//!
+//! The DS's matrix stack only has 31 slots, so the triangles are split into
+//! pieces that each use only a limited number of matrices, and the slots are
+//! reused between pieces.
+
+use cgmath::{Matrix, Matrix3, Matrix4, Point2, Point3, Vector3, vec3, InnerSpace, SquareMatrix, Transform};
+use std::collections::HashMap;
+use crate::errors::Result;
+use crate::import::gltf::{self, Scene};
+use crate::nds::TextureParams;
+use crate::nds::gpu_cmds::CmdWriter;
+use crate::nitro::Name;
+use crate::nitro::model::{Material, MaterialRaw, Model, ModelHeader, Object, ObjectRaw, Piece};
+use crate::nitro::render_cmds::{parse_render_cmds, RenderCmdWriter};
+use crate::nitro::texture_matrix::{TexMtxMode, TextureSrt};
+use crate::util::cur::Cur;
+use crate::util::fixed::to_fix16;
+
+/// Slots in the DS's matrix stack.
+const STACK_SIZE: usize = 31;
+/// Most matrices one piece can use. The rest of the stack is left for
+/// computing them.
+const MAX_PIECE_MATRICES: usize = 20;
+/// Texture size to use when we can't read the image.
+const DEFAULT_TEXTURE_DIM: u16 = 64;
+
+pub fn compile(scene: &Scene, model_name: &str) -> Result<Model> {
+    let objs = build_objects(scene)?;
+    let num_objects = objs.infos.len();
+
+    // Inverse bind matrices. Joints use the ones from their skin; everything
+    // else gets the inverse of its rest matrix.
+    let mut inv_binds = objs.world.iter()
+        .map(|m| m.invert().unwrap_or_else(Matrix4::identity))
+        .collect::<Vec<_>>();
+    let mut has_skin_inv_bind = vec![false; num_objects];
+    for skin in &scene.skins {
+        for (&joint, inv_bind) in skin.joints.iter().zip(skin.inv_binds.iter()) {
+            if let Some(obj) = objs.of_node[joint] {
+                if has_skin_inv_bind[obj] && inv_binds[obj] != *inv_bind {
+                    warn!("joint {} has different inverse bind matrices in different \
+                        skins; skinning may be wrong", scene.nodes[joint].name);
+                    continue;
+                }
+                inv_binds[obj] = *inv_bind;
+                has_skin_inv_bind[obj] = true;
+            }
+        }
+    }
+
+    // For putting normals in the local space of a joint
+    let inv_bind_normals = inv_binds.iter()
+        .map(normal_matrix)
+        .collect::<Vec<_>>();
+
+    // Matrices vertices can be drawn with. The first num_objects are the
+    // objects' matrices.
+    let mut keys = KeyTable::default();
+    for obj in 0..num_objects {
+        keys.get(MatrixKey::Object(obj));
+    }
+
+    let mut mats = Materials::default();
+    let mut tris_by_material: Vec<Vec<[Vert; 3]>> = vec![];
+
+    for &node_idx in &objs.node_order {
+        let node = &scene.nodes[node_idx];
+        let mesh = match node.mesh {
+            Some(mesh) => &scene.meshes[mesh],
+            None => continue,
+        };
+        let node_obj = objs.of_node[node_idx].unwrap();
+        let skin = node.skin.map(|s| &scene.skins[s]);
+
+        for prim in &mesh.primitives {
+            let mat_idx = mats.get(scene, prim.material, prim.normals.is_some())?;
+            if tris_by_material.len() <= mat_idx {
+                tris_by_material.resize_with(mat_idx + 1, Vec::new);
+            }
+            let mat = &mats.materials[mat_idx];
+            let dim = (mat.width as f64, mat.height as f64);
+            let textured = mat.texture_name.is_some();
+
+            let verts = (0..prim.positions.len())
+                .map(|i| {
+                    let p = prim.positions[i];
+                    let mut pos = Point3::new(p[0], p[1], p[2]);
+                    let mut normal = prim.normals.as_ref()
+                        .map(|n| vec3(n[i][0], n[i][1], n[i][2]));
+
+                    let key = match (skin, &prim.joints, &prim.weights) {
+                        (Some(skin), Some(joints), Some(weights)) => {
+                            let influences = (0..4)
+                                .filter_map(|k| {
+                                    let joint = *skin.joints.get(joints[i][k] as usize)?;
+                                    Some((objs.of_node[joint]?, weights[i][k]))
+                                })
+                                .collect::<Vec<_>>();
+                            let terms = quantize_weights(&influences);
+                            match terms.len() {
+                                0 => MatrixKey::Object(node_obj),
+                                1 => {
+                                    // Put it in the local space of the joint
+                                    let obj = terms[0].0;
+                                    pos = inv_binds[obj].transform_point(pos);
+                                    normal = normal.map(|n| inv_bind_normals[obj] * n);
+                                    MatrixKey::Object(obj)
+                                }
+                                _ => MatrixKey::Skin(terms),
+                            }
+                        }
+                        _ => MatrixKey::Object(node_obj),
+                    };
+
+                    let texcoord = match (textured, &prim.texcoords) {
+                        (true, Some(uvs)) => Some(Point2::new(uvs[i][0] * dim.0, uvs[i][1] * dim.1)),
+                        _ => None,
+                    };
+                    let color = prim.colors.as_ref()
+                        .map(|c| Point3::new(c[i][0] as f32, c[i][1] as f32, c[i][2] as f32));
+
+                    Vert {
+                        key: keys.get(key),
+                        pos,
+                        normal: normal.filter(|n| n.magnitude2() != 0.0).map(|n| n.normalize()),
+                        texcoord,
+                        color,
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            for tri in prim.indices.chunks(3) {
+                tris_by_material[mat_idx].push([
+                    verts[tri[0] as usize].clone(),
+                    verts[tri[1] as usize].clone(),
+                    verts[tri[2] as usize].clone(),
+                ]);
+            }
+        }
+    }
+
+    // Rest positions, for the bounding box
+    let key_matrices = keys.keys.iter()
+        .map(|key| match *key {
+            MatrixKey::Object(obj) => objs.world[obj],
+            MatrixKey::Skin(ref terms) => {
+                let mut m = Matrix4::from_scale(0.0);
+                for &(obj, weight) in terms {
+                    m += (weight as f64 / 256.0) * objs.world[obj] * inv_binds[obj];
+                }
+                m
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Scale positions down until they fit in the (1,3,12) fixed-point format
+    // of the vertex commands.
+    let max_coord = tris_by_material.iter()
+        .flat_map(|tris| tris.iter())
+        .flat_map(|tri| tri.iter())
+        .map(|v| v.pos.x.abs().max(v.pos.y.abs()).max(v.pos.z.abs()))
+        .fold(0.0, f64::max);
+    let mut up_scale = 1.0;
+    while max_coord / up_scale >= 7.99 {
+        up_scale *= 2.0;
+    }
+
+    // Split triangles into pieces
+    let mut pieces = vec![];
+    for (mat_idx, tris) in tris_by_material.iter().enumerate() {
+        let mut cur = PieceTris { mat_idx, tris: vec![], keys: vec![] };
+        for tri in tris {
+            let new_keys = tri.iter()
+                .map(|v| v.key)
+                .filter(|k| !cur.keys.contains(k))
+                .fold(vec![], |mut acc, k| { if !acc.contains(&k) { acc.push(k); } acc });
+            if cur.keys.len() + new_keys.len() > MAX_PIECE_MATRICES {
+                let next = PieceTris { mat_idx, tris: vec![], keys: vec![] };
+                pieces.push(std::mem::replace(&mut cur, next));
+            }
+            for k in tri.iter().map(|v| v.key) {
+                if !cur.keys.contains(&k) {
+                    cur.keys.push(k);
+                }
+            }
+            cur.tris.push(tri);
+        }
+        if !cur.tris.is_empty() {
+            pieces.push(cur);
+        }
+    }
+    if pieces.len() > 255 || mats.materials.len() > 255 {
+        bail!("model is too big (needs {} pieces and {} materials, the max is 255)",
+            pieces.len(), mats.materials.len());
+    }
+
+    // Render commands and GPU commands
+    let mut stack = Stack::new(&keys.keys, &objs.infos);
+    stack.store_root();
+    let mut nitro_pieces = vec![];
+    let mut cur_material = None;
+    let mut num_verts = 0;
+    let mut num_tris = 0;
+    let mut bbox_min = vec3(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+    let mut bbox_max = -bbox_min;
+
+    for (piece_idx, piece) in pieces.iter().enumerate() {
+        let mut slots = HashMap::new();
+        for &key in &piece.keys {
+            let slot = stack.ensure(key)?;
+            stack.protect(slot);
+            slots.insert(key, slot);
+        }
+
+        if cur_material != Some(piece.mat_idx) {
+            stack.w.bind_material(piece.mat_idx as u8);
+            cur_material = Some(piece.mat_idx);
+        }
+        stack.w.draw(piece_idx as u8);
+
+        let mut cw = CmdWriter::new();
+        cw.begin(0); // separate triangles
+        let mut cur_slot = None;
+        for tri in &piece.tris {
+            for v in tri.iter() {
+                let slot = slots[&v.key];
+                if cur_slot != Some(slot) {
+                    cw.restore(slot as u32);
+                    if up_scale != 1.0 {
+                        cw.scale((up_scale, up_scale, up_scale));
+                    }
+                    cur_slot = Some(slot);
+                }
+                if let Some(texcoord) = v.texcoord {
+                    cw.texcoord(texcoord);
+                }
+                if let Some(normal) = v.normal {
+                    cw.normal(normal);
+                } else if let Some(color) = v.color {
+                    cw.color(color);
+                }
+                cw.vertex(Point3::new(v.pos.x / up_scale, v.pos.y / up_scale, v.pos.z / up_scale));
+
+                let p = key_matrices[v.key].transform_point(v.pos);
+                for k in 0..3 {
+                    bbox_min[k] = bbox_min[k].min(p[k]);
+                    bbox_max[k] = bbox_max[k].max(p[k]);
+                }
+                num_verts += 1;
+            }
+            num_tris += 1;
+        }
+        cw.end();
+
+        nitro_pieces.push(Piece {
+            name: Name::from_str_truncated(&format!("polygon{}", piece_idx)),
+            gpu_commands: cw.finish(),
+            unknown: 0,
+        });
+
+        for &slot in slots.values() {
+            stack.unprotect(slot);
+        }
+    }
+    let render_cmds = stack.w.finish();
+    let render_ops = parse_render_cmds(Cur::new(&render_cmds))?;
+
+    if num_verts > 6144 {
+        warn!("model has {} vertices; the DS can only draw 6144 per frame", num_verts);
+    }
+    if num_verts > 0xffff {
+        bail!("model has too many vertices ({})", num_verts);
+    }
+
+    let mut bounding_box = [0; 6];
+    if num_verts != 0 {
+        let size = bbox_max - bbox_min;
+        let fx = |x: f64| to_fix16(x / up_scale, 1, 3, 12);
+        bounding_box = [
+            fx(bbox_min.x), fx(bbox_min.y), fx(bbox_min.z),
+            fx(size.x), fx(size.y), fx(size.z),
+        ];
+    }
+    let header = ModelHeader {
+        num_verts: num_verts as u16,
+        num_surfs: num_tris.min(0xffff) as u16,
+        num_tris: num_tris.min(0xffff) as u16,
+        num_quads: 0,
+        bounding_box,
+        ..ModelHeader::default()
+    };
+
+    let objects = objs.infos.into_iter()
+        .map(|info| info.object)
+        .collect();
+
+    Ok(Model {
+        name: Name::from_str_truncated(model_name),
+        materials: mats.materials,
+        pieces: nitro_pieces,
+        objects,
+        inv_binds,
+        render_ops,
+        up_scale,
+        down_scale: 1.0 / up_scale,
+        tex_mtx_mode: TexMtxMode::Maya,
+        render_cmds,
+        inv_bind_normals: vec![],
+        header,
+    })
+}
+
+struct ObjectInfo {
+    parent: Option<usize>,
+    object: Object,
+}
+
+struct Objects {
+    infos: Vec<ObjectInfo>,
+    /// Rest matrix for each object, in model space.
+    world: Vec<Matrix4<f64>>,
+    /// Object index for each node.
+    of_node: Vec<Option<usize>>,
+    /// Nodes in the order they became objects.
+    node_order: Vec<usize>,
+}
+
+/// Makes an object for every node in the scene, in depth-first order. Since
+/// the render commands can only compute matrices from a parent's, there has
+/// to be a single root; if the scene has several, an identity object is added
+/// above them.
+fn build_objects(scene: &Scene) -> Result<Objects> {
+    let mut names = NameTable::default();
+    let mut infos = vec![];
+    let mut world = vec![];
+    let mut of_node = vec![None; scene.nodes.len()];
+    let mut node_order = vec![];
+
+    let mut stack: Vec<(usize, Option<usize>)> = vec![];
+    if scene.roots.len() == 1 {
+        stack.push((scene.roots[0], None));
+    } else {
+        infos.push(ObjectInfo {
+            parent: None,
+            object: Object {
+                name: names.get("root"),
+                trans: None,
+                rot: None,
+                scale: None,
+                matrix: Matrix4::identity(),
+                visible: true,
+                raw: ObjectRaw::default(),
+            },
+        });
+        world.push(Matrix4::identity());
+        stack.extend(scene.roots.iter().rev().map(|&root| (root, Some(0))));
+    }
+
+    while let Some((node_idx, parent)) = stack.pop() {
+        if of_node[node_idx].is_some() {
+            continue;
+        }
+        let node = &scene.nodes[node_idx];
+        let obj_idx = infos.len();
+        of_node[node_idx] = Some(obj_idx);
+        node_order.push(node_idx);
+
+        let trans = Some(node.trans).filter(|&t| t != vec3(0.0, 0.0, 0.0));
+        let rot = Some(node.rot).filter(|&r| r != Matrix3::identity());
+        let scale = Some(node.scale).filter(|&s| s != vec3(1.0, 1.0, 1.0));
+        let matrix =
+            Matrix4::from_translation(node.trans) *
+            Matrix4::from(node.rot) *
+            Matrix4::from_nonuniform_scale(node.scale.x, node.scale.y, node.scale.z);
+
+        let name = if node.name.is_empty() {
+            format!("node{}", node_idx)
+        } else {
+            node.name.clone()
+        };
+
+        infos.push(ObjectInfo {
+            parent,
+            object: Object {
+                name: names.get(&name),
+                trans,
+                rot,
+                scale,
+                matrix,
+                visible: true,
+                raw: ObjectRaw::default(),
+            },
+        });
+        world.push(match parent {
+            Some(p) => world[p] * matrix,
+            None => matrix,
+        });
+
+        stack.extend(node.children.iter().rev().map(|&child| (child, Some(obj_idx))));
+    }
+
+    if infos.is_empty() {
+        bail!("glTF scene is empty");
+    }
+    if infos.len() > 255 {
+        bail!("too many nodes ({}, the max is 255)", infos.len());
+    }
+
+    Ok(Objects { infos, world, of_node, node_order })
+}
+
+/// Makes names unique and short enough for a Name.
+#[derive(Default)]
+struct NameTable {
+    used: Vec<Name>,
+}
+
+impl NameTable {
+    fn get(&mut self, desired: &str) -> Name {
+        let mut name = Name::from_str_truncated(desired);
+        let mut n = 1;
+        while self.used.contains(&name) {
+            let suffix = format!("_{}", n);
+            // Cut the base on a char boundary so there's room for the suffix
+            let mut len = desired.len().min(16 - suffix.len());
+            while !desired.is_char_boundary(len) {
+                len -= 1;
+            }
+            name = Name::from_str_truncated(&format!("{}{}", &desired[..len], suffix));
+            n += 1;
+        }
+        self.used.push(name);
+        name
+    }
+}
+
+/// A matrix a vertex can be drawn with.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum MatrixKey {
+    /// An object's matrix.
+    Object(usize),
+    /// A skinning matrix, from (object, weight in 256ths) terms.
+    Skin(Vec<(usize, u32)>),
+}
+
+#[derive(Default)]
+struct KeyTable {
+    keys: Vec<MatrixKey>,
+    map: HashMap<MatrixKey, usize>,
+}
+
+impl KeyTable {
+    fn get(&mut self, key: MatrixKey) -> usize {
+        if let Some(&idx) = self.map.get(&key) {
+            return idx;
+        }
+        let idx = self.keys.len();
+        self.keys.push(key.clone());
+        self.map.insert(key, idx);
+        idx
+    }
+}
+
+/// Converts skinning weights to the 256ths the skinning render command uses.
+/// The result is sorted by object, sums to 256, and has no zero weights.
+/// The matrix that transforms normals the way m transforms positions: the
+/// inverse transpose of its linear part. (Just using m would skew normals when
+/// m has a non-uniform scale.)
+pub fn normal_matrix(m: &Matrix4<f64>) -> Matrix3<f64> {
+    let linear = Matrix3::from_cols(m.x.truncate(), m.y.truncate(), m.z.truncate());
+    linear.invert()
+        .map(|inv| inv.transpose())
+        .unwrap_or(linear)
+}
+
+fn quantize_weights(influences: &[(usize, f64)]) -> Vec<(usize, u32)> {
+    let mut merged: Vec<(usize, f64)> = vec![];
+    for &(obj, weight) in influences {
+        if weight.is_nan() || weight <= 0.0 {
+            continue;
+        }
+        match merged.iter_mut().find(|x| x.0 == obj) {
+            Some(x) => x.1 += weight,
+            None => merged.push((obj, weight)),
+        }
+    }
+    let total = merged.iter().map(|x| x.1).sum::<f64>();
+    if total == 0.0 {
+        return vec![];
+    }
+
+    // Round down, then give what's left to the ones that lost the most
+    let scaled = merged.iter().map(|x| x.1 / total * 256.0).collect::<Vec<_>>();
+    let mut terms = merged.iter().zip(scaled.iter())
+        .map(|(x, &s)| (x.0, s.floor() as u32))
+        .collect::<Vec<_>>();
+    let mut left = 256 - terms.iter().map(|t| t.1).sum::<u32>();
+    let mut order = (0..terms.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| {
+        let fa = scaled[a] - scaled[a].floor();
+        let fb = scaled[b] - scaled[b].floor();
+        fb.partial_cmp(&fa).unwrap()
+    });
+    for &i in order.iter().cycle() {
+        if left == 0 { break; }
+        terms[i].1 += 1;
+        left -= 1;
+    }
+
+    terms.retain(|t| t.1 != 0);
+    terms.sort();
+    terms
+}
+
+#[derive(Clone)]
+struct Vert {
+    key: usize,
+    pos: Point3<f64>,
+    normal: Option<Vector3<f64>>,
+    texcoord: Option<Point2<f64>>,
+    color: Option<Point3<f32>>,
+}
+
+struct PieceTris<'a> {
+    mat_idx: usize,
+    tris: Vec<&'a [Vert; 3]>,
+    /// Matrices used by the tris.
+    keys: Vec<usize>,
+}
+
+/// Nitro materials, made on demand from glTF materials.
+#[derive(Default)]
+struct Materials {
+    materials: Vec<Material>,
+    /// Index for each glTF material (and the default material).
+    map: HashMap<Option<usize>, usize>,
+    names: NameTable,
+}
+
+impl Materials {
+    fn get(&mut self, scene: &Scene, gltf_mat: Option<usize>, lit: bool) -> Result<usize> {
+        if let Some(&idx) = self.map.get(&gltf_mat) {
+            if lit {
+                // Turn on light 0
+                self.materials[idx].raw.polygon_attr |= 1;
+            }
+            return Ok(idx);
+        }
+
+        let default = gltf::Material {
+            name: "default".to_string(),
+            base_color: [1.0; 4],
+            image: None,
+            wrap: (gltf::REPEAT, gltf::REPEAT),
+            double_sided: false,
+            blend: false,
+        };
+        let mat = match gltf_mat {
+            Some(i) => &scene.materials[i],
+            None => &default,
+        };
+
+        let image = mat.image.map(|i| &scene.images[i]);
+        let (width, height) = match image.and_then(|image| png_dim(image.png.as_ref()?)) {
+            Some((w, h)) if w <= 0xffff && h <= 0xffff => (w as u16, h as u16),
+            _ => (DEFAULT_TEXTURE_DIM, DEFAULT_TEXTURE_DIM),
+        };
+        let texture_name = image.map(|image| Name::from_str_truncated(&image.name));
+        let palette_name = image.map(|image| {
+            // The usual naming convention for palettes
+            let mut len = image.name.len().min(13);
+            while !image.name.is_char_boundary(len) {
+                len -= 1;
+            }
+            Name::from_str_truncated(&format!("{}_pl", &image.name[..len]))
+        });
+
+        let mut params = 0;
+        let (repeat_s, mirror_s) = wrap_bits(mat.wrap.0);
+        let (repeat_t, mirror_t) = wrap_bits(mat.wrap.1);
+        params |= (repeat_s as u32) << 16;
+        params |= (repeat_t as u32) << 17;
+        params |= (mirror_s as u32) << 18;
+        params |= (mirror_t as u32) << 19;
+
+        let c = mat.base_color;
+        let diffuse = [c[0] as f32, c[1] as f32, c[2] as f32];
+        let ambient = [diffuse[0] * 0.5, diffuse[1] * 0.5, diffuse[2] * 0.5];
+        // Alpha 0 draws wireframes on the DS
+        let alpha = if mat.blend { (c[3] as f32).max(1.0 / 31.0) } else { 1.0 };
+
+        let material = Material {
+            name: self.names.get(&mat.name),
+            texture_name,
+            palette_name,
+            params: TextureParams(params),
+            width,
+            height,
+            diffuse,
+            diffuse_is_default_vertex_color: true,
+            ambient,
+            specular: [0.0; 3],
+            enable_shininess_table: false,
+            emission: [0.0; 3],
+            alpha,
+            cull_backface: !mat.double_sided,
+            cull_frontface: false,
+            texture_srt: TextureSrt::default(),
+            texture_mat: Matrix4::identity(),
+            raw: MaterialRaw {
+                polygon_attr: lit as u32,
+                polygon_attr_mask: 0xffff_ffff,
+                mag_w: 0x1000, // 1.0
+                mag_h: 0x1000,
+                ..MaterialRaw::default()
+            },
+        };
+
+        let idx = self.materials.len();
+        self.materials.push(material);
+        self.map.insert(gltf_mat, idx);
+        Ok(idx)
+    }
+}
+
+/// Repeat and mirror bits for a glTF wrap mode.
+fn wrap_bits(wrap: u32) -> (bool, bool) {
+    match wrap {
+        gltf::REPEAT => (true, false),
+        gltf::MIRRORED_REPEAT => (true, true),
+        _ => (false, false),
+    }
+}
+
+/// Reads the dimensions from a PNG's header.
+fn png_dim(png: &[u8]) -> Option<(u32, u32)> {
+    if png.len() < 24 || &png[12..16] != b"IHDR" {
+        return None;
+    }
+    let w = u32::from_be_bytes([png[16], png[17], png[18], png[19]]);
+    let h = u32::from_be_bytes([png[20], png[21], png[22], png[23]]);
+    Some((w, h))
+}
+
+/// Tracks what's in the matrix stack while writing the render commands.
+struct Stack<'a> {
+    w: RenderCmdWriter,
+    keys: &'a [MatrixKey],
+    objects: &'a [ObjectInfo],
+    /// Key of the matrix in each slot.
+    slots: [Option<usize>; STACK_SIZE],
+    /// Protected slots can't be evicted. The root's slot stays protected.
+    protect_count: [u32; STACK_SIZE],
+    last_use: [u64; STACK_SIZE],
+    clock: u64,
+}
+
+impl<'a> Stack<'a> {
+    fn new(keys: &'a [MatrixKey], objects: &'a [ObjectInfo]) -> Stack<'a> {
+        Stack {
+            w: RenderCmdWriter::new(),
+            keys,
+            objects,
+            slots: [None; STACK_SIZE],
+            protect_count: [0; STACK_SIZE],
+            last_use: [0; STACK_SIZE],
+            clock: 0,
+        }
+    }
+
+    /// Computes the root object's matrix into slot 0, where it stays.
+    fn store_root(&mut self) {
+        self.w.mul_object(0, 0, None, Some(0));
+        self.slots[0] = Some(0);
+        self.protect_count[0] = 1;
+    }
+
+    fn protect(&mut self, slot: u8) {
+        self.protect_count[slot as usize] += 1;
+    }
+
+    fn unprotect(&mut self, slot: u8) {
+        self.protect_count[slot as usize] -= 1;
+    }
+
+    /// Makes sure the matrix for a key is in the stack, returning its slot.
+    fn ensure(&mut self, key: usize) -> Result<u8> {
+        self.clock += 1;
+        if let Some(slot) = self.slots.iter().position(|&k| k == Some(key)) {
+            self.last_use[slot] = self.clock;
+            return Ok(slot as u8);
+        }
+
+        let slot = match self.keys[key] {
+            MatrixKey::Object(obj) => {
+                let parent = match self.objects[obj].parent {
+                    Some(parent) => parent,
+                    None => bail!("object {} has no parent", obj),
+                };
+                let parent_slot = self.ensure(parent)?;
+                self.protect(parent_slot);
+                let slot = self.alloc();
+                self.unprotect(parent_slot);
+                let slot = slot?;
+                self.w.mul_object(obj as u8, parent as u8, Some(parent_slot), Some(slot));
+                slot
+            }
+            MatrixKey::Skin(ref terms) => {
+                let mut term_slots = vec![];
+                for &(obj, _) in terms {
+                    let slot = self.ensure(obj)?;
+                    self.protect(slot);
+                    term_slots.push(slot);
+                }
+                let slot = self.alloc();
+                for &s in &term_slots {
+                    self.unprotect(s);
+                }
+                let slot = slot?;
+                let cmd_terms = terms.iter().zip(term_slots.iter())
+                    .map(|(&(obj, weight), &s)| (s, obj as u8, weight as u8))
+                    .collect::<Vec<_>>();
+                self.w.skin(slot, &cmd_terms);
+                slot
+            }
+        };
+
+        self.slots[slot as usize] = Some(key);
+        self.last_use[slot as usize] = self.clock;
+        Ok(slot)
+    }
+
+    /// Finds a slot to put a new matrix in, evicting the least recently used
+    /// unprotected one if they're all full.
+    fn alloc(&mut self) -> Result<u8> {
+        if let Some(slot) = self.slots.iter().position(|k| k.is_none()) {
+            return Ok(slot as u8);
+        }
+        let slot = (0..STACK_SIZE)
+            .filter(|&s| self.protect_count[s] == 0)
+            .min_by_key(|&s| self.last_use[s]);
+        match slot {
+            Some(slot) => {
+                self.slots[slot] = None;
+                Ok(slot as u8)
+            }
+            None => bail!("ran out of matrix stack slots"),
+        }
+    }
+}
+
+#[test]
+fn test_quantize_weights() {
+    assert_eq!(quantize_weights(&[(3, 1.0), (1, 0.0)]), vec![(3, 256)]);
+    assert_eq!(quantize_weights(&[(3, 0.5), (1, 0.5)]), vec![(1, 128), (3, 128)]);
+    let terms = quantize_weights(&[(0, 1.0), (1, 1.0), (2, 1.0)]);
+    assert_eq!(terms.iter().map(|t| t.1).sum::<u32>(), 256);
+    // Same joint twice
+    assert_eq!(quantize_weights(&[(2, 0.25), (2, 0.75)]), vec![(2, 256)]);
+}
+
+#[test]
+fn test_normal_matrix() {
+    // A surface along (1,-1,0), with normal (1,1,0), stretched along x
+    let m = Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0);
+    let tangent = m.transform_vector(vec3(1.0, -1.0, 0.0));
+    let normal = normal_matrix(&m) * vec3(1.0, 1.0, 0.0);
+    assert_eq!(tangent.dot(normal), 0.0);
+    // No change for rotations
+    let rot = Matrix4::from_angle_z(cgmath::Deg(30.0));
+    let n = normal_matrix(&rot) * vec3(1.0, 0.0, 0.0);
+    assert!((n - rot.transform_vector(vec3(1.0, 0.0, 0.0))).magnitude() < 1e-12);
+}
+
+#[test]
+fn test_compile() {
+    use crate::import::gltf::{Mesh, Node, Primitive, Skin};
+    use crate::nitro::{read_container, write_container, Container};
+    use crate::primitives::{DynamicState, PolyType, Primitives};
+
+    let node = |name: &str, trans, children, mesh, skin| Node {
+        name: name.to_string(),
+        children,
+        trans,
+        rot: Matrix3::identity(),
+        scale: vec3(1.0, 1.0, 1.0),
+        mesh,
+        skin,
+    };
+    // hip -> arm, plus a skinned mesh, so a root has to be added
+    let mut nodes = vec![
+        node("hip", vec3(0.0, 1.0, 0.0), vec![1], None, None),
+        node("arm", vec3(2.0, 0.0, 0.0), vec![], Some(1), None),
+        node("body", vec3(0.0, 0.0, 0.0), vec![], Some(0), Some(0)),
+    ];
+    nodes[1].rot = Matrix3::from_angle_z(cgmath::Deg(90.0));
+    let arm_world =
+        Matrix4::from_translation(vec3(2.0, 1.0, 0.0)) *
+        Matrix4::from(nodes[1].rot);
+
+    let skinned = vec![[0.0, 1.0, 0.0], [2.0, 1.0, 0.0], [1.0, 20.0, -3.0]];
+    let rigid = vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.5, 0.0]];
+    let scene = Scene {
+        nodes,
+        roots: vec![0, 2],
+        meshes: vec![
+            Mesh { primitives: vec![Primitive {
+                positions: skinned.clone(),
+                normals: None,
+                texcoords: None,
+                colors: None,
+                joints: Some(vec![[0, 0, 0, 0], [1, 0, 0, 0], [0, 1, 0, 0]]),
+                weights: Some(vec![[1.0, 0.0, 0.0, 0.0], [1.0, 0.0, 0.0, 0.0], [0.5, 0.5, 0.0, 0.0]]),
+                indices: vec![0, 1, 2],
+                material: None,
+            }]},
+            Mesh { primitives: vec![Primitive {
+                positions: rigid.clone(),
+                normals: None,
+                texcoords: None,
+                colors: None,
+                joints: None,
+                weights: None,
+                indices: vec![0, 1, 2],
+                material: None,
+            }]},
+        ],
+        skins: vec![Skin {
+            joints: vec![0, 1],
+            inv_binds: vec![
+                Matrix4::from_translation(vec3(0.0, -1.0, 0.0)),
+                arm_world.invert().unwrap(),
+            ],
+        }],
+        materials: vec![],
+        images: vec![],
+    };
+
+    let model = compile(&scene, "test").unwrap();
+    assert_eq!(model.objects.len(), 4);
+    assert_eq!(model.objects[0].name, Name::from_str_truncated("root"));
+    assert_eq!(model.header.num_verts, 6);
+    assert_eq!(model.up_scale, 4.0);
+
+    // Drawing it at rest should give back the positions
+    let objects = model.objects.iter().map(|o| o.matrix).collect::<Vec<_>>();
+    let uv_mats = vec![Matrix4::identity(); model.materials.len()];
+    let visibility = vec![true; model.objects.len()];
+    let state = DynamicState { objects: &objects, uv_mats: &uv_mats, visibility: &visibility };
+    let prims = Primitives::build(&model, PolyType::Tris, state);
+    // The arm's mesh comes first since it's earlier in the node order
+    let mut expected = rigid.iter()
+        .map(|&p| {
+            let p = arm_world.transform_point(Point3::new(p[0], p[1], p[2]));
+            [p.x, p.y, p.z]
+        })
+        .collect::<Vec<_>>();
+    expected.extend(skinned.iter().cloned());
+    let got = prims.indices.iter()
+        .map(|&i| prims.vertices[i as usize].position)
+        .collect::<Vec<_>>();
+    assert_eq!(got.len(), expected.len());
+    for (g, e) in got.iter().zip(expected.iter()) {
+        for k in 0..3 {
+            assert!((g[k] as f64 - e[k]).abs() < 0.01, "{:?} != {:?}", got, expected);
+        }
+    }
+
+    // Check it can be written and read back
+    let cont = Container {
+        stamp: b"BMD0",
+        version: 2,
+        file_size: 0,
+        models: vec![model],
+        textures: vec![],
+        palettes: vec![],
+        animations: vec![],
+        patterns: vec![],
+        mat_anims: vec![],
+        mat_color_anims: vec![],
+        vis_anims: vec![],
+    };
+    let buf = write_container(&cont).unwrap();
+    let cont2 = read_container(Cur::new(&buf)).unwrap();
+    assert_eq!(cont2.models[0].render_ops.len(), cont.models[0].render_ops.len());
+}
@@ -0,0 +1,622 @@
+//! Loads the parts of a glTF 2.0 file (.gltf or .glb) that import uses.
+//!
+//! Only triangle meshes are read. Animations, cameras, morph targets, etc.
+//! are ignored.
+
+use cgmath::{Matrix3, Matrix4, Quaternion, Vector3, vec3, InnerSpace, SquareMatrix, One};
+use json::JsonValue;
+use std::path::Path;
+use crate::errors::Result;
+use crate::util::cur::Cur;
+
+pub struct Scene {
+    pub nodes: Vec<Node>,
+    /// Top-level nodes of the scene.
+    pub roots: Vec<usize>,
+    pub meshes: Vec<Mesh>,
+    pub skins: Vec<Skin>,
+    pub materials: Vec<Material>,
+    pub images: Vec<Image>,
+}
+
+pub struct Node {
+    pub name: String,
+    pub children: Vec<usize>,
+    pub trans: Vector3<f64>,
+    pub rot: Matrix3<f64>,
+    pub scale: Vector3<f64>,
+    pub mesh: Option<usize>,
+    pub skin: Option<usize>,
+}
+
+pub struct Mesh {
+    pub primitives: Vec<Primitive>,
+}
+
+pub struct Primitive {
+    pub positions: Vec<[f64; 3]>,
+    pub normals: Option<Vec<[f64; 3]>>,
+    pub texcoords: Option<Vec<[f64; 2]>>,
+    pub colors: Option<Vec<[f64; 3]>>,
+    pub joints: Option<Vec<[u32; 4]>>,
+    pub weights: Option<Vec<[f64; 4]>>,
+    /// Three per triangle.
+    pub indices: Vec<u32>,
+    pub material: Option<usize>,
+}
+
+pub struct Skin {
+    /// Node index for each joint.
+    pub joints: Vec<usize>,
+    pub inv_binds: Vec<Matrix4<f64>>,
+}
+
+pub struct Material {
+    pub name: String,
+    /// RGBA
+    pub base_color: [f64; 4],
+    /// Index into images of the base color texture.
+    pub image: Option<usize>,
+    /// glTF wrap mode for S and T.
+    pub wrap: (u32, u32),
+    pub double_sided: bool,
+    pub blend: bool,
+}
+
+pub struct Image {
+    pub name: String,
+    /// PNG file data, if the image is a PNG we could find.
+    pub png: Option<Vec<u8>>,
+}
+
+pub const REPEAT: u32 = 10497;
+pub const MIRRORED_REPEAT: u32 = 33648;
+
+pub fn load(path: &Path) -> Result<Scene> {
+    let bytes = std::fs::read(path)?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let (json_text, glb_bin) = if bytes.starts_with(b"glTF") {
+        read_glb(&bytes)?
+    } else {
+        (String::from_utf8_lossy(&bytes).into_owned(), None)
+    };
+    let json = match json::parse(&json_text) {
+        Ok(json) => json,
+        Err(e) => bail!("couldn't parse glTF JSON: {}", e),
+    };
+    if !json["asset"]["version"].as_str().unwrap_or("").starts_with('2') {
+        bail!("only glTF 2.0 is supported");
+    }
+
+    let mut buffers = vec![];
+    for (i, buffer) in json["buffers"].members().enumerate() {
+        let data = match buffer["uri"].as_str() {
+            None => match (i, &glb_bin) {
+                (0, Some(bin)) => bin.clone(),
+                _ => bail!("glTF buffer {} has no data", i),
+            },
+            Some(uri) => read_uri(uri, base_dir)?,
+        };
+        buffers.push(data);
+    }
+
+    let ctx = Ctx { json: &json, buffers: &buffers };
+
+    let mut nodes = vec![];
+    for node in json["nodes"].members() {
+        nodes.push(ctx.node(node)?);
+    }
+
+    let roots: Vec<usize> = if json["scenes"].is_array() && !json["scenes"].is_empty() {
+        let scene = json["scene"].as_usize().unwrap_or(0);
+        json["scenes"][scene]["nodes"].members()
+            .filter_map(|x| x.as_usize())
+            .collect()
+    } else {
+        // Every node that isn't a child of some other node
+        let mut is_child = vec![false; nodes.len()];
+        for node in &nodes {
+            for &child in &node.children {
+                if child < is_child.len() { is_child[child] = true; }
+            }
+        }
+        (0..nodes.len()).filter(|&i| !is_child[i]).collect()
+    };
+
+    let mut meshes = vec![];
+    for mesh in json["meshes"].members() {
+        let mut primitives = vec![];
+        for prim in mesh["primitives"].members() {
+            if let Some(prim) = ctx.primitive(prim)? {
+                primitives.push(prim);
+            }
+        }
+        meshes.push(Mesh { primitives });
+    }
+
+    let mut skins = vec![];
+    for skin in json["skins"].members() {
+        let joints = skin["joints"].members()
+            .filter_map(|x| x.as_usize())
+            .collect::<Vec<_>>();
+        let inv_binds = match skin["inverseBindMatrices"].as_usize() {
+            Some(acc) => {
+                let (data, n) = ctx.accessor(acc)?;
+                check!(n == 16)?;
+                data.chunks(16)
+                    .map(|m| Matrix4::new(
+                        m[0], m[1], m[2], m[3],
+                        m[4], m[5], m[6], m[7],
+                        m[8], m[9], m[10], m[11],
+                        m[12], m[13], m[14], m[15],
+                    ))
+                    .collect::<Vec<_>>()
+            }
+            None => vec![Matrix4::one(); joints.len()],
+        };
+        check!(inv_binds.len() >= joints.len())?;
+        skins.push(Skin { joints, inv_binds });
+    }
+
+    let mut materials = vec![];
+    for (i, mat) in json["materials"].members().enumerate() {
+        materials.push(ctx.material(mat, i));
+    }
+
+    let mut images = vec![];
+    for (i, image) in json["images"].members().enumerate() {
+        images.push(ctx.image(image, i, base_dir));
+    }
+
+    // Validate indices so users of the Scene don't have to
+    let num_nodes = nodes.len();
+    let bad_node = |&i: &usize| i >= num_nodes;
+    if roots.iter().any(bad_node) ||
+        nodes.iter().any(|n| n.children.iter().any(bad_node)) ||
+        skins.iter().any(|s| s.joints.iter().any(bad_node))
+    {
+        bail!("glTF node index out of range");
+    }
+    for node in &nodes {
+        if node.mesh.map(|i| i >= meshes.len()).unwrap_or(false) ||
+            node.skin.map(|i| i >= skins.len()).unwrap_or(false)
+        {
+            bail!("glTF mesh or skin index out of range");
+        }
+    }
+    for mesh in &meshes {
+        for prim in &mesh.primitives {
+            if prim.material.map(|i| i >= materials.len()).unwrap_or(false) {
+                bail!("glTF material index out of range");
+            }
+        }
+    }
+    for mat in &mut materials {
+        if mat.image.map(|i| i >= images.len()).unwrap_or(false) {
+            mat.image = None;
+        }
+    }
+
+    Ok(Scene { nodes, roots, meshes, skins, materials, images })
+}
+
+/// Splits an affine matrix into translation, rotation, and scale. Shears are
+/// lost.
+pub fn decompose(m: &Matrix4<f64>) -> (Vector3<f64>, Matrix3<f64>, Vector3<f64>) {
+    let x = m.x.truncate();
+    let y = m.y.truncate();
+    let z = m.z.truncate();
+    let trans = m.w.truncate();
+    let mut scale = vec3(x.magnitude(), y.magnitude(), z.magnitude());
+    if Matrix3::from_cols(x, y, z).determinant() < 0.0 {
+        scale.x = -scale.x;
+    }
+    let div = |v: Vector3<f64>, s: f64| if s == 0.0 { v } else { v / s };
+    let rot = Matrix3::from_cols(div(x, scale.x), div(y, scale.y), div(z, scale.z));
+    (trans, rot, scale)
+}
+
+/// Splits a GLB into its JSON and BIN chunks.
+fn read_glb(bytes: &[u8]) -> Result<(String, Option<Vec<u8>>)> {
+    let cur = Cur::new(bytes);
+    fields!(cur, glb {
+        magic: [u8; 4],
+        version: u32,
+        length: u32,
+        chunks: Cur,
+    });
+    check!(magic == b"glTF")?;
+    check!(version == 2)?;
+    check!(length as usize <= bytes.len())?;
+
+    let mut cur = chunks;
+    let mut json = None;
+    let mut bin = None;
+    while cur.pos() + 8 <= length as usize {
+        let chunk_len = cur.next::<u32>()?;
+        let chunk_type = cur.next_n_u8s(4)?;
+        let data = cur.next_n_u8s(chunk_len as usize)?;
+        match chunk_type {
+            b"JSON" => json = Some(String::from_utf8_lossy(data).into_owned()),
+            b"BIN\0" => bin = Some(data.to_vec()),
+            _ => (),
+        }
+    }
+    match json {
+        Some(json) => Ok((json, bin)),
+        None => bail!("GLB has no JSON chunk"),
+    }
+}
+
+/// Reads the data a URI points to: either a data URI or a file relative to
+/// the glTF.
+fn read_uri(uri: &str, base_dir: &Path) -> Result<Vec<u8>> {
+    if uri.starts_with("data:") {
+        let data = match uri.find(";base64,") {
+            Some(pos) => &uri[pos + 8..],
+            None => bail!("data URI isn't base64"),
+        };
+        return match decode_base64(data) {
+            Some(x) => Ok(x),
+            None => bail!("bad base64 in data URI"),
+        };
+    }
+    let path = base_dir.join(percent_decode(uri));
+    Ok(std::fs::read(&path)?)
+}
+
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut nbits = 0;
+    for c in s.bytes() {
+        let x = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => break,
+            b' ' | b'\n' | b'\r' | b'\t' => continue,
+            _ => return None,
+        };
+        acc = acc << 6 | x as u32;
+        nbits += 6;
+        if nbits >= 8 {
+            nbits -= 8;
+            out.push((acc >> nbits) as u8);
+            acc &= (1 << nbits) - 1;
+        }
+    }
+    Some(out)
+}
+
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(x) = u8::from_str_radix(&String::from_utf8_lossy(&bytes[i + 1..i + 3]), 16) {
+                out.push(x);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+struct Ctx<'a> {
+    json: &'a JsonValue,
+    buffers: &'a [Vec<u8>],
+}
+
+impl<'a> Ctx<'a> {
+    /// Reads an accessor as floats. Normalized integers are converted to
+    /// `[0,1]` (or `[-1,1]`). Returns the data and the number of components per
+    /// element.
+    fn accessor(&self, idx: usize) -> Result<(Vec<f64>, usize)> {
+        let acc = &self.json["accessors"][idx];
+        if acc.is_null() {
+            bail!("glTF accessor {} doesn't exist", idx);
+        }
+        let count = acc["count"].as_usize().unwrap_or(0);
+        let num_comps = match acc["type"].as_str() {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") => 4,
+            Some("MAT4") => 16,
+            _ => bail!("unsupported glTF accessor type"),
+        };
+        let comp_type = acc["componentType"].as_u32().unwrap_or(0);
+        let comp_size = match comp_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            _ => bail!("unknown glTF component type {}", comp_type),
+        };
+        let normalized = acc["normalized"].as_bool().unwrap_or(false);
+
+        let view_idx = match acc["bufferView"].as_usize() {
+            Some(x) => x,
+            // No buffer view means all zeros
+            None => return Ok((vec![0.0; count * num_comps], num_comps)),
+        };
+        let view = &self.json["bufferViews"][view_idx];
+        let buffer = match view["buffer"].as_usize().and_then(|i| self.buffers.get(i)) {
+            Some(x) => x,
+            None => bail!("glTF buffer view {} has no buffer", view_idx),
+        };
+        let view_off = view["byteOffset"].as_usize().unwrap_or(0);
+        let view_len = view["byteLength"].as_usize().unwrap_or(0);
+        let elem_size = comp_size * num_comps;
+        let stride = view["byteStride"].as_usize().unwrap_or(elem_size);
+        let off = view_off + acc["byteOffset"].as_usize().unwrap_or(0);
+
+        if count > 0 {
+            let end = off + stride * (count - 1) + elem_size;
+            if end > view_off + view_len || end > buffer.len() {
+                bail!("glTF accessor {} out of bounds", idx);
+            }
+        }
+
+        let mut data = Vec::with_capacity(count * num_comps);
+        for i in 0..count {
+            for j in 0..num_comps {
+                let p = off + i * stride + j * comp_size;
+                let b = &buffer[p..p + comp_size];
+                let x = match comp_type {
+                    5120 => {
+                        let x = b[0] as i8 as f64;
+                        if normalized { (x / 127.0).max(-1.0) } else { x }
+                    }
+                    5121 => {
+                        let x = b[0] as f64;
+                        if normalized { x / 255.0 } else { x }
+                    }
+                    5122 => {
+                        let x = i16::from_le_bytes([b[0], b[1]]) as f64;
+                        if normalized { (x / 32767.0).max(-1.0) } else { x }
+                    }
+                    5123 => {
+                        let x = u16::from_le_bytes([b[0], b[1]]) as f64;
+                        if normalized { x / 65535.0 } else { x }
+                    }
+                    5125 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    _ => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                };
+                data.push(x);
+            }
+        }
+        Ok((data, num_comps))
+    }
+
+    fn node(&self, node: &JsonValue) -> Result<Node> {
+        let name = node["name"].as_str().unwrap_or("").to_string();
+        let children = node["children"].members()
+            .filter_map(|x| x.as_usize())
+            .collect();
+        let mesh = node["mesh"].as_usize();
+        let skin = node["skin"].as_usize();
+
+        let nums = |v: &JsonValue| -> Vec<f64> {
+            v.members().filter_map(|x| x.as_f64()).collect()
+        };
+
+        let (trans, rot, scale);
+        if node["matrix"].is_array() {
+            let m = nums(&node["matrix"]);
+            check!(m.len() == 16)?;
+            let m = Matrix4::new(
+                m[0], m[1], m[2], m[3],
+                m[4], m[5], m[6], m[7],
+                m[8], m[9], m[10], m[11],
+                m[12], m[13], m[14], m[15],
+            );
+            (trans, rot, scale) = decompose(&m);
+        } else {
+            let t = nums(&node["translation"]);
+            trans = if t.len() == 3 { vec3(t[0], t[1], t[2]) } else { vec3(0.0, 0.0, 0.0) };
+            let r = nums(&node["rotation"]);
+            rot = if r.len() == 4 {
+                Matrix3::from(Quaternion::new(r[3], r[0], r[1], r[2]).normalize())
+            } else {
+                Matrix3::one()
+            };
+            let s = nums(&node["scale"]);
+            scale = if s.len() == 3 { vec3(s[0], s[1], s[2]) } else { vec3(1.0, 1.0, 1.0) };
+        }
+
+        Ok(Node { name, children, trans, rot, scale, mesh, skin })
+    }
+
+    /// Reads a primitive. Returns None (and warns) for primitives that aren't
+    /// made of triangles.
+    fn primitive(&self, prim: &JsonValue) -> Result<Option<Primitive>> {
+        let mode = prim["mode"].as_u32().unwrap_or(4);
+        if mode < 4 {
+            warn!("skipping glTF primitive made of points or lines");
+            return Ok(None);
+        }
+
+        let attrs = &prim["attributes"];
+        let position_acc = match attrs["POSITION"].as_usize() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let positions = self.vec3s(position_acc)?;
+        let num_verts = positions.len();
+        let check_len = |len: usize| -> Result<()> {
+            if len != num_verts {
+                bail!("glTF primitive attributes have different lengths");
+            }
+            Ok(())
+        };
+
+        let normals = match attrs["NORMAL"].as_usize() {
+            Some(acc) => Some(self.vec3s(acc)?),
+            None => None,
+        };
+        let texcoords = match attrs["TEXCOORD_0"].as_usize() {
+            Some(acc) => {
+                let (data, n) = self.accessor(acc)?;
+                check!(n == 2)?;
+                Some(data.chunks(2).map(|c| [c[0], c[1]]).collect::<Vec<_>>())
+            }
+            None => None,
+        };
+        let colors = match attrs["COLOR_0"].as_usize() {
+            Some(acc) => {
+                let (data, n) = self.accessor(acc)?;
+                check!(n == 3 || n == 4)?;
+                Some(data.chunks(n).map(|c| [c[0], c[1], c[2]]).collect::<Vec<_>>())
+            }
+            None => None,
+        };
+        let joints = match attrs["JOINTS_0"].as_usize() {
+            Some(acc) => {
+                let (data, n) = self.accessor(acc)?;
+                check!(n == 4)?;
+                Some(data.chunks(4)
+                    .map(|c| [c[0] as u32, c[1] as u32, c[2] as u32, c[3] as u32])
+                    .collect::<Vec<_>>())
+            }
+            None => None,
+        };
+        let weights = match attrs["WEIGHTS_0"].as_usize() {
+            Some(acc) => {
+                let (data, n) = self.accessor(acc)?;
+                check!(n == 4)?;
+                Some(data.chunks(4).map(|c| [c[0], c[1], c[2], c[3]]).collect::<Vec<_>>())
+            }
+            None => None,
+        };
+        if let Some(ref x) = normals { check_len(x.len())?; }
+        if let Some(ref x) = texcoords { check_len(x.len())?; }
+        if let Some(ref x) = colors { check_len(x.len())?; }
+        if let Some(ref x) = joints { check_len(x.len())?; }
+        if let Some(ref x) = weights { check_len(x.len())?; }
+
+        let raw_indices = match prim["indices"].as_usize() {
+            Some(acc) => {
+                let (data, n) = self.accessor(acc)?;
+                check!(n == 1)?;
+                data.into_iter().map(|x| x as u32).collect::<Vec<_>>()
+            }
+            None => (0..num_verts as u32).collect(),
+        };
+        if raw_indices.iter().any(|&i| i as usize >= num_verts) {
+            bail!("glTF index out of range");
+        }
+
+        // Convert strips and fans to triangles
+        let mut indices = vec![];
+        match mode {
+            4 => {
+                let len = raw_indices.len() / 3 * 3;
+                indices.extend_from_slice(&raw_indices[..len]);
+            }
+            5 => {
+                for i in 2..raw_indices.len() {
+                    let (a, b, c) = (raw_indices[i - 2], raw_indices[i - 1], raw_indices[i]);
+                    if i % 2 == 0 {
+                        indices.extend_from_slice(&[a, b, c]);
+                    } else {
+                        indices.extend_from_slice(&[b, a, c]);
+                    }
+                }
+            }
+            6 => {
+                for i in 2..raw_indices.len() {
+                    indices.extend_from_slice(&[raw_indices[0], raw_indices[i - 1], raw_indices[i]]);
+                }
+            }
+            _ => {
+                warn!("skipping glTF primitive with unknown mode {}", mode);
+                return Ok(None);
+            }
+        }
+
+        let material = prim["material"].as_usize();
+
+        Ok(Some(Primitive {
+            positions, normals, texcoords, colors, joints, weights, indices, material,
+        }))
+    }
+
+    fn vec3s(&self, acc: usize) -> Result<Vec<[f64; 3]>> {
+        let (data, n) = self.accessor(acc)?;
+        check!(n == 3)?;
+        Ok(data.chunks(3).map(|c| [c[0], c[1], c[2]]).collect())
+    }
+
+    fn material(&self, mat: &JsonValue, idx: usize) -> Material {
+        let name = match mat["name"].as_str() {
+            Some(name) => name.to_string(),
+            None => format!("material{}", idx),
+        };
+        let pbr = &mat["pbrMetallicRoughness"];
+        let mut base_color = [1.0; 4];
+        for (i, x) in pbr["baseColorFactor"].members().take(4).enumerate() {
+            base_color[i] = x.as_f64().unwrap_or(1.0);
+        }
+
+        let texture = &self.json["textures"][pbr["baseColorTexture"]["index"].as_usize().unwrap_or(usize::MAX)];
+        let image = texture["source"].as_usize();
+        let sampler = &self.json["samplers"][texture["sampler"].as_usize().unwrap_or(usize::MAX)];
+        let wrap = (
+            sampler["wrapS"].as_u32().unwrap_or(REPEAT),
+            sampler["wrapT"].as_u32().unwrap_or(REPEAT),
+        );
+
+        let double_sided = mat["doubleSided"].as_bool().unwrap_or(false);
+        let blend = mat["alphaMode"].as_str() == Some("BLEND");
+
+        Material { name, base_color, image, wrap, double_sided, blend }
+    }
+
+    fn image(&self, image: &JsonValue, idx: usize, base_dir: &Path) -> Image {
+        let uri = image["uri"].as_str();
+
+        // Name it after the file if there's no name
+        let name = match (image["name"].as_str(), uri) {
+            (Some(name), _) => name.to_string(),
+            (None, Some(uri)) if !uri.starts_with("data:") => {
+                let uri = percent_decode(uri);
+                Path::new(&uri).file_stem()
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| format!("image{}", idx))
+            }
+            _ => format!("image{}", idx),
+        };
+
+        let data = match uri {
+            Some(uri) => read_uri(uri, base_dir).ok(),
+            None => image["bufferView"].as_usize().and_then(|view_idx| {
+                let view = &self.json["bufferViews"][view_idx];
+                let buffer = self.buffers.get(view["buffer"].as_usize()?)?;
+                let off = view["byteOffset"].as_usize().unwrap_or(0);
+                let len = view["byteLength"].as_usize()?;
+                buffer.get(off..off + len).map(|x| x.to_vec())
+            }),
+        };
+        let png = data.filter(|data| data.starts_with(b"\x89PNG"));
+        if png.is_none() {
+            warn!("couldn't load image {} (only PNGs are supported)", name);
+        }
+
+        Image { name, png }
+    }
+}
+
+#[test]
+fn test_decode_base64() {
+    assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+    assert_eq!(decode_base64("AAEC/w==").unwrap(), &[0, 1, 2, 255]);
+}
@@ -0,0 +1,70 @@
+//! Import models from other formats into Nitro files.
+//!
+//! glTF (.gltf/.glb) and COLLADA (.dae) are supported. Both are loaded into
+//! the same Scene, whose meshes are compiled into render commands and GPU
+//! commands; see the `compile` module for how.
+
+mod gltf;
+mod collada;
+mod compile;
+
+use crate::cli::Args;
+use crate::errors::Result;
+use crate::nitro::{Container, Model, write_container};
+use crate::util::OutDir;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub(crate) fn main(args: &Args) -> Result<()> {
+    let out_dir_path = PathBuf::from(args.get_opt("output").unwrap());
+    let mut out_dir = OutDir::new(out_dir_path)?;
+
+    let path = Path::new(&args.free_args[0]);
+    let model = import(path)?;
+
+    info!("model {}: {} objects, {} materials, {} pieces, {} vertices",
+        model.name, model.objects.len(), model.materials.len(),
+        model.pieces.len(), model.header.num_verts);
+
+    let file_name = format!("{}.nsbmd", model.name.print_safe());
+    let cont = Container {
+        stamp: b"BMD0",
+        version: 2,
+        file_size: 0,
+        models: vec![model],
+        textures: vec![],
+        palettes: vec![],
+        animations: vec![],
+        patterns: vec![],
+        mat_anims: vec![],
+        mat_color_anims: vec![],
+        vis_anims: vec![],
+    };
+    let buf = write_container(&cont)?;
+
+    let mut f = out_dir.create_file(&file_name)?;
+    f.write_all(&buf)?;
+    f.flush()?;
+
+    info!("wrote {}", file_name);
+
+    Ok(())
+}
+
+/// Reads a model from a .gltf, .glb, or .dae file.
+pub fn import(path: &Path) -> Result<Model> {
+    let ext = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    let scene = match ext.as_deref() {
+        Some("gltf") | Some("glb") => gltf::load(path)?,
+        Some("dae") => collada::load(path)?,
+        _ => bail!("don't know how to import {}; expected a .gltf, .glb, or .dae file", path.display()),
+    };
+
+    let name = path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "model".to_string());
+
+    compile::compile(&scene, &name)
+}
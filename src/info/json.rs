@@ -0,0 +1,397 @@
+//! The same info as the text output, as JSON (for `info --json`).
+//!
+//! Things are referred to by their index in the database, the same as the
+//! numbers in the text output ("Model 0", "Texture 3", etc.).
+
+use crate::connection::{Connection, MaterialConnection, Match};
+use crate::db::Database;
+use crate::nitro::animation::Curve;
+use crate::nitro::material_animation::MatChannelTarget;
+use json::JsonValue;
+
+pub fn info_json(db: &Database, conn: &Connection) -> JsonValue {
+    let file = |file_id: usize| db.file_paths[file_id].to_string_lossy().into_owned();
+
+    let files = db.file_paths.iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+
+    let models = (0..db.models.len())
+        .map(|id| model_json(db, conn, id))
+        .collect::<Vec<_>>();
+
+    let textures = db.textures.iter().enumerate()
+        .map(|(id, texture)| {
+            let params = texture.params;
+            object!(
+                "name" => texture.name.to_string(),
+                "file" => file(db.textures_found_in[id]),
+                "offset" => params.offset(),
+                "width" => params.width(),
+                "height" => params.height(),
+                "format" => params.format().0,
+                "format_name" => params.format().desc().name,
+                "is_color0_transparent" => params.is_color0_transparent(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let palettes = db.palettes.iter().enumerate()
+        .map(|(id, palette)| object!(
+            "name" => palette.name.to_string(),
+            "file" => file(db.palettes_found_in[id]),
+            "offset" => palette.off,
+        ))
+        .collect::<Vec<_>>();
+
+    let animations = db.animations.iter().enumerate()
+        .map(|(id, anim)| {
+            let objects = anim.objects_curves.iter()
+                .map(|trs| object!(
+                    "trans" => array!(curve_json(&trs.trans[0]), curve_json(&trs.trans[1]), curve_json(&trs.trans[2])),
+                    "rotation" => curve_json(&trs.rotation),
+                    "scale" => array!(curve_json(&trs.scale[0]), curve_json(&trs.scale[1]), curve_json(&trs.scale[2])),
+                ))
+                .collect::<Vec<_>>();
+            object!(
+                "name" => anim.name.to_string(),
+                "file" => file(db.animations_found_in[id]),
+                "num_frames" => anim.num_frames,
+                "objects" => objects,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let patterns = db.patterns.iter().enumerate()
+        .map(|(id, pat)| {
+            let tracks = pat.material_tracks.iter()
+                .map(|track| {
+                    let keyframes = track.keyframes.iter()
+                        .map(|key| object!(
+                            "frame" => key.frame,
+                            "texture" => pat.texture_names[key.texture_idx as usize].to_string(),
+                            "palette" => pat.palette_names[key.palette_idx as usize].to_string(),
+                        ))
+                        .collect::<Vec<_>>();
+                    object!(
+                        "material" => track.name.to_string(),
+                        "keyframes" => keyframes,
+                    )
+                })
+                .collect::<Vec<_>>();
+            object!(
+                "name" => pat.name.to_string(),
+                "file" => file(db.patterns_found_in[id]),
+                "num_frames" => pat.num_frames,
+                "tracks" => tracks,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mat_anims = db.mat_anims.iter().enumerate()
+        .map(|(id, mat_anim)| {
+            let tracks = mat_anim.tracks.iter()
+                .map(|track| {
+                    let mut channels = object!();
+                    for channel in &track.channels {
+                        channels[channel_name(channel.target)] = curve_json(&channel.curve);
+                    }
+                    object!(
+                        "material" => track.name.to_string(),
+                        "channels" => channels,
+                    )
+                })
+                .collect::<Vec<_>>();
+            object!(
+                "name" => mat_anim.name.to_string(),
+                "file" => file(db.mat_anims_found_in[id]),
+                "num_frames" => mat_anim.num_frames,
+                "tracks" => tracks,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mat_color_anims = db.mat_color_anims.iter().enumerate()
+        .map(|(id, anim)| {
+            let tracks = anim.tracks.iter()
+                .map(|track| object!(
+                    "material" => track.name.to_string(),
+                    "diffuse" => curve_json(&track.diffuse),
+                    "ambient" => curve_json(&track.ambient),
+                    "specular" => curve_json(&track.specular),
+                    "emission" => curve_json(&track.emission),
+                    "alpha" => curve_json(&track.alpha),
+                ))
+                .collect::<Vec<_>>();
+            object!(
+                "name" => anim.name.to_string(),
+                "file" => file(db.mat_color_anims_found_in[id]),
+                "num_frames" => anim.num_frames,
+                "tracks" => tracks,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let vis_anims = db.vis_anims.iter().enumerate()
+        .map(|(id, anim)| object!(
+            "name" => anim.name.to_string(),
+            "file" => file(db.vis_anims_found_in[id]),
+            "num_frames" => anim.num_frames,
+            "num_objects" => anim.num_objects,
+        ))
+        .collect::<Vec<_>>();
+
+    object!(
+        "files" => files,
+        "models" => models,
+        "textures" => textures,
+        "palettes" => palettes,
+        "animations" => animations,
+        "patterns" => patterns,
+        "material_animations" => mat_anims,
+        "material_color_animations" => mat_color_anims,
+        "visibility_animations" => vis_anims,
+    )
+}
+
+fn model_json(db: &Database, conn: &Connection, model_id: usize) -> JsonValue {
+    let model = &db.models[model_id];
+    let mdl_conn = &conn.models[model_id];
+
+    let objects = model.objects.iter()
+        .map(|object| object!(
+            "name" => object.name.to_string(),
+            "trans" => object.trans.map(|t| array!(t.x, t.y, t.z)),
+            // Column-major
+            "rotation" => object.rot.map(|r| array!(
+                r.x.x, r.x.y, r.x.z,
+                r.y.x, r.y.y, r.y.z,
+                r.z.x, r.z.y, r.z.z,
+            )),
+            "scale" => object.scale.map(|s| array!(s.x, s.y, s.z)),
+            "visible" => object.visible,
+        ))
+        .collect::<Vec<_>>();
+
+    let pieces = model.pieces.iter()
+        .map(|piece| object!(
+            "name" => piece.name.to_string(),
+            "gpu_commands_size" => piece.gpu_commands.len(),
+        ))
+        .collect::<Vec<_>>();
+
+    let materials = model.materials.iter().zip(mdl_conn.materials.iter())
+        .map(|(material, mat_conn)| {
+            let params = material.params;
+            let srt = &material.texture_srt;
+            let palette_match = match *mat_conn {
+                MaterialConnection::TextureOkPaletteOk { palette, .. } => Some(palette),
+                _ => None,
+            };
+            object!(
+                "name" => material.name.to_string(),
+                "texture" => material.texture_name.map(|name| name.to_string()),
+                "palette" => material.palette_name.map(|name| name.to_string()),
+                "connection" => connection_status(mat_conn),
+                "texture_match" => match_json(mat_conn.texture()),
+                "palette_match" => match_json(palette_match),
+                "width" => material.width,
+                "height" => material.height,
+                "repeat" => array!(params.repeat_s(), params.repeat_t()),
+                "mirror" => array!(params.mirror_s(), params.mirror_t()),
+                "texcoord_transform_mode" => params.texcoord_transform_mode(),
+                "texture_srt" => object!(
+                    "scale" => array!(srt.scale.0, srt.scale.1),
+                    "rotation" => srt.rotation,
+                    "translation" => array!(srt.translation.0, srt.translation.1),
+                ),
+                "diffuse" => &material.diffuse[..],
+                "diffuse_is_default_vertex_color" => material.diffuse_is_default_vertex_color,
+                "ambient" => &material.ambient[..],
+                "specular" => &material.specular[..],
+                "enable_shininess_table" => material.enable_shininess_table,
+                "emission" => &material.emission[..],
+                "alpha" => material.alpha,
+                "cull_backface" => material.cull_backface,
+                "cull_frontface" => material.cull_frontface,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let patterns = mdl_conn.patterns.iter()
+        .map(|pat_conn| object!(
+            "pattern" => pat_conn.pattern_id,
+            "textures" => pat_conn.texture_ids.iter()
+                .map(|&m| match_json(m))
+                .collect::<Vec<_>>(),
+            // One list per texture, since palettes are resolved near it
+            "palettes" => pat_conn.palette_ids.iter()
+                .map(|ids| ids.iter().map(|&m| match_json(m)).collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+        ))
+        .collect::<Vec<_>>();
+
+    object!(
+        "name" => model.name.to_string(),
+        "file" => db.file_paths[db.models_found_in[model_id]].to_string_lossy().into_owned(),
+        "texture_matrix_mode" => format!("{:?}", model.tex_mtx_mode),
+        "objects" => objects,
+        "pieces" => pieces,
+        "materials" => materials,
+        "connections" => object!(
+            "animations" => mdl_conn.animations.clone(),
+            "animation_scores" => mdl_conn.animation_scores.clone(),
+            "patterns" => patterns,
+            "material_animations" => mdl_conn.mat_anims.iter()
+                .map(|x| x.mat_anim_id)
+                .collect::<Vec<_>>(),
+            "material_color_animations" => mdl_conn.mat_color_anims.clone(),
+            "visibility_animations" => mdl_conn.vis_anims.clone(),
+        ),
+    )
+}
+
+fn connection_status(mat_conn: &MaterialConnection) -> &'static str {
+    match *mat_conn {
+        MaterialConnection::NoTexture => "no_texture",
+        MaterialConnection::TextureMissing => "texture_missing",
+        MaterialConnection::TextureOkNoPalette { .. } => "texture_ok_no_palette",
+        MaterialConnection::TextureOkPaletteMissing { .. } => "texture_ok_palette_missing",
+        MaterialConnection::TextureOkPaletteOk { .. } => "texture_ok_palette_ok",
+    }
+}
+
+fn match_json(m: Option<Match<usize>>) -> JsonValue {
+    match m {
+        Some(Match { id, best }) => object!("id" => id, "best" => best),
+        None => JsonValue::Null,
+    }
+}
+
+/// Describes what kind of curve it is, without the values.
+fn curve_json<T>(curve: &Curve<T>) -> JsonValue {
+    match *curve {
+        Curve::None => object!("kind" => "none"),
+        Curve::Constant(_) => object!("kind" => "constant"),
+        Curve::Samples { start_frame, end_frame, ref values } => object!(
+            "kind" => "samples",
+            "start_frame" => start_frame,
+            "end_frame" => end_frame,
+            "num_samples" => values.len(),
+        ),
+    }
+}
+
+fn channel_name(target: MatChannelTarget) -> &'static str {
+    match target {
+        MatChannelTarget::ScaleU => "scale_u",
+        MatChannelTarget::ScaleV => "scale_v",
+        MatChannelTarget::Rotation => "rotation",
+        MatChannelTarget::TranslationU => "translation_u",
+        MatChannelTarget::TranslationV => "translation_v",
+    }
+}
+
+#[test]
+fn test_info_json() {
+    use crate::connection::ConnectionOptions;
+    use crate::nds::TextureParams;
+    use crate::nitro::{Animation, Name, Palette, Texture};
+    use crate::nitro::animation::TRSCurves;
+    use crate::test_util::{add_model, db_with_files, ModelBuilder};
+    use std::sync::Arc;
+
+    let name = Name::from_str_truncated;
+    let curves = |x: Curve<f64>| TRSCurves {
+        trans: [x, Curve::None, Curve::None],
+        rotation: Curve::None,
+        scale: [Curve::None, Curve::None, Curve::None],
+    };
+
+    let mut db = db_with_files(&["hero.nsbmd", "hero.nsbtx"]);
+    let model = ModelBuilder::new("hero")
+        .chain(&["hip", "arm"])
+        .material("default")
+        .build();
+    add_model(&mut db, model, 0);
+    db.animations.push(Animation {
+        name: name("walk"),
+        num_frames: 4,
+        objects_curves: vec![
+            curves(Curve::Samples { start_frame: 0, end_frame: 4, values: vec![0.0; 4] }),
+            curves(Curve::Constant(1.0)),
+        ],
+    });
+    db.animations_found_in.push(0);
+    db.textures.push(Texture {
+        name: name("skin"),
+        // 16-color 16x8 at offset 8
+        params: TextureParams(3 << 26 | 1 << 20 | 1),
+        data1: vec![0; 64].into(),
+        data2: Default::default(),
+        unknown: 0,
+    });
+    db.textures_found_in.push(1);
+    db.palettes.push(Palette {
+        name: name("skin_pl"),
+        off: 0,
+        pal_block: Arc::new(vec![0; 32].into_boxed_slice()),
+        unknown: 0,
+    });
+    db.palettes_found_in.push(1);
+    let conn = Connection::build(&db, ConnectionOptions::default());
+
+    let info = info_json(&db, &conn);
+
+    let keys = info.entries().map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(keys, [
+        "files", "models", "textures", "palettes", "animations", "patterns",
+        "material_animations", "material_color_animations",
+        "visibility_animations",
+    ]);
+    assert_eq!(info["files"], array!("hero.nsbmd", "hero.nsbtx"));
+
+    let model = &info["models"][0];
+    assert_eq!(model["name"], "hero");
+    assert_eq!(model["file"], "hero.nsbmd");
+    assert_eq!(model["objects"].len(), 2);
+    assert_eq!(model["objects"][0]["name"], "hip");
+    assert_eq!(model["objects"][0]["trans"], array!(1.0, 0.0, 0.0));
+    assert!(model["objects"][0]["rotation"].is_null());
+    assert_eq!(model["objects"][1]["visible"], true);
+    let material = &model["materials"][0];
+    assert_eq!(material["name"], "default");
+    assert!(material["texture"].is_null());
+    assert_eq!(material["connection"], "no_texture");
+    assert!(material["texture_match"].is_null());
+    assert_eq!(model["connections"]["animations"], array!(0));
+    assert_eq!(model["connections"]["patterns"], array!());
+
+    assert_eq!(info["textures"], array!(object!(
+        "name" => "skin",
+        "file" => "hero.nsbtx",
+        "offset" => 8,
+        "width" => 16,
+        "height" => 8,
+        "format" => 3,
+        "format_name" => "16-Color Palette Texture",
+        "is_color0_transparent" => false,
+    )));
+    assert_eq!(info["palettes"], array!(object!(
+        "name" => "skin_pl",
+        "file" => "hero.nsbtx",
+        "offset" => 0,
+    )));
+
+    let anim = &info["animations"][0];
+    assert_eq!(anim["name"], "walk");
+    assert_eq!(anim["num_frames"], 4);
+    assert_eq!(anim["objects"][0]["trans"][0], object!(
+        "kind" => "samples",
+        "start_frame" => 0,
+        "end_frame" => 4,
+        "num_samples" => 4,
+    ));
+    assert_eq!(anim["objects"][1]["trans"][0], object!("kind" => "constant"));
+    assert_eq!(anim["objects"][1]["rotation"], object!("kind" => "none"));
+}
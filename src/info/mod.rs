@@ -1,14 +1,28 @@
+mod json;
+
 use crate::cli::Args;
 use crate::errors::Result;
 use crate::db::Database;
-use crate::connection::{Connection, ConnectionOptions, MaterialConnection, Match};
+use crate::connection::{dump_overrides, Connection, ConnectionOptions, MaterialConnection, Match};
 
 pub fn main(args: &Args) -> Result<()> {
     let db = Database::from_cli_args(args)?;
 
-    let conn_options = ConnectionOptions::from_cli_args(args);
+    let conn_options = ConnectionOptions::from_cli_args(args)?;
     let conn = Connection::build(&db, conn_options);
 
+    if args.flags.contains(&"dump-connections") {
+        let dump = dump_overrides(&db, &conn);
+        println!("{}", ::json::stringify_pretty(dump, 2));
+        return Ok(());
+    }
+
+    if args.flags.contains(&"json") {
+        let info = json::info_json(&db, &conn);
+        println!("{}", ::json::stringify_pretty(info, 2));
+        return Ok(());
+    }
+
     db.print_status();
 
     println!();
@@ -31,6 +45,12 @@ pub fn main(args: &Args) -> Result<()> {
     for mat_anim_id in 0..db.mat_anims.len() {
         mat_anim_info(&db, mat_anim_id);
     }
+    for mat_color_anim_id in 0..db.mat_color_anims.len() {
+        mat_color_anim_info(&db, mat_color_anim_id);
+    }
+    for vis_anim_id in 0..db.vis_anims.len() {
+        vis_anim_info(&db, vis_anim_id);
+    }
 
     Ok(())
 }
@@ -46,12 +66,14 @@ fn model_info(db: &Database, conn: &Connection, model_id: usize) {
     println!("  Objects ({} total):", model.objects.len());
     for (i, object) in model.objects.iter().enumerate() {
         print!("    Object {}: {:?} ", i, object.name);
-        println!("({}{}{})",
+        println!("({}{}{}){}",
             object.trans.map(|_| "T").unwrap_or("-"),
             object.rot.map(|_| "R").unwrap_or("-"),
             object.scale.map(|_| "S").unwrap_or("-"),
+            if object.visible { "" } else { " (hidden)" },
         );
     }
+    println!("  Texture Matrix Mode: {:?}", model.tex_mtx_mode);
     println!("  Materials ({} total):", model.materials.len());
     for (i, material) in model.materials.iter().enumerate() {
         println!("    Material {}:", i);
@@ -77,7 +99,7 @@ fn model_info(db: &Database, conn: &Connection, model_id: usize) {
             match conn.models[model_id].materials[i] {
                 MaterialConnection::NoTexture =>
                     print!("(palette but no texture!?)"),
-                MaterialConnection::TextureMissing { .. } =>
+                MaterialConnection::TextureMissing =>
                     print!("(skipped; texture missing)"),
                 MaterialConnection::TextureOkNoPalette { .. } =>
                     unreachable!(),
@@ -101,6 +123,9 @@ fn model_info(db: &Database, conn: &Connection, model_id: usize) {
         println!("      Repeat (s,t): ({}, {})", params.repeat_s(), params.repeat_t());
         println!("      Mirror (s,t): ({}, {})", params.mirror_s(), params.mirror_t());
         println!("      Texcoord Transform Mode: {}", params.texcoord_transform_mode());
+        let srt = &material.texture_srt;
+        println!("      Texture SRT: scale {:?}, rotation {}, translation {:?}",
+            srt.scale, srt.rotation, srt.translation);
 
         println!("      Diffuse Color: {:?}", material.diffuse);
         println!("      Diffuse is Default Vertex Color: {}", material.diffuse_is_default_vertex_color);
@@ -119,6 +144,12 @@ fn model_info(db: &Database, conn: &Connection, model_id: usize) {
             }
         );
     }
+    let mdl_conn = &conn.models[model_id];
+    println!("  Animations ({} total, most likely first):", mdl_conn.animations.len());
+    for (&anim_id, &score) in mdl_conn.animations.iter().zip(mdl_conn.animation_scores.iter()) {
+        println!("    Animation {}: {:?} (confidence {:.2})",
+            anim_id, db.animations[anim_id].name, score);
+    }
     println!();
 }
 
@@ -220,3 +251,29 @@ fn mat_anim_info(db: &Database, mat_anim_id: usize) {
     }
     println!();
 }
+
+fn mat_color_anim_info(db: &Database, mat_color_anim_id: usize) {
+    let mat_color_anim = &db.mat_color_anims[mat_color_anim_id];
+    println!("Material Color Animation {}:", mat_color_anim_id);
+    println!("  Name: {:?}", mat_color_anim.name);
+    println!("  Found In: {}",
+        db.file_paths[db.mat_color_anims_found_in[mat_color_anim_id]].to_string_lossy());
+    println!("  Frames: {}", mat_color_anim.num_frames);
+    println!("  Tracks ({} total):", mat_color_anim.tracks.len());
+    for (i, track) in mat_color_anim.tracks.iter().enumerate() {
+        println!("    Track {}:", i);
+        println!("      Name: {}", track.name);
+    }
+    println!();
+}
+
+fn vis_anim_info(db: &Database, vis_anim_id: usize) {
+    let vis_anim = &db.vis_anims[vis_anim_id];
+    println!("Visibility Animation {}:", vis_anim_id);
+    println!("  Name: {:?}", vis_anim.name);
+    println!("  Found In: {}",
+        db.file_paths[db.vis_anims_found_in[vis_anim_id]].to_string_lossy());
+    println!("  Frames: {}", vis_anim.num_frames);
+    println!("  Objects: {}", vis_anim.num_objects);
+    println!();
+}
@@ -0,0 +1,120 @@
+//! apicula, NDS model viewer/converter
+//!
+//! This is the library half of apicula. It has the parsers for Nitro files
+//! (`nitro`), decoding and encoding for DS textures (`nds`), the intermediate
+//! vertex data used for drawing and exporting models (`primitives`), the
+//! COLLADA, glTF, and OBJ writers (`convert`), and glTF/COLLADA import
+//! (`import`).
+//!
+//! The model viewer is only built with the `viewer` feature (on by default),
+//! since it pulls in glium. Turn default features off if you only need the
+//! parser.
+//!
+//! ```no_run
+//! use apicula::Cur;
+//! use apicula::nitro::read_container;
+//! use apicula::nds::decode_texture;
+//!
+//! let buf = std::fs::read("model.nsbmd").unwrap();
+//! let cont = read_container(Cur::new(&buf)).unwrap();
+//! for model in &cont.models {
+//!     println!("model {} has {} materials", model.name, model.materials.len());
+//! }
+//! for texture in &cont.textures {
+//!     if !texture.params.format().desc().requires_palette {
+//!         let rgba = decode_texture(texture, None).unwrap();
+//!         println!("texture {} is {} bytes of RGBA", texture.name, rgba.0.len());
+//!     }
+//! }
+//! ```
+//!
+//! NSBMD and NSBTX containers can be written back out with
+//! `nitro::write_container`, eg. after editing a model's materials.
+//!
+//! To resolve which textures, palettes, and animations go with which models
+//! across many files the way the `apicula` command does, load them into a
+//! `db::Database` and build a `connection::Connection`.
+
+#![recursion_limit="128"]
+
+#[macro_use]
+extern crate log;
+#[cfg(feature = "viewer")]
+#[macro_use]
+extern crate glium;
+#[macro_use]
+extern crate json;
+
+#[macro_use]
+pub mod errors;
+#[macro_use]
+mod util;
+pub mod nitro;
+pub mod nds;
+pub mod db;
+pub mod connection;
+pub mod primitives;
+pub mod convert;
+pub mod import;
+mod cli;
+mod decompress;
+mod extract;
+#[cfg(feature = "viewer")]
+mod viewer;
+mod info;
+mod encode;
+mod textures;
+mod render;
+mod select;
+mod skeleton;
+mod logger;
+mod version;
+#[cfg(test)]
+mod test_util;
+
+pub use crate::util::cur::Cur;
+
+use crate::errors::Result;
+
+/// Runs the `apicula` command with the process's arguments. Returns the exit
+/// code.
+#[doc(hidden)]
+pub fn cli_main() -> i32 {
+    match main2() {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("{}", e);
+            1
+        }
+    }
+}
+
+fn main2() -> Result<()> {
+    init_logger(0);
+    let args = cli::parse_cli_args();
+    match args.subcommand {
+        "extract" => extract::main(&args)?,
+        #[cfg(feature = "viewer")]
+        "view" => viewer::main(&args)?,
+        #[cfg(not(feature = "viewer"))]
+        "view" => bail!("this apicula was built without the viewer (enable the viewer feature)"),
+        "convert" => convert::main(&args)?,
+        "info" => info::main(&args)?,
+        "render" => render::main(&args)?,
+        "import" => import::main(&args)?,
+        "encode-texture" => encode::main(&args)?,
+        "textures" => textures::main(&args)?,
+        cmd => bail!("unknown subcommand: {}", cmd),
+    }
+    Ok(())
+}
+
+fn init_logger(verbosity: u64) {
+    use log::Level;
+    let max_log_level = match verbosity {
+        0 => Level::Info,
+        1 => Level::Debug,
+        _ => Level::Trace,
+    };
+    logger::init(max_log_level);
+}
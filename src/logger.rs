@@ -23,10 +23,12 @@ impl Log for Logger {
             true => ColorChoice::Auto,
             false => ColorChoice::Never,
         };
-        let mut stderr = StandardStream::stderr(color_choice);
+        // Lock so lines from different threads don't get mixed up
+        let stderr = StandardStream::stderr(color_choice);
+        let mut stderr = stderr.lock();
         let _ = stderr.set_color(ColorSpec::new().set_fg(Some(Color::Green)));
         let _ = writeln!(&mut stderr, "[{}] {}",
-            record.level().to_string(),
+            record.level(),
             record.args(),
         );
         let _ = stderr.reset();
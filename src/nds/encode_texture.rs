@@ -0,0 +1,497 @@
+//! Encoding RGBA images into NDS textures; the inverse of `decode_texture`.
+//!
+//! Paletted formats are quantized with median cut. Block-compressed textures
+//! pick the palette mode for each 4x4 block that fits its texels best,
+//! preferring the modes that use less palette space when they're nearly as
+//! good, and share identical palette entries between blocks.
+
+use std::collections::HashMap;
+use crate::errors::Result;
+use super::{TextureFormat, TextureParams};
+
+/// A texture in its NDS format, with its palette.
+pub struct EncodedTexture {
+    /// Texture params for the dimensions and format. The offset is zero and
+    /// the repeat/mirror bits are unset.
+    pub params: TextureParams,
+    pub data1: Vec<u8>,
+    /// Only used by block-compressed textures.
+    pub data2: Vec<u8>,
+    /// RGB555 palette colors. Empty for direct color textures.
+    pub palette: Vec<u8>,
+}
+
+/// Color with 5-bit channels.
+type Rgb5 = [u8; 3];
+
+/// Texels with less alpha than this are transparent in formats that only
+/// have a transparent/opaque bit.
+const ALPHA_CUTOFF: u8 = 128;
+
+/// Encodes RGBA8888 pixels in the given format. The dimensions must be
+/// powers of two between 8 and 1024.
+pub fn encode_texture(rgba: &[u8], (width, height): (u32, u32), format: TextureFormat) -> Result<EncodedTexture> {
+    let valid_dim = |x: u32| x.is_power_of_two() && (8..=1024).contains(&x);
+    if !valid_dim(width) || !valid_dim(height) {
+        bail!("texture dimensions must be powers of two between 8 and 1024, got {}x{}",
+            width, height);
+    }
+    if rgba.len() != 4 * (width * height) as usize {
+        bail!("expected {} bytes of RGBA data, got {}", 4 * width * height, rgba.len());
+    }
+
+    let pixels = rgba.chunks(4)
+        .map(|p| [p[0], p[1], p[2], p[3]])
+        .collect::<Vec<[u8; 4]>>();
+
+    let mut params =
+        (width.trailing_zeros() - 3) << 20 |
+        (height.trailing_zeros() - 3) << 23 |
+        (format.0 as u32) << 26;
+
+    let (data1, data2, palette) = match format.0 {
+        1 => encode_translucent(&pixels, 5),
+        2..=4 => {
+            let bpp = format.desc().bpp as u32;
+            let (data, palette, color0_is_transparent) = encode_paletted(&pixels, bpp);
+            if color0_is_transparent {
+                params |= 1 << 29;
+            }
+            (data, vec![], palette)
+        }
+        5 => encode_compressed(&pixels, width as usize, height as usize)?,
+        6 => encode_translucent(&pixels, 3),
+        7 => (encode_direct(&pixels), vec![], vec![]),
+        _ => bail!("can't encode texture format {}", format.0),
+    };
+
+    // Give the plain paletted formats their full color count, so the
+    // palette works with anything that expects one the usual size
+    let num_colors = match format.0 {
+        1 => 32,
+        2 => 4,
+        3 => 16,
+        4 => 256,
+        6 => 8,
+        _ => palette.len(),
+    };
+
+    Ok(EncodedTexture {
+        params: TextureParams(params),
+        data1,
+        data2,
+        palette: palette_bytes(&palette, num_colors),
+    })
+}
+
+/// A3I5 (index_bits = 5) or A5I3 (index_bits = 3).
+fn encode_translucent(pixels: &[[u8; 4]], index_bits: u32) -> (Vec<u8>, Vec<u8>, Vec<Rgb5>) {
+    let alpha_bits = 8 - index_bits;
+    let alpha_max = (1 << alpha_bits) - 1;
+
+    // Colors of invisible texels don't matter
+    let hist = histogram(pixels.iter().filter(|p| p[3] != 0));
+    let palette = median_cut(&hist, 1 << index_bits);
+
+    let data = pixels.iter()
+        .map(|p| {
+            let idx = nearest(&palette, to_rgb5(*p)) as u8;
+            let a = ((p[3] as u32 * alpha_max + 127) / 255) as u8;
+            idx | a << index_bits
+        })
+        .collect();
+
+    (data, vec![], palette)
+}
+
+/// 4-, 16-, or 256-color paletted. Returns whether color 0 is used for
+/// transparent texels.
+fn encode_paletted(pixels: &[[u8; 4]], bpp: u32) -> (Vec<u8>, Vec<Rgb5>, bool) {
+    let num_colors = 1 << bpp;
+    let has_transparency = pixels.iter().any(|p| p[3] < ALPHA_CUTOFF);
+
+    let hist = histogram(pixels.iter().filter(|p| p[3] >= ALPHA_CUTOFF));
+    let (palette, first) = if has_transparency {
+        let mut palette = vec![[0, 0, 0]];
+        palette.extend(median_cut(&hist, num_colors - 1));
+        (palette, 1)
+    } else {
+        (median_cut(&hist, num_colors), 0)
+    };
+
+    let indices = pixels.iter()
+        .map(|p| {
+            if p[3] < ALPHA_CUTOFF {
+                0
+            } else {
+                (first + nearest(&palette[first..], to_rgb5(*p))) as u8
+            }
+        })
+        .collect::<Vec<u8>>();
+
+    let per_byte = (8 / bpp) as usize;
+    let data = indices.chunks(per_byte)
+        .map(|chunk| {
+            chunk.iter().enumerate()
+                .fold(0, |byte, (i, &idx)| byte | idx << (i as u32 * bpp))
+        })
+        .collect();
+
+    (data, palette, has_transparency)
+}
+
+fn encode_direct(pixels: &[[u8; 4]]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(2 * pixels.len());
+    for &p in pixels {
+        let alpha_bit = (p[3] >= ALPHA_CUTOFF) as u16;
+        let x = to_rgb555(to_rgb5(p)) | alpha_bit << 15;
+        data.extend_from_slice(&x.to_le_bytes());
+    }
+    data
+}
+
+/// Block-compressed (4x4 texels).
+///
+/// Each block has 2 bits per texel in data1 and a u16 in data2 with its
+/// palette mode and the address of its colors in the palette:
+///
+///   mode 0: 3 colors + transparent
+///   mode 1: 2 colors, their average, and transparent
+///   mode 2: 4 colors
+///   mode 3: 2 colors and two mixes of them (3:5 and 5:3)
+fn encode_compressed(pixels: &[[u8; 4]], width: usize, height: usize) -> Result<(Vec<u8>, Vec<u8>, Vec<Rgb5>)> {
+    let mut data1 = Vec::with_capacity(width * height / 4);
+    let mut data2 = Vec::with_capacity(width * height / 8);
+    let mut palette: Vec<Rgb5> = vec![];
+    let mut palette_entries: HashMap<Vec<Rgb5>, usize> = HashMap::new();
+
+    for by in 0..height / 4 {
+        for bx in 0..width / 4 {
+            let mut block = [[0; 4]; 16];
+            for (i, texel) in block.iter_mut().enumerate() {
+                let (x, y) = (4 * bx + i % 4, 4 * by + i / 4);
+                *texel = pixels[y * width + x];
+            }
+
+            let (mode, colors, texels) = encode_block(&block);
+
+            // Colors are addressed in pairs
+            let mut entry = colors;
+            if entry.len() % 2 != 0 {
+                entry.push([0, 0, 0]);
+            }
+            let addr = match palette_entries.get(&entry) {
+                Some(&addr) => addr,
+                None => {
+                    let addr = palette.len() / 2;
+                    palette.extend_from_slice(&entry);
+                    palette_entries.insert(entry, addr);
+                    addr
+                }
+            };
+            if addr >= 1 << 14 {
+                bail!("block-compressed texture needs too many palette colors; \
+                    try a smaller image or a different format");
+            }
+
+            let bits = texels.iter().enumerate()
+                .fold(0u32, |bits, (i, &t)| bits | (t as u32) << (2 * i));
+            data1.extend_from_slice(&bits.to_le_bytes());
+            let extra = addr as u16 | (mode as u16) << 14;
+            data2.extend_from_slice(&extra.to_le_bytes());
+        }
+    }
+
+    Ok((data1, data2, palette))
+}
+
+/// Picks the palette mode, colors, and texel indices for one 4x4 block.
+fn encode_block(block: &[[u8; 4]; 16]) -> (u8, Vec<Rgb5>, [u8; 16]) {
+    let opaque = block.iter()
+        .filter(|p| p[3] >= ALPHA_CUTOFF)
+        .map(|&p| to_rgb5(p))
+        .collect::<Vec<Rgb5>>();
+    let has_transparency = opaque.len() != 16;
+
+    if opaque.is_empty() {
+        return (1, vec![[0, 0, 0], [0, 0, 0]], [3; 16]);
+    }
+
+    // The two-color modes are allowed this much more squared error (in 8-bit
+    // units) per texel, since they take half the palette space.
+    let tolerance = 8 * opaque.len() as u32;
+
+    let hist = histogram_rgb5(&opaque);
+    let (c0, c1) = best_endpoints(&opaque, has_transparency);
+
+    let (mode, colors) = if has_transparency {
+        let two = vec![c0, c1];
+        let three = pad_colors(median_cut(&hist, 3), 3);
+        let e_two = block_error(&opaque, &decoded_colors(1, &two));
+        let e_three = block_error(&opaque, &decoded_colors(0, &three));
+        if e_two <= e_three + tolerance { (1, two) } else { (0, three) }
+    } else {
+        let two = vec![c0, c1];
+        let four = pad_colors(median_cut(&hist, 4), 4);
+        let e_two = block_error(&opaque, &decoded_colors(3, &two));
+        let e_four = block_error(&opaque, &decoded_colors(2, &four));
+        if e_two <= e_four + tolerance { (3, two) } else { (2, four) }
+    };
+
+    let candidates = decoded_colors(mode, &colors);
+    let mut texels = [3; 16];
+    for (t, p) in texels.iter_mut().zip(block.iter()) {
+        if p[3] >= ALPHA_CUTOFF {
+            *t = nearest_decoded(&candidates, expand(to_rgb5(*p))) as u8;
+        }
+    }
+
+    (mode, colors, texels)
+}
+
+/// Chooses the two colors for the interpolating modes (1 and 3). Tries the
+/// pair of colors farthest apart and the two-color median cut, and keeps
+/// whichever fits better.
+fn best_endpoints(colors: &[Rgb5], has_transparency: bool) -> (Rgb5, Rgb5) {
+    let mode = if has_transparency { 1 } else { 3 };
+
+    let mut farthest = (colors[0], colors[0]);
+    let mut max_dist = 0;
+    for (i, &a) in colors.iter().enumerate() {
+        for &b in &colors[i + 1..] {
+            let d = dist(expand(a), expand(b));
+            if d > max_dist {
+                max_dist = d;
+                farthest = (a, b);
+            }
+        }
+    }
+
+    let split = pad_colors(median_cut(&histogram_rgb5(colors), 2), 2);
+    let split = (split[0], split[1]);
+
+    let error = |(a, b): (Rgb5, Rgb5)| block_error(colors, &decoded_colors(mode, &[a, b]));
+    if error(split) < error(farthest) { split } else { farthest }
+}
+
+/// The colors (8-bit, as the DS would draw them) that the texel indices
+/// 0..3 select in a block with the given mode and palette colors. Transparent
+/// texels are left out.
+fn decoded_colors(mode: u8, colors: &[Rgb5]) -> Vec<[u32; 3]> {
+    let c = |i: usize| expand(colors[i]);
+    let mix = |a: [u32; 3], b: [u32; 3], wa: u32, wb: u32| {
+        [
+            (wa * a[0] + wb * b[0]) / (wa + wb),
+            (wa * a[1] + wb * b[1]) / (wa + wb),
+            (wa * a[2] + wb * b[2]) / (wa + wb),
+        ]
+    };
+    match mode {
+        0 => vec![c(0), c(1), c(2)],
+        1 => vec![c(0), c(1), mix(c(0), c(1), 1, 1)],
+        2 => vec![c(0), c(1), c(2), c(3)],
+        3 => vec![c(0), c(1), mix(c(1), c(0), 3, 5), mix(c(0), c(1), 3, 5)],
+        _ => unreachable!(),
+    }
+}
+
+fn block_error(colors: &[Rgb5], candidates: &[[u32; 3]]) -> u32 {
+    colors.iter()
+        .map(|&c| {
+            let c = expand(c);
+            candidates.iter().map(|&d| dist(c, d)).min().unwrap()
+        })
+        .sum()
+}
+
+fn pad_colors(mut colors: Vec<Rgb5>, n: usize) -> Vec<Rgb5> {
+    let last = colors.last().cloned().unwrap_or([0, 0, 0]);
+    colors.resize(n, last);
+    colors
+}
+
+fn histogram<'a>(pixels: impl Iterator<Item=&'a [u8; 4]>) -> Vec<(Rgb5, u32)> {
+    let colors = pixels.map(|&p| to_rgb5(p)).collect::<Vec<_>>();
+    histogram_rgb5(&colors)
+}
+
+/// Distinct colors with their counts, in a deterministic order.
+fn histogram_rgb5(colors: &[Rgb5]) -> Vec<(Rgb5, u32)> {
+    let mut counts: HashMap<Rgb5, u32> = HashMap::new();
+    for &c in colors {
+        *counts.entry(c).or_insert(0) += 1;
+    }
+    let mut hist = counts.into_iter().collect::<Vec<_>>();
+    hist.sort();
+    hist
+}
+
+/// Picks at most n colors to represent the histogram. Returns the colors
+/// exactly if there are few enough.
+fn median_cut(hist: &[(Rgb5, u32)], n: usize) -> Vec<Rgb5> {
+    if hist.len() <= n {
+        return hist.iter().map(|x| x.0).collect();
+    }
+
+    let range = |b: &[(Rgb5, u32)], ch: usize| {
+        let min = b.iter().map(|x| x.0[ch]).min().unwrap();
+        let max = b.iter().map(|x| x.0[ch]).max().unwrap();
+        max - min
+    };
+
+    let mut boxes = vec![hist.to_vec()];
+    while boxes.len() < n {
+        // Split the box with the widest channel range
+        let widest = boxes.iter().enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .max_by_key(|(_, b)| (0..3).map(|ch| range(b, ch)).max().unwrap());
+        let idx = match widest {
+            Some((idx, _)) => idx,
+            None => break,
+        };
+        let mut b = boxes.swap_remove(idx);
+        let ch = (0..3).max_by_key(|&ch| range(&b, ch)).unwrap();
+        b.sort_by_key(|x| (x.0[ch], x.0));
+
+        // Weighted median, leaving at least one color on each side
+        let total = b.iter().map(|x| x.1).sum::<u32>();
+        let mut acc = 0;
+        let mut split = 1;
+        for (i, x) in b.iter().enumerate() {
+            acc += x.1;
+            if 2 * acc >= total {
+                split = (i + 1).clamp(1, b.len() - 1);
+                break;
+            }
+        }
+        let rest = b.split_off(split);
+        boxes.push(b);
+        boxes.push(rest);
+    }
+
+    boxes.iter()
+        .map(|b| {
+            let total = b.iter().map(|x| x.1 as u64).sum::<u64>();
+            let avg = |ch: usize| {
+                let sum = b.iter().map(|x| x.0[ch] as u64 * x.1 as u64).sum::<u64>();
+                ((sum + total / 2) / total) as u8
+            };
+            [avg(0), avg(1), avg(2)]
+        })
+        .collect()
+}
+
+fn nearest(palette: &[Rgb5], c: Rgb5) -> usize {
+    let candidates = palette.iter().map(|&p| expand(p)).collect::<Vec<_>>();
+    nearest_decoded(&candidates, expand(c))
+}
+
+fn nearest_decoded(candidates: &[[u32; 3]], c: [u32; 3]) -> usize {
+    (0..candidates.len())
+        .min_by_key(|&i| dist(candidates[i], c))
+        .unwrap_or(0)
+}
+
+fn dist(a: [u32; 3], b: [u32; 3]) -> u32 {
+    let d = |i: usize| (a[i] as i32 - b[i] as i32).pow(2) as u32;
+    d(0) + d(1) + d(2)
+}
+
+fn to_rgb5(p: [u8; 4]) -> Rgb5 {
+    let f = |x: u8| ((x as u32 * 31 + 127) / 255) as u8;
+    [f(p[0]), f(p[1]), f(p[2])]
+}
+
+fn to_rgb555(c: Rgb5) -> u16 {
+    c[0] as u16 | (c[1] as u16) << 5 | (c[2] as u16) << 10
+}
+
+/// To 8-bit, the same way the decoder does.
+fn expand(c: Rgb5) -> [u32; 3] {
+    let f = |x: u8| ((x << 3) | (x >> 2)) as u32;
+    [f(c[0]), f(c[1]), f(c[2])]
+}
+
+/// Palette data for at least num_colors colors (unused ones are black),
+/// padded to a multiple of 16 bytes so palettes placed one after another stay
+/// aligned.
+fn palette_bytes(palette: &[Rgb5], num_colors: usize) -> Vec<u8> {
+    let mut bytes = vec![];
+    for &c in palette {
+        bytes.extend_from_slice(&to_rgb555(c).to_le_bytes());
+    }
+    while bytes.len() < 2 * num_colors || bytes.len() % 16 != 0 {
+        bytes.push(0);
+    }
+    bytes
+}
+
+#[test]
+fn test_encode_decode() {
+    use std::sync::Arc;
+    use crate::nds::decode_texture;
+    use crate::nitro::{Name, Palette, Texture};
+
+    // A gradient with a transparent corner and a few translucent texels
+    let (w, h) = (16, 8);
+    let mut rgba = vec![];
+    for y in 0..h {
+        for x in 0..w {
+            let a = if x < 4 && y < 4 { 0 } else if x == 15 { 100 } else { 255 };
+            rgba.extend_from_slice(&[(x * 16) as u8, (y * 32) as u8, 200, a]);
+        }
+    }
+
+    for format in 1..8 {
+        let enc = encode_texture(&rgba, (w, h), TextureFormat(format)).unwrap();
+        let tex = Texture {
+            name: Name::from_str_truncated("tex"),
+            params: enc.params,
+            data1: enc.data1.into(),
+            data2: enc.data2.into(),
+            unknown: 0,
+        };
+        let pal = Palette {
+            name: Name::from_str_truncated("tex_pl"),
+            off: 0,
+            pal_block: Arc::new(enc.palette.into_boxed_slice()),
+            unknown: 0,
+        };
+        assert_eq!(tex.params.dim(), (w, h));
+        assert_eq!(tex.params.format().0, format);
+        let full_palette_len = match format {
+            1 => 64,
+            2 => 16,
+            3 => 32,
+            4 => 512,
+            6 => 16,
+            _ => 0,
+        };
+        assert!(pal.pal_block.len() >= full_palette_len);
+        let decoded = decode_texture(&tex, Some(&pal)).unwrap().0;
+
+        // Allowed mean color error per channel
+        let tolerance = match format {
+            2 => 28.0,
+            6 => 18.0,
+            3 => 14.0,
+            1 | 5 => 10.0,
+            _ => 4.0,
+        };
+        let mut total_error = 0;
+        let mut num_opaque = 0;
+        for (p, q) in rgba.chunks(4).zip(decoded.chunks(4)) {
+            if format == 1 || format == 6 {
+                assert!((p[3] as i32 - q[3] as i32).abs() <= 40);
+            } else {
+                assert_eq!(p[3] < ALPHA_CUTOFF, q[3] < ALPHA_CUTOFF);
+            }
+            if p[3] >= ALPHA_CUTOFF {
+                total_error += (0..3).map(|ch| (p[ch] as i32 - q[ch] as i32).abs()).sum::<i32>();
+                num_opaque += 3;
+            }
+        }
+        let mean_error = total_error as f64 / num_opaque as f64;
+        assert!(mean_error <= tolerance, "format {}: mean error {}", format, mean_error);
+    }
+}
@@ -0,0 +1,88 @@
+//! File Name Tables.
+//!
+//! An FNT gives names to the files in a flat array of files (the File
+//! Allocation Table, FAT) by arranging them in a directory tree. It is used
+//! both for the filesystem of an NDS ROM and for NARC archives.
+//!
+//! See: <http://problemkaputt.de/gbatek.htm#dscartridgenitroromandnitroarcfilesystems>
+
+use crate::errors::Result;
+use crate::util::cur::Cur;
+
+/// Reads the FNT at `fnt`, returning the path of every file ID (less than
+/// `num_files`) it names, eg. "data/pokemon/p001.nsbmd".
+pub fn read_fnt(fnt: Cur, num_files: usize) -> Result<Vec<Option<String>>> {
+    let mut paths: Vec<Option<String>> = vec![None; num_files];
+    let (_, _, num_dirs) = fnt.nth::<(u32, u16, u16)>(0)?;
+    let mut visited = vec![false; num_dirs as usize];
+    read_dir(fnt, 0, String::new(), &mut visited, &mut paths)?;
+    Ok(paths)
+}
+
+/// Records the paths of every file under the directory `dir_idx` (and its
+/// subdirectories) in `paths`.
+fn read_dir(
+    fnt: Cur,
+    dir_idx: u16,
+    dir_path: String,
+    visited: &mut [bool],
+    paths: &mut [Option<String>],
+) -> Result<()> {
+    if dir_idx as usize >= visited.len() || visited[dir_idx as usize] {
+        bail!("bad directory in file name table");
+    }
+    visited[dir_idx as usize] = true;
+
+    let (subtable_off, first_file_id, _parent) =
+        fnt.nth::<(u32, u16, u16)>(dir_idx as usize)?;
+
+    let mut cur = fnt + subtable_off;
+    let mut file_id = first_file_id as usize;
+    loop {
+        let ty_len = cur.next::<u8>()?;
+        if ty_len == 0 {
+            // End of subtable
+            return Ok(());
+        }
+        let is_dir = ty_len & 0x80 != 0;
+        let name_len = (ty_len & 0x7f) as usize;
+        let name = sanitize_name(cur.next_n_u8s(name_len)?);
+        let path = format!("{}{}", dir_path, name);
+
+        if is_dir {
+            let sub_dir_id = cur.next::<u16>()?;
+            check!(sub_dir_id & 0xf000 == 0xf000)?;
+            read_dir(fnt, sub_dir_id & 0xfff, path + "/", visited, paths)?;
+        } else {
+            if file_id < paths.len() {
+                paths[file_id] = Some(path);
+            }
+            file_id += 1;
+        }
+    }
+}
+
+/// Make an FNT entry name safe to use as a path component.
+fn sanitize_name(name: &[u8]) -> String {
+    let name = name.iter()
+        .map(|&b| {
+            let c = b as char;
+            if c.is_ascii_graphic() && c != '/' && c != '\\' && c != ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    match name.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => name,
+    }
+}
+
+#[test]
+fn test_sanitize_name() {
+    assert_eq!(sanitize_name(b"p001.nsbmd"), "p001.nsbmd");
+    assert_eq!(sanitize_name(b"a/b c"), "a_b_c");
+    assert_eq!(sanitize_name(b".."), "_");
+}
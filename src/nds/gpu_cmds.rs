@@ -13,6 +13,7 @@ use crate::errors::Result;
 use crate::util::bits::BitField;
 use crate::util::fixed::fix16;
 use crate::util::fixed::fix32;
+use crate::util::fixed::{to_fix16, to_fix32};
 use crate::util::view::View;
 
 /// DS GPU command.
@@ -286,3 +287,128 @@ fn num_params(opcode: u8) -> Result<usize> {
     }
     Ok(SIZES[opcode] as usize)
 }
+
+/// Packs GPU commands into their memory representation; the inverse of
+/// `CmdParser`.
+#[derive(Default)]
+pub struct CmdWriter {
+    buf: Vec<u8>,
+    /// Commands waiting to fill out a group of four opcodes.
+    pending: Vec<(u8, Vec<u32>)>,
+}
+
+impl CmdWriter {
+    pub fn new() -> CmdWriter {
+        CmdWriter { buf: vec![], pending: vec![] }
+    }
+
+    fn cmd(&mut self, opcode: u8, params: Vec<u32>) {
+        debug_assert_eq!(num_params(opcode).ok(), Some(params.len()));
+        self.pending.push((opcode, params));
+        if self.pending.len() == 4 {
+            self.flush();
+        }
+    }
+
+    /// Writes out the pending commands, padding with NOPs.
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        for i in 0..4 {
+            let opcode = self.pending.get(i).map(|x| x.0).unwrap_or(0x00);
+            self.buf.push(opcode);
+        }
+        for (_, params) in self.pending.drain(..) {
+            for p in params {
+                self.buf.extend_from_slice(&p.to_le_bytes());
+            }
+        }
+    }
+
+    pub fn restore(&mut self, idx: u32) {
+        self.cmd(0x14, vec![idx & 31]);
+    }
+
+    pub fn scale(&mut self, (sx, sy, sz): (f64, f64, f64)) {
+        self.cmd(0x1b, vec![
+            to_fix32(sx, 1, 19, 12),
+            to_fix32(sy, 1, 19, 12),
+            to_fix32(sz, 1, 19, 12),
+        ]);
+    }
+
+    pub fn begin(&mut self, prim_type: u32) {
+        self.cmd(0x40, vec![prim_type & 3]);
+    }
+
+    pub fn end(&mut self) {
+        self.cmd(0x41, vec![]);
+    }
+
+    /// Sends a vertex with VTX_16. Coordinates must be in (-8, 8).
+    pub fn vertex(&mut self, position: Point3<f64>) {
+        let x = to_fix16(position.x, 1, 3, 12) as u32;
+        let y = to_fix16(position.y, 1, 3, 12) as u32;
+        let z = to_fix16(position.z, 1, 3, 12) as u32;
+        self.cmd(0x23, vec![x | y << 16, z]);
+    }
+
+    /// Sets the texcoord, in texels.
+    pub fn texcoord(&mut self, texcoord: Point2<f64>) {
+        let s = to_fix16(texcoord.x, 1, 11, 4) as u32;
+        let t = to_fix16(texcoord.y, 1, 11, 4) as u32;
+        self.cmd(0x22, vec![s | t << 16]);
+    }
+
+    pub fn color(&mut self, color: Point3<f32>) {
+        let c = |x: f32| (x.clamp(0.0, 1.0) * 31.0).round() as u32;
+        self.cmd(0x20, vec![c(color.x) | c(color.y) << 5 | c(color.z) << 10]);
+    }
+
+    /// Sets the normal. It should be normalized.
+    pub fn normal(&mut self, normal: Vector3<f64>) {
+        let x = to_fix32(normal.x, 1, 0, 9);
+        let y = to_fix32(normal.y, 1, 0, 9);
+        let z = to_fix32(normal.z, 1, 0, 9);
+        self.cmd(0x21, vec![x | y << 10 | z << 20]);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.flush();
+        self.buf
+    }
+}
+
+#[test]
+fn test_cmd_writer() {
+    let mut w = CmdWriter::new();
+    w.restore(3);
+    w.begin(0);
+    w.texcoord(Point2::new(16.0, -2.5));
+    w.normal(vec3(0.0, -1.0, 0.5));
+    w.color(Point3::new(1.0, 0.0, 0.5));
+    w.vertex(Point3::new(1.5, -7.0, 0.25));
+    w.end();
+    let buf = w.finish();
+    assert_eq!(buf.len() % 4, 0);
+
+    let cmds = CmdParser::new(&buf).collect::<Result<Vec<_>>>().unwrap();
+    assert_eq!(cmds.len(), 8); // including one NOP for padding
+    match cmds[0] { GpuCmd::Restore { idx } => assert_eq!(idx, 3), _ => panic!() }
+    match cmds[1] { GpuCmd::Begin { prim_type } => assert_eq!(prim_type, 0), _ => panic!() }
+    match cmds[2] {
+        GpuCmd::TexCoord { texcoord } => assert_eq!(texcoord, Point2::new(16.0, -2.5)),
+        _ => panic!(),
+    }
+    match cmds[3] {
+        GpuCmd::Normal { normal } => assert_eq!(normal, vec3(0.0, -1.0, 0.5)),
+        _ => panic!(),
+    }
+    match cmds[5] {
+        GpuCmd::Vertex { position } => assert_eq!(position, Point3::new(1.5, -7.0, 0.25)),
+        _ => panic!(),
+    }
+    match cmds[6] { GpuCmd::End => (), _ => panic!() }
+    match cmds[7] { GpuCmd::Nop => (), _ => panic!() }
+}
@@ -4,7 +4,10 @@ pub mod gpu_cmds;
 pub mod texture_formats;
 pub mod texture_params;
 pub mod decode_texture;
+pub mod encode_texture;
+pub mod fnt;
 
 pub use self::texture_formats::{TextureFormat, Alpha};
 pub use self::texture_params::TextureParams;
 pub use self::decode_texture::decode_texture;
+pub use self::encode_texture::encode_texture;
@@ -19,6 +19,14 @@ impl TextureFormat {
         &DESCS[self.0 as usize]
     }
 
+    /// Looks up a format by its short name (see `SHORT_NAMES`).
+    pub fn from_short_name(name: &str) -> Option<TextureFormat> {
+        let name = name.to_ascii_lowercase();
+        SHORT_NAMES.iter()
+            .position(|&n| n == name)
+            .map(|i| TextureFormat(i as u8 + 1))
+    }
+
     /// How many bytes a texture of the given size takes up in this format.
     pub fn byte_len(self, (width, height): (u32, u32)) -> usize {
         let bit_len = width * height * self.desc().bpp as u32;
@@ -59,6 +67,11 @@ pub enum AlphaDesc {
     Translucent,
 }
 
+/// Short names for formats 1-7, for the command line.
+pub static SHORT_NAMES: [&str; 7] = [
+    "a3i5", "pal4", "pal16", "pal256", "4x4", "a5i3", "direct",
+];
+
 pub static DESCS: [FormatDesc; 8] = [
     // 0, not really a real texture format
     FormatDesc {
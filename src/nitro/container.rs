@@ -13,14 +13,20 @@
 //! enforce this. We'll read any kind of file we can get our hands on!
 
 use crate::errors::Result;
-use crate::nitro::{Model, Texture, Palette, Animation, Pattern, MaterialAnimation};
+use crate::nitro::{
+    Model, Texture, Palette, Animation, Pattern, MaterialAnimation,
+    MaterialColorAnimation, VisibilityAnimation,
+};
 use crate::nitro::info_block;
 use crate::util::cur::Cur;
+use crate::util::SharedBytes;
 
-const STAMPS: [&[u8]; 5] = [b"BMD0", b"BTX0", b"BCA0", b"BTP0", b"BTA0"];
+/// The first four bytes of the kinds of Nitro files we can read.
+pub const STAMPS: [&[u8]; 7] = [b"BMD0", b"BTX0", b"BCA0", b"BTP0", b"BTA0", b"BMA0", b"BVA0"];
 
 pub struct Container {
     pub stamp: &'static [u8],
+    pub version: u16,
     pub file_size: u32,
     pub models: Vec<Model>,
     pub textures: Vec<Texture>,
@@ -28,9 +34,22 @@ pub struct Container {
     pub animations: Vec<Animation>,
     pub patterns: Vec<Pattern>,
     pub mat_anims: Vec<MaterialAnimation>,
+    pub mat_color_anims: Vec<MaterialColorAnimation>,
+    pub vis_anims: Vec<VisibilityAnimation>,
 }
 
+/// Reads the container at cur. Texture data is copied out of the buffer.
 pub fn read_container(cur: Cur) -> Result<Container> {
+    read_container_in(cur, None)
+}
+
+/// Reads the container at the start of buf. Texture data is shared with buf
+/// instead of copied.
+pub fn read_shared_container(buf: &SharedBytes) -> Result<Container> {
+    read_container_in(Cur::new(buf), Some(buf))
+}
+
+fn read_container_in(cur: Cur, shared: Option<&SharedBytes>) -> Result<Container> {
     fields!(cur, container {
         stamp: [u8; 4],
         bom: u16,
@@ -45,7 +64,7 @@ pub fn read_container(cur: Cur) -> Result<Container> {
         match STAMPS.iter().find(|&s| s == &stamp) {
             Some(x) => x,
             None => bail!("unrecognized Nitro container: expected \
-                the first four bytes to be one of: BMD0, BTX0, BCA0, BTP0, BTA0"),
+                the first four bytes to be one of: BMD0, BTX0, BCA0, BTP0, BTA0, BMA0, BVA0"),
         };
 
     check!(bom == 0xfeff)?;
@@ -53,14 +72,14 @@ pub fn read_container(cur: Cur) -> Result<Container> {
     check!(file_size > 16)?;
 
     let mut cont = Container {
-        stamp, file_size, models: vec![], textures: vec![],
+        stamp, version, file_size, models: vec![], textures: vec![],
         palettes: vec![], animations: vec![], patterns: vec![],
-        mat_anims: vec![],
+        mat_anims: vec![], mat_color_anims: vec![], vis_anims: vec![],
     };
 
     for section_off in section_offs {
         let section_cur = cur + section_off;
-        if let Err(e) = read_section(&mut cont, section_cur) {
+        if let Err(e) = read_section(&mut cont, section_cur, shared) {
             debug!("skipping Nitro section: {}", e);
         }
     }
@@ -68,16 +87,18 @@ pub fn read_container(cur: Cur) -> Result<Container> {
     Ok(cont)
 }
 
-fn read_section(cont: &mut Container, cur: Cur) -> Result<()> {
+fn read_section(cont: &mut Container, cur: Cur, shared: Option<&SharedBytes>) -> Result<()> {
     let stamp = cur.clone().next_n_u8s(4)?;
     match stamp {
         b"MDL0" => add_mdl(cont, cur),
-        b"TEX0" => add_tex(cont, cur),
+        b"TEX0" => add_tex(cont, cur, shared),
         b"JNT0" => add_jnt(cont, cur),
         b"PAT0" => add_pat(cont, cur),
         b"SRT0" => add_srt(cont, cur),
+        b"MAT0" => add_mat(cont, cur),
+        b"VIS0" => add_vis(cont, cur),
         _ => bail!("unrecognized Nitro format: expected the first four \
-            bytes to be one of: MDL0, TEX0, JNT0, PAT0, SRT0"),
+            bytes to be one of: MDL0, TEX0, JNT0, PAT0, SRT0, MAT0, VIS0"),
     }
 }
 
@@ -104,10 +125,10 @@ fn add_mdl(cont: &mut Container, cur: Cur) -> Result<()> {
 }
 
 // This work is already done for us in read_tex; see that module for why.
-fn add_tex(cont: &mut Container, cur: Cur) -> Result<()> {
+fn add_tex(cont: &mut Container, cur: Cur, shared: Option<&SharedBytes>) -> Result<()> {
     use crate::nitro::tex::read_tex;
 
-    let (textures, palettes) = read_tex(cur)?;
+    let (textures, palettes) = read_tex(cur, shared)?;
     cont.textures.extend(textures.into_iter());
     cont.palettes.extend(palettes.into_iter());
     Ok(())
@@ -179,3 +200,47 @@ fn add_srt(cont: &mut Container, cur: Cur) -> Result<()> {
     }
     Ok(())
 }
+
+// A MAT is a container for material color animations.
+fn add_mat(cont: &mut Container, cur: Cur) -> Result<()> {
+    use crate::nitro::material_color_animation::read_mat_color_anim;
+
+    fields!(cur, MAT0 {
+        stamp: [u8; 4],
+        section_size: u32,
+        end: Cur,
+    });
+    check!(stamp == b"MAT0")?;
+
+    for (off, name) in info_block::read::<u32>(end)? {
+        match read_mat_color_anim(cur + off, name) {
+            Ok(mat_color_anim) => cont.mat_color_anims.push(mat_color_anim),
+            Err(e) => {
+                error!("error on material color animation {}: {}", name, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// A VIS is a container for visibility animations.
+fn add_vis(cont: &mut Container, cur: Cur) -> Result<()> {
+    use crate::nitro::visibility_animation::read_vis_anim;
+
+    fields!(cur, VIS0 {
+        stamp: [u8; 4],
+        section_size: u32,
+        end: Cur,
+    });
+    check!(stamp == b"VIS0")?;
+
+    for (off, name) in info_block::read::<u32>(end)? {
+        match read_vis_anim(cur + off, name) {
+            Ok(vis_anim) => cont.vis_anims.push(vis_anim),
+            Err(e) => {
+                error!("error on visibility animation {}: {}", name, e);
+            }
+        }
+    }
+    Ok(())
+}
@@ -10,6 +10,7 @@ use std::fmt::Debug;
 use std::iter::Zip;
 use crate::util::cur::Cur;
 use crate::util::view::{View, Viewable};
+use crate::util::writer::Writer;
 
 pub type Iterator<'a, T> = Zip<View<'a, T>, View<'a, Name>>;
 
@@ -39,3 +40,141 @@ pub fn read<T>(cur: Cur) -> Result<Iterator<T>> where
 
     Ok(data.zip(names))
 }
+
+/// Writes an info block for the given names. The data for each entry is left
+/// zeroed; returns the position of each entry's datum so the caller can fill
+/// them in later (they're usually offsets to things that haven't been written
+/// yet).
+pub fn write(w: &mut Writer, names: &[Name], size_of_datum: usize) -> Result<Vec<usize>> {
+    let count = names.len();
+    if count > 255 {
+        bail!("too many entries for an info block ({}, max is 255)", count);
+    }
+    let nodes = build_tree(names)?;
+
+    let tree_size = 4 + 4 * nodes.len();
+    let data_section_size = 4 + count * size_of_datum;
+    let size = 4 + tree_size + data_section_size + 16 * count;
+    if size > 0xffff {
+        bail!("info block too large");
+    }
+
+    w.u8(0);
+    w.u8(count as u8);
+    w.u16(size as u16);
+
+    w.u16(8);
+    w.u16(tree_size as u16);
+    for node in &nodes {
+        w.bytes(node);
+    }
+
+    w.u16(size_of_datum as u16);
+    w.u16(data_section_size as u16);
+    let data_pos = (0..count)
+        .map(|i| w.pos() + i * size_of_datum)
+        .collect();
+    w.bytes(&vec![0; count * size_of_datum]);
+
+    for name in names {
+        w.bytes(&name.0);
+    }
+
+    Ok(data_pos)
+}
+
+/// The unknown data in an info block is a Patricia tree the runtime uses to
+/// look up entries by name. Each node is (ref bit, left, right, entry index).
+/// Node 0 is the root; there's one other node per entry.
+///
+/// To find a name, start from the root and go to its left child. Then go left
+/// or right depending on bit `ref_bit` of the name until the ref bit stops
+/// decreasing. The node you end on has the entry's index.
+fn build_tree(names: &[Name]) -> Result<Vec<[u8; 4]>> {
+    const REF_BIT: usize = 0;
+    const LEFT: usize = 1;
+    const RIGHT: usize = 2;
+
+    fn bit(name: &Name, i: u8) -> bool {
+        (name.0[i as usize / 8] >> (i % 8)) & 1 != 0
+    }
+
+    let mut nodes = vec![[127, 0, 0, 0]];
+
+    for (idx, name) in names.iter().enumerate() {
+        // Find the entry we'd end up at now. The root acts like it has a name
+        // with all zero bits.
+        let mut p = 0;
+        let mut x = nodes[0][LEFT] as usize;
+        while nodes[p][REF_BIT] > nodes[x][REF_BIT] {
+            p = x;
+            let side = if bit(name, nodes[x][REF_BIT]) { RIGHT } else { LEFT };
+            x = nodes[x][side] as usize;
+        }
+        let found = if x == 0 { Name([0; 16]) } else { names[nodes[x][3] as usize] };
+
+        // The new node tests the highest bit where we differ from it.
+        let diff_bit = match (0..127).rev().find(|&i| bit(name, i) != bit(&found, i)) {
+            Some(i) => i,
+            None => bail!("can't write info block: duplicate name {}", name),
+        };
+
+        // Insert the new node above the first node with a lower ref bit.
+        let mut p = 0;
+        let mut x = nodes[0][LEFT] as usize;
+        while nodes[p][REF_BIT] > nodes[x][REF_BIT] && nodes[x][REF_BIT] > diff_bit {
+            p = x;
+            let side = if bit(name, nodes[x][REF_BIT]) { RIGHT } else { LEFT };
+            x = nodes[x][side] as usize;
+        }
+        let n = nodes.len() as u8;
+        let x = x as u8;
+        if bit(name, diff_bit) {
+            nodes.push([diff_bit, x, n, idx as u8]);
+        } else {
+            nodes.push([diff_bit, n, x, idx as u8]);
+        }
+        if p == 0 || !bit(name, nodes[p][REF_BIT]) {
+            nodes[p][LEFT] = n;
+        } else {
+            nodes[p][RIGHT] = n;
+        }
+    }
+
+    Ok(nodes)
+}
+
+#[test]
+fn test_write() {
+    let names = ["body", "head", "arm_l", "arm_r", "leg_l", "leg_r", "a", "b"]
+        .iter()
+        .map(|s| Name::from_str_truncated(s))
+        .collect::<Vec<_>>();
+
+    let mut w = Writer::new();
+    let data_pos = write(&mut w, &names, 4).unwrap();
+    for (i, &pos) in data_pos.iter().enumerate() {
+        w.set_u32(pos, i as u32 * 10);
+    }
+    let buf = w.into_vec();
+
+    let read_back = read::<u32>(Cur::new(&buf)).unwrap().collect::<Vec<_>>();
+    assert_eq!(read_back.len(), names.len());
+    for (i, &(datum, name)) in read_back.iter().enumerate() {
+        assert_eq!(datum, i as u32 * 10);
+        assert_eq!(name, names[i]);
+    }
+
+    // Look up every name with the tree the way the runtime does
+    let nodes = build_tree(&names).unwrap();
+    for (i, name) in names.iter().enumerate() {
+        let bit = |b: u8| (name.0[b as usize / 8] >> (b % 8)) & 1 != 0;
+        let mut prev = nodes[0];
+        let mut node = nodes[prev[1] as usize];
+        while prev[0] > node[0] {
+            prev = node;
+            node = nodes[if bit(node[0]) { node[2] } else { node[1] } as usize];
+        }
+        assert_eq!(node[3] as usize, i);
+    }
+}
@@ -1,11 +1,17 @@
-// HIGHLY INCOMPLETE!!!
+//! Material animations (SRT0).
+//!
+//! Animates the texture SRT (see `nitro::texture_matrix`) of materials, for
+//! things like scrolling water or conveyor belts.
 
 use super::animation::Curve;
+use crate::util::angle::unwrap_angles;
+use crate::util::bits::BitField;
 use crate::util::cur::Cur;
 use crate::util::view::Viewable;
-use crate::util::fixed::fix16;
-use cgmath::{Matrix4, vec3};
+use crate::util::fixed::{fix16, fix32};
+use cgmath::Matrix4;
 use crate::nitro::Name;
+use crate::nitro::texture_matrix::{TexMtxMode, TextureSrt};
 use crate::errors::Result;
 use super::info_block;
 
@@ -19,21 +25,26 @@ pub struct MaterialAnimation {
 /// Targets one material in a model and animates it.
 pub struct MaterialTrack {
     pub name: Name,
+    /// One channel for each target, in the order ScaleU, ScaleV, Rotation,
+    /// TranslationU, TranslationV.
     pub channels: [MaterialChannel; 5],
 }
 
-/// Targets one material property for animation????
+/// Animates one component of a material's texture SRT.
 pub struct MaterialChannel {
     pub num_frames: u16,
     pub target: MatChannelTarget,
+    /// Rotations are in radians.
     pub curve: Curve<f64>,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum MatChannelTarget {
+    ScaleU,
+    ScaleV,
+    Rotation,
     TranslationU,
     TranslationV,
-    Unknown,
 }
 
 pub fn read_mat_anim(cur: Cur, name: Name) -> Result<MaterialAnimation> {
@@ -42,17 +53,19 @@ pub fn read_mat_anim(cur: Cur, name: Name) -> Result<MaterialAnimation> {
     fields!(cur, mat_anim {
         _unknown: [u8; 4],  // b'M\0AT' ??
         num_frames: u16,
-        unknown: u16,
+        _flags: u8,
+        _tex_mtx_mode: u8,
         end: Cur,
     });
 
+    use self::MatChannelTarget::*;
     let tracks = info_block::read::<[ChannelData; 5]>(end)?
         .map(|(chans, name)| {
-            let c0 = read_channel(cur, chans[0], MatChannelTarget::Unknown)?;
-            let c1 = read_channel(cur, chans[1], MatChannelTarget::Unknown)?;
-            let c2 = read_channel(cur, chans[2], MatChannelTarget::Unknown)?;
-            let c3 = read_channel(cur, chans[3], MatChannelTarget::TranslationU)?;
-            let c4 = read_channel(cur, chans[4], MatChannelTarget::TranslationV)?;
+            let c0 = read_channel(cur, chans[0], ScaleU)?;
+            let c1 = read_channel(cur, chans[1], ScaleV)?;
+            let c2 = read_channel(cur, chans[2], Rotation)?;
+            let c3 = read_channel(cur, chans[3], TranslationU)?;
+            let c4 = read_channel(cur, chans[4], TranslationV)?;
             let channels = [c0, c1, c2, c3, c4];
             Ok(MaterialTrack { name, channels })
         })
@@ -68,8 +81,10 @@ pub fn read_mat_anim(cur: Cur, name: Name) -> Result<MaterialAnimation> {
 #[derive(Debug, Copy, Clone)]
 struct ChannelData {
     num_frames: u16,
-    flags: u8, // some sort of flags
-    offset: u32, // meaning appears to depend on flags
+    flags: u8,
+    /// Either the constant value of the channel or the offset to its samples,
+    /// depending on flags.
+    value_or_offset: u32,
 }
 impl Viewable for ChannelData {
     fn size() -> usize { 8 }
@@ -78,14 +93,46 @@ impl Viewable for ChannelData {
         let num_frames = cur.next::<u16>().unwrap();
         let _dummy = cur.next::<u8>().unwrap(); // always 0?
         let flags = cur.next::<u8>().unwrap();
-        let offset = cur.next::<u32>().unwrap();
-        ChannelData { num_frames, flags, offset }
+        let value_or_offset = cur.next::<u32>().unwrap();
+        ChannelData { num_frames, flags, value_or_offset }
     }
 }
 
 fn read_channel(base_cur: Cur, data: ChannelData, target: MatChannelTarget) -> Result<MaterialChannel> {
-    if !((data.flags == 16) && target != MatChannelTarget::Unknown) {
-        // That's the only case I understand; otherwise bail.
+    // flags:
+    //   bit 4: samples are 16-bit (instead of 32-bit)
+    //   bit 5: channel is constant
+    //   bit 6: samples are every 2 frames
+    //   bit 7: samples are every 4 frames
+    let is_fx16 = data.flags.bits(4,5) != 0;
+    let is_const = data.flags.bits(5,6) != 0;
+    let rate = match data.flags.bits(6,8) {
+        0 => 0,
+        1 => 1,
+        _ => 2,
+    };
+
+    // Rotations are stored as a (sin, cos) pair of fix16s.
+    let rotation = |x: u32| {
+        let sin = fix16(x.bits(0,16) as u16, 1, 3, 12);
+        let cos = fix16(x.bits(16,32) as u16, 1, 3, 12);
+        sin.atan2(cos)
+    };
+
+    if is_const {
+        let v = data.value_or_offset;
+        let v = match target {
+            MatChannelTarget::Rotation => rotation(v),
+            _ => fix32(v, 1, 19, 12),
+        };
+        return Ok(MaterialChannel {
+            num_frames: data.num_frames,
+            target,
+            curve: Curve::Constant(v),
+        });
+    }
+
+    if data.num_frames == 0 {
         return Ok(MaterialChannel {
             num_frames: 0,
             target,
@@ -93,13 +140,37 @@ fn read_channel(base_cur: Cur, data: ChannelData, target: MatChannelTarget) -> R
         });
     }
 
-    let values = (base_cur + data.offset)
-        .next_n::<u16>(data.num_frames as usize)?
-        .map(|n| fix16(n, 1, 10, 5))
-        .collect::<Vec<f64>>();
+    let num_samples = ((data.num_frames as usize - 1) >> rate) + 1;
+    let mut cur = base_cur + data.value_or_offset;
+    let values = match target {
+        MatChannelTarget::Rotation => {
+            let mut values = cur.next_n::<u32>(num_samples)?
+                .map(rotation)
+                .collect::<Vec<f64>>();
+            unwrap_angles(&mut values);
+            values
+        }
+        MatChannelTarget::TranslationU | MatChannelTarget::TranslationV if is_fx16 => {
+            // 16-bit translations have fewer fractional bits than the other
+            // channels
+            cur.next_n::<u16>(num_samples)?
+                .map(|x| fix16(x, 1, 10, 5))
+                .collect::<Vec<f64>>()
+        }
+        _ if is_fx16 => {
+            cur.next_n::<u16>(num_samples)?
+                .map(|x| fix16(x, 1, 3, 12))
+                .collect::<Vec<f64>>()
+        }
+        _ => {
+            cur.next_n::<u32>(num_samples)?
+                .map(|x| fix32(x, 1, 19, 12))
+                .collect::<Vec<f64>>()
+        }
+    };
     let curve = Curve::Samples {
         start_frame: 0,
-        end_frame: data.num_frames,
+        end_frame: (num_samples << rate) as u16,
         values,
     };
 
@@ -111,16 +182,38 @@ fn read_channel(base_cur: Cur, data: ChannelData, target: MatChannelTarget) -> R
 }
 
 impl MaterialTrack {
-    pub fn eval_uv_mat(&self, frame: u16) -> Matrix4<f64> {
-        let (mut u, mut v) = (0.0, 0.0);
-        for chan in &self.channels {
-            if chan.target == MatChannelTarget::TranslationU {
-                u = chan.curve.sample_at(u, frame);
-            }
-            if chan.target == MatChannelTarget::TranslationV {
-                v = chan.curve.sample_at(v, frame);
-            }
+    /// Sample the texture SRT at the given frame.
+    pub fn sample_srt(&self, frame: u16) -> TextureSrt {
+        let sample = |i: usize, default: f64| self.channels[i].curve.sample_at(default, frame);
+        TextureSrt {
+            scale: (sample(0, 1.0), sample(1, 1.0)),
+            rotation: sample(2, 0.0),
+            translation: (sample(3, 0.0), sample(4, 0.0)),
         }
-        Matrix4::from_translation(vec3(u, v, 0.0))
+    }
+
+    /// The texture matrix at the given frame, for a material with the given
+    /// texture dimensions in a model with the given matrix mode.
+    pub fn eval_uv_mat(&self, frame: u16, mode: TexMtxMode, dim: (u16, u16)) -> Matrix4<f64> {
+        self.sample_srt(frame).matrix(mode, dim)
+    }
+}
+
+#[test]
+fn test_read_fx16_channel() {
+    // 0x0020 and 0xffe0 are 1 and -1 as (1,10,5), 0x1000 is 1 as (1,3,12)
+    let buf = [0x20, 0x00, 0xe0, 0xff, 0x00, 0x10, 0x00, 0x10];
+    let data = |value_or_offset| ChannelData { num_frames: 2, flags: 0x10, value_or_offset };
+
+    let chan = read_channel(Cur::new(&buf), data(0), MatChannelTarget::TranslationU).unwrap();
+    match chan.curve {
+        Curve::Samples { values, .. } => assert_eq!(values, [1.0, -1.0]),
+        _ => panic!("expected samples"),
+    }
+
+    let chan = read_channel(Cur::new(&buf), data(4), MatChannelTarget::ScaleU).unwrap();
+    match chan.curve {
+        Curve::Samples { values, .. } => assert_eq!(values, [1.0, 1.0]),
+        _ => panic!("expected samples"),
     }
 }
@@ -0,0 +1,234 @@
+//! Material color animations (MAT0).
+//!
+//! Animates the diffuse, ambient, specular, and emission colors and the
+//! polygon alpha of materials, for things like fades, glows, and damage
+//! flashes.
+
+use super::animation::Curve;
+use super::model::Material;
+use crate::util::bits::BitField;
+use crate::util::cur::Cur;
+use cgmath::{Vector3, vec3};
+use crate::nitro::Name;
+use crate::errors::Result;
+use super::info_block;
+
+/// Material color animation.
+pub struct MaterialColorAnimation {
+    pub name: Name,
+    pub num_frames: u16,
+    pub tracks: Vec<MaterialColorTrack>,
+}
+
+/// Targets one material in a model (by name) and animates its colors.
+pub struct MaterialColorTrack {
+    pub name: Name,
+    pub diffuse: Curve<Vector3<f64>>,
+    pub ambient: Curve<Vector3<f64>>,
+    pub specular: Curve<Vector3<f64>>,
+    pub emission: Curve<Vector3<f64>>,
+    pub alpha: Curve<f64>,
+}
+
+/// The animatable colors of a material.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MaterialColors {
+    pub diffuse: [f32; 3],
+    pub ambient: [f32; 3],
+    pub specular: [f32; 3],
+    pub emission: [f32; 3],
+    pub alpha: f32,
+}
+
+impl MaterialColors {
+    /// The colors of a material at rest.
+    pub fn from_material(mat: &Material) -> MaterialColors {
+        MaterialColors {
+            diffuse: mat.diffuse,
+            ambient: mat.ambient,
+            specular: mat.specular,
+            emission: mat.emission,
+            alpha: mat.alpha,
+        }
+    }
+}
+
+pub fn read_mat_color_anim(cur: Cur, name: Name) -> Result<MaterialColorAnimation> {
+    debug!("material color animation: {:?}", name);
+
+    fields!(cur, mat_color_anim {
+        stamp: [u8; 4],
+        num_frames: u16,
+        _flags: u16,
+        end: Cur,
+    });
+
+    check!(stamp == b"M\0AM")?;
+
+    let tracks = info_block::read::<[u32; 5]>(end)?
+        .map(|(infos, name)| {
+            Ok(MaterialColorTrack {
+                name,
+                diffuse: read_channel(cur, infos[0], num_frames, rgb)?,
+                ambient: read_channel(cur, infos[1], num_frames, rgb)?,
+                specular: read_channel(cur, infos[2], num_frames, rgb)?,
+                emission: read_channel(cur, infos[3], num_frames, rgb)?,
+                alpha: read_channel(cur, infos[4], num_frames, alpha)?,
+            })
+        })
+        .collect::<Result<Vec<MaterialColorTrack>>>()?;
+
+    Ok(MaterialColorAnimation {
+        name,
+        num_frames,
+        tracks,
+    })
+}
+
+fn rgb(x: u16) -> Vector3<f64> {
+    vec3(x.bits(0,5) as f64 / 31.0, x.bits(5,10) as f64 / 31.0, x.bits(10,15) as f64 / 31.0)
+}
+
+fn alpha(x: u16) -> f64 {
+    x.bits(0,5) as f64 / 31.0
+}
+
+fn read_channel<T>(base_cur: Cur, info: u32, num_frames: u16, f: fn(u16) -> T) -> Result<Curve<T>> {
+    // info:
+    //   bits 0-16: either the constant value or the offset to the samples
+    //   bit 29: channel is constant
+    //   bit 30: samples are every 2 frames
+    //   bit 31: samples are every 4 frames
+    let value_or_offset = info.bits(0,16);
+    let is_const = info.bits(29,30) != 0;
+    let rate = match info.bits(30,32) {
+        0 => 0,
+        1 => 1,
+        _ => 2,
+    };
+
+    if is_const {
+        return Ok(Curve::Constant(f(value_or_offset as u16)));
+    }
+    if num_frames == 0 {
+        return Ok(Curve::None);
+    }
+
+    let num_samples = ((num_frames as usize - 1) >> rate) + 1;
+    let values = (base_cur + value_or_offset)
+        .next_n::<u16>(num_samples)?
+        .map(f)
+        .collect::<Vec<T>>();
+
+    Ok(Curve::Samples {
+        start_frame: 0,
+        end_frame: (num_samples << rate) as u16,
+        values,
+    })
+}
+
+impl MaterialColorTrack {
+    /// Sample the colors at the given frame. Colors that aren't animated are
+    /// taken from `base`.
+    pub fn sample(&self, frame: u16, base: MaterialColors) -> MaterialColors {
+        let color = |curve: &Curve<Vector3<f64>>, base: [f32; 3]| {
+            let base = vec3(base[0] as f64, base[1] as f64, base[2] as f64);
+            let c = curve.sample_at(base, frame);
+            [c.x as f32, c.y as f32, c.z as f32]
+        };
+        MaterialColors {
+            diffuse: color(&self.diffuse, base.diffuse),
+            ambient: color(&self.ambient, base.ambient),
+            specular: color(&self.specular, base.specular),
+            emission: color(&self.emission, base.emission),
+            alpha: self.alpha.sample_at(base.alpha as f64, frame) as f32,
+        }
+    }
+}
+
+#[test]
+fn test_read_mat0() {
+    use crate::nitro::read_container;
+
+    // An NSBMA with one animation. Its one track has sampled, half-rate, and
+    // constant channels.
+    let bytes: &[u8] = &[
+        // BMA0, version 1
+        0x42, 0x4d, 0x41, 0x30, 0xff, 0xfe, 0x01, 0x00,
+        // file size, header size, 1 section
+        0x90, 0x00, 0x00, 0x00, 0x10, 0x00, 0x01, 0x00,
+        // section offset
+        0x14, 0x00, 0x00, 0x00,
+        // MAT0, section size
+        0x4d, 0x41, 0x54, 0x30, 0x7c, 0x00, 0x00, 0x00,
+        // info block: 1 entry, 0x28 bytes
+        0x00, 0x01, 0x28, 0x00,
+        // lookup tree
+        0x08, 0x00, 0x0c, 0x00, 0x7f, 0x01, 0x00, 0x00,
+        0x1e, 0x00, 0x01, 0x00,
+        // 4-byte data, offset of the animation
+        0x04, 0x00, 0x08, 0x00, 0x30, 0x00, 0x00, 0x00,
+        // "glow"
+        0x67, 0x6c, 0x6f, 0x77, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // M\0AM, 4 frames, flags
+        0x4d, 0x00, 0x41, 0x4d, 0x04, 0x00, 0x00, 0x00,
+        // info block: 1 entry, 0x38 bytes
+        0x00, 0x01, 0x38, 0x00,
+        // lookup tree
+        0x08, 0x00, 0x0c, 0x00, 0x7f, 0x01, 0x00, 0x00,
+        0x16, 0x00, 0x01, 0x00,
+        // 20-byte data
+        0x14, 0x00, 0x18, 0x00,
+        // diffuse: samples at 0x40; ambient: constant white
+        0x40, 0x00, 0x00, 0x00, 0xff, 0x7f, 0x00, 0x20,
+        // specular: constant black; emission: every 2 frames at 0x48
+        0x00, 0x00, 0x00, 0x20, 0x48, 0x00, 0x00, 0x40,
+        // alpha: constant 16
+        0x10, 0x00, 0x00, 0x20,
+        // "mat"
+        0x6d, 0x61, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // diffuse samples: red, dark red, black, blue
+        0x1f, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x7c,
+        // emission samples: green, black
+        0xe0, 0x03, 0x00, 0x00,
+    ];
+
+    let cont = read_container(Cur::new(bytes)).unwrap();
+    assert_eq!(cont.mat_color_anims.len(), 1);
+    let anim = &cont.mat_color_anims[0];
+    assert_eq!(anim.name.to_string(), "glow");
+    assert_eq!(anim.num_frames, 4);
+    assert_eq!(anim.tracks.len(), 1);
+    let track = &anim.tracks[0];
+    assert_eq!(track.name.to_string(), "mat");
+
+    match track.diffuse {
+        Curve::Samples { start_frame: 0, end_frame: 4, ref values } => {
+            assert_eq!(values.len(), 4);
+            assert_eq!(values[0], vec3(1.0, 0.0, 0.0));
+            assert_eq!(values[1], vec3(16.0 / 31.0, 0.0, 0.0));
+            assert_eq!(values[3], vec3(0.0, 0.0, 1.0));
+        }
+        _ => panic!("diffuse should be sampled"),
+    }
+    match track.ambient {
+        Curve::Constant(c) => assert_eq!(c, vec3(1.0, 1.0, 1.0)),
+        _ => panic!("ambient should be constant"),
+    }
+    match track.specular {
+        Curve::Constant(c) => assert_eq!(c, vec3(0.0, 0.0, 0.0)),
+        _ => panic!("specular should be constant"),
+    }
+    match track.emission {
+        Curve::Samples { start_frame: 0, end_frame: 4, ref values } => {
+            assert_eq!(values, &[vec3(0.0, 1.0, 0.0), vec3(0.0, 0.0, 0.0)]);
+        }
+        _ => panic!("emission should be sampled"),
+    }
+    match track.alpha {
+        Curve::Constant(a) => assert_eq!(a, 16.0 / 31.0),
+        _ => panic!("alpha should be constant"),
+    }
+}
@@ -20,14 +20,22 @@ pub mod name;
 pub mod render_cmds;
 pub mod pattern;
 pub mod material_animation;
+pub mod material_color_animation;
+pub mod visibility_animation;
+pub mod narc;
+pub mod texture_matrix;
+pub mod write;
 mod info_block;
 mod rotation;
 
 pub use self::name::Name;
-pub use self::container::Container;
+pub use self::container::{Container, read_container, read_shared_container};
+pub use self::write::write_container;
 pub use self::model::Model;
 pub use self::tex::Texture;
 pub use self::tex::Palette;
 pub use self::animation::Animation;
 pub use self::pattern::Pattern;
 pub use self::material_animation::MaterialAnimation;
+pub use self::material_color_animation::MaterialColorAnimation;
+pub use self::visibility_animation::VisibilityAnimation;
@@ -4,6 +4,7 @@ use crate::nitro::info_block;
 use crate::nitro::Name;
 use crate::nds::TextureParams;
 use crate::nitro::render_cmds::Op;
+use crate::nitro::texture_matrix::{TexMtxMode, TextureSrt};
 use crate::util::bits::BitField;
 use crate::util::cur::Cur;
 use crate::util::fixed::{fix16, fix32};
@@ -18,6 +19,30 @@ pub struct Model {
     pub render_ops: Vec<Op>,
     pub up_scale: f64,
     pub down_scale: f64,
+    /// Conventions for the materials' texture matrices.
+    pub tex_mtx_mode: TexMtxMode,
+    /// The render commands `render_ops` was parsed from.
+    pub render_cmds: Vec<u8>,
+    /// The 3x3 matrix stored after each inverse bind matrix (maybe for
+    /// normals?).
+    pub inv_bind_normals: Vec<Matrix3<f64>>,
+    pub header: ModelHeader,
+}
+
+/// Fields from the model header we don't use, but need to write the model
+/// back out.
+#[derive(Copy, Clone, Default)]
+pub struct ModelHeader {
+    pub sbc_type: u8,
+    pub scaling_rule: u8,
+    pub unknown2: [u8; 2],
+    pub num_verts: u16,
+    pub num_surfs: u16,
+    pub num_tris: u16,
+    pub num_quads: u16,
+    /// Fixed-point (1,3,12) x, y, z of the min corner then the size.
+    pub bounding_box: [u16; 6],
+    pub unknown3: [u8; 8],
 }
 
 pub fn read_model(cur: Cur, name: Name) -> Result<Model> {
@@ -29,7 +54,9 @@ pub fn read_model(cur: Cur, name: Name) -> Result<Model> {
         materials_off: u32,
         pieces_off: u32,
         inv_binds_off: u32,
-        unknown1: [u8; 3],
+        sbc_type: u8,
+        scaling_rule: u8,
+        tex_mtx_mode: u8,
         num_objects: u8,
         num_materials: u8,
         num_pieces: u8,
@@ -40,27 +67,54 @@ pub fn read_model(cur: Cur, name: Name) -> Result<Model> {
         num_surfs: u16,
         num_tris: u16,
         num_quads: u16,
-        bounding_box_x_min: (fix16(1,3,12)),
-        bounding_box_y_min: (fix16(1,3,12)),
-        bounding_box_z_min: (fix16(1,3,12)),
-        bounding_box_x_max: (fix16(1,3,12)),
-        bounding_box_y_max: (fix16(1,3,12)),
-        bounding_box_z_max: (fix16(1,3,12)),
+        bounding_box: [u16; 6],
         unknown3: [u8; 8],
         objects_cur: Cur,
     });
 
-    use super::render_cmds::parse_render_cmds;
+    use super::render_cmds::{parse_render_cmds, render_cmds_bytes};
     let render_ops = parse_render_cmds(cur + render_cmds_off)?;
+    let render_cmds = render_cmds_bytes(cur + render_cmds_off)?.to_vec();
 
     let pieces = read_pieces(cur + pieces_off)?;
-    let materials = read_materials(cur + materials_off)?;
-    let objects = read_objects(objects_cur)?;
-    let inv_binds = read_inv_binds(cur + inv_binds_off, num_objects as usize);
+    let tex_mtx_mode = TexMtxMode::from_u8(tex_mtx_mode);
+    let materials = read_materials(cur + materials_off, tex_mtx_mode)?;
+    let mut objects = read_objects(objects_cur)?;
+    let (inv_binds, inv_bind_normals) =
+        read_inv_binds(cur + inv_binds_off, num_objects as usize);
+
+    let header = ModelHeader {
+        sbc_type,
+        scaling_rule,
+        unknown2: [unknown2[0], unknown2[1]],
+        num_verts,
+        num_surfs,
+        num_tris,
+        num_quads,
+        bounding_box: [
+            bounding_box.nth(0), bounding_box.nth(1), bounding_box.nth(2),
+            bounding_box.nth(3), bounding_box.nth(4), bounding_box.nth(5),
+        ],
+        unknown3: {
+            let mut a = [0; 8];
+            a.copy_from_slice(unknown3);
+            a
+        },
+    };
+
+    // Record the rest visibility for each object
+    for op in &render_ops {
+        if let Op::SetVisibility { object_idx, visible } = *op {
+            if let Some(obj) = objects.get_mut(object_idx as usize) {
+                obj.visible = visible;
+            }
+        }
+    }
 
     let model = Model {
         name, materials, pieces, objects, inv_binds,
-        render_ops, up_scale, down_scale,
+        render_ops, up_scale, down_scale, tex_mtx_mode,
+        render_cmds, inv_bind_normals, header,
     };
 
     validate_render_ops(&model)?;
@@ -73,6 +127,7 @@ fn validate_render_ops(model: &Model) -> Result<()> {
     for op in &model.render_ops {
         let good = match *op {
             Op::MulObject { object_idx } => (object_idx as usize) < model.objects.len(),
+            Op::SetVisibility { object_idx, .. } => (object_idx as usize) < model.objects.len(),
             Op::BindMaterial { material_idx } => (material_idx as usize) < model.materials.len(),
             Op::Draw { piece_idx } => (piece_idx as usize) < model.pieces.len(),
             Op::Skin { ref terms } => {
@@ -94,6 +149,7 @@ fn validate_render_ops(model: &Model) -> Result<()> {
 pub struct Piece {
     pub name: Name,
     pub gpu_commands: Vec<u8>,
+    pub unknown: u32,
 }
 
 fn read_pieces(cur: Cur) -> Result<Vec<Piece>> {
@@ -120,7 +176,7 @@ fn read_piece(cur: Cur, name: Name) -> Result<Piece> {
         .next_n_u8s(cmds_len as usize)?
         .to_vec();
 
-    Ok(Piece { name, gpu_commands })
+    Ok(Piece { name, gpu_commands, unknown })
 }
 
 /// Material contains drawing state, eg. diffuse color, texture name, whether to
@@ -144,10 +200,35 @@ pub struct Material {
 
     pub cull_backface: bool,
     pub cull_frontface: bool,
+    /// The SRT the texture matrix was made from.
+    pub texture_srt: TextureSrt,
     pub texture_mat: Matrix4<f64>,
+
+    pub raw: MaterialRaw,
+}
+
+/// Material fields as they were in the file. The ones above are decoded from
+/// these; these are needed to write the material back out, since we don't
+/// understand all the bits.
+#[derive(Clone, Default)]
+pub struct MaterialRaw {
+    pub polygon_attr: u32,
+    pub polygon_attr_mask: u32,
+    pub teximage_param: u32,
+    pub unknown3: u32,
+    pub pltt_base: u16,
+    pub misc: u16,
+    pub mag_w: u32,
+    pub mag_h: u32,
+    /// Everything after the fixed part (the texture SRT and possibly more).
+    pub extra: Vec<u8>,
+    /// Position of the material's texture and palette in the pairing
+    /// dictionaries, so they can be written back in the same order.
+    pub texture_pairing_idx: Option<u8>,
+    pub palette_pairing_idx: Option<u8>,
 }
 
-fn read_materials(cur: Cur) -> Result<Vec<Material>> {
+fn read_materials(cur: Cur, tex_mtx_mode: TexMtxMode) -> Result<Vec<Material>> {
     fields!(cur, materials {
         texture_pairing_off: u16,
         palette_pairing_off: u16,
@@ -155,30 +236,34 @@ fn read_materials(cur: Cur) -> Result<Vec<Material>> {
     });
 
     let mut materials = info_block::read::<u32>(end)?
-        .map(|(off, name)| read_material(cur + off, name))
+        .map(|(off, name)| read_material(cur + off, name, tex_mtx_mode))
         .collect::<Result<Vec<_>>>()?;
 
     // Pair each texture with materials.
     let tex_cur = cur + texture_pairing_off;
-    for ((off, num, _), name) in info_block::read::<(u16, u8, u8)>(tex_cur)? {
+    for (i, ((off, num, _), name)) in info_block::read::<(u16, u8, u8)>(tex_cur)?.enumerate() {
         trace!("texture pairing: {}", name);
         fields!(cur + off, texture_pairings {
             material_ids: [u8; num],
         });
         for &mat_id in material_ids {
-            materials[mat_id as usize].texture_name = Some(name);
+            let mat = &mut materials[mat_id as usize];
+            mat.texture_name = Some(name);
+            mat.raw.texture_pairing_idx = Some(i as u8);
         }
     }
 
     // Pair each palette with materials.
     let pal_cur = cur + palette_pairing_off;
-    for ((off, num, _), name) in info_block::read::<(u16, u8, u8)>(pal_cur)? {
+    for (i, ((off, num, _), name)) in info_block::read::<(u16, u8, u8)>(pal_cur)?.enumerate() {
         trace!("palette pairing: {}", name);
         fields!(cur + off, palette_pairings {
             material_ids: [u8; num],
         });
         for &mat_id in material_ids {
-            materials[mat_id as usize].palette_name = Some(name);
+            let mat = &mut materials[mat_id as usize];
+            mat.palette_name = Some(name);
+            mat.raw.palette_pairing_idx = Some(i as u8);
         }
     }
 
@@ -190,7 +275,7 @@ fn read_materials(cur: Cur) -> Result<Vec<Material>> {
     Ok(materials)
 }
 
-fn read_material(cur: Cur, name: Name) -> Result<Material> {
+fn read_material(cur: Cur, name: Name, tex_mtx_mode: TexMtxMode) -> Result<Material> {
     debug!("material: {:?}", name);
 
     fields!(cur, material {
@@ -208,8 +293,8 @@ fn read_material(cur: Cur, name: Name) -> Result<Material> {
         // only read if unknown3 != 0?
         width: u16,
         height: u16,
-        unknown5: (fix32(1,19,12)), // usually 1.0
-        unknown6: (fix32(1,19,12)), // usually 1.0
+        mag_w: u32, // usually 1.0
+        mag_h: u32, // usually 1.0
 
         end: Cur,
     });
@@ -238,54 +323,30 @@ fn read_material(cur: Cur, name: Name) -> Result<Material> {
     let cull_frontface = polygon_attr.bits(7,8) == 0;
 
 
-    let texture_mat: Matrix4<f64>;
-    if misc.bits(0,1) != 0 {
-        // Read texture matrix
-        let mut x: Option<(f64, f64)> = None;
-        let mut y: Option<(u16, u16)> = None;
-        let mut z: Option<(f64, f64)> = None;
-        let mut cur = end;
-        let fx32 = |x| fix32(x, 1, 19, 12);
-        if misc.bits(1,2) == 0 {
-            let x1 = fx32(cur.next::<u32>()?);
-            let x2 = fx32(cur.next::<u32>()?);
-            x = Some((x1, x2));
-        }
-        if misc.bits(2,3) == 0 {
-            let y1 = cur.next::<u16>()?;
-            let y2 = cur.next::<u16>()?;
-            y = Some((y1, y2));
-        }
-        if misc.bits(3,4) == 0 {
-            let z1 = fx32(cur.next::<u32>()?);
-            let z2 = fx32(cur.next::<u32>()?);
-            z = Some((z1, z2));
-        }
-
-        texture_mat = match (x, y, z) {
-            (None, None, None) => Matrix4::one(),
-            (Some((x1, x2)), None, None) => {
-                let c = 0.0; // TODO: this isn't actually zero
-                Matrix4::new(
-                    x1, 0.0, 0.0, 0.0,
-                    0.0, x2, 0.0, 0.0,
-                    0.0, 0.0, 0.0, 0.0,
-                    0.0, c, 0.0, 1.0,
-                )
-            }
-            _ => {
-                // TODO
-                warn!("material: texture matrix is unimplemented");
-                Matrix4::one()
-            }
-        }
-
-        // TODO: some kind of scaling involving unknown5/6 goes here
-        //       skipped when they are 1.0
-
-    } else {
-        texture_mat = Matrix4::one();
-    }
+    let texture_srt = read_texture_srt(end, misc)?;
+    let texture_mat = texture_srt.matrix(tex_mtx_mode, (width, height));
+
+    // The fixed part is 44 bytes
+    let extra_len = (sizeof as usize).saturating_sub(44);
+    let mut extra_cur = end;
+    let extra = extra_cur.next_n_u8s(extra_len)
+        .map(|x| x.to_vec())
+        .unwrap_or_default();
+
+    let raw = MaterialRaw {
+        polygon_attr,
+        polygon_attr_mask,
+        teximage_param,
+        unknown3,
+        pltt_base,
+        misc,
+        mag_w,
+        mag_h,
+        extra,
+        // Filled in with the pairings
+        texture_pairing_idx: None,
+        palette_pairing_idx: None,
+    };
 
     Ok(Material {
         name,
@@ -303,10 +364,44 @@ fn read_material(cur: Cur, name: Name) -> Result<Material> {
         alpha,
         cull_backface,
         cull_frontface,
+        texture_srt,
         texture_mat,
+        raw,
     })
 }
 
+/// Reads the texture SRT that follows a material. `misc` says which parts are
+/// present.
+pub fn read_texture_srt(mut cur: Cur, misc: u16) -> Result<TextureSrt> {
+    let mut texture_srt = TextureSrt::default();
+    if misc.bits(0,1) != 0 {
+        // Read texture matrix. Each of the scale, rotation, and translation
+        // is omitted when it is the identity.
+        let fx32 = |x| fix32(x, 1, 19, 12);
+        let fx16 = |x| fix16(x, 1, 3, 12);
+        if misc.bits(1,2) == 0 {
+            let sx = fx32(cur.next::<u32>()?);
+            let sy = fx32(cur.next::<u32>()?);
+            texture_srt.scale = (sx, sy);
+        }
+        if misc.bits(2,3) == 0 {
+            let sin = fx16(cur.next::<u16>()?);
+            let cos = fx16(cur.next::<u16>()?);
+            texture_srt.rotation = sin.atan2(cos);
+        }
+        if misc.bits(3,4) == 0 {
+            let tx = fx32(cur.next::<u32>()?);
+            let ty = fx32(cur.next::<u32>()?);
+            texture_srt.translation = (tx, ty);
+        }
+
+        // NOTE: mag_w/mag_h scale the matrix when the texture bound at runtime
+        // has different dimensions than width/height. Since texcoords get
+        // normalized by width/height, they don't matter for us.
+    }
+    Ok(texture_srt)
+}
+
 /// An object, basically just a matrix, typically corresponding to a single bone
 /// in a skeleton. The value stored here is the rest pose. When the model is
 /// animated, its the object matrices that change.
@@ -318,6 +413,21 @@ pub struct Object {
 
     /// Matrix for the above TRS transform.
     pub matrix: Matrix4<f64>,
+
+    /// Whether pieces drawn under this object are visible at rest.
+    pub visible: bool,
+
+    pub raw: ObjectRaw,
+}
+
+/// Parts of an object's encoding that don't affect its TRS, kept so writing it
+/// back out gives the same bytes.
+#[derive(Copy, Clone, Default)]
+pub struct ObjectRaw {
+    pub flags: u16,
+    /// First rotation entry. Still present when the rotation is a pivot
+    /// matrix, though it's not used then.
+    pub m0: u16,
 }
 
 fn read_objects(cur: Cur) -> Result<Vec<Object>> {
@@ -391,7 +501,9 @@ fn read_object(mut cur: Cur, name: Name) -> Result<Object> {
         matrix = Matrix4::from_translation(t) * matrix;
     }
 
-    Ok(Object { name, trans, rot, scale, matrix })
+    let raw = ObjectRaw { flags, m0 };
+
+    Ok(Object { name, trans, rot, scale, matrix, visible: true, raw })
 }
 
 
@@ -401,16 +513,18 @@ fn read_object(mut cur: Cur, name: Name) -> Result<Object> {
 /// need them but have them anyway.
 ///
 /// We just read as many (up to num_objects) as we can get. (Thus why we don't
-/// need to return a Result.)
-fn read_inv_binds(mut cur: Cur, num_objects: usize) -> Vec<Matrix4<f64>> {
+/// need to return a Result.) Also returns the 3x3 matrix that goes with each.
+fn read_inv_binds(mut cur: Cur, num_objects: usize) -> (Vec<Matrix4<f64>>, Vec<Matrix3<f64>>) {
     // Each element in the inv bind array consists of
     // * one 4 x 3 matrix, the inverse of some local-to-world object
     //  transform; this is the one we care about
-    // * one 3 x 3 matrix, possibly for normals(?) that we ignore
+    // * one 3 x 3 matrix, possibly for normals(?) that we only keep for
+    //   writing
     // Each matrix entry is a 4-byte fixed point number.
     let elem_size = (4*3 + 3*3) * 4;
 
     let mut inv_binds = Vec::<Matrix4<f64>>::with_capacity(num_objects);
+    let mut normals = Vec::<Matrix3<f64>>::with_capacity(num_objects);
     for _ in 0..num_objects {
         if cur.bytes_remaining() < elem_size { break; }
 
@@ -422,7 +536,14 @@ fn read_inv_binds(mut cur: Cur, num_objects: usize) -> Vec<Matrix4<f64>> {
             m(6), m(7), m(8), 0.0,
             m(9), m(10), m(11), 1.0,
         ));
-        cur.jump_forward(3*3*4);
+
+        let entries = cur.next_n::<u32>(3*3).unwrap();
+        let m = |i| fix32(entries.nth(i), 1, 19, 12);
+        normals.push(Matrix3::new(
+            m(0), m(1), m(2),
+            m(3), m(4), m(5),
+            m(6), m(7), m(8),
+        ));
     }
-    inv_binds
+    (inv_binds, normals)
 }
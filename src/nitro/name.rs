@@ -14,6 +14,14 @@ impl Name {
         name
     }
 
+    /// Makes a name from a string, cutting it off at 16 bytes.
+    pub fn from_str_truncated(s: &str) -> Name {
+        let mut name = Name([0; 16]);
+        let len = s.len().min(16);
+        name.0[..len].copy_from_slice(&s.as_bytes()[..len]);
+        name
+    }
+
     /// Returns an object that formats the name as a non-empty string
     /// of letters, digits, and underscores.
     pub fn print_safe(&self) -> NameSafePrinter {
@@ -0,0 +1,128 @@
+//! NARC archives.
+//!
+//! A NARC packs a bunch of files (often Nitro files) into one. It has a FAT
+//! (BTAF) giving the location of each file in the data section (GMIF) and an
+//! FNT (BTNF) that may give names to them.
+//!
+//! See: <http://problemkaputt.de/gbatek.htm#dscartridgenitroromandnitroarcfilesystems>
+
+use crate::errors::Result;
+use crate::nds::fnt::read_fnt;
+use crate::util::cur::Cur;
+
+pub struct Narc<'a> {
+    pub files: Vec<NarcFile<'a>>,
+}
+
+pub struct NarcFile<'a> {
+    /// Path of the file in the archive, if it has one.
+    pub name: Option<String>,
+    /// Index of the file in the archive's FAT. Unnamed files are known by
+    /// this.
+    pub index: usize,
+    pub data: &'a [u8],
+}
+
+pub fn is_narc(buf: &[u8]) -> bool {
+    buf.starts_with(b"NARC")
+}
+
+pub fn read_narc(buf: &[u8]) -> Result<Narc<'_>> {
+    let cur = Cur::new(buf);
+    fields!(cur, narc {
+        stamp: [u8; 4],
+        _bom: u16,
+        _version: u16,
+        _file_size: u32,
+        header_size: u16,
+        _num_sections: u16,
+    });
+    check!(stamp == b"NARC")?;
+
+    let btaf = cur + header_size;
+    fields!(btaf, btaf {
+        stamp: [u8; 4],
+        section_size: u32,
+        num_files: u16,
+        _reserved: u16,
+        entries: [(u32, u32); num_files],
+    });
+    check!(stamp == b"BTAF")?;
+
+    let btnf = btaf + section_size;
+    fields!(btnf, btnf {
+        stamp: [u8; 4],
+        section_size: u32,
+        fnt: Cur,
+    });
+    check!(stamp == b"BTNF")?;
+
+    let gmif = btnf + section_size;
+    fields!(gmif, gmif {
+        stamp: [u8; 4],
+        _section_size: u32,
+        data: Cur,
+    });
+    check!(stamp == b"GMIF")?;
+    let data = data.slice_from_cur_to_end();
+
+    let mut names = match read_fnt(fnt, num_files as usize) {
+        Ok(names) => names,
+        Err(e) => {
+            debug!("couldn't read NARC file names: {}", e);
+            vec![None; num_files as usize]
+        }
+    };
+
+    let mut files = Vec::with_capacity(num_files as usize);
+    for (i, (start, end)) in entries.enumerate() {
+        let (start, end) = (start as usize, end as usize);
+        if start > end || end > data.len() {
+            debug!("skipping out-of-bounds NARC member {}", i);
+            continue;
+        }
+        files.push(NarcFile {
+            name: names[i].take(),
+            index: i,
+            data: &data[start..end],
+        });
+    }
+
+    Ok(Narc { files })
+}
+
+#[test]
+fn test_read_narc() {
+    let mut buf = vec![];
+    buf.extend_from_slice(b"NARC\xfe\xff\x00\x01");
+    buf.extend_from_slice(&[0; 4]); // file size
+    buf.extend_from_slice(&[16, 0, 3, 0]);
+
+    // The second file is out-of-bounds
+    buf.extend_from_slice(b"BTAF");
+    buf.extend_from_slice(&[36, 0, 0, 0, 3, 0, 0, 0]);
+    for (start, end) in [(0u32, 4u32), (4, 100), (4, 8)] {
+        buf.extend_from_slice(&start.to_le_bytes());
+        buf.extend_from_slice(&end.to_le_bytes());
+    }
+
+    // Only names the first file
+    buf.extend_from_slice(b"BTNF");
+    buf.extend_from_slice(&[24, 0, 0, 0]);
+    buf.extend_from_slice(&[8, 0, 0, 0, 0, 0, 1, 0]);
+    buf.extend_from_slice(b"\x05a.bin\x00\xff");
+
+    buf.extend_from_slice(b"GMIF");
+    buf.extend_from_slice(&[16, 0, 0, 0]);
+    buf.extend_from_slice(b"AAAABBBB");
+
+    assert!(is_narc(&buf));
+    let narc = read_narc(&buf).unwrap();
+    let files = narc.files.iter()
+        .map(|file| (file.name.as_deref(), file.index, file.data))
+        .collect::<Vec<_>>();
+    assert_eq!(files, [
+        (Some("a.bin"), 0, &b"AAAA"[..]),
+        (None, 2, &b"BBBB"[..]),
+    ]);
+}
@@ -12,27 +12,33 @@ pub struct SkinTerm {
 /// A render command is analyzed into zero or more render ops (think micro-ops
 /// to a CPU instruction).
 pub enum Op {
-    /// cur_matrix = matrix_stack[stack_pos]
+    /// `cur_matrix = matrix_stack[stack_pos]`
     LoadMatrix { stack_pos: u8 },
-    /// matrix_stack[stack_pos] = cur_matrix
+    /// `matrix_stack[stack_pos] = cur_matrix`
     StoreMatrix { stack_pos: u8 },
-    /// cur_matrix = cur_matrix * object_matrices[object_idx]
+    /// `cur_matrix = cur_matrix * object_matrices[object_idx]`
     MulObject { object_idx: u8 },
+    /// ```text
     /// cur_matrix = ∑_{term}
     ///     term.weight *
     ///     matrix_stack[term.stack_pos] *
     ///     inv_bind_matrices[term.inv_bind_idx]
+    /// ```
     /// (ie. the skinning equation)
     Skin { terms: Box<[SkinTerm]> },
-    /// cur_matrix = cur_matrix * scale(up_scale)
+    /// `cur_matrix = cur_matrix * scale(up_scale)`
     ScaleUp,
-    /// cur_matrix = cur_matrix * scale(model.down_scale)
+    /// `cur_matrix = cur_matrix * scale(model.down_scale)`
     ScaleDown,
 
-    /// Bind materials[material_idx] for subsequent draw calls.
+    /// Subsequent draw calls are skipped while `objects[object_idx]` is hidden.
+    /// `visible` is whether it is visible at rest.
+    SetVisibility { object_idx: u8, visible: bool },
+
+    /// Bind `materials[material_idx]` for subsequent draw calls.
     BindMaterial { material_idx: u8 },
 
-    /// Draw pieces[piece_idx].
+    /// Draw `pieces[piece_idx]`.
     Draw { piece_idx: u8 },
 }
 
@@ -55,16 +61,15 @@ pub fn parse_render_cmds(mut cur: Cur) -> Result<Vec<Op>> {
                 return Ok(ops);
             }
             0x02 => {
-                // Unknown
-                // Here's what I know about it:
-                // * there is always one of them in a model, after the initial matrix
-                //   stack setup but before the 0x03/0x04/0x05 command sequences
-                // * the first parameter is num_objects - 1 (ie. the index of the last
-                //   object)
-                // * the second parameter is always 1; if this 1 is changed to a zero
-                //   using a debugger, the model is not drawn (this is probably why
-                //   other people have called this "visibility")
-                // * running it emits no GPU commands
+                // Visibility
+                // Selects the object whose visibility controls the following
+                // material binds/draws. The second parameter is whether it is
+                // visible at rest; a visibility animation (VIS0) overrides it.
+                // Running it emits no GPU commands.
+                ops.push(Op::SetVisibility {
+                    object_idx: params[0],
+                    visible: params[1] & 1 != 0,
+                });
             }
             0x03 => {
                 // Load a matrix from the stack
@@ -148,6 +153,98 @@ pub fn parse_render_cmds(mut cur: Cur) -> Result<Vec<Op>> {
     }
 }
 
+/// Builds a bytestream of render commands; the inverse of
+/// `parse_render_cmds`.
+#[derive(Default)]
+pub struct RenderCmdWriter {
+    buf: Vec<u8>,
+}
+
+impl RenderCmdWriter {
+    pub fn new() -> RenderCmdWriter {
+        RenderCmdWriter { buf: vec![] }
+    }
+
+    /// Multiplies by an object matrix, loading the current matrix from
+    /// `load_pos` first and storing the result to `store_pos` after if
+    /// they're given.
+    pub fn mul_object(
+        &mut self,
+        object_idx: u8,
+        parent_idx: u8,
+        load_pos: Option<u8>,
+        store_pos: Option<u8>,
+    ) {
+        let params = [object_idx, parent_idx, 0];
+        match (store_pos, load_pos) {
+            (None, None) => {
+                self.buf.push(0x06);
+                self.buf.extend_from_slice(&params);
+            }
+            (Some(store), None) => {
+                self.buf.push(0x26);
+                self.buf.extend_from_slice(&params);
+                self.buf.push(store);
+            }
+            (None, Some(load)) => {
+                self.buf.push(0x46);
+                self.buf.extend_from_slice(&params);
+                self.buf.push(load);
+            }
+            (Some(store), Some(load)) => {
+                self.buf.push(0x66);
+                self.buf.extend_from_slice(&params);
+                self.buf.push(store);
+                self.buf.push(load);
+            }
+        }
+    }
+
+    /// Stores the skinning matrix for the given terms to `store_pos`. Weights
+    /// are in 256ths.
+    pub fn skin(&mut self, store_pos: u8, terms: &[(u8, u8, u8)]) {
+        self.buf.push(0x09);
+        self.buf.push(store_pos);
+        self.buf.push(terms.len() as u8);
+        for &(stack_pos, inv_bind_idx, weight) in terms {
+            self.buf.extend_from_slice(&[stack_pos, inv_bind_idx, weight]);
+        }
+    }
+
+    pub fn set_visibility(&mut self, object_idx: u8, visible: bool) {
+        self.buf.extend_from_slice(&[0x02, object_idx, visible as u8]);
+    }
+
+    pub fn bind_material(&mut self, material_idx: u8) {
+        self.buf.extend_from_slice(&[0x04, material_idx]);
+    }
+
+    pub fn draw(&mut self, piece_idx: u8) {
+        self.buf.extend_from_slice(&[0x05, piece_idx]);
+    }
+
+    /// Adds the end command and returns the bytestream.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.buf.push(0x01);
+        self.buf
+    }
+}
+
+/// Returns the bytestream of render commands, up to and including the end
+/// command.
+pub fn render_cmds_bytes<'a>(cur: Cur<'a>) -> Result<&'a [u8]> {
+    let mut c = cur;
+    loop {
+        let (opcode, _) = next_opcode_params(&mut c)?;
+        if opcode == 0x01 {
+            break;
+        }
+    }
+    let len = c.pos() - cur.pos();
+    let mut cur = cur;
+    Ok(cur.next_n_u8s(len)?)
+}
+
 /// Fetch the next opcode and its parameters from the bytestream.
 fn next_opcode_params<'a>(cur: &mut Cur<'a>) -> Result<(u8, &'a [u8])> {
     let opcode = cur.next::<u8>()?;
@@ -188,3 +285,31 @@ fn next_opcode_params<'a>(cur: &mut Cur<'a>) -> Result<(u8, &'a [u8])> {
     let params = cur.next_n_u8s(params_len)?;
     Ok((opcode, params))
 }
+
+#[test]
+fn test_render_cmd_writer() {
+    let mut w = RenderCmdWriter::new();
+    w.mul_object(0, 0, None, Some(0));
+    w.mul_object(1, 0, Some(0), Some(1));
+    w.skin(2, &[(0, 0, 128), (1, 1, 128)]);
+    w.set_visibility(1, false);
+    w.bind_material(3);
+    w.draw(4);
+    let buf = w.finish();
+
+    let ops = parse_render_cmds(Cur::new(&buf)).unwrap();
+    assert_eq!(ops.len(), 10);
+    match ops[2] { Op::LoadMatrix { stack_pos: 0 } => (), _ => panic!() }
+    match ops[5] {
+        Op::Skin { ref terms } => {
+            assert_eq!(terms.len(), 2);
+            assert_eq!(terms[1].weight, 0.5);
+            assert_eq!(terms[1].inv_bind_idx, 1);
+        }
+        _ => panic!(),
+    }
+    match ops[6] { Op::StoreMatrix { stack_pos: 2 } => (), _ => panic!() }
+    match ops[7] { Op::SetVisibility { object_idx: 1, visible: false } => (), _ => panic!() }
+    match ops[9] { Op::Draw { piece_idx: 4 } => (), _ => panic!() }
+    assert_eq!(render_cmds_bytes(Cur::new(&buf)).unwrap().len(), buf.len());
+}
@@ -43,6 +43,51 @@ pub fn pivot_mat(select: u16, neg: u16, a: f64, b: f64) -> Matrix3<f64> {
     }
 }
 
+/// Tries to write a matrix in the compressed form `pivot_mat` reads. Returns
+/// (select, neg, a, b).
+pub fn encode_pivot_mat(m: &Matrix3<f64>) -> Option<(u16, u16, f64, f64)> {
+    for select in 0..9 {
+        for neg in 0..8 {
+            if let Some((a, b)) = pivot_mat_coeffs(m, select, neg) {
+                return Some((select, neg, a, b));
+            }
+        }
+    }
+    None
+}
+
+/// Finds a and b such that `pivot_mat(select, neg, a, b) == m`, if there are
+/// any.
+pub fn pivot_mat_coeffs(m: &Matrix3<f64>, select: u16, neg: u16) -> Option<(f64, f64)> {
+    if select >= 9 || neg >= 8 {
+        return None;
+    }
+
+    // pivot_mat is affine in a and b, so we can find where they go by
+    // plugging in zeros and ones.
+    let entries = |m: &Matrix3<f64>| -> [f64; 9] {
+        [m.x.x, m.x.y, m.x.z, m.y.x, m.y.y, m.y.z, m.z.x, m.z.y, m.z.z]
+    };
+    let target = entries(m);
+    let o = entries(&pivot_mat(select, neg, 0.0, 0.0));
+    let ma = entries(&pivot_mat(select, neg, 1.0, 0.0));
+    let mb = entries(&pivot_mat(select, neg, 0.0, 1.0));
+
+    // Each of a and b appears twice (up to sign); read it from the first
+    // place it appears.
+    let coeff = |mx: &[f64; 9]| {
+        (0..9).find(|&i| mx[i] != o[i]).map(|i| target[i] * (mx[i] - o[i]))
+    };
+    let (a, b) = (coeff(&ma)?, coeff(&mb)?);
+
+    let candidate = entries(&pivot_mat(select, neg, a, b));
+    if candidate.iter().zip(target.iter()).all(|(x, y)| x == y) {
+        Some((a, b))
+    } else {
+        None
+    }
+}
+
 pub fn basis_mat((in0,in1,in2,in3,in4): (u16,u16,u16,u16,u16)) -> Matrix3<f64> {
     // Credit for figuring this out goes to MKDS Course Modifier.
     //
@@ -87,3 +132,16 @@ pub fn basis_mat((in0,in1,in2,in3,in4): (u16,u16,u16,u16,u16)) -> Matrix3<f64> {
 
     Matrix3::from_cols(a, b, c)
 }
+
+#[test]
+fn test_encode_pivot_mat() {
+    for select in 0..9 {
+        for neg in 0..8 {
+            let m = pivot_mat(select, neg, 0.75, -0.5);
+            let (select2, neg2, a, b) = encode_pivot_mat(&m).unwrap();
+            assert_eq!(pivot_mat(select2, neg2, a, b), m);
+        }
+    }
+    let not_pivot = Matrix3::new(1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0);
+    assert!(encode_pivot_mat(&not_pivot).is_none());
+}
@@ -1,26 +1,35 @@
-use std::rc::Rc;
+use std::sync::Arc;
 use crate::nitro::Name;
 use crate::nitro::info_block;
 use crate::errors::Result;
 use crate::util::cur::Cur;
+use crate::util::SharedBytes;
 use crate::nds::{TextureParams, TextureFormat};
 
 pub struct Texture {
     pub name: Name,
     pub params: TextureParams,
-    pub data1: Vec<u8>,
+    pub data1: SharedBytes,
     /// Only used by block-compressed textures.
-    pub data2: Vec<u8>,
+    pub data2: SharedBytes,
+    /// Second word of the texture's entry in the info block. Only kept for
+    /// writing.
+    pub unknown: u32,
 }
 
 pub struct Palette {
     pub name: Name,
     pub off: u32,
     /// Since we don't know how large a palette is
-    pub pal_block: Rc<Box<[u8]>>,
+    pub pal_block: Arc<Box<[u8]>>,
+    /// Second half of the palette's entry in the info block. Only kept for
+    /// writing.
+    pub unknown: u16,
 }
 
-pub fn read_tex(cur: Cur) -> Result<(Vec<Texture>, Vec<Palette>)> {
+/// Reads a TEX0. If the cur is over `shared`, the texture data is shared with
+/// it; otherwise it's copied.
+pub fn read_tex(cur: Cur, shared: Option<&SharedBytes>) -> Result<(Vec<Texture>, Vec<Palette>)> {
     fields!(cur, TEX0 {
         stamp: [u8; 4],
         section_size: u32,
@@ -47,7 +56,7 @@ pub fn read_tex(cur: Cur) -> Result<(Vec<Texture>, Vec<Palette>)> {
     // Stores palette data.
     let pal_block_len = (pal_block_len_shr_3 as usize) << 3;
     let pal_block = (cur + pal_block_off).next_n_u8s(pal_block_len)?.to_vec().into_boxed_slice();
-    let pal_block = Rc::new(pal_block);
+    let pal_block = Arc::new(pal_block);
 
     // Stores regular texture data.
     let tex_cur = cur + tex_block_off;
@@ -56,11 +65,14 @@ pub fn read_tex(cur: Cur) -> Result<(Vec<Texture>, Vec<Palette>)> {
     // one half the length in the compressed2 block.
     let compressed1_cur = cur + compressed1_block_off;
     let compressed2_cur = cur + compressed2_block_off;
-
+    let share = |bytes: &[u8]| match shared {
+        Some(shared) => shared.slice_of(bytes),
+        None => SharedBytes::from(bytes.to_vec()),
+    };
 
     let textures =
         info_block::read::<(u32, u32)>(cur + texture_off)?
-        .map(|((params, _unknown), name)| {
+        .map(|((params, unknown), name)| {
             debug!("texture: {:?}", name);
 
             let params = TextureParams(params);
@@ -71,25 +83,25 @@ pub fn read_tex(cur: Cur) -> Result<(Vec<Texture>, Vec<Palette>)> {
             let (data1, data2);
             match params.format() {
                 TextureFormat(5) => {
-                    data1 = (compressed1_cur + off).next_n_u8s(len)?.to_vec();
-                    data2 = (compressed2_cur + off/2).next_n_u8s(len/2)?.to_vec();
+                    data1 = share((compressed1_cur + off).next_n_u8s(len)?);
+                    data2 = share((compressed2_cur + off/2).next_n_u8s(len/2)?);
                 },
                 _ => {
-                    data1 = (tex_cur + off).next_n_u8s(len)?.to_vec();
-                    data2 = vec![];
+                    data1 = share((tex_cur + off).next_n_u8s(len)?);
+                    data2 = SharedBytes::default();
                 }
             }
 
-            Ok(Texture { name, params, data1, data2 })
+            Ok(Texture { name, params, data1, data2, unknown })
         })
         .collect::<Result<Vec<_>>>()?;
 
     let palettes =
         info_block::read::<(u16, u16)>(cur + palette_off)?
-        .map(|((off_shr_3, _unknown), name)| {
+        .map(|((off_shr_3, unknown), name)| {
             debug!("palette: {:?}", name);
             let off = (off_shr_3 as u32) << 3;
-            Palette { name, off, pal_block: Rc::clone(&pal_block) }
+            Palette { name, off, pal_block: Arc::clone(&pal_block), unknown }
         })
         .collect::<Vec<_>>();
 
@@ -0,0 +1,139 @@
+//! Texture matrices.
+//!
+//! The texture matrix for a material is stored as a scale, rotation, and
+//! translation (SRT) in the conventions of the 3D program the model was made
+//! with. Which program that was is recorded in the model header. The Nitro
+//! runtime turns the SRT into a matrix in a way that depends on the program so
+//! the texture looks the way it did in the artist's program.
+//!
+//! Each program works in UV space (u right, v up, one unit = one copy of the
+//! texture). The DS works in texel space (s right, t down, one unit = one
+//! texel), so the UV transform is conjugated into texel space.
+
+use cgmath::{Matrix4, One, vec3};
+
+/// The program whose texture transform conventions a model uses.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TexMtxMode {
+    Maya,
+    Softimage3D,
+    Max3DS,
+    SoftimageXSI,
+}
+
+impl TexMtxMode {
+    pub fn from_u8(x: u8) -> TexMtxMode {
+        match x {
+            1 => TexMtxMode::Softimage3D,
+            2 => TexMtxMode::Max3DS,
+            3 => TexMtxMode::SoftimageXSI,
+            _ => TexMtxMode::Maya,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            TexMtxMode::Maya => 0,
+            TexMtxMode::Softimage3D => 1,
+            TexMtxMode::Max3DS => 2,
+            TexMtxMode::SoftimageXSI => 3,
+        }
+    }
+}
+
+/// Scale, rotation, and translation for a texture (in UV space).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TextureSrt {
+    pub scale: (f64, f64),
+    /// Counter-clockwise rotation, in radians.
+    pub rotation: f64,
+    pub translation: (f64, f64),
+}
+
+impl Default for TextureSrt {
+    fn default() -> TextureSrt {
+        TextureSrt {
+            scale: (1.0, 1.0),
+            rotation: 0.0,
+            translation: (0.0, 0.0),
+        }
+    }
+}
+
+impl TextureSrt {
+    /// The texture matrix for this SRT acting on texcoords in texel space, for
+    /// a texture of the given dimensions.
+    pub fn matrix(&self, mode: TexMtxMode, (width, height): (u16, u16)) -> Matrix4<f64> {
+        if *self == TextureSrt::default() {
+            return Matrix4::one();
+        }
+
+        let (sx, sy) = self.scale;
+        // The translation is stored with v pointing down; flip it to match
+        // the rest of UV space
+        let (tx, ty) = (self.translation.0, -self.translation.1);
+        let scale = Matrix4::from_nonuniform_scale(sx, sy, 1.0);
+        let rotate = Matrix4::from_angle_z(cgmath::Rad(self.rotation));
+        let translate = Matrix4::from_translation(vec3(tx, ty, 0.0));
+        let to_center = Matrix4::from_translation(vec3(-0.5, -0.5, 0.0));
+        let from_center = Matrix4::from_translation(vec3(0.5, 0.5, 0.0));
+
+        let uv_mat = match mode {
+            // Scale and offset, then rotate about the center
+            TexMtxMode::Maya =>
+                from_center * rotate * to_center * translate * scale,
+            // Rotate and scale about the center, then offset. Positive
+            // offsets move the texture, not the UVs, hence the inverse.
+            TexMtxMode::Max3DS =>
+                Matrix4::from_translation(vec3(-tx, -ty, 0.0)) *
+                from_center * scale * rotate * to_center,
+            // Scale, rotate, then offset, all about the origin
+            TexMtxMode::Softimage3D | TexMtxMode::SoftimageXSI =>
+                translate * rotate * scale,
+        };
+
+        // (s, t) -> (u, v) = (s/width, 1 - t/height)
+        let (w, h) = (width.max(1) as f64, height.max(1) as f64);
+        let texel_to_uv =
+            Matrix4::from_translation(vec3(0.0, 1.0, 0.0)) *
+            Matrix4::from_nonuniform_scale(1.0 / w, -1.0 / h, 1.0);
+        let uv_to_texel =
+            Matrix4::from_nonuniform_scale(w, -h, 1.0) *
+            Matrix4::from_translation(vec3(0.0, -1.0, 0.0));
+
+        uv_to_texel * uv_mat * texel_to_uv
+    }
+}
+
+#[test]
+fn test_scale_only() {
+    // Scaling happens about the bottom-left (in UV space), ie. the
+    // top-left is translated by (1 - scale) * height
+    use cgmath::vec4;
+    let srt = TextureSrt { scale: (2.0, 0.5), ..Default::default() };
+    let mat = srt.matrix(TexMtxMode::Maya, (32, 16));
+    let st = mat * vec4(4.0, 4.0, 0.0, 1.0);
+    assert!((st.x - 8.0).abs() < 1e-9);
+    assert!((st.y - (0.5 * 4.0 + 0.5 * 16.0)).abs() < 1e-9);
+}
+
+#[test]
+fn test_translation() {
+    // A translation of (1/4, 1/2) on a 32x16 texture is (8, 8) texels, with t
+    // down. Scaling s by 2 shows whether the offset is applied before or
+    // after the scale.
+    use cgmath::vec4;
+    let srt = TextureSrt { scale: (2.0, 1.0), rotation: 0.0, translation: (0.25, 0.5) };
+    let cases = [
+        (TexMtxMode::Maya, (16.0, 12.0)),
+        (TexMtxMode::Softimage3D, (16.0, 12.0)),
+        // Scales about the center, and offsets the texture instead of the UVs
+        (TexMtxMode::Max3DS, (-16.0, -4.0)),
+        (TexMtxMode::SoftimageXSI, (16.0, 12.0)),
+    ];
+    for &(mode, (s, t)) in &cases {
+        let st = srt.matrix(mode, (32, 16)) * vec4(4.0, 4.0, 0.0, 1.0);
+        assert!((st.x - s).abs() < 1e-9 && (st.y - t).abs() < 1e-9,
+            "{:?}: got ({}, {}), expected ({}, {})", mode, st.x, st.y, s, t);
+    }
+}
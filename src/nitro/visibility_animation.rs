@@ -0,0 +1,89 @@
+//! Visibility animations (VIS0).
+//!
+//! Toggles whether each object is visible on each frame. Mostly used for
+//! swapping between parts of a model, eg. facial expressions or held items.
+//! When an object is hidden, the pieces drawn under it (see `Op::SetVisibility`)
+//! are not drawn.
+
+use crate::util::cur::Cur;
+use crate::nitro::Name;
+use crate::errors::Result;
+
+pub struct VisibilityAnimation {
+    pub name: Name,
+    pub num_frames: u16,
+    pub num_objects: u16,
+    /// One bit per (frame, object), packed in frame-major order.
+    bits: Vec<u32>,
+}
+
+pub fn read_vis_anim(cur: Cur, name: Name) -> Result<VisibilityAnimation> {
+    debug!("visibility animation: {:?}", name);
+
+    fields!(cur, vis_anim {
+        stamp: [u8; 4],
+        num_frames: u16,
+        num_objects: u16,
+        _flags: u16,
+        _unknown: u16,
+        end: Cur,
+    });
+
+    check!(stamp == b"V\0AV")?;
+    check!(num_frames != 0)?;
+
+    let num_bits = num_frames as usize * num_objects as usize;
+    let bits = end.clone()
+        .next_n::<u32>(num_bits.div_ceil(32))?
+        .collect::<Vec<u32>>();
+
+    Ok(VisibilityAnimation {
+        name,
+        num_frames,
+        num_objects,
+        bits,
+    })
+}
+
+impl VisibilityAnimation {
+    /// Whether the object is visible on the given frame. Objects the animation
+    /// doesn't know about are always visible.
+    pub fn is_visible(&self, frame: u16, object_idx: usize) -> bool {
+        if frame >= self.num_frames || object_idx >= self.num_objects as usize {
+            return true;
+        }
+        let i = frame as usize * self.num_objects as usize + object_idx;
+        self.bits[i >> 5] & (1 << (i & 31)) != 0
+    }
+
+    /// Whether the object's visibility is ever different from `rest`.
+    pub fn animates(&self, object_idx: usize, rest: bool) -> bool {
+        (0..self.num_frames).any(|frame| self.is_visible(frame, object_idx) != rest)
+    }
+}
+
+#[test]
+fn test_is_visible() {
+    // 3 objects, 12 frames; object 1 is hidden on odd frames
+    let mut bits = vec![0u32; 2];
+    for frame in 0..12 {
+        for obj in 0..3 {
+            if obj != 1 || frame % 2 == 0 {
+                let i = frame * 3 + obj;
+                bits[i >> 5] |= 1 << (i & 31);
+            }
+        }
+    }
+    let anim = VisibilityAnimation {
+        name: Name([0; 16]),
+        num_frames: 12,
+        num_objects: 3,
+        bits,
+    };
+    assert!(anim.is_visible(11, 0));
+    assert!(anim.is_visible(10, 1));
+    assert!(!anim.is_visible(11, 1));
+    assert!(anim.is_visible(11, 5));
+    assert!(anim.animates(1, true));
+    assert!(!anim.animates(2, true));
+}
@@ -0,0 +1,404 @@
+//! Write Nitro files.
+//!
+//! The inverse of the readers in the parent module. Only the kinds of
+//! sections found in NSBMD and NSBTX files (MDL0 and TEX0) can be written.
+//!
+//! Parsing a file and writing it back out should give the same bytes. Parts
+//! of the format we don't understand are kept from the original file in the
+//! `raw` fields of the parsed structures, so changing the decoded fields (eg.
+//! a material's colors or an object's TRS) and writing also works. The render
+//! commands and GPU commands are written exactly as they are stored.
+
+mod model;
+mod tex;
+
+use crate::errors::Result;
+use crate::nitro::{Container, Model, Name, Texture, Palette};
+use crate::nitro::info_block;
+use crate::util::writer::Writer;
+
+/// Writes a container (eg. an NSBMD or NSBTX file). Sections are written in
+/// the order MDL0, TEX0; ones that would be empty are left out.
+pub fn write_container(cont: &Container) -> Result<Vec<u8>> {
+    let has_animations =
+        !cont.animations.is_empty() || !cont.patterns.is_empty() ||
+        !cont.mat_anims.is_empty() || !cont.mat_color_anims.is_empty() ||
+        !cont.vis_anims.is_empty();
+    if has_animations {
+        bail!("writing animations is not supported");
+    }
+
+    let mut sections = vec![];
+    if !cont.models.is_empty() {
+        sections.push(write_mdl(&cont.models)?);
+    }
+    if !cont.textures.is_empty() || !cont.palettes.is_empty() {
+        sections.push(tex::write_tex(&cont.textures, &cont.palettes)?);
+    }
+
+    let mut w = Writer::new();
+    w.bytes(cont.stamp);
+    w.u16(0xfeff); // BOM
+    w.u16(cont.version);
+    let file_size_pos = w.pos();
+    w.u32(0);
+    w.u16(16); // header size
+    w.u16(sections.len() as u16);
+    let section_offs_pos = w.pos();
+    for _ in &sections {
+        w.u32(0);
+    }
+
+    for (i, section) in sections.iter().enumerate() {
+        w.align(4);
+        let off = w.pos() as u32;
+        w.set_u32(section_offs_pos + 4 * i, off);
+        w.bytes(section);
+    }
+
+    let file_size = w.pos() as u32;
+    w.set_u32(file_size_pos, file_size);
+
+    Ok(w.into_vec())
+}
+
+/// Writes an MDL0 section holding the given models.
+pub fn write_mdl(models: &[Model]) -> Result<Vec<u8>> {
+    let mut w = Writer::new();
+    w.bytes(b"MDL0");
+    let section_size_pos = w.pos();
+    w.u32(0);
+
+    let names = models.iter().map(|model| model.name).collect::<Vec<Name>>();
+    let data_pos = info_block::write(&mut w, &names, 4)?;
+
+    for (model, &pos) in models.iter().zip(data_pos.iter()) {
+        w.align(4);
+        let off = w.pos() as u32;
+        w.set_u32(pos, off);
+        let buf = model::write_model(model)?;
+        w.bytes(&buf);
+    }
+
+    let section_size = w.pos() as u32;
+    w.set_u32(section_size_pos, section_size);
+
+    Ok(w.into_vec())
+}
+
+/// Writes a TEX0 section holding the given textures and palettes.
+pub fn write_tex(textures: &[Texture], palettes: &[Palette]) -> Result<Vec<u8>> {
+    tex::write_tex(textures, palettes)
+}
+
+#[test]
+fn test_round_trip() {
+    use cgmath::{Matrix3, Matrix4, One, vec3};
+    use std::sync::Arc;
+    use crate::nds::TextureParams;
+    use crate::nitro::model::{Material, MaterialRaw, ModelHeader, Object, ObjectRaw, Piece};
+    use crate::nitro::read_container;
+    use crate::nitro::rotation::pivot_mat;
+    use crate::nitro::texture_matrix::{TexMtxMode, TextureSrt};
+    use crate::util::cur::Cur;
+
+    let name = Name::from_str_truncated;
+
+    let object = |n, trans, rot, scale| Object {
+        name: name(n),
+        trans,
+        rot,
+        scale,
+        matrix: Matrix4::one(),
+        visible: true,
+        raw: ObjectRaw::default(),
+    };
+    let objects = vec![
+        object("root", Some(vec3(1.0, -2.5, 0.25)), Some(pivot_mat(4, 5, 0.5, -0.75)), None),
+        object("arm", None, Some(Matrix3::new(
+            0.5, 0.25, 0.0,
+            -0.25, 0.5, 0.125,
+            0.0, 0.0, 1.0,
+        )), Some(vec3(2.0, 1.0, 1.0))),
+        object("hand", None, None, None),
+    ];
+
+    let material = |n: &str, tex: Option<&str>, srt| Material {
+        name: name(n),
+        texture_name: tex.map(name),
+        palette_name: tex.map(|_| name("pal")),
+        params: TextureParams(0x0003_0000),
+        width: 8,
+        height: 8,
+        diffuse: [1.0, 10.0 / 31.0, 0.0],
+        diffuse_is_default_vertex_color: true,
+        ambient: [0.0, 0.0, 20.0 / 31.0],
+        specular: [0.0; 3],
+        enable_shininess_table: false,
+        emission: [3.0 / 31.0; 3],
+        alpha: 16.0 / 31.0,
+        cull_backface: true,
+        cull_frontface: false,
+        texture_srt: srt,
+        texture_mat: Matrix4::one(),
+        raw: MaterialRaw { polygon_attr_mask: 0xffff_ffff, ..MaterialRaw::default() },
+    };
+    let srt = TextureSrt {
+        scale: (2.0, 1.0),
+        rotation: 0.0,
+        translation: (0.5, 0.0),
+    };
+    let materials = vec![
+        material("mat_a", Some("tex_a"), TextureSrt::default()),
+        material("mat_b", Some("tex_b"), srt),
+        material("mat_c", Some("tex_a"), TextureSrt::default()),
+    ];
+
+    let piece = |n, len| Piece {
+        name: name(n),
+        gpu_commands: vec![0; len],
+        unknown: 0,
+    };
+
+    let model = Model {
+        name: name("model"),
+        materials,
+        pieces: vec![piece("piece0", 8), piece("piece1", 12)],
+        objects,
+        inv_binds: vec![Matrix4::from_translation(vec3(0.0, 1.0, 0.0)); 3],
+        render_ops: vec![],
+        up_scale: 4.0,
+        down_scale: 0.25,
+        tex_mtx_mode: TexMtxMode::Max3DS,
+        render_cmds: vec![
+            0x06, 0, 0, 0,
+            0x04, 0, 0x05, 0,
+            0x06, 1, 0, 0,
+            0x04, 1, 0x05, 1,
+            0x01,
+        ],
+        inv_bind_normals: vec![Matrix3::one(); 3],
+        header: ModelHeader::default(),
+    };
+
+    let pal_block = Arc::new(vec![7; 32].into_boxed_slice());
+    let textures = vec![
+        // 4-color 8x8
+        Texture {
+            name: name("tex_a"),
+            params: TextureParams(2 << 26),
+            data1: (0..16).collect::<Vec<u8>>().into(),
+            data2: Default::default(),
+            unknown: 0,
+        },
+        // Block-compressed 8x8
+        Texture {
+            name: name("tex_b"),
+            params: TextureParams(5 << 26),
+            data1: (100..116).collect::<Vec<u8>>().into(),
+            data2: (200..208).collect::<Vec<u8>>().into(),
+            unknown: 0,
+        },
+    ];
+    let palettes = vec![
+        Palette { name: name("pal"), off: 0, pal_block: Arc::clone(&pal_block), unknown: 0 },
+        Palette { name: name("pal2"), off: 16, pal_block: Arc::clone(&pal_block), unknown: 0 },
+    ];
+
+    let cont = Container {
+        stamp: b"BMD0",
+        version: 2,
+        file_size: 0,
+        models: vec![model],
+        textures,
+        palettes,
+        animations: vec![],
+        patterns: vec![],
+        mat_anims: vec![],
+        mat_color_anims: vec![],
+        vis_anims: vec![],
+    };
+
+    let buf = write_container(&cont).unwrap();
+    let cont2 = read_container(Cur::new(&buf)).unwrap();
+    let buf2 = write_container(&cont2).unwrap();
+    assert!(buf == buf2);
+
+    // Check it read back the same
+    assert_eq!(cont2.file_size as usize, buf.len());
+    let (model, model2) = (&cont.models[0], &cont2.models[0]);
+    assert_eq!(model2.render_ops.len(), 6);
+    for (o, o2) in model.objects.iter().zip(model2.objects.iter()) {
+        assert_eq!(o.name, o2.name);
+        assert_eq!(o.trans, o2.trans);
+        assert_eq!(o.rot, o2.rot);
+        assert_eq!(o.scale, o2.scale);
+    }
+    assert!(model2.objects[0].raw.flags & (1 << 3) != 0); // used a pivot
+    for (m, m2) in model.materials.iter().zip(model2.materials.iter()) {
+        assert_eq!(m.name, m2.name);
+        assert_eq!(m.texture_name, m2.texture_name);
+        assert_eq!(m.palette_name, m2.palette_name);
+        assert_eq!(m.params.0, m2.params.0);
+        assert_eq!((m.diffuse, m.ambient, m.emission), (m2.diffuse, m2.ambient, m2.emission));
+        assert_eq!(m.alpha, m2.alpha);
+        assert_eq!((m.cull_backface, m.cull_frontface), (m2.cull_backface, m2.cull_frontface));
+        assert_eq!(m.texture_srt, m2.texture_srt);
+    }
+    assert_eq!(model2.pieces[1].gpu_commands.len(), 12);
+    assert_eq!(model2.inv_binds, model.inv_binds);
+    assert_eq!((model2.up_scale, model2.down_scale), (4.0, 0.25));
+    for (t, t2) in cont.textures.iter().zip(cont2.textures.iter()) {
+        assert_eq!(t.name, t2.name);
+        assert_eq!(t.data1[..], t2.data1[..]);
+        assert_eq!(t.data2[..], t2.data2[..]);
+    }
+    assert_eq!(cont2.palettes[1].off, 16);
+    assert_eq!(&cont2.palettes[1].pal_block[..], &pal_block[..]);
+}
+
+#[test]
+fn test_round_trip_bytes() {
+    use crate::nitro::read_container;
+    use crate::util::cur::Cur;
+
+    // An NSBMD with a model, a texture, and a palette, laid out the way the
+    // official tools lay them out.
+    let bytes: &[u8] = &[
+        // BMD0, version 2
+        0x42, 0x4d, 0x44, 0x30, 0xff, 0xfe, 0x02, 0x00,
+        // file size, header size, 2 sections
+        0xb4, 0x02, 0x00, 0x00, 0x10, 0x00, 0x02, 0x00,
+        // section offsets
+        0x18, 0x00, 0x00, 0x00, 0x0c, 0x02, 0x00, 0x00,
+        // MDL0, section size
+        0x4d, 0x44, 0x4c, 0x30, 0xf4, 0x01, 0x00, 0x00,
+        // Models info block: 1 entry, 40 bytes
+        0x00, 0x01, 0x28, 0x00,
+        // name lookup tree
+        0x08, 0x00, 0x0c, 0x00, 0x7f, 0x01, 0x00, 0x00, 0x06, 0x00, 0x01, 0x00,
+        // data
+        0x04, 0x00, 0x08, 0x00, 0x30, 0x00, 0x00, 0x00,
+        // "m"
+        0x6d, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // Model m: size, render cmds, materials, pieces, inv binds offsets
+        0xc4, 0x01, 0x00, 0x00, 0x78, 0x00, 0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x30, 0x01, 0x00, 0x00,
+        0x70, 0x01, 0x00, 0x00,
+        // sbc type, scaling rule, tex mtx mode, 1 object, 1 material, 1 piece, unknown
+        0x00, 0x00, 0x00, 0x01, 0x01, 0x01, 0x00, 0x00,
+        // up scale 1.0, down scale 1.0
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+        // 3 verts, 1 surf, 1 tri, 0 quads
+        0x03, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00,
+        // bounding box
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x10, 0x00, 0x00,
+        // bounding box scale, inverse
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+        // Objects info block: 1 entry, 40 bytes
+        0x00, 0x01, 0x28, 0x00,
+        // name lookup tree
+        0x08, 0x00, 0x0c, 0x00, 0x7f, 0x01, 0x00, 0x00, 0x06, 0x00, 0x01, 0x00,
+        // data
+        0x04, 0x00, 0x08, 0x00, 0x28, 0x00, 0x00, 0x00,
+        // "o"
+        0x6f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // object o: translation only, (1, 0, -2)
+        0x06, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xe0, 0xff, 0xff,
+        // render cmds: mul object 0, bind material 0, draw piece 0, end; padding
+        0x06, 0x00, 0x00, 0x00, 0x04, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x00,
+        // Materials: texture pairing, palette pairing offsets
+        0x2c, 0x00, 0x54, 0x00,
+        // Materials info block: 1 entry, 40 bytes
+        0x00, 0x01, 0x28, 0x00,
+        // name lookup tree
+        0x08, 0x00, 0x0c, 0x00, 0x7f, 0x01, 0x00, 0x00, 0x16, 0x00, 0x01, 0x00,
+        // data
+        0x04, 0x00, 0x08, 0x00, 0x80, 0x00, 0x00, 0x00,
+        // "mat"
+        0x6d, 0x61, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // Texture pairing info block: 1 entry, 40 bytes
+        0x00, 0x01, 0x28, 0x00,
+        // name lookup tree
+        0x08, 0x00, 0x0c, 0x00, 0x7f, 0x01, 0x00, 0x00, 0x16, 0x00, 0x01, 0x00,
+        // data
+        0x04, 0x00, 0x08, 0x00, 0x7c, 0x00, 0x01, 0x00,
+        // "tex"
+        0x74, 0x65, 0x78, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // Palette pairing info block: 1 entry, 40 bytes
+        0x00, 0x01, 0x28, 0x00,
+        // name lookup tree
+        0x08, 0x00, 0x0c, 0x00, 0x7f, 0x01, 0x00, 0x00, 0x2e, 0x00, 0x01, 0x00,
+        // data
+        0x04, 0x00, 0x08, 0x00, 0x7d, 0x00, 0x01, 0x00,
+        // "tex_pl"
+        0x74, 0x65, 0x78, 0x5f, 0x70, 0x6c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // material IDs for tex, tex_pl; padding
+        0x00, 0x00, 0x00, 0x00,
+        // material mat: size 44; diffuse, ambient; specular, emission
+        0x00, 0x00, 0x2c, 0x00, 0xff, 0xff, 0x10, 0x42, 0x00, 0x00, 0x00, 0x00,
+        // polygon attr, mask, teximage params, unknown
+        0x80, 0x00, 0x1f, 0x00, 0xff, 0xf8, 0x1f, 0x3f, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // palette base, misc (no SRT), 8x8, mag
+        0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x08, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+        // Pieces info block: 1 entry, 40 bytes
+        0x00, 0x01, 0x28, 0x00,
+        // name lookup tree
+        0x08, 0x00, 0x0c, 0x00, 0x7f, 0x01, 0x00, 0x00, 0x06, 0x00, 0x01, 0x00,
+        // data
+        0x04, 0x00, 0x08, 0x00, 0x28, 0x00, 0x00, 0x00,
+        // "p"
+        0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // piece p: size 16, unknown, commands offset, length
+        0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00,
+        // GPU commands: begin (triangles), end
+        0x40, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // inverse bind matrix
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x10, 0x00, 0x00, 0x00, 0xf0, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00,
+        // inverse bind normal matrix
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x10, 0x00, 0x00,
+        // TEX0, section size
+        0x54, 0x45, 0x58, 0x30, 0xa8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // texture data: 16 bytes at 0x90, info at 0x3c
+        0x02, 0x00, 0x3c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // compressed texture data: none
+        0x00, 0x00, 0x3c, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa0, 0x00, 0x00, 0x00, 0xa0, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        // palette data: 8 bytes at 0xa0, info at 0x68
+        0x01, 0x00, 0x00, 0x00, 0x68, 0x00, 0x00, 0x00, 0xa0, 0x00, 0x00, 0x00,
+        // Textures info block: 1 entry, 44 bytes
+        0x00, 0x01, 0x2c, 0x00,
+        // name lookup tree
+        0x08, 0x00, 0x0c, 0x00, 0x7f, 0x01, 0x00, 0x00, 0x16, 0x00, 0x01, 0x00,
+        // data
+        0x08, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00,
+        // "tex"
+        0x74, 0x65, 0x78, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // Palettes info block: 1 entry, 40 bytes
+        0x00, 0x01, 0x28, 0x00,
+        // name lookup tree
+        0x08, 0x00, 0x0c, 0x00, 0x7f, 0x01, 0x00, 0x00, 0x2e, 0x00, 0x01, 0x00,
+        // data
+        0x04, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // "tex_pl"
+        0x74, 0x65, 0x78, 0x5f, 0x70, 0x6c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // texture data
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+        // palette data
+        0xff, 0x7f, 0x1f, 0x00, 0xe0, 0x03, 0x00, 0x7c,
+    ];
+
+    let cont = read_container(Cur::new(bytes)).unwrap();
+    assert_eq!(cont.models.len(), 1);
+    assert_eq!(cont.models[0].materials[0].texture_name.unwrap().to_string(), "tex");
+    assert_eq!(cont.textures.len(), 1);
+    assert_eq!(cont.palettes.len(), 1);
+
+    let written = write_container(&cont).unwrap();
+    assert_eq!(written.len(), bytes.len());
+    let first_diff = written.iter().zip(bytes.iter()).position(|(a, b)| a != b);
+    assert_eq!(first_diff, None);
+}
@@ -0,0 +1,407 @@
+use cgmath::{Matrix3, Matrix4, Vector3};
+use crate::errors::Result;
+use crate::nitro::info_block;
+use crate::nitro::model::{Material, Model, Object, read_texture_srt};
+use crate::nitro::rotation::{encode_pivot_mat, pivot_mat_coeffs};
+use crate::nitro::texture_matrix::TextureSrt;
+use crate::nitro::Name;
+use crate::util::bits::BitField;
+use crate::util::cur::Cur;
+use crate::util::fixed::{to_fix16, to_fix32};
+use crate::util::writer::Writer;
+
+fn fx32(x: f64) -> u32 { to_fix32(x, 1, 19, 12) }
+fn fx16(x: f64) -> u16 { to_fix16(x, 1, 3, 12) }
+
+/// Writes a model. Offsets are relative to the start of the returned buffer.
+pub fn write_model(model: &Model) -> Result<Vec<u8>> {
+    if model.objects.len() > 255 || model.materials.len() > 255 || model.pieces.len() > 255 {
+        bail!("model {} has too many objects, materials, or pieces", model.name);
+    }
+
+    let mut w = Writer::new();
+
+    let header = &model.header;
+    w.u32(0); // section size
+    w.u32(0); // render cmds offset
+    w.u32(0); // materials offset
+    w.u32(0); // pieces offset
+    w.u32(0); // inv binds offset
+    w.u8(header.sbc_type);
+    w.u8(header.scaling_rule);
+    w.u8(model.tex_mtx_mode.to_u8());
+    w.u8(model.objects.len() as u8);
+    w.u8(model.materials.len() as u8);
+    w.u8(model.pieces.len() as u8);
+    w.bytes(&header.unknown2);
+    w.u32(fx32(model.up_scale));
+    w.u32(fx32(model.down_scale));
+    w.u16(header.num_verts);
+    w.u16(header.num_surfs);
+    w.u16(header.num_tris);
+    w.u16(header.num_quads);
+    for &x in &header.bounding_box {
+        w.u16(x);
+    }
+    w.bytes(&header.unknown3);
+
+    // Objects
+    let objects_start = w.pos();
+    let names = model.objects.iter().map(|o| o.name).collect::<Vec<_>>();
+    let data_pos = info_block::write(&mut w, &names, 4)?;
+    for (obj, &pos) in model.objects.iter().zip(data_pos.iter()) {
+        w.align(4);
+        let off = (w.pos() - objects_start) as u32;
+        w.set_u32(pos, off);
+        write_object(&mut w, obj);
+    }
+
+    w.align(4);
+    let render_cmds_off = w.pos() as u32;
+    w.bytes(&model.render_cmds);
+
+    w.align(4);
+    let materials_off = w.pos() as u32;
+    let buf = write_materials(&model.materials)?;
+    w.bytes(&buf);
+
+    w.align(4);
+    let pieces_off = w.pos() as u32;
+    let buf = write_pieces(model)?;
+    w.bytes(&buf);
+
+    w.align(4);
+    let inv_binds_off = w.pos() as u32;
+    write_inv_binds(&mut w, &model.inv_binds, &model.inv_bind_normals);
+
+    let section_size = w.pos() as u32;
+    w.set_u32(0, section_size);
+    w.set_u32(4, render_cmds_off);
+    w.set_u32(8, materials_off);
+    w.set_u32(12, pieces_off);
+    w.set_u32(16, inv_binds_off);
+
+    Ok(w.into_vec())
+}
+
+fn write_object(w: &mut Writer, obj: &Object) {
+    // Keep the bits we don't know the meaning of
+    let mut flags = obj.raw.flags & 0xf000;
+    let mut m0 = 0;
+
+    if obj.trans.is_none() { flags |= 1 << 0; }
+    if obj.scale.is_none() { flags |= 1 << 2; }
+
+    // The rotation is stored as a pivot matrix when possible. Prefer the same
+    // pivot the original file used.
+    let mut pivot = None;
+    let mut full = None;
+    match obj.rot {
+        None => flags |= 1 << 1,
+        Some(ref rot) => {
+            let raw_pivot = if obj.raw.flags.bits(3,4) != 0 {
+                let select = obj.raw.flags.bits(4,8);
+                let neg = obj.raw.flags.bits(8,12);
+                pivot_mat_coeffs(rot, select, neg)
+                    .map(|(a, b)| (select, neg, a, b))
+            } else {
+                None
+            };
+            match raw_pivot.or_else(|| encode_pivot_mat(rot)) {
+                Some((select, neg, a, b)) => {
+                    flags |= 1 << 3;
+                    flags |= select << 4;
+                    flags |= neg << 8;
+                    if obj.raw.flags.bits(3,4) != 0 {
+                        m0 = obj.raw.m0;
+                    }
+                    pivot = Some((a, b));
+                }
+                None => {
+                    m0 = fx16(rot.x.x);
+                    full = Some(rot);
+                }
+            }
+        }
+    }
+
+    w.u16(flags);
+    w.u16(m0);
+
+    let vec3 = |w: &mut Writer, v: &Vector3<f64>| {
+        w.u32(fx32(v.x));
+        w.u32(fx32(v.y));
+        w.u32(fx32(v.z));
+    };
+
+    if let Some(ref t) = obj.trans {
+        vec3(w, t);
+    }
+    if let Some((a, b)) = pivot {
+        w.u16(fx16(a));
+        w.u16(fx16(b));
+    }
+    if let Some(m) = full {
+        // Same order the reader uses (m0 was already written)
+        for &x in &[m.x.y, m.x.z, m.y.x, m.y.y, m.y.z, m.z.x, m.z.y, m.z.z] {
+            w.u16(fx16(x));
+        }
+    }
+    if let Some(ref s) = obj.scale {
+        vec3(w, s);
+    }
+}
+
+/// Writes the materials section. Offsets are relative to its start.
+fn write_materials(materials: &[Material]) -> Result<Vec<u8>> {
+    let mut w = Writer::new();
+    w.u16(0); // texture pairing offset
+    w.u16(0); // palette pairing offset
+
+    let names = materials.iter().map(|mat| mat.name).collect::<Vec<_>>();
+    let mat_data_pos = info_block::write(&mut w, &names, 4)?;
+
+    // Group materials by the texture/palette they use. Groups keep their
+    // place in the dictionary they were read from; new ones go after, in
+    // order of first use.
+    let pairings = |textures: bool| {
+        let mut groups: Vec<(Name, Vec<u8>, u16)> = vec![];
+        for (i, mat) in materials.iter().enumerate() {
+            let (name, idx) = if textures {
+                (mat.texture_name, mat.raw.texture_pairing_idx)
+            } else {
+                (mat.palette_name, mat.raw.palette_pairing_idx)
+            };
+            if let Some(name) = name {
+                let place = idx.map(u16::from).unwrap_or(0x100);
+                match groups.iter_mut().find(|g| g.0 == name) {
+                    Some(g) => {
+                        g.1.push(i as u8);
+                        g.2 = g.2.min(place);
+                    }
+                    None => groups.push((name, vec![i as u8], place)),
+                }
+            }
+        }
+        groups.sort_by_key(|g| g.2);
+        groups
+    };
+    let tex_pairings = pairings(true);
+    let pal_pairings = pairings(false);
+
+    let tex_pairing_off = w.pos() as u16;
+    let names = tex_pairings.iter().map(|g| g.0).collect::<Vec<_>>();
+    let tex_data_pos = info_block::write(&mut w, &names, 4)?;
+
+    let pal_pairing_off = w.pos() as u16;
+    let names = pal_pairings.iter().map(|g| g.0).collect::<Vec<_>>();
+    let pal_data_pos = info_block::write(&mut w, &names, 4)?;
+
+    w.set_u16(0, tex_pairing_off);
+    w.set_u16(2, pal_pairing_off);
+
+    // Material ID lists
+    let groups = tex_pairings.iter().zip(tex_data_pos.iter())
+        .chain(pal_pairings.iter().zip(pal_data_pos.iter()));
+    for ((_, ids, _), &pos) in groups {
+        let off = w.pos();
+        if off > 0xffff {
+            bail!("materials section too large");
+        }
+        w.set_u16(pos, off as u16);
+        w.set_u16(pos + 2, ids.len() as u16);
+        w.bytes(ids);
+    }
+
+    for (mat, &pos) in materials.iter().zip(mat_data_pos.iter()) {
+        w.align(4);
+        let off = w.pos() as u32;
+        w.set_u32(pos, off);
+        write_material(&mut w, mat);
+    }
+
+    Ok(w.into_vec())
+}
+
+fn write_material(w: &mut Writer, mat: &Material) {
+    let raw = &mat.raw;
+
+    fn rgb(c: [f32; 3]) -> u32 {
+        let f = |x: f32| (x.clamp(0.0, 1.0) * 31.0).round() as u32;
+        f(c[0]) | f(c[1]) << 5 | f(c[2]) << 10
+    }
+    let dif_amb =
+        rgb(mat.diffuse) |
+        (mat.diffuse_is_default_vertex_color as u32) << 15 |
+        rgb(mat.ambient) << 16;
+    let spe_emi =
+        rgb(mat.specular) |
+        (mat.enable_shininess_table as u32) << 15 |
+        rgb(mat.emission) << 16;
+
+    let alpha = (mat.alpha.clamp(0.0, 1.0) * 31.0).round() as u32;
+    let mut polygon_attr = raw.polygon_attr & !(0x1f << 16) & !(0b11 << 6);
+    polygon_attr |= alpha << 16;
+    polygon_attr |= (!mat.cull_backface as u32) << 6;
+    polygon_attr |= (!mat.cull_frontface as u32) << 7;
+
+    let mask = raw.polygon_attr_mask;
+    let teximage_param = (raw.teximage_param & !mask) | (mat.params.0 & mask);
+
+    // Keep the stored SRT if it hasn't changed. Otherwise replace it.
+    let raw_srt = read_texture_srt(Cur::new(&raw.extra), raw.misc).ok();
+    let (misc, extra) = if raw_srt == Some(mat.texture_srt) {
+        (raw.misc, raw.extra.clone())
+    } else {
+        encode_texture_srt(&mat.texture_srt, raw.misc)
+    };
+
+    w.u16(0);
+    w.u16((44 + extra.len()) as u16);
+    w.u32(dif_amb);
+    w.u32(spe_emi);
+    w.u32(polygon_attr);
+    w.u32(mask);
+    w.u32(teximage_param);
+    w.u32(raw.unknown3);
+    w.u16(raw.pltt_base);
+    w.u16(misc);
+    w.u16(mat.width);
+    w.u16(mat.height);
+    w.u32(raw.mag_w);
+    w.u32(raw.mag_h);
+    w.bytes(&extra);
+}
+
+/// Encodes a texture SRT the way `read_texture_srt` reads it. Returns the new
+/// misc bits and the bytes to put after the material.
+fn encode_texture_srt(srt: &TextureSrt, misc: u16) -> (u16, Vec<u8>) {
+    let mut misc = misc & !0xf;
+    let mut w = Writer::new();
+    let default = TextureSrt::default();
+
+    if *srt == default {
+        return (misc, vec![]);
+    }
+    misc |= 1;
+
+    if srt.scale == default.scale {
+        misc |= 1 << 1;
+    } else {
+        w.u32(fx32(srt.scale.0));
+        w.u32(fx32(srt.scale.1));
+    }
+    if srt.rotation == default.rotation {
+        misc |= 1 << 2;
+    } else {
+        w.u16(fx16(srt.rotation.sin()));
+        w.u16(fx16(srt.rotation.cos()));
+    }
+    if srt.translation == default.translation {
+        misc |= 1 << 3;
+    } else {
+        w.u32(fx32(srt.translation.0));
+        w.u32(fx32(srt.translation.1));
+    }
+
+    (misc, w.into_vec())
+}
+
+/// Writes the pieces section. Offsets are relative to its start.
+fn write_pieces(model: &Model) -> Result<Vec<u8>> {
+    let mut w = Writer::new();
+
+    let names = model.pieces.iter().map(|piece| piece.name).collect::<Vec<_>>();
+    let data_pos = info_block::write(&mut w, &names, 4)?;
+
+    // All the piece headers come first, then all the command data
+    let mut header_pos = vec![];
+    for (piece, &pos) in model.pieces.iter().zip(data_pos.iter()) {
+        w.align(4);
+        let off = w.pos();
+        w.set_u32(pos, off as u32);
+        header_pos.push(off);
+
+        w.u16(0);
+        w.u16(16); // section size
+        w.u32(piece.unknown);
+        w.u32(0); // commands offset
+        w.u32(piece.gpu_commands.len() as u32);
+    }
+
+    for (piece, &pos) in model.pieces.iter().zip(header_pos.iter()) {
+        w.align(4);
+        let off = (w.pos() - pos) as u32;
+        w.set_u32(pos + 8, off);
+        w.bytes(&piece.gpu_commands);
+    }
+
+    Ok(w.into_vec())
+}
+
+fn write_inv_binds(w: &mut Writer, inv_binds: &[Matrix4<f64>], normals: &[Matrix3<f64>]) {
+    for (i, m) in inv_binds.iter().enumerate() {
+        for c in &[m.x, m.y, m.z, m.w] {
+            w.u32(fx32(c.x));
+            w.u32(fx32(c.y));
+            w.u32(fx32(c.z));
+        }
+
+        let n = normals.get(i).cloned().unwrap_or_else(|| {
+            Matrix3::new(
+                m.x.x, m.x.y, m.x.z,
+                m.y.x, m.y.y, m.y.z,
+                m.z.x, m.z.y, m.z.z,
+            )
+        });
+        for c in &[n.x, n.y, n.z] {
+            w.u32(fx32(c.x));
+            w.u32(fx32(c.y));
+            w.u32(fx32(c.z));
+        }
+    }
+}
+
+#[test]
+fn test_pairing_order() {
+    use crate::nitro::model::read_model;
+    use crate::test_util::ModelBuilder;
+
+    let name = Name::from_str_truncated;
+    let mut model = ModelBuilder::new("model")
+        .chain(&["root"])
+        .material("mat0")
+        .material("mat1")
+        .material("mat2")
+        .build();
+    // Both dictionaries list "a" first, though mat0 uses "b"
+    let pairs = [("b", "pb", 1), ("a", "pa", 0), ("b", "pa", 1)];
+    for (mat, &(tex, pal, tex_idx)) in model.materials.iter_mut().zip(pairs.iter()) {
+        mat.texture_name = Some(name(tex));
+        mat.palette_name = Some(name(pal));
+        mat.raw.texture_pairing_idx = Some(tex_idx);
+        mat.raw.palette_pairing_idx = Some(if pal == "pa" { 0 } else { 1 });
+    }
+
+    let buf = write_model(&model).unwrap();
+    let model2 = read_model(Cur::new(&buf), model.name).unwrap();
+    let order = |m: &Model| {
+        m.materials.iter()
+            .map(|mat| (mat.raw.texture_pairing_idx, mat.raw.palette_pairing_idx))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(order(&model2), [(Some(1), Some(1)), (Some(0), Some(0)), (Some(1), Some(0))]);
+    for (m, m2) in model.materials.iter().zip(model2.materials.iter()) {
+        assert_eq!((m.texture_name, m.palette_name), (m2.texture_name, m2.palette_name));
+    }
+    assert!(write_model(&model2).unwrap() == buf);
+
+    // Without a dictionary to follow, they go in order of first use
+    for mat in &mut model.materials {
+        mat.raw.texture_pairing_idx = None;
+        mat.raw.palette_pairing_idx = None;
+    }
+    let buf = write_model(&model).unwrap();
+    let model2 = read_model(Cur::new(&buf), model.name).unwrap();
+    assert_eq!(order(&model2), [(Some(0), Some(0)), (Some(1), Some(1)), (Some(0), Some(1))]);
+}
@@ -0,0 +1,197 @@
+use std::sync::Arc;
+use crate::errors::Result;
+use crate::nds::TextureParams;
+use crate::nitro::{Palette, Texture};
+use crate::nitro::info_block;
+use crate::util::writer::Writer;
+
+/// Writes a TEX0 section.
+///
+/// Textures are placed at the offsets in their params if they fit together
+/// there (which they do for textures that all came from the same TEX0),
+/// otherwise they're packed one after another and their params updated.
+/// Palettes keep their offsets into their palette block; when they come from
+/// several different blocks, the blocks are put one after another.
+pub fn write_tex(textures: &[Texture], palettes: &[Palette]) -> Result<Vec<u8>> {
+    if textures.len() > 255 || palettes.len() > 255 {
+        bail!("too many textures or palettes for a TEX0");
+    }
+
+    let is_compressed = |tex: &Texture| tex.params.format().0 == 5;
+    let Placement { params, tex_block, compressed1_block, compressed2_block } =
+        match place_textures(textures, &is_compressed, false) {
+            Some(x) => x,
+            None => match place_textures(textures, &is_compressed, true) {
+                Some(x) => x,
+                None => bail!("TEX0 texture data too large"),
+            },
+        };
+
+    // Collect the distinct palette blocks
+    let mut pal_blocks: Vec<&Arc<Box<[u8]>>> = vec![];
+    let mut pal_offs = vec![];
+    for pal in palettes {
+        let idx = match pal_blocks.iter().position(|b| Arc::ptr_eq(b, &pal.pal_block)) {
+            Some(idx) => idx,
+            None => {
+                pal_blocks.push(&pal.pal_block);
+                pal_blocks.len() - 1
+            }
+        };
+        let base = pal_blocks[..idx].iter()
+            .map(|b| round_up(b.len(), 8))
+            .sum::<usize>();
+        pal_offs.push(base + pal.off as usize);
+    }
+    let mut pal_block = vec![];
+    for block in &pal_blocks {
+        pal_block.extend_from_slice(block);
+        pal_block.resize(round_up(pal_block.len(), 8), 0);
+    }
+
+    let block_len_shr_3 = |len: usize| -> Result<u16> {
+        let x = round_up(len, 8) >> 3;
+        if x > 0xffff {
+            bail!("TEX0 data too large");
+        }
+        Ok(x as u16)
+    };
+
+    let mut w = Writer::new();
+    w.bytes(b"TEX0");
+    w.u32(0); // section size
+    w.u32(0);
+    w.u16(block_len_shr_3(tex_block.len())?);
+    w.u16(0); // texture info block offset
+    w.u32(0);
+    w.u32(0); // tex block offset
+    w.u32(0);
+    w.u16(block_len_shr_3(compressed1_block.len())?);
+    w.u16(0); // compressed info offset
+    w.u32(0);
+    w.u32(0); // compressed1 block offset
+    w.u32(0); // compressed2 block offset
+    w.u32(0);
+    w.u16(block_len_shr_3(pal_block.len())?);
+    w.u16(0);
+    w.u32(0); // palette info block offset
+    w.u32(0); // palette block offset
+
+    let texture_off = w.pos();
+    let names = textures.iter().map(|tex| tex.name).collect::<Vec<_>>();
+    let data_pos = info_block::write(&mut w, &names, 8)?;
+    for ((tex, &params), &pos) in textures.iter().zip(params.iter()).zip(data_pos.iter()) {
+        w.set_u32(pos, params.0);
+        w.set_u32(pos + 4, tex.unknown);
+    }
+
+    let palette_off = w.pos();
+    let names = palettes.iter().map(|pal| pal.name).collect::<Vec<_>>();
+    let data_pos = info_block::write(&mut w, &names, 4)?;
+    for ((pal, &off), &pos) in palettes.iter().zip(pal_offs.iter()).zip(data_pos.iter()) {
+        if off >> 3 > 0xffff {
+            bail!("palette data too large");
+        }
+        w.set_u16(pos, (off >> 3) as u16);
+        w.set_u16(pos + 2, pal.unknown);
+    }
+
+    let block = |w: &mut Writer, data: &[u8]| {
+        w.align(8);
+        let off = w.pos() as u32;
+        w.bytes(data);
+        off
+    };
+    let tex_block_off = block(&mut w, &tex_block);
+    let compressed1_block_off = block(&mut w, &compressed1_block);
+    let compressed2_block_off = block(&mut w, &compressed2_block);
+    let pal_block_off = block(&mut w, &pal_block);
+    w.align(4);
+
+    let section_size = w.pos() as u32;
+    w.set_u32(4, section_size);
+    w.set_u16(14, texture_off as u16);
+    w.set_u32(20, tex_block_off);
+    w.set_u16(30, texture_off as u16);
+    w.set_u32(36, compressed1_block_off);
+    w.set_u32(40, compressed2_block_off);
+    w.set_u32(52, palette_off as u32);
+    w.set_u32(56, pal_block_off);
+
+    Ok(w.into_vec())
+}
+
+/// Where the texture data goes.
+struct Placement {
+    /// Params for each texture, with the offset to its data.
+    params: Vec<TextureParams>,
+    tex_block: Vec<u8>,
+    compressed1_block: Vec<u8>,
+    compressed2_block: Vec<u8>,
+}
+
+/// Lays out the texture data. Without `repack`, fails if the textures' current
+/// offsets overlap with different data.
+fn place_textures(
+    textures: &[Texture],
+    is_compressed: &dyn Fn(&Texture) -> bool,
+    repack: bool,
+) -> Option<Placement> {
+    /// Block data, and which bytes have been written to.
+    type Block = (Vec<u8>, Vec<bool>);
+
+    let mut params = vec![];
+    let mut tex_block: Block = (vec![], vec![]);
+    let mut compressed1_block: Block = (vec![], vec![]);
+    let mut compressed2_block: Block = (vec![], vec![]);
+
+    // Copies data into block at off. Fails if something different was
+    // already written there.
+    fn put(block: &mut Block, off: usize, data: &[u8]) -> Option<()> {
+        let end = off + data.len();
+        if block.0.len() < end {
+            block.0.resize(end, 0);
+            block.1.resize(end, false);
+        }
+        for (i, &x) in data.iter().enumerate() {
+            if block.1[off + i] && block.0[off + i] != x {
+                return None;
+            }
+            block.0[off + i] = x;
+            block.1[off + i] = true;
+        }
+        Some(())
+    }
+
+    for tex in textures {
+        let off = if repack {
+            let block = if is_compressed(tex) { &compressed1_block } else { &tex_block };
+            round_up(block.0.len(), 8)
+        } else {
+            tex.params.offset() as usize
+        };
+        if off >> 3 > 0xffff {
+            return None;
+        }
+
+        if is_compressed(tex) {
+            put(&mut compressed1_block, off, &tex.data1)?;
+            put(&mut compressed2_block, off / 2, &tex.data2)?;
+        } else {
+            put(&mut tex_block, off, &tex.data1)?;
+        }
+
+        params.push(TextureParams((tex.params.0 & !0xffff) | (off >> 3) as u32));
+    }
+
+    Some(Placement {
+        params,
+        tex_block: tex_block.0,
+        compressed1_block: compressed1_block.0,
+        compressed2_block: compressed2_block.0,
+    })
+}
+
+fn round_up(x: usize, n: usize) -> usize {
+    x.div_ceil(n) * n
+}
@@ -38,6 +38,8 @@ pub struct DynamicState<'a> {
     pub objects: &'a [Matrix4<f64>],
     /// UV-transform matrices to use for each material.
     pub uv_mats: &'a [Matrix4<f64>],
+    /// Whether each object is visible.
+    pub visibility: &'a [bool],
 }
 
 /// Info about the result of a draw call, ie. the result of drawing a piece
@@ -62,6 +64,11 @@ pub struct DrawCall {
     pub used_vertex_color: bool,
     /// Whether normals were set during this call.
     pub used_normals: bool,
+    /// Whether the object controlling visibility was visible. If not, all
+    /// the vertices of this call are collapsed to the origin.
+    pub visible: bool,
+    /// The object controlling visibility, if any.
+    pub vis_object: Option<u8>,
 }
 
 #[derive(Copy, Clone)]
@@ -85,6 +92,7 @@ impl Default for Vertex {
 }
 
 // For glium
+#[cfg(feature = "viewer")]
 implement_vertex!(Vertex, position, texcoord, color, normal);
 
 impl Primitives {
@@ -96,22 +104,34 @@ impl Primitives {
                 Op::LoadMatrix { stack_pos } => b.load_matrix(stack_pos),
                 Op::StoreMatrix { stack_pos } => b.store_matrix(stack_pos),
                 Op::MulObject { object_idx } => b.mul_by_object(object_idx),
-                Op::Skin { ref terms } => b.blend(&*terms),
+                Op::Skin { ref terms } => b.blend(terms),
                 Op::ScaleUp => b.scale_up(),
                 Op::ScaleDown => b.scale_down(),
+                Op::SetVisibility { object_idx, .. } => b.set_visibility(object_idx),
                 Op::BindMaterial { material_idx } => b.bind_material(material_idx),
                 Op::Draw { piece_idx } => b.draw(piece_idx),
             }
         }
         b.done()
     }
+
+    /// Drops the draw calls for pieces under hidden objects, for consumers
+    /// that can't animate visibility anyway and would otherwise get their
+    /// collapsed faces. Their vertices are kept, so indices stay valid.
+    pub fn remove_hidden_draw_calls(&mut self) {
+        self.draw_calls.retain(|call| call.visible);
+    }
 }
 
 struct GpuState {
     cur_matrix: Matrix4<f64>,
     matrix_stack: Vec<Matrix4<f64>>,
-    /// TODO: texture transforms are barely implemented atm
     texture_matrix: Matrix4<f64>,
+    /// Where texcoords come from and how the texture matrix applies to them
+    /// (see `TextureParams::texcoord_transform_mode`).
+    texcoord_mode: u8,
+    /// The last texcoord set with a TexCoord command.
+    texcoord: Point2<f64>,
 }
 
 impl GpuState {
@@ -120,6 +140,8 @@ impl GpuState {
             cur_matrix: Matrix4::one(),
             matrix_stack: vec![Matrix4::one(); 32],
             texture_matrix: Matrix4::one(),
+            texcoord_mode: 0,
+            texcoord: Point2::new(0.0, 0.0),
         }
     }
     fn restore(&mut self, stack_pos: u8) {
@@ -141,6 +163,10 @@ struct Builder<'a, 'b> {
     gpu: GpuState,
     cur_texture_dim: (u32, u32),
     cur_material: u8,
+    /// The object controlling the visibility of draws, and whether it is
+    /// visible.
+    cur_vis_object: Option<u8>,
+    cur_visible: bool,
     vertices: Vec<Vertex>,
     indices: Vec<u16>,
     draw_calls: Vec<DrawCall>,
@@ -163,6 +189,8 @@ impl<'a, 'b> Builder<'a, 'b> {
             indices: vec![],
             draw_calls: vec![],
             cur_material: 0,
+            cur_vis_object: None,
+            cur_visible: true,
             cur_draw_call: DrawCall {
                 vertex_range: 0..0,
                 index_range: 0..0,
@@ -171,6 +199,8 @@ impl<'a, 'b> Builder<'a, 'b> {
                 used_texcoords: false,
                 used_vertex_color: false,
                 used_normals: false,
+                visible: true,
+                vis_object: None,
             },
             cur_texture_dim: (1,1),
             prim_type: 0,
@@ -194,6 +224,8 @@ impl<'a, 'b> Builder<'a, 'b> {
             used_texcoords: false,
             used_vertex_color: false,
             used_normals: false,
+            visible: self.cur_visible,
+            vis_object: self.cur_vis_object,
         };
 
         self.next_vertex = Default::default();
@@ -248,6 +280,11 @@ impl<'a, 'b> Builder<'a, 'b> {
         self.gpu.mul_matrix(&Matrix4::from_scale(self.model.down_scale));
     }
 
+    fn set_visibility(&mut self, object_idx: u8) {
+        self.cur_vis_object = Some(object_idx);
+        self.cur_visible = self.state.visibility[object_idx as usize];
+    }
+
     fn bind_material(&mut self, material_idx: u8) {
         self.cur_material = material_idx;
     }
@@ -259,6 +296,7 @@ impl<'a, 'b> Builder<'a, 'b> {
         let dim = (mat.width as u32, mat.height as u32);
         self.cur_texture_dim = dim;
         self.gpu.texture_matrix = mat.texture_mat;
+        self.gpu.texcoord_mode = mat.params.texcoord_transform_mode();
 
         self.begin_draw_call(piece_idx, cur_material);
 
@@ -271,6 +309,18 @@ impl<'a, 'b> Builder<'a, 'b> {
         self.end_draw_call();
     }
 
+    /// Sets the texcoord (in texel space) for the next vertex.
+    fn set_texcoord(&mut self, texcoord: Point2<f64>) {
+        self.cur_draw_call.used_texcoords = true;
+
+        // Transform into OpenGL-type [0,1]x[0,1] texture space.
+        let texcoord = Point2::new(
+            texcoord.x / self.cur_texture_dim.0 as f64,
+            1.0 - texcoord.y / self.cur_texture_dim.1 as f64, // y-down to y-up
+        );
+        self.next_vertex.texcoord = [texcoord.x as f32, texcoord.y as f32];
+    }
+
     fn begin_prim(&mut self, prim_type: u32) {
         self.end_prim();
         self.first_vertex_in_prim = self.vertices.len() as u16;
@@ -379,17 +429,16 @@ fn run_gpu_cmds(b: &mut Builder, commands: &[u8]) {
             GpuCmd::Begin { prim_type } => b.begin_prim(prim_type),
             GpuCmd::End => b.end_prim(),
             GpuCmd::TexCoord { texcoord } => {
-                b.cur_draw_call.used_texcoords = true;
-
-                // Apply texture matrix
-                let texcoord = b.gpu.texture_matrix * vec4(texcoord.x, texcoord.y, 0.0, 1.0);
-
-                // Transform into OpenGL-type [0,1]x[0,1] texture space.
-                let texcoord = Point2::new(
-                    texcoord.x / b.cur_texture_dim.0 as f64,
-                    1.0 - texcoord.y / b.cur_texture_dim.1 as f64, // y-down to y-up
-                );
-                b.next_vertex.texcoord = [texcoord.x as f32, texcoord.y as f32];
+                b.gpu.texcoord = texcoord;
+
+                // In modes 2 and 3, the texcoord is computed when the normal
+                // or vertex is given. Mode 0 (no transform) is treated like
+                // mode 1; the texture matrix is the identity for materials
+                // without one anyway.
+                if b.gpu.texcoord_mode < 2 {
+                    let st = b.gpu.texture_matrix * vec4(texcoord.x, texcoord.y, 0.0, 1.0);
+                    b.set_texcoord(Point2::new(st.x, st.y));
+                }
             }
             // On the real DS, a normal command computes the lighting factor for
             // the current lights from the normal and sets that as the vertex
@@ -406,10 +455,37 @@ fn run_gpu_cmds(b: &mut Builder, commands: &[u8]) {
                 let n = b.gpu.cur_matrix.transform_vector(normal).normalize();
                 b.next_vertex.normal = [n.x as f32, n.y as f32, n.z as f32];
                 b.next_vertex.color = [1.0, 1.0, 1.0];
+
+                if b.gpu.texcoord_mode == 2 {
+                    // Texcoord from normal, used for environment mapping. The
+                    // runtime builds the matrix from the camera; we don't
+                    // have one, so use the normal in model space, mapping the
+                    // unit sphere onto the texture.
+                    let (w, h) = (b.cur_texture_dim.0 as f64, b.cur_texture_dim.1 as f64);
+                    let s = (0.5 + 0.5 * n.x) * w;
+                    let t = (0.5 - 0.5 * n.y) * h;
+                    let st = b.gpu.texture_matrix * vec4(s, t, 0.0, 1.0);
+                    b.set_texcoord(Point2::new(st.x, st.y));
+                }
             }
             GpuCmd::Vertex { position } => {
+                if b.gpu.texcoord_mode == 3 {
+                    // Texcoord from vertex: (s, t) + the untransformed
+                    // position times the texture matrix.
+                    let m = b.gpu.texture_matrix;
+                    let st = m * vec4(position.x, position.y, position.z, 0.0);
+                    let st = Point2::new(b.gpu.texcoord.x + st.x, b.gpu.texcoord.y + st.y);
+                    b.set_texcoord(st);
+                }
+
                 let p = b.gpu.cur_matrix.transform_point(position);
                 b.next_vertex.position = [p.x as f32, p.y as f32, p.z as f32];
+                if !b.cur_visible {
+                    // Hidden pieces still produce vertices, so the vertex
+                    // count doesn't change as visibility is animated. Collapse
+                    // them to a point instead.
+                    b.next_vertex.position = [0.0, 0.0, 0.0];
+                }
                 b.vertices.push(b.next_vertex);
             }
         }
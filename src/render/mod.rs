@@ -0,0 +1,307 @@
+//! Headless rendering of models to PNGs.
+//!
+//! Builds the same primitives the viewer does and draws them with a small
+//! software rasterizer, so it works without a GPU or a window (eg. for making
+//! thumbnails on a server).
+
+mod raster;
+
+use self::raster::{ClipVertex, Cull, Framebuffer, Texture, Wrap};
+use crate::cli::Args;
+use crate::cli::parse_size;
+use crate::connection::{Connection, ConnectionOptions};
+use crate::convert::write_rgba;
+use crate::db::{AnimationId, Database, ModelId};
+use crate::errors::Result;
+use crate::nds::decode_texture;
+use crate::primitives::{DynamicState, PolyType, Primitives};
+use crate::select::Selection;
+use crate::util::namers::UniqueNamer;
+use crate::util::OutDir;
+use cgmath::{vec3, vec4, EuclideanSpace, InnerSpace, Matrix4, PerspectiveFov, Point3, Rad};
+use std::path::PathBuf;
+
+/// Vertical field of view (same as the viewer).
+static FOV_Y: f32 = 1.1;
+/// Angle the camera looks down at the model from.
+static ALTITUDE: f32 = 0.35;
+/// Direction of the light (same as the viewer).
+static LIGHT_VEC: [f32; 3] = [0.0, -0.624695, -0.78086877];
+
+pub fn main(args: &Args) -> Result<()> {
+    let out_dir_path = PathBuf::from(args.get_opt("output").unwrap());
+    let mut out_dir = OutDir::new(out_dir_path)?;
+
+    let db = Database::from_cli_args(args)?;
+
+    db.print_status();
+
+    let conn_options = ConnectionOptions::from_cli_args(args)?;
+    let conn = Connection::build(&db, conn_options);
+
+    let selection = Selection::from_cli_args(args);
+    let opt_str = |name| args.get_opt(name).map(|s| s.to_string_lossy().into_owned());
+    let frame = opt_str("frame").map(|s| s.parse::<u16>().unwrap()).unwrap_or(0);
+    let turntable = opt_str("turntable").map(|s| s.parse::<u16>().unwrap().max(1));
+    let size = opt_str("size").and_then(|s| parse_size(&s)).unwrap_or((256, 256));
+
+    let mut models_rendered = 0;
+    let mut pngs_written = 0;
+
+    let mut file_namer = UniqueNamer::new();
+
+    for model_id in selection.model_ids(&db) {
+        let model = &db.models[model_id];
+
+        let anim_id = match selection.animation {
+            None => None,
+            Some(ref pattern) => {
+                let anim_id = conn.models[model_id].animations.iter()
+                    .find(|&&id| pattern.matches(id, &db.animations[id].name));
+                match anim_id {
+                    Some(&id) => Some(id),
+                    None => {
+                        warn!("no animation matching {} goes with model {}, skipping",
+                            pattern, model.name);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        debug!("Rendering model {} ({})...", model.name, model_id);
+
+        let scene = Scene::build(&db, &conn, model_id, anim_id, frame);
+        let name = file_namer.get_fresh_name(format!("{}", model.name.print_safe()));
+
+        let angles = match turntable {
+            None => vec![0.0],
+            Some(n) => (0..n)
+                .map(|i| i as f32 / n as f32 * 2.0 * std::f32::consts::PI)
+                .collect(),
+        };
+        for (i, &azimuth) in angles.iter().enumerate() {
+            let rgba = scene.render(size, azimuth);
+
+            let file_name = match turntable {
+                None => format!("{}.png", name),
+                Some(_) => format!("{}_{:03}.png", name, i),
+            };
+            let mut png_file = out_dir.create_file(&file_name)?;
+            match write_rgba(&mut png_file, &rgba, size) {
+                Ok(()) => { pngs_written += 1; }
+                Err(e) => error!("failed writing PNG: {}", e),
+            }
+        }
+
+        models_rendered += 1;
+    }
+
+    let plural = |x| if x != 1 { "s" } else { "" };
+    println!("Rendered {} model{}, {} PNG{}.",
+        models_rendered, plural(models_rendered),
+        pngs_written, plural(pngs_written));
+
+    Ok(())
+}
+
+/// Everything needed to draw a posed model.
+struct Scene<'a> {
+    db: &'a Database,
+    model_id: ModelId,
+    prims: Primitives,
+    /// Decoded image for each material. None for materials without one.
+    images: Vec<MaterialImage>,
+    /// Bounding sphere for the model.
+    center: Point3<f32>,
+    radius: f32,
+}
+
+enum MaterialImage {
+    None,
+    /// Decoded RGBA and dimensions.
+    Image(Vec<u8>, (u32, u32)),
+    /// The material has a texture but we couldn't find it.
+    Missing,
+}
+
+impl<'a> Scene<'a> {
+    fn build(
+        db: &'a Database,
+        conn: &Connection,
+        model_id: ModelId,
+        anim_id: Option<AnimationId>,
+        frame: u16,
+    ) -> Scene<'a> {
+        let model = &db.models[model_id];
+
+        let mut objects = model.objects.iter()
+            .map(|obj| obj.matrix)
+            .collect::<Vec<_>>();
+        if let Some(anim_id) = anim_id {
+            let anim = &db.animations[anim_id];
+            if frame >= anim.num_frames {
+                warn!("animation {} only has {} frames", anim.name, anim.num_frames);
+            }
+            let frame = frame.min(anim.num_frames.saturating_sub(1));
+            for (i, curves) in anim.objects_curves.iter().enumerate().take(objects.len()) {
+                objects[i] = curves.sample_at(frame);
+            }
+        }
+        let uv_mats = model.materials.iter()
+            .map(|mat| mat.texture_mat)
+            .collect::<Vec<_>>();
+        let visibility = model.objects.iter()
+            .map(|obj| obj.visible)
+            .collect::<Vec<_>>();
+        let state = DynamicState {
+            objects: &objects,
+            uv_mats: &uv_mats,
+            visibility: &visibility,
+        };
+        let prims = Primitives::build(model, PolyType::Tris, state);
+
+        let images = conn.models[model_id].materials.iter()
+            .map(|mat_conn| {
+                match mat_conn.image_id() {
+                    Ok(Some((texture_id, palette_id))) => {
+                        let texture = &db.textures[texture_id];
+                        let palette = palette_id.map(|id| &db.palettes[id]);
+                        match decode_texture(texture, palette) {
+                            Ok(rgba) => MaterialImage::Image(rgba.0, texture.params.dim()),
+                            Err(_) => MaterialImage::Missing,
+                        }
+                    }
+                    Ok(None) => MaterialImage::None,
+                    Err(_) => MaterialImage::Missing,
+                }
+            })
+            .collect();
+
+        let (center, radius) = bounding_sphere(&prims);
+
+        Scene { db, model_id, prims, images, center, radius }
+    }
+
+    /// Renders the model seen from the given azimuth. Returns RGBA.
+    fn render(&self, (width, height): (u32, u32), azimuth: f32) -> Vec<u8> {
+        let model = &self.db.models[self.model_id];
+        let aspect = width as f32 / height as f32;
+
+        // Back the camera up until the bounding sphere fits in the narrower
+        // of the two fields of view.
+        let fov_x = 2.0 * ((FOV_Y / 2.0).tan() * aspect).atan();
+        let fov = FOV_Y.min(fov_x);
+        let dist = self.radius / (fov / 2.0).sin();
+        let dir = vec3(
+            azimuth.sin() * ALTITUDE.cos(),
+            ALTITUDE.sin(),
+            azimuth.cos() * ALTITUDE.cos(),
+        );
+        let eye = self.center + dir * dist;
+        let view = Matrix4::look_at(eye, self.center, vec3(0.0, 1.0, 0.0));
+        let persp: Matrix4<f32> = PerspectiveFov {
+            fovy: Rad(FOV_Y),
+            aspect,
+            near: (dist - self.radius * 1.01).max(dist * 0.01),
+            far: dist + self.radius * 1.01,
+        }.into();
+        let matrix = persp * view;
+
+        let mut fb = Framebuffer::new(width, height);
+
+        let white = [255, 255, 255, 255];
+        let magenta = [255, 0, 255, 255];
+
+        for call in &self.prims.draw_calls {
+            let material = &model.materials[call.mat_id as usize];
+
+            let cull = match (material.cull_backface, material.cull_frontface) {
+                (false, false) => Cull::None,
+                (true, false) => Cull::Clockwise,
+                (false, true) => Cull::CounterClockwise,
+                (true, true) => continue,
+            };
+
+            let wrap_fn = |repeat, mirror| {
+                match (repeat, mirror) {
+                    (false, _) => Wrap::Clamp,
+                    (true, false) => Wrap::Repeat,
+                    (true, true) => Wrap::Mirror,
+                }
+            };
+            let params = &material.params;
+            let wrap = (
+                wrap_fn(params.repeat_s(), params.mirror_s()),
+                wrap_fn(params.repeat_t(), params.mirror_t()),
+            );
+            let texture = match self.images[call.mat_id as usize] {
+                MaterialImage::Image(ref rgba, dim) => Texture { rgba, dim, wrap },
+                MaterialImage::None => Texture { rgba: &white, dim: (1, 1), wrap },
+                MaterialImage::Missing => Texture { rgba: &magenta, dim: (1, 1), wrap },
+            };
+
+            let light = vec3(LIGHT_VEC[0], LIGHT_VEC[1], LIGHT_VEC[2]);
+            let shade = |v: &crate::primitives::Vertex| -> ClipVertex {
+                let p = v.position;
+                let position = matrix * vec4(p[0], p[1], p[2], 1.0);
+
+                let mut color = v.color;
+                if call.used_normals {
+                    let n = vec3(v.normal[0], v.normal[1], v.normal[2]);
+                    let diff_level = (-light.dot(n)).max(0.0);
+                    for (k, x) in color.iter_mut().enumerate().take(3) {
+                        *x *= material.emission[k]
+                            + material.diffuse[k] * diff_level
+                            + material.ambient[k];
+                    }
+                }
+
+                ClipVertex {
+                    position,
+                    texcoord: v.texcoord,
+                    color: [color[0], color[1], color[2], material.alpha],
+                }
+            };
+
+            let indices = &self.prims.indices[call.index_range.clone()];
+            for tri in indices.chunks(3) {
+                if tri.len() < 3 { break; }
+                let vs = [
+                    shade(&self.prims.vertices[tri[0] as usize]),
+                    shade(&self.prims.vertices[tri[1] as usize]),
+                    shade(&self.prims.vertices[tri[2] as usize]),
+                ];
+                fb.draw_triangle(vs, &texture, cull);
+            }
+        }
+
+        fb.to_rgba()
+    }
+}
+
+/// Finds a sphere around all the vertices that get drawn.
+fn bounding_sphere(prims: &Primitives) -> (Point3<f32>, f32) {
+    let mut min = vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+    let mut max = -min;
+    for &i in &prims.indices {
+        let p = prims.vertices[i as usize].position;
+        for k in 0..3 {
+            min[k] = min[k].min(p[k]);
+            max[k] = max[k].max(p[k]);
+        }
+    }
+    if min.x > max.x {
+        // Nothing to draw
+        return (Point3::origin(), 1.0);
+    }
+
+    let center = Point3::from_vec((min + max) / 2.0);
+    let radius = prims.indices.iter()
+        .map(|&i| {
+            let p = prims.vertices[i as usize].position;
+            (Point3::new(p[0], p[1], p[2]) - center).magnitude()
+        })
+        .fold(0.0, f32::max);
+    (center, radius.max(1e-3))
+}
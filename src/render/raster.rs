@@ -0,0 +1,274 @@
+//! A small software rasterizer.
+//!
+//! Draws triangles into a color buffer with a depth buffer, doing about what
+//! the viewer's shaders do on the GPU: perspective-correct texcoords and
+//! colors, nearest-neighbor texture sampling, discarding fully transparent
+//! fragments, and alpha blending.
+
+use cgmath::Vector4;
+
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    /// Premultiplied RGBA, starting from the top-left.
+    color: Vec<[f32; 4]>,
+    depth: Vec<f32>,
+}
+
+/// A vertex ready to be rasterized.
+#[derive(Copy, Clone)]
+pub struct ClipVertex {
+    /// Position in clip space.
+    pub position: Vector4<f32>,
+    /// Texcoord in `[0,1]x[0,1]` space (y-up).
+    pub texcoord: [f32; 2],
+    pub color: [f32; 4],
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Wrap {
+    Clamp,
+    Repeat,
+    Mirror,
+}
+
+pub struct Texture<'a> {
+    /// RGBA rows, starting from the top.
+    pub rgba: &'a [u8],
+    pub dim: (u32, u32),
+    pub wrap: (Wrap, Wrap),
+}
+
+/// Which triangles to skip, by their winding on the screen.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Cull {
+    None,
+    Clockwise,
+    CounterClockwise,
+}
+
+impl Framebuffer {
+    /// Makes a framebuffer cleared to transparent black.
+    pub fn new(width: u32, height: u32) -> Framebuffer {
+        let n = width as usize * height as usize;
+        Framebuffer {
+            width,
+            height,
+            color: vec![[0.0; 4]; n],
+            depth: vec![1.0; n],
+        }
+    }
+
+    /// Converts to straight-alpha 8-bit RGBA.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(4 * self.color.len());
+        for &[r, g, b, a] in &self.color {
+            let unpremul = |x: f32| if a == 0.0 { 0.0 } else { x / a };
+            let to_u8 = |x: f32| (x.clamp(0.0, 1.0) * 255.0 + 0.5) as u8;
+            rgba.extend_from_slice(&[
+                to_u8(unpremul(r)),
+                to_u8(unpremul(g)),
+                to_u8(unpremul(b)),
+                to_u8(a),
+            ]);
+        }
+        rgba
+    }
+
+    pub fn draw_triangle(&mut self, vs: [ClipVertex; 3], texture: &Texture, cull: Cull) {
+        // Clip against the near plane (z > -w). The camera is placed so the
+        // model is entirely in front of it, so the other planes are left to
+        // the scissoring and depth test in raster_triangle.
+        let inside = |v: &ClipVertex| v.position.z > -v.position.w;
+        if vs.iter().all(inside) {
+            self.raster_triangle(vs, texture, cull);
+            return;
+        }
+
+        let mut poly: Vec<ClipVertex> = Vec::with_capacity(4);
+        for i in 0..3 {
+            let a = &vs[i];
+            let b = &vs[(i + 1) % 3];
+            if inside(a) {
+                poly.push(*a);
+            }
+            if inside(a) != inside(b) {
+                let da = a.position.z + a.position.w;
+                let db = b.position.z + b.position.w;
+                poly.push(lerp_vertex(a, b, da / (da - db)));
+            }
+        }
+        for i in 1..poly.len().saturating_sub(1) {
+            self.raster_triangle([poly[0], poly[i], poly[i + 1]], texture, cull);
+        }
+    }
+
+    fn raster_triangle(&mut self, vs: [ClipVertex; 3], texture: &Texture, cull: Cull) {
+        let (w, h) = (self.width as f32, self.height as f32);
+
+        // Screen-space positions (y-down) and 1/w
+        let mut sx = [0.0; 3];
+        let mut sy = [0.0; 3];
+        let mut sz = [0.0; 3];
+        let mut inv_w = [0.0; 3];
+        for i in 0..3 {
+            let p = vs[i].position;
+            inv_w[i] = 1.0 / p.w;
+            sx[i] = (p.x * inv_w[i] * 0.5 + 0.5) * w;
+            sy[i] = (0.5 - p.y * inv_w[i] * 0.5) * h;
+            sz[i] = p.z * inv_w[i];
+        }
+
+        // Twice the signed area. Since y points down on the screen, this is
+        // positive for triangles that are clockwise in GL's window space.
+        let area = (sx[1] - sx[0]) * (sy[2] - sy[0]) - (sx[2] - sx[0]) * (sy[1] - sy[0]);
+        if area == 0.0 || !area.is_finite() {
+            return;
+        }
+        match cull {
+            Cull::Clockwise if area > 0.0 => return,
+            Cull::CounterClockwise if area < 0.0 => return,
+            _ => (),
+        }
+
+        let min_x = sx.iter().cloned().fold(w, f32::min).max(0.0) as u32;
+        let max_x = sx.iter().cloned().fold(0.0, f32::max).min(w - 1.0).max(0.0) as u32;
+        let min_y = sy.iter().cloned().fold(h, f32::min).max(0.0) as u32;
+        let max_y = sy.iter().cloned().fold(0.0, f32::max).min(h - 1.0).max(0.0) as u32;
+
+        let edge = |i: usize, j: usize, x: f32, y: f32| {
+            (sx[j] - sx[i]) * (y - sy[i]) - (x - sx[i]) * (sy[j] - sy[i])
+        };
+
+        for py in min_y..=max_y {
+            for px in min_x..=max_x {
+                let (x, y) = (px as f32 + 0.5, py as f32 + 0.5);
+
+                // Barycentric coordinates
+                let l0 = edge(1, 2, x, y) / area;
+                let l1 = edge(2, 0, x, y) / area;
+                let l2 = edge(0, 1, x, y) / area;
+                if l0 < 0.0 || l1 < 0.0 || l2 < 0.0 {
+                    continue;
+                }
+
+                let z = l0 * sz[0] + l1 * sz[1] + l2 * sz[2];
+                if !(-1.0..=1.0).contains(&z) {
+                    continue;
+                }
+                let idx = py as usize * self.width as usize + px as usize;
+                if z >= self.depth[idx] {
+                    continue;
+                }
+
+                // Perspective-correct interpolation
+                let (p0, p1, p2) = (l0 * inv_w[0], l1 * inv_w[1], l2 * inv_w[2]);
+                let norm = 1.0 / (p0 + p1 + p2);
+                let interp = |a: f32, b: f32, c: f32| (p0 * a + p1 * b + p2 * c) * norm;
+
+                let mut color: [f32; 4] = std::array::from_fn(|k| {
+                    interp(vs[0].color[k], vs[1].color[k], vs[2].color[k])
+                });
+                let u = interp(vs[0].texcoord[0], vs[1].texcoord[0], vs[2].texcoord[0]);
+                let v = interp(vs[0].texcoord[1], vs[1].texcoord[1], vs[2].texcoord[1]);
+                let texel = texture.sample(u, v);
+                for k in 0..4 {
+                    color[k] *= texel[k];
+                }
+
+                let a = color[3];
+                if a <= 0.0 {
+                    continue;
+                }
+
+                self.depth[idx] = z;
+                let dst = &mut self.color[idx];
+                for k in 0..3 {
+                    dst[k] = color[k] * a + dst[k] * (1.0 - a);
+                }
+                dst[3] = a + dst[3] * (1.0 - a);
+            }
+        }
+    }
+}
+
+impl<'a> Texture<'a> {
+    /// Nearest-neighbor sample at a texcoord.
+    fn sample(&self, u: f32, v: f32) -> [f32; 4] {
+        let (w, h) = (self.dim.0 as i64, self.dim.1 as i64);
+        // Texcoords are y-up, the image rows are y-down.
+        let x = wrap((u * w as f32).floor() as i64, w, self.wrap.0);
+        let y = wrap(((1.0 - v) * h as f32).floor() as i64, h, self.wrap.1);
+        let i = 4 * (y * w + x) as usize;
+        let c = &self.rgba[i..i + 4];
+        [
+            c[0] as f32 / 255.0,
+            c[1] as f32 / 255.0,
+            c[2] as f32 / 255.0,
+            c[3] as f32 / 255.0,
+        ]
+    }
+}
+
+fn wrap(x: i64, n: i64, mode: Wrap) -> i64 {
+    match mode {
+        Wrap::Clamp => x.max(0).min(n - 1),
+        Wrap::Repeat => x.rem_euclid(n),
+        Wrap::Mirror => {
+            let x = x.rem_euclid(2 * n);
+            if x < n { x } else { 2 * n - 1 - x }
+        }
+    }
+}
+
+fn lerp_vertex(a: &ClipVertex, b: &ClipVertex, t: f32) -> ClipVertex {
+    let lerp = |x: f32, y: f32| x + (y - x) * t;
+    ClipVertex {
+        position: a.position + (b.position - a.position) * t,
+        texcoord: [
+            lerp(a.texcoord[0], b.texcoord[0]),
+            lerp(a.texcoord[1], b.texcoord[1]),
+        ],
+        color: [
+            lerp(a.color[0], b.color[0]),
+            lerp(a.color[1], b.color[1]),
+            lerp(a.color[2], b.color[2]),
+            lerp(a.color[3], b.color[3]),
+        ],
+    }
+}
+
+#[test]
+fn test_wrap() {
+    assert_eq!(wrap(5, 4, Wrap::Clamp), 3);
+    assert_eq!(wrap(-1, 4, Wrap::Repeat), 3);
+    assert_eq!(wrap(4, 4, Wrap::Mirror), 3);
+    assert_eq!(wrap(-1, 4, Wrap::Mirror), 0);
+}
+
+#[test]
+fn test_draw_triangle() {
+    use cgmath::vec4;
+
+    // A big counter-clockwise triangle covering the middle of the screen
+    let v = |x, y| ClipVertex {
+        position: vec4(x, y, 0.0, 1.0),
+        texcoord: [0.0, 0.0],
+        color: [1.0, 0.5, 0.0, 1.0],
+    };
+    let tri = [v(-1.0, -1.0), v(1.0, -1.0), v(0.0, 1.0)];
+    let white = [255, 255, 255, 255];
+    let texture = Texture { rgba: &white, dim: (1, 1), wrap: (Wrap::Clamp, Wrap::Clamp) };
+
+    let mut fb = Framebuffer::new(4, 4);
+    fb.draw_triangle(tri, &texture, Cull::Clockwise);
+    let rgba = fb.to_rgba();
+    let pixel = |x: usize, y: usize| &rgba[4 * (y * 4 + x)..4 * (y * 4 + x) + 4];
+    assert_eq!(pixel(1, 2), &[255, 128, 0, 255]);
+    assert_eq!(pixel(0, 0), &[0, 0, 0, 0]);
+
+    // Culled when it's facing the other way
+    let mut fb = Framebuffer::new(4, 4);
+    fb.draw_triangle(tri, &texture, Cull::CounterClockwise);
+    assert!(fb.to_rgba().iter().all(|&x| x == 0));
+}
@@ -0,0 +1,135 @@
+//! Picking out models and animations with the --model, --file, --animation,
+//! and --start options.
+//!
+//! Whole directories of textures and animations are often loaded just so they
+//! can be connected to one model; these say which model(s) we actually want.
+
+use crate::cli::Args;
+use crate::connection::Connection;
+use crate::db::{Database, ModelId};
+use crate::nitro::Name;
+use regex::Regex;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Matches things by index or by name. The name is a regex, which must match
+/// the whole name (so a plain name matches just itself).
+pub struct NamePattern {
+    pattern: String,
+    index: Option<usize>,
+    regex: Option<Regex>,
+}
+
+impl NamePattern {
+    pub fn new(pattern: &str) -> NamePattern {
+        NamePattern {
+            pattern: pattern.to_string(),
+            index: pattern.parse().ok(),
+            regex: Regex::new(&format!("^(?:{})$", pattern)).ok(),
+        }
+    }
+
+    pub fn matches(&self, index: usize, name: &Name) -> bool {
+        if self.index == Some(index) {
+            return true;
+        }
+        let name = name.to_string();
+        match self.regex {
+            Some(ref regex) => regex.is_match(&name),
+            // Not a valid regex; compare it literally
+            None => name == self.pattern,
+        }
+    }
+}
+
+impl fmt::Display for NamePattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+#[derive(Default)]
+pub struct Selection {
+    /// Only use models matching this.
+    pub model: Option<NamePattern>,
+    /// Only use models from files whose paths end with this.
+    pub file: Option<PathBuf>,
+    /// Only attach animations matching this.
+    pub animation: Option<NamePattern>,
+}
+
+impl Selection {
+    /// Creates a Selection from the CLI arguments.
+    pub(crate) fn from_cli_args(args: &Args) -> Selection {
+        let opt_str = |name| args.get_opt(name).map(|s| s.to_string_lossy().into_owned());
+        Selection {
+            model: opt_str("model").map(|s| NamePattern::new(&s)),
+            file: args.get_opt("file").map(PathBuf::from),
+            animation: opt_str("animation").map(|s| NamePattern::new(&s)),
+        }
+    }
+
+    pub fn wants_model(&self, db: &Database, model_id: ModelId) -> bool {
+        if let Some(ref pattern) = self.model {
+            if !pattern.matches(model_id, &db.models[model_id].name) {
+                return false;
+            }
+        }
+        if let Some(ref file) = self.file {
+            let path = &db.file_paths[db.models_found_in[model_id]];
+            if !path.ends_with(file) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// The IDs of the selected models. Warns if there aren't any.
+    pub fn model_ids(&self, db: &Database) -> Vec<ModelId> {
+        let model_ids = (0..db.models.len())
+            .filter(|&model_id| self.wants_model(db, model_id))
+            .collect::<Vec<_>>();
+        if model_ids.is_empty() && !db.models.is_empty() {
+            match (&self.model, &self.file) {
+                (Some(pattern), Some(file)) =>
+                    warn!("no models matching {} in {}", pattern, file.display()),
+                (Some(pattern), None) => warn!("no models matching {}", pattern),
+                (None, Some(file)) => warn!("no models in {}", file.display()),
+                (None, None) => (),
+            }
+        }
+        model_ids
+    }
+
+    /// Detaches the animations that don't match --animation from every model.
+    pub fn filter_animations(&self, db: &Database, conn: &mut Connection) {
+        let pattern = match self.animation {
+            Some(ref pattern) => pattern,
+            None => return,
+        };
+        for mdl_conn in &mut conn.models {
+            let (animations, scores) = mdl_conn.animations.iter()
+                .zip(mdl_conn.animation_scores.iter())
+                .filter(|&(&id, _)| pattern.matches(id, &db.animations[id].name))
+                .unzip();
+            mdl_conn.animations = animations;
+            mdl_conn.animation_scores = scores;
+        }
+    }
+}
+
+#[test]
+fn test_name_pattern() {
+    let name = |s| Name::from_str_truncated(s);
+    let p = NamePattern::new("hero");
+    assert!(p.matches(0, &name("hero")));
+    assert!(!p.matches(0, &name("superhero")));
+    let p = NamePattern::new("hero_.*");
+    assert!(p.matches(0, &name("hero_walk")));
+    assert!(!p.matches(0, &name("hero")));
+    let p = NamePattern::new("3");
+    assert!(p.matches(3, &name("hero")));
+    assert!(!p.matches(2, &name("hero")));
+    let p = NamePattern::new("a(b");
+    assert!(p.matches(0, &name("a(b")));
+}
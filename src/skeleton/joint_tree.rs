@@ -3,37 +3,47 @@
 //! Our problem is this: given the symbolic matrix M which is to be applied to
 //! each vertex
 //!
+//! ```text
 //!     V(p) = M(p) (vertex pos)
+//! ```
 //!
 //! (where the dependence is on the pose p, ie. the value of the object
 //! matrices) determine a skin which has the same effect. That is, give the
 //! folllowing data
 //!
 //! - a tree of joints
-//! - for every joint j, a local-to-parent transform C[j](p), which is an
+//! - for every joint j, a local-to-parent transform `C[j](p)`, which is an
 //!   SMatrix, the totality of which determine the local-to-world transforms
-//!   A[j](p) by
+//!   `A[j](p)` by
 //!
+//!   ```text
 //!       A[j](p) = C[root](p) ... C[grandparent j](p) C[parent j](p) C[j](p)
+//!   ```
 //!
 //! - for every vertex, a list of influences (w_i, j_i) where w_i is a number
 //!   (the weight) and j_i is a joint
 //!
 //! such that when the skinning equation is applied
 //!
+//! ```text
 //!     v(p) = ∑_i w_i A[j_i](p) A[j_i](rest)^{-1} V(rest)
+//! ```
 //!
 //! we have V(p) = v(p) for every pose p.
 //!
 //! If you had a tree of joints and you wrote down the possible M's you would
 //! get you'd find either
 //!
+//! ```text
 //!     (object) (object) ... (object)
+//! ```
 //!
 //! for vertices that are influenced by a single joint (and (vertex pos) should
 //! be in the space of that joint), or
 //!
+//! ```text
 //!     ∑ (weight) (object) (object) ... (object) (inv bind)
+//! ```
 //!
 //! for vertices influenced by multiple joints (and (vertex pos) should be in
 //! its rest model-space position). The vast majority of matrices I've seen do
@@ -44,12 +54,14 @@
 //!
 //! CONSTANT LEAVES ARE SUPERFLUOUS:
 //!
-//! Suppose we have a solution where there is a leaf joint j with C[j]
-//! independant of p. Then A[j](p) = A[parent j](p) C[j] so
+//! Suppose we have a solution where there is a leaf joint j with `C[j]`
+//! independant of p. Then `A[j](p) = A[parent j](p) C[j]` so
 //!
+//! ```text
 //!     A[j](p) A[j](rest)^{-1} =
 //!     A[parent j](p) C[j] C[j]^{-1} A[parent j](rest)^{-1} =
 //!     A[parent j](p) A[parent j](rest)^{-1}
+//! ```
 //!
 //! so for the purpose of skinning, we might just as well have used j's parent
 //! instead of j in every influence. In other words, constant leaves are
@@ -63,33 +75,38 @@
 //!
 //! Write M as a scaled composition of SMatrices
 //!
+//! ```text
 //!     M(p) = a M1(p) M2(p) ... Mn(p) K1 K2 ... Km = a M'(p) K
+//! ```
 //!
 //! where a is a scalar and  K = K1 ... Km is the longest suffix that does not
 //! depend on the pose. Then by making a chain of joints j_1 -> ... -> j_n with
-//! C[j_i] = M_i we can find a joint j = j_n with A[j] = M'.
+//! `C[j_i] = M_i` we can find a joint j = j_n with `A[j] = M'`.
 //!
 //! Then taking j to be the sole influence with a weight of a we get
 //!
+//! ```text
 //!     v(p) = a A[j](p) A[j](rest)^{-1} V(rest)
 //!          = a M'(p) M'(rest)^{-1} V(rest)
 //!          = a M'(p) M'(rest)^{-1} M'(rest) K (vertex pos)
 //!          = a M'(p) K (vertex pos)
 //!          = M(p) (vertex pos)
 //!          = V(p)
+//! ```
 //!
 //! SOLUTION WHEN M IS A SKINNING MATRIX:
 //!
 //! Suppose M(p) = ∑_i a_i M_i(p) K_i where K_i is the longest suffix of each
 //! term that does not depend on the pose. Comparing to the skinning formula, we
-//! try to interpret this where a_i = w_i, M_i(p) = A[j_i](p), and K_i =
-//! A[j_i](rest)^{-1}. Indeed assuming that
+//! try to interpret this where a_i = w_i, `M_i(p) = A[j_i](p)`, and
+//! `K_i = A[j_i](rest)^{-1}`. Indeed assuming that
 //!
 //! * (I) M_i(rest) K_i = 1, and
 //! * (II) M(rest) = 1, or equivalently, assuming (I), ∑_i a_i = 1
 //!
 //! we get
 //!
+//! ```text
 //!     v(p) = ∑_i w_i A[j_i](p) A[j_i](rest)^{-1} V(rest)
 //!          = ∑_i a_i M_i(p) M_i(rest)^{-1} V(rest)
 //!          = ∑_i a_i M_i(p) K_i V(rest)
@@ -97,6 +114,7 @@
 //!          = ∑_i a_i M_i(p) K_i (vertex pos)
 //!          = M(p) (vertex pos)
 //!          = V(p)
+//! ```
 //!
 //! (There is additionally a trivial solution for M = 0 with an empty influence
 //! list.)
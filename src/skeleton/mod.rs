@@ -8,26 +8,31 @@
 //! and so on all the way up the tree is a the joint's local-to-world transform.
 //! Example:
 //!
+//! ```text
 //!      A       A's local-to-parent = a, B's = b, etc.
 //!     / \      D's local-to-world = a c d
 //!    B   C     D's world-to-local = d^{-1} c^{-1} a^{-1}
 //!       /
 //!      D
+//! ```
 //!
 //! A skin vertex consists of a list of influences, each influence containing a
 //! joint that influences the vertex and a weight controlling how great the
 //! influence is. The vertex's final position is determined by the skinning
 //! equation
 //!
+//! ```text
 //!     (vertex final pos) =
 //!       ∑_{influence}
 //!         (weight) (pose local-to-world) (rest world-to-local) (vertex rest pos)
+//! ```
 //!
 //! See eg. section X in the COLLADA 1.4 spec for more details.
 //!
 //! In NSBMD models, this skeleton data has been compiled into an imperative
 //! list of rendering commands. Example for the above tree:
 //!
+//! ```text
 //!     multiply cur matrix by a
 //!     multiply cur matrix by c
 //!     multiply cur matrix by d
@@ -36,6 +41,7 @@
 //!     restore stack slot 1
 //!     draw vertices for D
 //!     etc.
+//! ```
 //!
 //! Our job here is to essentially reverse this procedure, reconstructing  the
 //! skeleton data from this list of imperative commands.
@@ -28,6 +28,7 @@ impl VertexRecord {
                 Op::Skin { ref terms } => b.skin(&*terms),
                 Op::ScaleUp => b.scale_up(),
                 Op::ScaleDown => b.scale_down(),
+                Op::SetVisibility { .. } => (),
                 Op::BindMaterial { .. } => (),
                 Op::Draw { piece_idx } => b.draw(piece_idx),
             }
@@ -0,0 +1,179 @@
+//! Hand-built databases and models for tests.
+
+use cgmath::{Matrix4, One, Point2, Point3, Vector3};
+use crate::db::{Database, FileId, ModelId};
+use crate::nds::gpu_cmds::CmdWriter;
+use crate::nds::TextureParams;
+use crate::nitro::model::{Material, MaterialRaw, ModelHeader, Object, ObjectRaw, Piece};
+use crate::nitro::render_cmds::{parse_render_cmds, RenderCmdWriter};
+use crate::nitro::texture_matrix::{TexMtxMode, TextureSrt};
+use crate::nitro::{Model, Name};
+use crate::util::cur::Cur;
+
+/// A database with the given files, and nothing found in them yet.
+pub fn db_with_files(paths: &[&str]) -> Database {
+    Database {
+        file_paths: paths.iter().map(|&p| p.into()).collect(),
+        ..Database::default()
+    }
+}
+
+/// Adds a model as if it had been found in the given file.
+pub fn add_model(db: &mut Database, model: Model, file_id: FileId) -> ModelId {
+    db.models.push(model);
+    db.models_found_in.push(file_id);
+    db.models.len() - 1
+}
+
+/// Builds a model out of objects, materials, and triangles. Each object's
+/// matrix is kept in the matrix stack at its own index, and each triangle is
+/// its own piece, drawn under one object.
+pub struct ModelBuilder {
+    name: Name,
+    objects: Vec<(Object, Option<usize>)>,
+    materials: Vec<Material>,
+    draws: Vec<(usize, usize, Piece)>,
+}
+
+/// The corners of every triangle, in its object's space.
+const TRIANGLE: [[f64; 3]; 3] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+
+impl ModelBuilder {
+    pub fn new(name: &str) -> ModelBuilder {
+        ModelBuilder {
+            name: Name::from_str_truncated(name),
+            objects: vec![],
+            materials: vec![],
+            draws: vec![],
+        }
+    }
+
+    /// Adds an object translated from its parent (the first object must be
+    /// the root).
+    pub fn object(mut self, name: &str, parent: Option<usize>, trans: Vector3<f64>) -> ModelBuilder {
+        assert_eq!(parent.is_none(), self.objects.is_empty());
+        let object = Object {
+            name: Name::from_str_truncated(name),
+            trans: Some(trans).filter(|&t| t != Vector3::new(0.0, 0.0, 0.0)),
+            rot: None,
+            scale: None,
+            matrix: Matrix4::from_translation(trans),
+            visible: true,
+            raw: ObjectRaw::default(),
+        };
+        self.objects.push((object, parent));
+        self
+    }
+
+    /// Adds a chain of objects, each one unit along x from its parent.
+    pub fn chain(mut self, names: &[&str]) -> ModelBuilder {
+        for &name in names {
+            let parent = self.objects.len().checked_sub(1);
+            self = self.object(name, parent, Vector3::new(1.0, 0.0, 0.0));
+        }
+        self
+    }
+
+    /// Hides an object at rest.
+    pub fn hide(mut self, object_idx: usize) -> ModelBuilder {
+        self.objects[object_idx].0.visible = false;
+        self
+    }
+
+    /// Adds an untextured material.
+    pub fn material(mut self, name: &str) -> ModelBuilder {
+        self.materials.push(Material {
+            name: Name::from_str_truncated(name),
+            texture_name: None,
+            palette_name: None,
+            params: TextureParams(0),
+            width: 0,
+            height: 0,
+            diffuse: [1.0; 3],
+            diffuse_is_default_vertex_color: true,
+            ambient: [0.5; 3],
+            specular: [0.0; 3],
+            enable_shininess_table: false,
+            emission: [0.0; 3],
+            alpha: 1.0,
+            cull_backface: true,
+            cull_frontface: false,
+            texture_srt: TextureSrt::default(),
+            texture_mat: Matrix4::one(),
+            raw: MaterialRaw::default(),
+        });
+        self
+    }
+
+    /// Draws a triangle with only positions under an object.
+    pub fn triangle(self, object_idx: usize, material_idx: usize) -> ModelBuilder {
+        self.triangle_with(object_idx, material_idx, None, None)
+    }
+
+    /// Draws a triangle under an object, optionally with texcoords (in
+    /// texels) and a normal.
+    pub fn triangle_with(
+        mut self,
+        object_idx: usize,
+        material_idx: usize,
+        texcoords: Option<[[f64; 2]; 3]>,
+        normal: Option<Vector3<f64>>,
+    ) -> ModelBuilder {
+        let mut cw = CmdWriter::new();
+        cw.restore(object_idx as u32);
+        cw.begin(0); // separate triangles
+        for (i, p) in TRIANGLE.iter().enumerate() {
+            if let Some(texcoords) = texcoords {
+                cw.texcoord(Point2::new(texcoords[i][0], texcoords[i][1]));
+            }
+            if let Some(normal) = normal {
+                cw.normal(normal);
+            }
+            cw.vertex(Point3::new(p[0], p[1], p[2]));
+        }
+        cw.end();
+
+        let piece = Piece {
+            name: Name::from_str_truncated(&format!("polygon{}", self.draws.len())),
+            gpu_commands: cw.finish(),
+            unknown: 0,
+        };
+        self.draws.push((object_idx, material_idx, piece));
+        self
+    }
+
+    pub fn build(self) -> Model {
+        let mut w = RenderCmdWriter::new();
+        for (i, (_, parent)) in self.objects.iter().enumerate() {
+            let i = i as u8;
+            match *parent {
+                None => w.mul_object(i, i, None, Some(i)),
+                Some(p) => w.mul_object(i, p as u8, Some(p as u8), Some(i)),
+            }
+        }
+        let mut pieces = vec![];
+        for (piece_idx, (object_idx, material_idx, piece)) in self.draws.into_iter().enumerate() {
+            w.set_visibility(object_idx as u8, self.objects[object_idx].0.visible);
+            w.bind_material(material_idx as u8);
+            w.draw(piece_idx as u8);
+            pieces.push(piece);
+        }
+        let render_cmds = w.finish();
+        let render_ops = parse_render_cmds(Cur::new(&render_cmds)).unwrap();
+
+        Model {
+            name: self.name,
+            materials: self.materials,
+            pieces,
+            objects: self.objects.into_iter().map(|(object, _)| object).collect(),
+            inv_binds: vec![],
+            render_ops,
+            up_scale: 1.0,
+            down_scale: 1.0,
+            tex_mtx_mode: TexMtxMode::Maya,
+            render_cmds,
+            inv_bind_normals: vec![],
+            header: ModelHeader::default(),
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Dump every texture to a PNG, without going through models.
+//!
+//! Each texture is decoded with the palettes whose names match it (by
+//! default) or with every palette it could use (--palettes all). A JSON
+//! manifest describing each image is written alongside the PNGs.
+
+use crate::cli::Args;
+use crate::convert::image_namer::append_pl;
+use crate::convert::write_rgba;
+use crate::db::{Database, PaletteId, TextureId};
+use crate::errors::Result;
+use crate::nds::decode_texture;
+use crate::nitro::{Palette, Texture};
+use crate::util::namers::UniqueNamer;
+use crate::util::OutDir;
+use json::JsonValue;
+use std::io::Write;
+use std::path::PathBuf;
+
+pub fn main(args: &Args) -> Result<()> {
+    let out_dir_path = PathBuf::from(args.get_opt("output").unwrap());
+    let mut out_dir = OutDir::new(out_dir_path)?;
+
+    let db = Database::from_cli_args(args)?;
+
+    db.print_status();
+
+    let all_palettes = args.get_opt("palettes").map(|s| s == "all").unwrap_or(false);
+
+    let mut namer = UniqueNamer::new();
+    let mut manifest = array!();
+    let mut pngs_written = 0;
+    let mut num_unpaired = 0;
+
+    for (texture_id, texture) in db.textures.iter().enumerate() {
+        let palette_ids: Vec<Option<PaletteId>> =
+            if !texture.params.format().desc().requires_palette {
+                vec![None]
+            } else if all_palettes {
+                (0..db.palettes.len())
+                    .filter(|&id| palette_fits(texture, &db.palettes[id]))
+                    .map(Some)
+                    .collect()
+            } else {
+                matching_palettes(&db, texture_id).into_iter().map(Some).collect()
+            };
+
+        if palette_ids.is_empty() {
+            debug!("no palette for texture {}", texture.name);
+            num_unpaired += 1;
+            continue;
+        }
+
+        for palette_id in palette_ids {
+            let palette = palette_id.map(|id| &db.palettes[id]);
+
+            let rgba = match decode_texture(texture, palette) {
+                Ok(rgba) => rgba,
+                Err(e) => {
+                    error!("error decoding texture {}: {}", texture.name, e);
+                    continue;
+                }
+            };
+
+            let image_name = match palette {
+                Some(pal) if all_palettes =>
+                    namer.get_fresh_name(format!("{}-{}", texture.name.print_safe(), pal.name.print_safe())),
+                _ =>
+                    namer.get_fresh_name(texture.name.print_safe().to_string()),
+            };
+            let file_name = format!("{}.png", image_name);
+
+            let dim = texture.params.dim();
+            let mut png_file = out_dir.create_file(&file_name)?;
+            match write_rgba(&mut png_file, &rgba.0[..], dim) {
+                Ok(()) => { pngs_written += 1; }
+                Err(e) => {
+                    error!("failed writing PNG: {}", e);
+                    continue;
+                }
+            }
+
+            manifest.push(manifest_entry(&db, &file_name, texture_id, palette_id)).unwrap();
+        }
+    }
+
+    let mut f = out_dir.create_file("textures.json")?;
+    f.write_all(json::stringify_pretty(manifest, 2).as_bytes())?;
+    f.flush()?;
+
+    if num_unpaired != 0 {
+        info!("{} textures had no palette to go with them{}", num_unpaired,
+            if all_palettes { "" } else { " (try --palettes all)" });
+    }
+
+    let plural = |x| if x != 1 { "s" } else { "" };
+    println!("Wrote {} PNG{} and textures.json.", pngs_written, plural(pngs_written));
+
+    Ok(())
+}
+
+/// Palettes that go with a texture by name: the same name as the texture, or
+/// the texture name plus "_pl". Ones in the same file as the texture win over
+/// ones in other files. If there are none, but the texture's file has only
+/// one palette, that one.
+fn matching_palettes(db: &Database, texture_id: TextureId) -> Vec<PaletteId> {
+    let texture = &db.textures[texture_id];
+    let file_id = db.textures_found_in[texture_id];
+    let mut ids = vec![];
+    for name in &[texture.name, append_pl(&texture.name)] {
+        for &id in db.palettes_by_name.get(name).into_iter().flatten() {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+    }
+
+    if ids.iter().any(|&id| db.palettes_found_in[id] == file_id) {
+        ids.retain(|&id| db.palettes_found_in[id] == file_id);
+    }
+
+    if ids.is_empty() {
+        let in_file = (0..db.palettes.len())
+            .filter(|&id| db.palettes_found_in[id] == file_id)
+            .collect::<Vec<_>>();
+        if in_file.len() == 1 {
+            ids = in_file;
+        }
+    }
+
+    ids.retain(|&id| palette_fits(texture, &db.palettes[id]));
+    ids
+}
+
+/// Whether the palette has enough colors for the texture. Only the colors the
+/// texels actually use count, since palettes are often cut short to the used
+/// colors instead of holding the format's full count.
+fn palette_fits(texture: &Texture, palette: &Palette) -> bool {
+    let data1 = &texture.data1[..];
+    let highest = |bits: u32| {
+        let mask = (1 << bits) - 1;
+        data1.iter()
+            .flat_map(|&b| (0..8 / bits).map(move |i| (b >> (i * bits)) & mask))
+            .max()
+            .map(|x| x as usize + 1)
+            .unwrap_or(0)
+    };
+    let num_colors = match texture.params.format().0 {
+        1 => data1.iter().map(|&b| (b & 0x1f) as usize + 1).max().unwrap_or(0),
+        2 => highest(2),
+        3 => highest(4),
+        4 => highest(8),
+        5 => {
+            // Enough for the highest colors any block uses
+            texture.data2.chunks_exact(2)
+                .map(|x| u16::from_le_bytes([x[0], x[1]]))
+                .map(|extra| (((extra & 0x3fff) as usize) << 1) + 4)
+                .max()
+                .unwrap_or(0)
+        }
+        6 => data1.iter().map(|&b| (b & 0x07) as usize + 1).max().unwrap_or(0),
+        _ => 0,
+    };
+    let len = palette.pal_block.len().saturating_sub(palette.off as usize);
+    2 * num_colors <= len
+}
+
+fn manifest_entry(
+    db: &Database,
+    file_name: &str,
+    texture_id: TextureId,
+    palette_id: Option<PaletteId>,
+) -> JsonValue {
+    let texture = &db.textures[texture_id];
+    let params = texture.params;
+    let source = |file_id: usize| db.file_paths[file_id].to_string_lossy().into_owned();
+
+    let mut entry = object!(
+        "file" => file_name,
+        "texture" => texture.name.print_safe().to_string(),
+        "texture_source" => source(db.textures_found_in[texture_id]),
+        "palette" => JsonValue::Null,
+        "palette_source" => JsonValue::Null,
+        "format" => params.format().desc().name,
+        "format_id" => params.format().0,
+        "width" => params.width(),
+        "height" => params.height(),
+        "is_color0_transparent" => params.is_color0_transparent(),
+        "repeat" => array!(params.repeat_s(), params.repeat_t()),
+        "mirror" => array!(params.mirror_s(), params.mirror_t()),
+    );
+    if let Some(id) = palette_id {
+        entry["palette"] = db.palettes[id].name.print_safe().to_string().into();
+        entry["palette_source"] = source(db.palettes_found_in[id]).into();
+    }
+    entry
+}
+
+#[cfg(test)]
+fn test_db() -> Database {
+    use crate::nds::TextureParams;
+    use crate::nitro::Name;
+    use std::sync::Arc;
+
+    let texture = |name: &str, params: u32, data1: Vec<u8>| Texture {
+        name: Name::from_str_truncated(name),
+        params: TextureParams(params),
+        data1: data1.into(),
+        data2: Default::default(),
+        unknown: 0,
+    };
+    let palette = |name: &str, len: usize| Palette {
+        name: Name::from_str_truncated(name),
+        off: 0,
+        pal_block: Arc::new(vec![0; len].into_boxed_slice()),
+        unknown: 0,
+    };
+
+    let mut db = Database::default();
+    db.file_paths = vec!["a.nsbtx".into(), "b.nsbtx".into(), "c.nsbtx".into()];
+    // 4-color 8x8, repeating in s, color 0 transparent, using all 4 colors
+    db.textures.push(texture("grass", 2 << 26 | 1 << 16 | 1 << 29, vec![0xe4; 16]));
+    // 16-color 8x8, using colors 0 and 15
+    let mut rock = vec![0; 32];
+    rock[5] = 0xf0;
+    db.textures.push(texture("rock", 3 << 26, rock));
+    db.textures.push(texture("sky", 2 << 26, vec![0; 16]));
+    db.textures.push(texture("cloud", 2 << 26, vec![0; 16]));
+    db.textures_found_in = vec![0, 0, 1, 2];
+    db.palettes.push(palette("grass_pl", 8));
+    db.palettes.push(palette("grass", 8));
+    db.palettes.push(palette("rock_pl", 8));
+    db.palettes.push(palette("other", 8));
+    db.palettes.push(palette("misc", 8));
+    db.palettes_found_in = vec![0, 1, 0, 1, 2];
+    for (id, pal) in db.palettes.iter().enumerate() {
+        db.palettes_by_name.entry(pal.name).or_insert(vec![]).push(id);
+    }
+    db
+}
+
+#[test]
+fn test_matching_palettes() {
+    let db = test_db();
+    // The one in the same file wins over the one named exactly the same
+    assert_eq!(matching_palettes(&db, 0), vec![0]);
+    // rock_pl is too small for color 15
+    assert!(matching_palettes(&db, 1).is_empty());
+    // No name matches and more than one palette in the file
+    assert!(matching_palettes(&db, 2).is_empty());
+    // No name matches, but the only palette in the file
+    assert_eq!(matching_palettes(&db, 3), vec![4]);
+}
+
+#[test]
+fn test_palette_fits() {
+    use crate::nds::TextureParams;
+
+    let mut db = test_db();
+    assert!(palette_fits(&db.textures[0], &db.palettes[0]));
+    assert!(!palette_fits(&db.textures[1], &db.palettes[2]));
+
+    // The offset counts against the block
+    db.palettes[0].off = 2;
+    assert!(!palette_fits(&db.textures[0], &db.palettes[0]));
+
+    // Only the colors the texels use are needed, not the whole 16
+    db.textures[1].data1 = vec![0x10; 32].into();
+    assert!(palette_fits(&db.textures[1], &db.palettes[2]));
+
+    // Compressed textures need as many colors as the highest palette index
+    // in data2 (in pairs) plus four
+    let tex = &mut db.textures[0];
+    tex.params = TextureParams(5 << 26);
+    tex.data2 = vec![0x00, 0x00, 0x01, 0x00].into();
+    assert!(!palette_fits(tex, &db.palettes[4]));
+    db.palettes[4].pal_block = std::sync::Arc::new(vec![0; 12].into_boxed_slice());
+    assert!(palette_fits(&db.textures[0], &db.palettes[4]));
+}
+
+#[test]
+fn test_manifest_entry() {
+    let db = test_db();
+    let entry = manifest_entry(&db, "grass.png", 0, Some(0));
+    assert_eq!(entry, object!(
+        "file" => "grass.png",
+        "texture" => "grass",
+        "texture_source" => "a.nsbtx",
+        "palette" => "grass_pl",
+        "palette_source" => "a.nsbtx",
+        "format" => "4-Color Palette Texture",
+        "format_id" => 2,
+        "width" => 8,
+        "height" => 8,
+        "is_color0_transparent" => true,
+        "repeat" => array!(true, false),
+        "mirror" => array!(false, false),
+    ));
+
+    let entry = manifest_entry(&db, "rock.png", 1, None);
+    assert!(entry["palette"].is_null() && entry["palette_source"].is_null());
+    assert_eq!(entry["format_id"], 3);
+}
+
+#[test]
+fn test_dump_encoded_texture() {
+    use crate::nds::{encode_texture, TextureFormat};
+    use crate::nitro::Name;
+    use std::sync::Arc;
+
+    // Two colors in a 16-color texture
+    let mut rgba = vec![];
+    for i in 0..64 {
+        rgba.extend_from_slice(if i % 3 == 0 { &[255, 0, 0, 255] } else { &[0, 0, 255, 255] });
+    }
+    let enc = encode_texture(&rgba, (8, 8), TextureFormat(3)).unwrap();
+
+    let mut db = Database::default();
+    db.file_paths = vec!["enc.nsbtx".into()];
+    db.textures.push(Texture {
+        name: Name::from_str_truncated("enc"),
+        params: enc.params,
+        data1: enc.data1.into(),
+        data2: enc.data2.into(),
+        unknown: 0,
+    });
+    db.textures_found_in = vec![0];
+    db.palettes.push(Palette {
+        name: Name::from_str_truncated("enc_pl"),
+        off: 0,
+        pal_block: Arc::new(enc.palette.into_boxed_slice()),
+        unknown: 0,
+    });
+    db.palettes_found_in = vec![0];
+    db.palettes_by_name.insert(db.palettes[0].name, vec![0]);
+
+    assert_eq!(matching_palettes(&db, 0), vec![0]);
+    let decoded = decode_texture(&db.textures[0], Some(&db.palettes[0])).unwrap();
+    assert_eq!(&decoded.0[..], &rgba[..]);
+}
@@ -0,0 +1,25 @@
+//! Angle helpers.
+
+use std::f64::consts::PI;
+
+/// Adjusts a sequence of angles (in radians) by multiples of 2π so that
+/// consecutive angles are close to each other and interpolating between them
+/// takes the short way around.
+pub fn unwrap_angles<'a, I: IntoIterator<Item = &'a mut f64>>(angles: I) {
+    let mut prev: Option<f64> = None;
+    for angle in angles {
+        if let Some(prev) = prev {
+            while *angle - prev > PI { *angle -= 2.0 * PI; }
+            while *angle - prev < -PI { *angle += 2.0 * PI; }
+        }
+        prev = Some(*angle);
+    }
+}
+
+#[test]
+fn test_unwrap_angles() {
+    let mut angles = [0.9 * PI, -0.9 * PI, -0.7 * PI];
+    unwrap_angles(&mut angles);
+    assert!((angles[1] - 1.1 * PI).abs() < 1e-9);
+    assert!((angles[2] - 1.3 * PI).abs() < 1e-9);
+}
@@ -5,7 +5,7 @@
 //! its low part.
 //!
 //! # Examples
-//! ```
+//! ```ignore
 //! let x = 0xabcdef00u32;
 //! assert_eq!(x.bits(8, 16), 0xef);
 //! assert_eq!(x.bits(16, 28), 0xbcd);
@@ -5,7 +5,7 @@
 //!
 //! # Examples
 //!
-//! ```
+//! ```ignore
 //! fields!(cur, MyName { // "MyName" is a human-readable name used only for logging
 //!     // Read a u32, placing its value in the variable x.
 //!     x: u32,
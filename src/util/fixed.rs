@@ -39,3 +39,42 @@ pub fn fix16(x: u16, sign_bits: u32, int_bits: u32, frac_bits: u32) -> f64 {
     assert!(sign_bits + int_bits + frac_bits <= 16);
     fix32(x as u32, sign_bits, int_bits, frac_bits)
 }
+
+/// Converts to a fixed-point number; the inverse of `fix32`. Rounds to the
+/// nearest representable value, clamping if it's out of range. Only the low
+/// `sign_bits + int_bits + frac_bits` bits of the result are used.
+pub fn to_fix32(x: f64, sign_bits: u32, int_bits: u32, frac_bits: u32) -> u32 {
+    assert!(sign_bits <= 1);
+    assert!(int_bits + frac_bits > 0);
+    assert!(sign_bits + int_bits + frac_bits <= 32);
+
+    let n = int_bits + frac_bits;
+    let (min, max) = if sign_bits == 0 {
+        (0, (1i64 << n) - 1)
+    } else {
+        (-(1i64 << n), (1i64 << n) - 1)
+    };
+    let y = (x * 2.0f64.powi(frac_bits as i32)).round() as i64;
+    let y = y.clamp(min, max) as u32;
+
+    let width = sign_bits + n;
+    if width == 32 { y } else { y & ((1 << width) - 1) }
+}
+
+/// Like `to_fix32` but for `u16`s.
+pub fn to_fix16(x: f64, sign_bits: u32, int_bits: u32, frac_bits: u32) -> u16 {
+    assert!(sign_bits + int_bits + frac_bits <= 16);
+    to_fix32(x, sign_bits, int_bits, frac_bits) as u16
+}
+
+#[test]
+fn test_to_fix() {
+    for &x in &[0u16, 1, 0x1000, 0x7fff, 0x8000, 0xffff, 0xf000] {
+        assert_eq!(to_fix16(fix16(x, 1, 3, 12), 1, 3, 12), x);
+    }
+    for &x in &[0u32, 0x1000, 0xffff_f000, 0x8000_0000, 0x7fff_ffff] {
+        assert_eq!(to_fix32(fix32(x, 1, 19, 12), 1, 19, 12), x);
+    }
+    assert_eq!(to_fix16(100.0, 1, 3, 12), 0x7fff);
+    assert_eq!(to_fix16(1.0 / 31.0, 0, 0, 5), 1);
+}
@@ -0,0 +1,77 @@
+//! Minimal glob patterns for filtering input files.
+//!
+//! `*` matches any run of characters except `/`, `**` matches any run of
+//! characters including `/`, and `?` matches any one character except `/`.
+//! Everything else matches itself.
+
+use std::path::Path;
+
+pub struct Glob {
+    pattern: Vec<char>,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        Glob { pattern: pattern.chars().collect() }
+    }
+
+    /// Whether the path matches. The path's components are joined with `/`. A
+    /// pattern with no `/` in it is matched against just the file name, so
+    /// `*.narc` matches a NARC in any directory.
+    pub fn matches(&self, path: &Path) -> bool {
+        let s: String =
+            if self.pattern.contains(&'/') {
+                path.components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/")
+            } else {
+                match path.file_name() {
+                    Some(name) => name.to_string_lossy().into_owned(),
+                    None => return false,
+                }
+            };
+        let s = s.chars().collect::<Vec<char>>();
+        glob_match(&self.pattern, &s)
+    }
+}
+
+fn glob_match(p: &[char], s: &[char]) -> bool {
+    match p.first() {
+        None => s.is_empty(),
+        Some('*') if p.get(1) == Some(&'*') => {
+            let rest = &p[2..];
+            // "**/" also matches no directories at all
+            if rest.first() == Some(&'/') && glob_match(&rest[1..], s) {
+                return true;
+            }
+            (0..=s.len()).any(|i| glob_match(rest, &s[i..]))
+        }
+        Some('*') => {
+            for i in 0..=s.len() {
+                if glob_match(&p[1..], &s[i..]) {
+                    return true;
+                }
+                if i < s.len() && s[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => !s.is_empty() && s[0] != '/' && glob_match(&p[1..], &s[1..]),
+        Some(&c) => !s.is_empty() && s[0] == c && glob_match(&p[1..], &s[1..]),
+    }
+}
+
+#[test]
+fn test_glob() {
+    let m = |pattern, path| Glob::new(pattern).matches(Path::new(path));
+    assert!(m("*.nsbmd", "data/pokemon/a.nsbmd"));
+    assert!(!m("*.nsbmd", "data/pokemon/a.nsbtx"));
+    assert!(m("data/*/a.nsb??", "data/pokemon/a.nsbmd"));
+    assert!(!m("data/*/a.nsbmd", "data/pokemon/x/a.nsbmd"));
+    assert!(m("data/**/a.nsbmd", "data/pokemon/x/a.nsbmd"));
+    assert!(m("data/**/a.nsbmd", "data/a.nsbmd"));
+    assert!(m("**/sound/**", "rom/data/sound/bgm/x.sdat"));
+    assert!(!m("**/sound/**", "rom/data/sounds/x.sdat"));
+}
@@ -1,5 +1,6 @@
 //! More-or-less general-purpose utility functions.
 
+pub mod angle;
 pub mod bits;
 pub mod cur;
 #[macro_use]
@@ -7,11 +8,15 @@ pub mod fields;
 pub mod bivec;
 pub mod bimap;
 pub mod fixed;
+pub mod glob;
 pub mod namers;
 pub mod view;
 pub mod out_dir;
+pub mod shared_bytes;
 pub mod tree;
+pub mod writer;
 
 pub use self::bivec::BiVec;
 pub use self::bimap::BiMap;
 pub use self::out_dir::OutDir;
+pub use self::shared_bytes::SharedBytes;
@@ -24,7 +24,12 @@ impl OutDir {
             }
             self.created = true;
         }
-        Ok(fs::File::create(self.path.join(filename))?)
+        let path = self.path.join(filename);
+        // The filename may contain subdirectories
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(fs::File::create(path)?)
     }
 
 }
@@ -0,0 +1,68 @@
+//! Byte buffers that many things can hold parts of.
+
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+/// A range of a shared byte buffer, like a whole file. Things parsed out of
+/// the buffer can keep a SharedBytes of their part of it instead of copying
+/// it; the buffer is freed once nothing refers to it.
+#[derive(Clone)]
+pub struct SharedBytes {
+    buf: Arc<dyn AsRef<[u8]> + Send + Sync>,
+    range: Range<usize>,
+}
+
+impl SharedBytes {
+    /// Shares all of buf.
+    pub fn new<B: AsRef<[u8]> + Send + Sync + 'static>(buf: B) -> SharedBytes {
+        let len = buf.as_ref().len();
+        SharedBytes { buf: Arc::new(buf), range: 0..len }
+    }
+
+    /// The part of this buffer that sub covers. sub must be a subslice of
+    /// this one (eg. one gotten from a Cur over it).
+    pub fn slice_of(&self, sub: &[u8]) -> SharedBytes {
+        let start = (sub.as_ptr() as usize).wrapping_sub(self.as_ptr() as usize);
+        assert!(start <= self.len() && sub.len() <= self.len() - start,
+            "slice_of: not a subslice");
+        let start = self.range.start + start;
+        SharedBytes { buf: Arc::clone(&self.buf), range: start..start + sub.len() }
+    }
+}
+
+impl Deref for SharedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &(*self.buf).as_ref()[self.range.clone()]
+    }
+}
+
+impl From<Vec<u8>> for SharedBytes {
+    fn from(v: Vec<u8>) -> SharedBytes {
+        SharedBytes::new(v)
+    }
+}
+
+impl Default for SharedBytes {
+    fn default() -> SharedBytes {
+        SharedBytes::new(Vec::new())
+    }
+}
+
+impl fmt::Debug for SharedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedBytes({} bytes)", self.len())
+    }
+}
+
+#[test]
+fn test_slice_of() {
+    let whole = SharedBytes::new((0..16).collect::<Vec<u8>>());
+    let part = whole.slice_of(&whole[4..10]);
+    assert_eq!(&part[..], &[4, 5, 6, 7, 8, 9]);
+    let part_of_part = part.slice_of(&part[2..4]);
+    assert_eq!(&part_of_part[..], &[6, 7]);
+    assert_eq!(part_of_part.slice_of(&part_of_part[2..]).len(), 0);
+}
@@ -0,0 +1,54 @@
+//! Writing little-endian binary data (the opposite of `Cur`).
+
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer { buf: vec![] }
+    }
+
+    /// Current position, ie. the number of bytes written so far.
+    pub fn pos(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn u8(&mut self, x: u8) {
+        self.buf.push(x);
+    }
+
+    pub fn u16(&mut self, x: u16) {
+        self.buf.extend_from_slice(&x.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, x: u32) {
+        self.buf.extend_from_slice(&x.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Pads with zeros until the position is a multiple of `n`.
+    pub fn align(&mut self, n: usize) {
+        while !self.buf.len().is_multiple_of(n) {
+            self.buf.push(0);
+        }
+    }
+
+    /// Overwrites a u16 written earlier (eg. a placeholder for an offset to
+    /// something that hadn't been written yet).
+    pub fn set_u16(&mut self, pos: usize, x: u16) {
+        self.buf[pos..pos + 2].copy_from_slice(&x.to_le_bytes());
+    }
+
+    /// Overwrites a u32 written earlier.
+    pub fn set_u32(&mut self, pos: usize, x: u32) {
+        self.buf[pos..pos + 4].copy_from_slice(&x.to_le_bytes());
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+}
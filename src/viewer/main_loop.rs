@@ -2,10 +2,10 @@ use glium::winit;
 use winit::dpi::{PhysicalSize, PhysicalPosition};
 use winit::keyboard::ModifiersState;
 use super::viewer::Viewer;
-use crate::db::Database;
+use crate::db::{Database, ModelId};
 use crate::connection::Connection;
 
-pub fn main_loop(db: Database, conn: Connection) {
+pub fn main_loop(db: Database, conn: Connection, start_model_id: ModelId) {
     let event_loop = winit::event_loop::EventLoop::builder()
         .build()
         .expect("event loop building");
@@ -14,7 +14,7 @@ pub fn main_loop(db: Database, conn: Connection) {
         .with_vsync(true)
         .build(&event_loop);
 
-    let mut viewer = Viewer::new(&display, db, conn);
+    let mut viewer = Viewer::new(&display, db, conn, start_model_id);
 
     struct State {
         last_mouse_xy: PhysicalPosition<f64>,
@@ -7,6 +7,7 @@ use crate::cli::Args;
 use crate::db::Database;
 use crate::connection::{Connection, ConnectionOptions};
 use crate::errors::Result;
+use crate::select::{NamePattern, Selection};
 
 /// Initial window width.
 pub static WINDOW_WIDTH: u32 = 640;
@@ -34,13 +35,31 @@ pub fn main(args: &Args) -> Result<()> {
         return Ok(());
     }
 
-    let conn_options = ConnectionOptions::from_cli_args(args);
-    let conn = Connection::build(&db, conn_options);
+    let conn_options = ConnectionOptions::from_cli_args(args)?;
+    let mut conn = Connection::build(&db, conn_options);
+
+    Selection::from_cli_args(args).filter_animations(&db, &mut conn);
+
+    let start_model_id = match args.get_opt("start") {
+        None => 0,
+        Some(s) => {
+            let pattern = NamePattern::new(&s.to_string_lossy());
+            let model_id = (0..db.models.len())
+                .find(|&id| pattern.matches(id, &db.models[id].name));
+            match model_id {
+                Some(id) => id,
+                None => {
+                    warn!("no model matching {}, starting at the first one", pattern);
+                    0
+                }
+            }
+        }
+    };
 
     // Print the controls
     println!("{}", viewer::CONTROL_HELP);
 
-    main_loop::main_loop(db, conn);
+    main_loop::main_loop(db, conn, start_model_id);
 
     Ok(())
 }
@@ -7,6 +7,7 @@ use cgmath::{PerspectiveFov, Rad, Matrix4};
 use crate::db::Database;
 use glium::{VertexBuffer, IndexBuffer, Frame, Surface, Program};
 use crate::nitro::Model;
+use crate::nitro::material_color_animation::MaterialColors;
 use crate::primitives::{Primitives, DrawCall, Vertex};
 use self::texture_cache::{TextureCache, ImageId};
 use super::{Z_NEAR, Z_FAR, FOV_Y};
@@ -149,7 +150,7 @@ impl ModelViewer {
         }
     }
 
-    pub fn draw(&self, target: &mut Frame, model: &Model) {
+    pub fn draw(&self, target: &mut Frame, model: &Model, mat_colors: &[MaterialColors]) {
         // Do nothing if there isn't vertex/index data
         let vertex_buffer = match self.vertex_buffer {
             Some(ref vb) => vb,
@@ -174,6 +175,7 @@ impl ModelViewer {
         // Do each draw call
         for call in &self.draw_calls {
             let material = &model.materials[call.mat_id as usize];
+            let colors = &mat_colors[call.mat_id as usize];
 
             let texture = match self.material_map.get(call.mat_id as usize) {
                 Some(&MaterialTextureBinding::None) =>
@@ -230,7 +232,7 @@ impl ModelViewer {
             if !call.used_normals || !self.light_on {
                 let uniforms = uniform! {
                     matrix: model_view_persp,
-                    alpha: material.alpha,
+                    alpha: colors.alpha,
                     tex: sampler,
                 };
                 target.draw(
@@ -245,10 +247,10 @@ impl ModelViewer {
                     matrix: model_view_persp,
                     light_vec: [0.0, -0.624695, -0.78086877f32],
                     light_color: [1.0, 1.0, 1.0f32],
-                    diffuse_color: material.diffuse,
-                    ambient_color: material.ambient,
-                    emission_color: material.emission,
-                    alpha: material.alpha,
+                    diffuse_color: colors.diffuse,
+                    ambient_color: colors.ambient,
+                    emission_color: colors.emission,
+                    alpha: colors.alpha,
                     tex: sampler,
                 };
                 target.draw(
@@ -1,11 +1,15 @@
 use std::ops::Range;
 use super::model_viewer::{ModelViewer, MaterialTextureBinding};
-use crate::db::{Database, ModelId, AnimationId, PatternId, MatAnimId, FileId};
+use crate::db::{Database, ModelId, AnimationId, PatternId, MatAnimId, MatColorAnimId, VisAnimId, FileId};
 use crate::connection::Connection;
 use glium::{Frame, Surface};
 use glium::winit;
 use winit::keyboard::{KeyCode, ModifiersState};
-use crate::nitro::{Model, Animation, Pattern, MaterialAnimation};
+use crate::nitro::{
+    Model, Animation, Pattern, MaterialAnimation, MaterialColorAnimation,
+    VisibilityAnimation,
+};
+use crate::nitro::material_color_animation::MaterialColors;
 use crate::primitives::{Primitives, PolyType, DynamicState};
 use cgmath::{Matrix4, InnerSpace, Vector3, vec3, vec2};
 use super::fps::FpsCounter;
@@ -27,11 +31,19 @@ pub struct Viewer {
     /// Current UV transform matrices for each material (changed as
     /// MaterialAnimation plays).
     uv_mats: Vec<Matrix4<f64>>,
+    /// Current colors for each material (changed as MaterialColorAnimation
+    /// plays).
+    mat_colors: Vec<MaterialColors>,
+    /// Current visibility of each object (changed as VisibilityAnimation
+    /// plays).
+    visibility: Vec<bool>,
 
     // States for each different kind of animation.
     anim_state: AnimState,
     pat_state: AnimState,
     mat_anim_state: AnimState,
+    mat_color_anim_state: AnimState,
+    vis_anim_state: AnimState,
 
     /// Accumulator for time.
     time_acc: f64,
@@ -120,17 +132,19 @@ pub static CONTROL_HELP: &'static str =
         "  OP           Prev/Next Animation           (+Alt to single-step instead)\n",
         "  KL           Prev/Next Pattern Animation   (+Alt to single-step instead)\n",
         "  ;'           Prev/Next Material Animation  (+Alt to single-step instead)\n",
+        "  []           Prev/Next Color Animation     (+Alt to single-step instead)\n",
+        "  NM           Prev/Next Visibility Animation (+Alt to single-step instead)\n",
         "  Space        Print Info\n",
         "  T            Toggle Lights                 (Models with normals only)\n"
     );
 
 
 impl Viewer {
-    pub fn new(display: &Display, db: Database, conn: Connection) -> Viewer {
+    pub fn new(display: &Display, db: Database, conn: Connection, start_model_id: ModelId) -> Viewer {
         let model_viewer = ModelViewer::new(&display);
 
-        // Create a viewer for model 0
-        assert!(db.models.len() > 0);
+        // Create a viewer for the starting model
+        assert!(start_model_id < db.models.len());
         let mut viewer = Viewer {
             db,
             conn,
@@ -139,15 +153,19 @@ impl Viewer {
             object_mats: vec![],
             material_map: vec![],
             uv_mats: vec![],
+            mat_colors: vec![],
+            visibility: vec![],
             anim_state: AnimState::none(),
             pat_state: AnimState::none(),
             mat_anim_state: AnimState::none(),
+            mat_color_anim_state: AnimState::none(),
+            vis_anim_state: AnimState::none(),
             time_acc: 0.0,
             fps_counter: FpsCounter::new(),
             move_vector: vec3(0.0, 0.0, 0.0),
             speed_idx: DEFAULT_SPEED_IDX,
         };
-        viewer.change_model(display, 0);
+        viewer.change_model(display, start_model_id);
         viewer
     }
 
@@ -179,13 +197,19 @@ impl Viewer {
             if !self.mat_anim_state.single_stepping {
                 self.next_mat_anim_frame();
             }
+            if !self.mat_color_anim_state.single_stepping {
+                self.next_mat_color_anim_frame();
+            }
+            if !self.vis_anim_state.single_stepping {
+                self.next_vis_anim_frame();
+            }
             self.time_acc -= FRAMERATE;
         }
     }
 
     pub fn draw(&self, frame: &mut Frame) {
         frame.clear_color_srgb_and_depth(BG_COLOR, 1.0);
-        self.model_viewer.draw(frame, self.cur_model(&self.db))
+        self.model_viewer.draw(frame, self.cur_model(&self.db), &self.mat_colors)
     }
 
     /// Handle key press/release events.
@@ -297,6 +321,50 @@ impl Viewer {
                 self.prev_mat_anim_frame();
             }
 
+            // Next/prev material color animation
+            Key::BracketRight if !alt => {
+                let num_mat_color_anims = self.conn.models[self.model_id].mat_color_anims.len();
+                self.mat_color_anim_state.next(num_mat_color_anims);
+                self.update_mat_colors();
+            }
+            Key::BracketLeft if !alt => {
+                let num_mat_color_anims = self.conn.models[self.model_id].mat_color_anims.len();
+                self.mat_color_anim_state.prev(num_mat_color_anims);
+                self.update_mat_colors();
+            }
+
+            // Single-step material color animation
+            Key::BracketRight if alt => {
+                self.mat_color_anim_state.single_stepping = true;
+                self.next_mat_color_anim_frame();
+            }
+            Key::BracketLeft if alt => {
+                self.mat_color_anim_state.single_stepping = true;
+                self.prev_mat_color_anim_frame();
+            }
+
+            // Next/prev visibility animation
+            Key::KeyM if !alt => {
+                let num_vis_anims = self.conn.models[self.model_id].vis_anims.len();
+                self.vis_anim_state.next(num_vis_anims);
+                self.update_visibility();
+            }
+            Key::KeyN if !alt => {
+                let num_vis_anims = self.conn.models[self.model_id].vis_anims.len();
+                self.vis_anim_state.prev(num_vis_anims);
+                self.update_visibility();
+            }
+
+            // Single-step visibility animation
+            Key::KeyM if alt => {
+                self.vis_anim_state.single_stepping = true;
+                self.next_vis_anim_frame();
+            }
+            Key::KeyN if alt => {
+                self.vis_anim_state.single_stepping = true;
+                self.prev_vis_anim_frame();
+            }
+
             // Speed up/down
             Key::ShiftLeft => {
                 if self.speed_idx != SPEEDS.len() - 1 {
@@ -378,6 +446,30 @@ impl Viewer {
         } else {
             write!(s, "No Material Animation === ").unwrap()
         }
+        if let Some(mat_color_anim_id) = self.mat_color_anim_id() {
+            let mat_color_anim = self.cur_mat_color_anim(&self.db).unwrap();
+            write!(s, "{anim_name}[{anim_id}/{num_anims}] ({cur_frame}/{num_frames}) === ",
+                anim_name = mat_color_anim.name,
+                anim_id = mat_color_anim_id,
+                num_anims = self.db.mat_color_anims.len(),
+                cur_frame = self.mat_color_anim_state.frame,
+                num_frames = mat_color_anim.num_frames,
+            ).unwrap()
+        } else {
+            write!(s, "No Color Animation === ").unwrap()
+        }
+        if let Some(vis_anim_id) = self.vis_anim_id() {
+            let vis_anim = self.cur_vis_anim(&self.db).unwrap();
+            write!(s, "{anim_name}[{anim_id}/{num_anims}] ({cur_frame}/{num_frames}) === ",
+                anim_name = vis_anim.name,
+                anim_id = vis_anim_id,
+                num_anims = self.db.vis_anims.len(),
+                cur_frame = self.vis_anim_state.frame,
+                num_frames = vis_anim.num_frames,
+            ).unwrap()
+        } else {
+            write!(s, "No Visibility Animation === ").unwrap()
+        }
         write!(s, "{:5.2}fps", self.fps_counter.fps()).unwrap();
     }
 
@@ -430,6 +522,30 @@ impl Viewer {
             println!("No Material Animation Playing")
         }
 
+        if let Some(mat_color_anim_id) = self.mat_color_anim_id() {
+            let mat_color_anim = self.cur_mat_color_anim(&self.db).unwrap();
+            println!("Material Color Animation: {:?} [{}/{}]",
+                mat_color_anim.name,
+                mat_color_anim_id,
+                self.db.mat_color_anims.len(),
+            );
+            println!("Found in file: {}", self.db.file_paths[self.db.mat_color_anims_found_in[mat_color_anim_id]].display());
+        } else {
+            println!("No Material Color Animation Playing")
+        }
+
+        if let Some(vis_anim_id) = self.vis_anim_id() {
+            let vis_anim = self.cur_vis_anim(&self.db).unwrap();
+            println!("Visibility Animation: {:?} [{}/{}]",
+                vis_anim.name,
+                vis_anim_id,
+                self.db.vis_anims.len(),
+            );
+            println!("Found in file: {}", self.db.file_paths[self.db.vis_anims_found_in[vis_anim_id]].display());
+        } else {
+            println!("No Visibility Animation Playing")
+        }
+
         println!();
     }
 
@@ -462,12 +578,18 @@ impl Viewer {
         self.anim_state = AnimState::none();
         self.pat_state = AnimState::none();
         self.mat_anim_state = AnimState::none();
+        self.mat_color_anim_state = AnimState::none();
+        self.vis_anim_state = AnimState::none();
 
         self.model_id = model_id;
         self.reset_state_from_model();
         self.update_material_map(display);
 
-        let state = DynamicState { objects: &self.object_mats, uv_mats: &self.uv_mats };
+        let state = DynamicState {
+            objects: &self.object_mats,
+            uv_mats: &self.uv_mats,
+            visibility: &self.visibility,
+        };
         let prims = Primitives::build(self.cur_model(&self.db), PolyType::Tris, state);
         self.model_viewer.change_model(display, &self.db, prims, self.material_map.clone());
     }
@@ -502,10 +624,26 @@ impl Viewer {
         }
     }
 
+    fn reset_mat_colors_from_model(&mut self) {
+        self.mat_colors.clear();
+        for material in &self.cur_model(&self.db).materials {
+            self.mat_colors.push(MaterialColors::from_material(material))
+        }
+    }
+
+    fn reset_visibility_from_model(&mut self) {
+        self.visibility.clear();
+        for obj in &self.cur_model(&self.db).objects {
+            self.visibility.push(obj.visible);
+        }
+    }
+
     fn reset_state_from_model(&mut self) {
         self.reset_object_mats_from_model();
         self.reset_material_map_from_model();
         self.reset_uv_mats_from_model();
+        self.reset_mat_colors_from_model();
+        self.reset_visibility_from_model();
     }
 
     // Update dynamic state to match the current animation state.
@@ -539,14 +677,9 @@ impl Viewer {
 
                 let pat_conn_idx = self.pat_state.connection_idx.unwrap();
                 let pat_conn = &self.conn.models[self.model_id].patterns[pat_conn_idx];
-                let (texture_id, palette_id) = (
-                    pat_conn.texture_ids[texture_idx as usize],
-                    pat_conn.palette_ids[palette_idx as usize],
-                );
-
-                self.material_map[mat_id] = match (texture_id, palette_id) {
-                    (Some(t), Some(p)) => MaterialTextureBinding::ImageId((t, Some(p))),
-                    _ => MaterialTextureBinding::Missing,
+                self.material_map[mat_id] = match pat_conn.image_id(texture_idx, palette_idx) {
+                    Some(image_id) => MaterialTextureBinding::ImageId(image_id),
+                    None => MaterialTextureBinding::Missing,
                 };
             }
         }
@@ -556,22 +689,60 @@ impl Viewer {
     fn update_uv_mats(&mut self) {
         self.reset_uv_mats_from_model();
         if let Some(mat_anim) = self.cur_mat_anim(&self.db) {
+            let model = self.cur_model(&self.db);
             for track in &mat_anim.tracks {
-                for (i, mat) in self.cur_model(&self.db).materials.iter().enumerate() {
+                for (i, mat) in model.materials.iter().enumerate() {
+                    if mat.name == track.name {
+                        self.uv_mats[i] = track.eval_uv_mat(
+                            self.mat_anim_state.frame,
+                            model.tex_mtx_mode,
+                            (mat.width, mat.height),
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+        self.update_vertices();
+    }
+
+    fn update_mat_colors(&mut self) {
+        self.reset_mat_colors_from_model();
+        if let Some(mat_color_anim) = self.cur_mat_color_anim(&self.db) {
+            let model = self.cur_model(&self.db);
+            for track in &mat_color_anim.tracks {
+                for (i, mat) in model.materials.iter().enumerate() {
                     if mat.name == track.name {
-                        self.uv_mats[i] = track.eval_uv_mat(self.mat_anim_state.frame);
+                        self.mat_colors[i] = track.sample(
+                            self.mat_color_anim_state.frame,
+                            self.mat_colors[i],
+                        );
                         break;
                     }
                 }
             }
         }
+    }
+
+    fn update_visibility(&mut self) {
+        self.reset_visibility_from_model();
+        if let Some(vis_anim) = self.cur_vis_anim(&self.db) {
+            for i in 0..self.visibility.len() {
+                if i >= vis_anim.num_objects as usize { break }
+                self.visibility[i] = vis_anim.is_visible(self.vis_anim_state.frame, i);
+            }
+        }
         self.update_vertices();
     }
 
     /// Updates the vertices of the current model (eg. because an animation or
     /// material animation has advanced).
     fn update_vertices(&mut self) {
-        let state = DynamicState { objects: &self.object_mats, uv_mats: &self.uv_mats };
+        let state = DynamicState {
+            objects: &self.object_mats,
+            uv_mats: &self.uv_mats,
+            visibility: &self.visibility,
+        };
         let prims = Primitives::build(self.cur_model(&self.db), PolyType::Tris, state);
         self.model_viewer.update_vertices(&prims.vertices);
     }
@@ -624,6 +795,34 @@ impl Viewer {
         }
     }
 
+    pub fn next_mat_color_anim_frame(&mut self) {
+        if let Some(mat_color_anim) = self.cur_mat_color_anim(&self.db) {
+            self.mat_color_anim_state.frame = next_u16(self.mat_color_anim_state.frame, 0..mat_color_anim.num_frames);
+            self.update_mat_colors();
+        }
+    }
+
+    pub fn prev_mat_color_anim_frame(&mut self) {
+        if let Some(mat_color_anim) = self.cur_mat_color_anim(&self.db) {
+            self.mat_color_anim_state.frame = prev_u16(self.mat_color_anim_state.frame, 0..mat_color_anim.num_frames);
+            self.update_mat_colors();
+        }
+    }
+
+    pub fn next_vis_anim_frame(&mut self) {
+        if let Some(vis_anim) = self.cur_vis_anim(&self.db) {
+            self.vis_anim_state.frame = next_u16(self.vis_anim_state.frame, 0..vis_anim.num_frames);
+            self.update_visibility();
+        }
+    }
+
+    pub fn prev_vis_anim_frame(&mut self) {
+        if let Some(vis_anim) = self.cur_vis_anim(&self.db) {
+            self.vis_anim_state.frame = prev_u16(self.vis_anim_state.frame, 0..vis_anim.num_frames);
+            self.update_visibility();
+        }
+    }
+
     pub fn set_aspect_ratio(&mut self, aspect_ratio: f64) {
         self.model_viewer.aspect_ratio = aspect_ratio as f32;
     }
@@ -645,6 +844,14 @@ impl Viewer {
         Some(&db.mat_anims[self.mat_anim_id()?])
     }
 
+    fn cur_mat_color_anim<'a>(&self, db: &'a Database) -> Option<&'a MaterialColorAnimation> {
+        Some(&db.mat_color_anims[self.mat_color_anim_id()?])
+    }
+
+    fn cur_vis_anim<'a>(&self, db: &'a Database) -> Option<&'a VisibilityAnimation> {
+        Some(&db.vis_anims[self.vis_anim_id()?])
+    }
+
     fn animation_id(&self) -> Option<AnimationId> {
         let idx = self.anim_state.connection_idx?;
         Some(self.conn.models[self.model_id].animations[idx])
@@ -660,6 +867,16 @@ impl Viewer {
         Some(self.conn.models[self.model_id].mat_anims[idx].mat_anim_id)
     }
 
+    fn mat_color_anim_id(&self) -> Option<MatColorAnimId> {
+        let idx = self.mat_color_anim_state.connection_idx?;
+        Some(self.conn.models[self.model_id].mat_color_anims[idx])
+    }
+
+    fn vis_anim_id(&self) -> Option<VisAnimId> {
+        let idx = self.vis_anim_state.connection_idx?;
+        Some(self.conn.models[self.model_id].vis_anims[idx])
+    }
+
     pub fn speed(&self) -> f32 {
         SPEEDS[self.speed_idx]
     }